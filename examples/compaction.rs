@@ -1,5 +1,5 @@
 use std::error::Error;
-use zaichik;
+use zaichik::protocol::CompactionMode;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -12,7 +12,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut consumer = zaichik::Client::connect(&format!("127.0.0.1:{}", port)).await?;
 
     producer
-        .create_topic("hello".to_string(), 0, 10_000)
+        .create_topic("hello".to_string(), 0, 10_000, 0, 0, CompactionMode::Dedup)
         .await?;
 
     // Запишем в hello 100 сообщений
@@ -36,17 +36,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     consumer.subscribe_on("hello".to_string()).await?;
 
-    let message = consumer.read_message().await?;
-    consumer.commit().await?;
-    println!("Result is {:?}", message);
+    if let Some(message) = consumer.read_message().await? {
+        if let zaichik::ZaichikFrame::Publish { id, .. } = message {
+            consumer.commit(id).await?;
+        }
+        println!("Result is {:?}", message);
+    }
 
-    let message1 = consumer.read_message().await?;
-    consumer.commit().await?;
-    println!("Result is {:?}", message1);
+    if let Some(message1) = consumer.read_message().await? {
+        if let zaichik::ZaichikFrame::Publish { id, .. } = message1 {
+            consumer.commit(id).await?;
+        }
+        println!("Result is {:?}", message1);
+    }
 
     // В выводе на экран можно увидеть, что мы пропустили дублированные сообщения
-    // Result is Publish { topic: "hello", key: Some("key1"), payload: [109, 101, 115, 115, 97, 103, 101] }
-    // Result is Publish { topic: "hello", key: Some("key2"), payload: [109, 101, 115, 115, 97, 103, 101, 49] }
+    // Result is Publish { topic: "hello", key: Some("key1"), payload: [109, 101, 115, 115, 97, 103, 101], headers: {}, id: 0 }
+    // Result is Publish { topic: "hello", key: Some("key2"), payload: [109, 101, 115, 115, 97, 103, 101, 49], headers: {}, id: 1 }
 
     Ok(())
 }
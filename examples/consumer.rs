@@ -0,0 +1,26 @@
+use std::error::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let port = std::env::vars()
+        .find(|(key, _value)| key == "PORT")
+        .map(|(_key, value)| value)
+        .unwrap_or_else(|| "8889".to_string());
+
+    // Consumer читает сообщения, которые опубликовал пример producer.
+    let mut consumer = zaichik::Consumer::connect(&format!("127.0.0.1:{}", port)).await?;
+    consumer.subscribe_on("producer".to_string()).await?;
+
+    for _ in 0..5 {
+        match consumer.read_message_checked().await? {
+            Some(zaichik::ZaichikFrame::Publish { id, payload, .. }) => {
+                println!("Result is {:?}", String::from_utf8_lossy(&payload));
+                consumer.commit(id).await?;
+            }
+            Some(other) => println!("Unexpected frame {:?}", other),
+            None => break,
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,4 @@
 use std::error::Error;
-use zaichik;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -20,9 +19,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
         )
         .await?;
 
-    let result = client.read_message().await?;
-
-    println!("Result is {:?}", result);
+    // read_message вернет None, если брокер закрыл соединение.
+    if let Some(result) = client.read_message().await? {
+        println!("Result is {:?}", result);
+    }
 
     client.close().await?;
 
@@ -0,0 +1,37 @@
+use std::error::Error;
+use zaichik::protocol::CompactionMode;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let port = std::env::vars()
+        .find(|(key, _value)| key == "PORT")
+        .map(|(_key, value)| value)
+        .unwrap_or_else(|| "8889".to_string());
+
+    // У Producer нет методов подписки, так что это соединение только публикует.
+    let mut producer = zaichik::Producer::connect(&format!("127.0.0.1:{}", port)).await?;
+    producer
+        .create_topic(
+            "producer".to_string(),
+            10_000,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+        )
+        .await?;
+
+    for i in 0..5 {
+        producer
+            .publish(
+                "producer".to_string(),
+                None,
+                format!("message{}", i).into_bytes(),
+            )
+            .await?;
+    }
+
+    producer.shutdown().await?;
+
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use futures::SinkExt;
+use std::error::Error;
+use tokio::stream::StreamExt;
+use zaichik::protocol::CompactionMode;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let port = std::env::vars()
+        .find(|(key, _value)| key == "PORT")
+        .map(|(_key, value)| value)
+        .unwrap_or_else(|| "8889".to_string());
+
+    let mut producer = zaichik::Client::connect(&format!("127.0.0.1:{}", port)).await?;
+    producer
+        .create_topic(
+            "publisher".to_string(),
+            10_000,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+        )
+        .await?;
+
+    // Вместо publish на каждое сообщение отправляем весь стрим в sink топика.
+    // send_all сбросит буфер соединения, когда стрим закончится.
+    let mut payloads =
+        futures::stream::iter((0..5).map(|i| Ok(format!("message{}", i).into_bytes())));
+    producer
+        .publisher("publisher".to_string())
+        .send_all(&mut payloads)
+        .await?;
+
+    let mut consumer = zaichik::Client::connect(&format!("127.0.0.1:{}", port)).await?;
+    consumer.subscribe_on("publisher".to_string()).await?;
+
+    let mut messages = consumer.into_stream().take(5);
+    while let Some(message) = messages.next().await {
+        println!("Result is {:?}", message?);
+    }
+
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use std::error::Error;
-use zaichik;
+use zaichik::protocol::CompactionMode;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -11,7 +11,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut producer = zaichik::Client::connect(&format!("127.0.0.1:{}", port)).await?;
 
     producer
-        .create_topic("hello".to_string(), 10_000, 0)
+        .create_topic("hello".to_string(), 10_000, 0, 0, 0, CompactionMode::Dedup)
         .await?;
 
     producer
@@ -27,9 +27,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     consumer.subscribe_on("hello".to_string()).await?;
 
-    let message = consumer.read_message().await?;
-    consumer.commit().await?;
-    println!("Result is {:?}", message);
+    if let Some(message) = consumer.read_message().await? {
+        if let zaichik::ZaichikFrame::Publish { id, .. } = message {
+            consumer.commit(id).await?;
+        }
+        println!("Result is {:?}", message);
+    }
 
     Ok(())
 }
@@ -0,0 +1,49 @@
+use std::error::Error;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let port = std::env::vars()
+        .find(|(key, _value)| key == "PORT")
+        .map(|(_key, value)| value)
+        .unwrap_or_else(|| "8889".to_string());
+    let addr = format!("127.0.0.1:{}", port);
+
+    // Эхо-сервис: отвечает на каждый запрос тем же payload.
+    let mut responder = zaichik::Client::connect(&addr).await?;
+    responder
+        .subscribe_with(
+            "echo.requests".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await?;
+    tokio::spawn(async move {
+        while let Ok(Some(request)) = responder.read_message().await {
+            if let zaichik::ZaichikFrame::Publish {
+                id, ref payload, ..
+            } = request
+            {
+                let _ = responder.reply(&request, payload.clone()).await;
+                let _ = responder.commit(id).await;
+            }
+        }
+    });
+
+    let mut client = zaichik::Client::connect(&addr).await?;
+    let reply = client
+        .request(
+            "echo.requests".to_string(),
+            "hello".to_string().into_bytes(),
+            "echo.replies".to_string(),
+            Duration::from_secs(5),
+        )
+        .await?;
+
+    if let zaichik::ZaichikFrame::Publish { payload, .. } = reply {
+        println!("Reply is {:?}", String::from_utf8_lossy(&payload));
+    }
+
+    client.close().await?;
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use std::error::Error;
+use tokio::stream::StreamExt;
+use zaichik::protocol::CompactionMode;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let port = std::env::vars()
+        .find(|(key, _value)| key == "PORT")
+        .map(|(_key, value)| value)
+        .unwrap_or_else(|| "8889".to_string());
+
+    let mut producer = zaichik::Client::connect(&format!("127.0.0.1:{}", port)).await?;
+
+    // Топик с retention, чтобы консьюмер получил сообщения,
+    // даже если подпишется позже продьюсера.
+    producer
+        .create_topic("stream".to_string(), 10_000, 0, 0, 0, CompactionMode::Dedup)
+        .await?;
+
+    for i in 0..5 {
+        producer
+            .publish(
+                "stream".to_string(),
+                None,
+                format!("message{}", i).into_bytes(),
+            )
+            .await?;
+    }
+
+    let mut consumer = zaichik::Client::connect(&format!("127.0.0.1:{}", port)).await?;
+    consumer.subscribe_on("stream".to_string()).await?;
+
+    // Стрим сам отправляет Commit, поэтому мы можем просто
+    // взять первые три сообщения.
+    let mut messages = consumer.into_stream().take(3);
+
+    while let Some(message) = messages.next().await {
+        println!("Result is {:?}", message?);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use std::error::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let port = std::env::vars()
+        .find(|(key, _value)| key == "PORT")
+        .map(|(_key, value)| value)
+        .unwrap_or_else(|| "8889".to_string());
+
+    let mut client = zaichik::Client::connect(&format!("127.0.0.1:{}", port)).await?;
+
+    client.subscribe_on("first".to_string()).await?;
+    client.subscribe_on("second".to_string()).await?;
+
+    // Пишем по сообщению в каждый топик и читаем оба.
+    for topic in &["first", "second"] {
+        client
+            .publish(topic.to_string(), None, "message".to_string().into_bytes())
+            .await?;
+    }
+
+    for _ in 0..2 {
+        if let Some(message) = client.read_message().await? {
+            if let zaichik::ZaichikFrame::Publish { id, .. } = message {
+                client.commit(id).await?;
+            }
+            println!("Result is {:?}", message);
+        }
+    }
+
+    // Отписываемся от первого топика. Сообщения из него больше
+    // не должны до нас доходить.
+    client.unsubscribe("first".to_string()).await?;
+
+    client
+        .publish(
+            "first".to_string(),
+            None,
+            "skipped".to_string().into_bytes(),
+        )
+        .await?;
+    client
+        .publish(
+            "second".to_string(),
+            None,
+            "delivered".to_string().into_bytes(),
+        )
+        .await?;
+
+    if let Some(message) = client.read_message().await? {
+        if let zaichik::ZaichikFrame::Publish { id, .. } = message {
+            client.commit(id).await?;
+        }
+        println!("Result is {:?}", message);
+
+        match message {
+            zaichik::ZaichikFrame::Publish { topic, .. } => assert_eq!("second", topic),
+            frame => panic!("Unexpected frame {:?}", frame),
+        }
+    }
+
+    client.close().await?;
+
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// Списки пользователей, которым разрешено читать и писать в топик.
+// Если для топика нет списка на нужный вид доступа, то доступ открыт всем.
+// Если список есть, то подключения без аутентификации в него не попадают.
+// Админские фреймы, в отличие от топиков, закрыты всем, кроме пользователей из admins.
+#[derive(Clone, Debug, Default)]
+pub struct AclRules {
+    readers: HashMap<String, HashSet<String>>,
+    writers: HashMap<String, HashSet<String>>,
+    admins: HashSet<String>,
+}
+
+impl AclRules {
+    pub fn new() -> AclRules {
+        AclRules {
+            readers: HashMap::new(),
+            writers: HashMap::new(),
+            admins: HashSet::new(),
+        }
+    }
+
+    // Разбираем настройку вида "orders:read:alice,bob;orders:write:alice;admin:carol".
+    pub fn from_spec(spec: &str) -> Result<AclRules, String> {
+        let mut rules = AclRules::new();
+
+        for rule in spec
+            .split(';')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+        {
+            let parts = rule.splitn(3, ':').collect::<Vec<_>>();
+            let (topic, access, principals) = match parts.as_slice() {
+                [topic, access, principals] if !topic.is_empty() => (*topic, *access, *principals),
+                ["admin", principals] => {
+                    for principal in principals.split(',').map(str::trim) {
+                        if !principal.is_empty() {
+                            rules.allow_admin(principal);
+                        }
+                    }
+                    continue;
+                }
+                _ => {
+                    return Err(format!(
+                        "Expected topic:access:principals or admin:principals, got {}",
+                        rule
+                    ))
+                }
+            };
+
+            let access = match access {
+                "read" => Access::Read,
+                "write" => Access::Write,
+                _ => return Err(format!("Access should be read or write, got {}", access)),
+            };
+
+            for principal in principals.split(',').map(str::trim) {
+                if !principal.is_empty() {
+                    rules.allow(topic, principal, access);
+                }
+            }
+        }
+
+        Ok(rules)
+    }
+
+    pub fn allow(&mut self, topic: &str, principal: &str, access: Access) {
+        let allowed = match access {
+            Access::Read => &mut self.readers,
+            Access::Write => &mut self.writers,
+        };
+
+        allowed
+            .entry(topic.to_string())
+            .or_default()
+            .insert(principal.to_string());
+    }
+
+    pub fn allow_admin(&mut self, principal: &str) {
+        self.admins.insert(principal.to_string());
+    }
+
+    pub fn is_admin(&self, principal: Option<&str>) -> bool {
+        principal.is_some_and(|principal| self.admins.contains(principal))
+    }
+
+    pub fn is_allowed(&self, principal: Option<&str>, topic: &str, access: Access) -> bool {
+        let allowed = match access {
+            Access::Read => &self.readers,
+            Access::Write => &self.writers,
+        };
+
+        match (allowed.get(topic), principal) {
+            (None, _) => true,
+            (Some(principals), Some(principal)) => principals.contains(principal),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_and_write_are_checked_separately() {
+        let rules = AclRules::from_spec("orders:read:alice,bob; orders:write:alice").unwrap();
+
+        assert!(rules.is_allowed(Some("alice"), "orders", Access::Read));
+        assert!(rules.is_allowed(Some("alice"), "orders", Access::Write));
+        assert!(rules.is_allowed(Some("bob"), "orders", Access::Read));
+        assert!(!rules.is_allowed(Some("bob"), "orders", Access::Write));
+        assert!(!rules.is_allowed(None, "orders", Access::Read));
+    }
+
+    #[test]
+    fn test_topics_without_rules_are_open() {
+        let mut rules = AclRules::new();
+        rules.allow("orders", "alice", Access::Write);
+
+        assert!(rules.is_allowed(Some("bob"), "events", Access::Write));
+        assert!(rules.is_allowed(None, "events", Access::Read));
+        assert!(rules.is_allowed(Some("bob"), "orders", Access::Read));
+    }
+
+    #[test]
+    fn test_admins_are_listed_explicitly() {
+        let rules = AclRules::from_spec("orders:write:alice;admin:carol, dave").unwrap();
+
+        assert!(rules.is_admin(Some("carol")));
+        assert!(rules.is_admin(Some("dave")));
+        assert!(!rules.is_admin(Some("alice")));
+        assert!(!rules.is_admin(None));
+        assert!(!AclRules::new().is_admin(Some("carol")));
+    }
+
+    #[test]
+    fn test_from_spec_rejects_unknown_access() {
+        assert!(AclRules::from_spec("orders:delete:alice").is_err());
+        assert!(AclRules::from_spec("orders:alice").is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+// Набор токенов, с которыми клиенты могут подключиться к брокеру.
+// Каждому токену соответствует имя пользователя (principal), от лица
+// которого работает подключение.
+#[derive(Clone, Debug, Default)]
+pub struct Authenticator {
+    principals_by_token: HashMap<String, String>,
+}
+
+impl Authenticator {
+    pub fn new() -> Authenticator {
+        Authenticator {
+            principals_by_token: HashMap::new(),
+        }
+    }
+
+    // Разбираем настройку вида "alice:token1,bob:token2".
+    pub fn from_spec(spec: &str) -> Result<Authenticator, String> {
+        let mut authenticator = Authenticator::new();
+
+        for entry in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            match entry.split_once(':') {
+                Some((principal, token)) if !principal.is_empty() && !token.is_empty() => {
+                    authenticator.add_token(principal.to_string(), token.to_string());
+                }
+                _ => return Err(format!("Expected principal:token, got {}", entry)),
+            }
+        }
+
+        Ok(authenticator)
+    }
+
+    pub fn add_token(&mut self, principal: String, token: String) {
+        self.principals_by_token.insert(token, principal);
+    }
+
+    // Возвращает имя пользователя, если токен нам известен.
+    pub fn authenticate(&self, token: &str) -> Option<&str> {
+        self.principals_by_token.get(token).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_spec() {
+        let authenticator = Authenticator::from_spec("alice:secret, bob:other").unwrap();
+
+        assert_eq!(Some("alice"), authenticator.authenticate("secret"));
+        assert_eq!(Some("bob"), authenticator.authenticate("other"));
+        assert_eq!(None, authenticator.authenticate("alice"));
+    }
+
+    #[test]
+    fn test_from_spec_rejects_entry_without_token() {
+        assert!(Authenticator::from_spec("alice").is_err());
+        assert!(Authenticator::from_spec("alice:").is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time;
+
+// Задержки между повторными попытками: base, base * factor, base * factor^2 и так
+// далее, но не больше max_delay. Итератор бесконечный, число попыток ограничивают через take:
+//
+//     for delay in Backoff::new().take(5) {
+//         tokio::time::delay_for(delay).await;
+//         ...
+//     }
+//
+// jitter - доля задержки, на которую ее можно случайно уменьшить, от 0 до 1. С jitter 0.5
+// задержка 400ms превращается в случайную от 200ms до 400ms, так что клиенты, которые
+// потеряли брокер одновременно, не переподключаются к нему тоже одновременно.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    base: time::Duration,
+    factor: u32,
+    max: time::Duration,
+    jitter: f64,
+    // Задержка без jitter, которую вернет следующий next.
+    current: time::Duration,
+    rng: u64,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        let base = time::Duration::from_millis(100);
+
+        Backoff {
+            base,
+            factor: 2,
+            max: time::Duration::from_secs(5),
+            jitter: 0.0,
+            current: base,
+            rng: seed(),
+        }
+    }
+
+    pub fn base(mut self, base: time::Duration) -> Backoff {
+        self.base = base;
+        self.current = base;
+        self
+    }
+
+    // factor 1 - все задержки одинаковые, 0 считается за 1.
+    pub fn factor(mut self, factor: u32) -> Backoff {
+        self.factor = factor.max(1);
+        self
+    }
+
+    pub fn max_delay(mut self, max: time::Duration) -> Backoff {
+        self.max = max;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: f64) -> Backoff {
+        self.jitter = if jitter.is_nan() {
+            0.0
+        } else {
+            jitter.clamp(0.0, 1.0)
+        };
+        self
+    }
+
+    // Следующая задержка снова будет base, например после удачной попытки.
+    pub fn reset(&mut self) {
+        self.current = self.base;
+    }
+
+    // xorshift64: для jitter криптостойкость не нужна, а своя зависимость ради этого лишняя.
+    fn next_random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new()
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = time::Duration;
+
+    fn next(&mut self) -> Option<time::Duration> {
+        let delay = std::cmp::min(self.current, self.max);
+        self.current = std::cmp::min(
+            self.current.checked_mul(self.factor).unwrap_or(self.max),
+            self.max,
+        );
+
+        if self.jitter == 0.0 {
+            return Some(delay);
+        }
+
+        let cut = self.jitter * self.next_random();
+        Some(delay.mul_f64(1.0 - cut))
+    }
+}
+
+// Свой seed у каждого Backoff, иначе у клиентов, созданных одновременно, совпал бы и jitter.
+// xorshift не выходит из нуля, поэтому ноль заменяем.
+fn seed() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(
+        time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or(0),
+    );
+
+    match hasher.finish() {
+        0 => 0x9E37_79B9_7F4A_7C15,
+        seed => seed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_by_factor_up_to_max() {
+        let delays: Vec<_> = Backoff::new()
+            .base(time::Duration::from_millis(100))
+            .factor(3)
+            .max_delay(time::Duration::from_secs(2))
+            .take(6)
+            .collect();
+
+        let expected: Vec<_> = [100, 300, 900, 2000, 2000, 2000]
+            .iter()
+            .map(|millis| time::Duration::from_millis(*millis))
+            .collect();
+        assert_eq!(expected, delays);
+    }
+
+    #[test]
+    fn test_backoff_jitter_stays_within_bounds() {
+        let max = time::Duration::from_secs(1);
+        let mut backoff = Backoff::new()
+            .base(time::Duration::from_millis(10))
+            .max_delay(max)
+            .jitter(0.5);
+        let mut exact = Backoff::new()
+            .base(time::Duration::from_millis(10))
+            .max_delay(max);
+
+        for _ in 0..1000 {
+            let delay = backoff.next().unwrap();
+            let upper = exact.next().unwrap();
+
+            assert!(delay <= upper, "{:?} > {:?}", delay, upper);
+            assert!(delay >= upper / 2, "{:?} < {:?} / 2", delay, upper);
+        }
+    }
+
+    #[test]
+    fn test_backoff_reset_starts_from_base() {
+        let mut backoff = Backoff::new()
+            .base(time::Duration::from_millis(50))
+            .max_delay(time::Duration::from_secs(1));
+
+        backoff.next();
+        backoff.next();
+        assert_eq!(Some(time::Duration::from_millis(200)), backoff.next());
+
+        backoff.reset();
+        assert_eq!(Some(time::Duration::from_millis(50)), backoff.next());
+    }
+
+    #[test]
+    fn test_backoff_huge_factor_does_not_overflow() {
+        let max = time::Duration::from_secs(60);
+        let mut backoff = Backoff::new()
+            .base(time::Duration::from_secs(u64::MAX / 2))
+            .factor(u32::MAX)
+            .max_delay(max);
+
+        assert_eq!(Some(max), backoff.next());
+        assert_eq!(Some(max), backoff.next());
+    }
+}
@@ -0,0 +1,265 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::error::Error;
+use std::io::{Read, Write};
+use zaichik::protocol;
+
+// Консольный клиент брокера для отладки и скриптов:
+//
+//   zaichik-cli publish orders '{"id": 1}' --key order-1
+//   zaichik-cli subscribe orders --hex
+//   zaichik-cli create-topic orders --retention-ttl 60000
+//   zaichik-cli list-topics
+//
+// Адрес брокера задается через --addr, по умолчанию 127.0.0.1:8889.
+#[tokio::main]
+async fn main() {
+    let matches = app().get_matches();
+
+    if let Err(error) = run(&matches).await {
+        eprintln!("zaichik-cli: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("zaichik-cli")
+        .about("Command line client for the zaichik broker")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .takes_value(true)
+                .default_value("127.0.0.1:8889")
+                .global(true)
+                .help("Broker address"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["bincode", "json"])
+                .default_value("bincode")
+                .global(true)
+                .help("Wire format the broker expects"),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .global(true)
+                .help("Token for brokers with authentication enabled"),
+        )
+        .subcommand(
+            SubCommand::with_name("publish")
+                .about("Publish one message, payload is read from stdin if omitted")
+                .arg(Arg::with_name("topic").required(true))
+                .arg(Arg::with_name("payload"))
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .takes_value(true)
+                        .help("Message key"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("subscribe")
+                .about("Print messages of a topic to stdout, one per line")
+                .arg(Arg::with_name("topic").required(true))
+                .arg(
+                    Arg::with_name("hex")
+                        .long("hex")
+                        .help("Print payloads as hex instead of UTF-8"),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .possible_values(&["earliest", "latest", "snapshot"])
+                        .default_value("earliest")
+                        .help("Where delivery starts"),
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .takes_value(true)
+                        .help("Exit after this many messages"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("create-topic")
+                .about("Create a topic")
+                .arg(Arg::with_name("topic").required(true))
+                .arg(number_arg(
+                    "retention-ttl",
+                    "Retention in milliseconds, 0 - keep nothing",
+                ))
+                .arg(number_arg(
+                    "compaction-window",
+                    "Compaction window in milliseconds, 0 - no compaction",
+                ))
+                .arg(number_arg(
+                    "max-messages",
+                    "Retained messages limit, 0 - no limit",
+                ))
+                .arg(number_arg(
+                    "max-bytes",
+                    "Retained bytes limit, 0 - no limit",
+                ))
+                .arg(
+                    Arg::with_name("compaction")
+                        .long("compaction")
+                        .takes_value(true)
+                        .possible_values(&["dedup", "key-latest"])
+                        .default_value("dedup")
+                        .help("Compaction mode"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("list-topics").about("Print topic names"))
+}
+
+fn number_arg(name: &'static str, help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name)
+        .long(name)
+        .takes_value(true)
+        .default_value("0")
+        .help(help)
+}
+
+fn number(matches: &ArgMatches, name: &str) -> Result<u64, Box<dyn Error>> {
+    let value = matches.value_of(name).unwrap_or("0");
+    value
+        .parse::<u64>()
+        .map_err(|_| format!("--{} should be a number, got {}", name, value).into())
+}
+
+async fn connect(matches: &ArgMatches<'_>) -> Result<zaichik::Client, Box<dyn Error>> {
+    let mut options = zaichik::Client::builder();
+    if matches.value_of("format") == Some("json") {
+        options = options.format(protocol::SerializationFormat::Json);
+    }
+    if let Some(token) = matches.value_of("token") {
+        options = options.token(token.to_string());
+    }
+
+    options.connect(matches.value_of("addr").unwrap()).await
+}
+
+async fn run(matches: &ArgMatches<'_>) -> Result<(), Box<dyn Error>> {
+    // Глобальные опции clap дублирует в матчи подкоманды, поэтому дальше
+    // достаточно матчей подкоманды.
+    let (command, matches) = match matches.subcommand() {
+        (command, Some(matches)) => (command, matches),
+        _ => unreachable!("clap requires a subcommand"),
+    };
+    let mut client = connect(matches).await?;
+
+    match command {
+        "publish" => {
+            let topic = matches.value_of("topic").unwrap().to_string();
+            let key = matches.value_of("key").map(str::to_string);
+            let payload = match matches.value_of("payload") {
+                Some(payload) => payload.as_bytes().to_vec(),
+                None => {
+                    let mut payload = Vec::new();
+                    std::io::stdin().read_to_end(&mut payload)?;
+                    payload
+                }
+            };
+
+            client.publish(topic, key, payload).await?;
+        }
+        "subscribe" => {
+            let topic = matches.value_of("topic").unwrap().to_string();
+            let start = match matches.value_of("from") {
+                Some("latest") => protocol::DeliveryStart::Latest,
+                Some("snapshot") => protocol::DeliveryStart::SnapshotOnly,
+                _ => protocol::DeliveryStart::Earliest,
+            };
+            let count = match matches.value_of("count") {
+                Some(_) => Some(number(matches, "count")?),
+                None => None,
+            };
+
+            client
+                .subscribe_with(topic.clone(), zaichik::SubscribeOptions::new().start(start))
+                .await?;
+            tokio::select! {
+                result = print_messages(&mut client, matches.is_present("hex"), count) => result?,
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            // После Ctrl-C или последнего сообщения снимаем подписку сами,
+            // чтобы брокер не пытался доставить следующее сообщение закрытому соединению.
+            client.unsubscribe(topic).await?;
+        }
+        "create-topic" => {
+            let topic = matches.value_of("topic").unwrap().to_string();
+            let compaction_mode = match matches.value_of("compaction") {
+                Some("key-latest") => protocol::CompactionMode::KeyLatest,
+                _ => protocol::CompactionMode::Dedup,
+            };
+
+            let created = client
+                .create_topic(
+                    topic.clone(),
+                    number(matches, "retention-ttl")?,
+                    number(matches, "compaction-window")?,
+                    number(matches, "max-messages")?,
+                    number(matches, "max-bytes")?,
+                    compaction_mode,
+                )
+                .await?;
+            if created.already_existed {
+                println!("Topic {} already exists", topic);
+            } else {
+                println!("Created topic {}", topic);
+            }
+        }
+        "list-topics" => {
+            for topic in client.list_topics().await? {
+                println!("{}", topic);
+            }
+        }
+        _ => unreachable!("clap rejects unknown subcommands"),
+    }
+
+    client.close().await?;
+    Ok(())
+}
+
+async fn print_messages(
+    client: &mut zaichik::Client,
+    hex: bool,
+    count: Option<u64>,
+) -> Result<(), Box<dyn Error>> {
+    let mut printed = 0;
+
+    while count.is_none_or(|count| printed < count) {
+        match client.read_message_checked().await? {
+            Some(protocol::ZaichikFrame::Publish { id, payload, .. }) => {
+                let line = if hex {
+                    to_hex(&payload)
+                } else {
+                    String::from_utf8_lossy(&payload).into_owned()
+                };
+
+                // Скрипт, который читает stdout через pipe, должен видеть
+                // сообщение сразу, а не когда заполнится буфер.
+                let stdout = std::io::stdout();
+                let mut stdout = stdout.lock();
+                writeln!(stdout, "{}", line)?;
+                stdout.flush()?;
+
+                client.commit(id).await?;
+                printed += 1;
+            }
+            Some(_) => {}
+            None => return Err("Broker closed the connection".into()),
+        }
+    }
+
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,92 @@
+use std::error::Error;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Runtime;
+
+use crate::{protocol, ClientBuilder, SubscribeOptions};
+
+// Клиент для синхронного кода: скриптов, тестов без async, FFI. Каждый метод
+// блокирует текущий поток, пока не закончится такой же метод асинхронного Client.
+//
+// Клиент владеет своим однопоточным рантаймом tokio, и соединение работает только
+// внутри вызовов: между ними фреймы от брокера ждут в сокете, а keepalive не отправляется.
+// Вызывать методы изнутри другого рантайма tokio нельзя, block_on там паникует,
+// в async коде нужен обычный Client.
+//
+// Клиент можно передать в другой поток, но методы берут &mut self, так что пользоваться
+// им из нескольких потоков сразу можно только через Mutex. Обычно проще завести
+// по клиенту на поток.
+pub struct Client {
+    // Клиент удаляется раньше рантайма, пока его сокет еще зарегистрирован в рантайме,
+    // поэтому порядок полей важен.
+    client: crate::Client,
+    runtime: Runtime,
+}
+
+impl Client {
+    pub fn connect(server_addr: &str) -> Result<Client, Box<dyn Error>> {
+        Self::connect_with(server_addr, crate::Client::builder())
+    }
+
+    pub fn connect_with(
+        server_addr: &str,
+        options: ClientBuilder,
+    ) -> Result<Client, Box<dyn Error>> {
+        let mut runtime = Self::runtime()?;
+        let client = runtime.block_on(options.connect(server_addr))?;
+        Ok(Client { client, runtime })
+    }
+
+    // Как Client::connect_stream. Стрим будет работать в рантайме этого клиента.
+    pub fn connect_stream<S>(stream: S) -> Result<Client, Box<dyn Error>>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let mut runtime = Self::runtime()?;
+        let client = runtime.block_on(crate::Client::connect_stream(stream))?;
+        Ok(Client { client, runtime })
+    }
+
+    fn runtime() -> io::Result<Runtime> {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+    }
+
+    pub fn publish(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+    ) -> io::Result<()> {
+        let client = &mut self.client;
+        self.runtime.block_on(client.publish(topic, key, payload))
+    }
+
+    pub fn subscribe_on(&mut self, topic: String) -> io::Result<()> {
+        let client = &mut self.client;
+        self.runtime.block_on(client.subscribe_on(topic))
+    }
+
+    pub fn subscribe_with(&mut self, topic: String, options: SubscribeOptions) -> io::Result<()> {
+        let client = &mut self.client;
+        self.runtime.block_on(client.subscribe_with(topic, options))
+    }
+
+    // Ждет следующий фрейм от брокера. Ok(None) - брокер закрыл соединение.
+    pub fn read_message(&mut self) -> io::Result<Option<protocol::ZaichikFrame>> {
+        let client = &mut self.client;
+        self.runtime.block_on(client.read_message())
+    }
+
+    pub fn commit(&mut self, id: u64) -> io::Result<()> {
+        let client = &mut self.client;
+        self.runtime.block_on(client.commit(id))
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        let client = &mut self.client;
+        self.runtime.block_on(client.close())
+    }
+}
@@ -0,0 +1,966 @@
+// Брокер: принимает подключения по TCP, UNIX сокету или в памяти и обслуживает их.
+// Бинарник zaichik читает настройки из переменных окружения и запускает run_broker,
+// а приложение может встроить брокер в себя через spawn_in_memory_broker.
+use crate::connection_registry::ConnectionRegistry;
+use crate::topic_registry::TopicRegistry;
+use crate::{duplex, metrics, protocol, subscription_manager, unix_socket, DuplexStream};
+use futures::SinkExt;
+use std::sync::{Arc, RwLock};
+use std::time;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::stream::StreamExt;
+use tokio::sync::{broadcast, mpsc};
+
+pub use crate::acl::{Access, AclRules};
+pub use crate::auth::Authenticator;
+pub use crate::events::BrokerEvents;
+pub use crate::tls::load_tls_acceptor;
+pub use crate::topic_controller::{DEFAULT_BUFFER_SIZE, DEFAULT_COMPACTION_MAX_KEYS};
+pub use crate::topic_registry::{
+    LimitPolicy, TopicLimits, DEFAULT_MAX_COMPACTION_WINDOW, DEFAULT_MAX_RETENTION_TTL,
+    DEFAULT_MAX_TOPIC_NAME_LEN,
+};
+
+// Сколько при остановке брокера мы ждем, пока подключения допишут
+// клиентам то, что уже начали отправлять.
+const SHUTDOWN_GRACE_PERIOD: time::Duration = time::Duration::from_secs(5);
+
+// Сколько мы ждем Authenticate от клиента, если включена аутентификация.
+const AUTHENTICATION_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+// Сколько подписок на топики может быть у одного подключения, если лимит не поменяли
+// через ZAICHIK_MAX_SUBSCRIPTIONS. Каждая подписка держит свой стрим топика в памяти.
+pub const DEFAULT_MAX_SUBSCRIPTIONS: usize = 10_000;
+
+// Настройки, общие для всех подключений к брокеру.
+#[derive(Clone)]
+pub struct BrokerConfig {
+    format: protocol::SerializationFormat,
+    idle_timeout: Option<time::Duration>,
+    // Если задан, то каждое подключение сначала проходит TLS рукопожатие.
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    // Если задан, то клиент должен прислать Authenticate с одним из известных токенов.
+    auth: Option<Arc<Authenticator>>,
+    // Правила доступа к топикам для пользователей из auth.
+    acl: Arc<AclRules>,
+    metrics_addr: Option<std::net::SocketAddr>,
+    data_dir: Option<std::path::PathBuf>,
+    // Если задан, то брокер слушает еще и UNIX сокет по этому пути.
+    unix_socket: Option<std::path::PathBuf>,
+    topic_buffer_size: u32,
+    max_topic_name_len: usize,
+    compaction_max_keys: usize,
+    topic_limits: TopicLimits,
+    max_subscriptions: usize,
+    // Подписывать ли опубликованные сообщения адресом и пользователем издателя.
+    producer_headers: bool,
+    // Если задан, то брокер сообщает ему о подключениях, подписках и публикациях.
+    events: Option<Arc<dyn BrokerEvents>>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> BrokerConfig {
+        BrokerConfig {
+            format: protocol::SerializationFormat::Bincode,
+            idle_timeout: None,
+            tls: None,
+            auth: None,
+            acl: Arc::new(AclRules::new()),
+            metrics_addr: None,
+            data_dir: None,
+            unix_socket: None,
+            topic_buffer_size: DEFAULT_BUFFER_SIZE,
+            max_topic_name_len: DEFAULT_MAX_TOPIC_NAME_LEN,
+            compaction_max_keys: DEFAULT_COMPACTION_MAX_KEYS,
+            topic_limits: TopicLimits::default(),
+            max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+            producer_headers: false,
+            events: None,
+        }
+    }
+}
+
+// Настройки по умолчанию совпадают с тем, что делает бинарник zaichik без переменных
+// окружения. Имена переменных, которые меняют ту же настройку, указаны рядом.
+impl BrokerConfig {
+    // Формат фреймов на проводе (FORMAT). Клиенты должны подключаться с тем же форматом.
+    pub fn format(mut self, format: protocol::SerializationFormat) -> BrokerConfig {
+        self.format = format;
+        self
+    }
+
+    // Через сколько тишины от клиента брокер закрывает соединение (IDLE_TIMEOUT).
+    pub fn idle_timeout(mut self, idle_timeout: Option<time::Duration>) -> BrokerConfig {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    // Принимать только TLS подключения (ZAICHIK_TLS_CERT и ZAICHIK_TLS_KEY),
+    // см. load_tls_acceptor.
+    pub fn tls(mut self, tls: Option<tokio_rustls::TlsAcceptor>) -> BrokerConfig {
+        self.tls = tls;
+        self
+    }
+
+    // Токены клиентов (ZAICHIK_AUTH_TOKENS). Без них подключиться может любой клиент.
+    pub fn auth(mut self, auth: Option<Authenticator>) -> BrokerConfig {
+        self.auth = auth.map(Arc::new);
+        self
+    }
+
+    // Кому можно читать и писать в топики и кто админ (ZAICHIK_ACL).
+    pub fn acl(mut self, acl: AclRules) -> BrokerConfig {
+        self.acl = Arc::new(acl);
+        self
+    }
+
+    // HTTP эндпоинт с метриками (ZAICHIK_METRICS_ADDR), нужна фича metrics.
+    pub fn metrics_addr(mut self, addr: Option<std::net::SocketAddr>) -> BrokerConfig {
+        self.metrics_addr = addr;
+        self
+    }
+
+    // Директория для retained сообщений топиков (ZAICHIK_DATA_DIR).
+    pub fn data_dir(mut self, data_dir: Option<std::path::PathBuf>) -> BrokerConfig {
+        self.data_dir = data_dir;
+        self
+    }
+
+    // UNIX сокет в дополнение к TCP (ZAICHIK_UNIX_SOCKET).
+    pub fn unix_socket(mut self, path: Option<std::path::PathBuf>) -> BrokerConfig {
+        self.unix_socket = path;
+        self
+    }
+
+    // Размер буфера топиков, в CreateTopic которых он не указан (ZAICHIK_TOPIC_BUFFER_SIZE).
+    pub fn topic_buffer_size(mut self, size: u32) -> BrokerConfig {
+        self.topic_buffer_size = size;
+        self
+    }
+
+    // Максимальная длина имени топика в байтах (ZAICHIK_MAX_TOPIC_NAME_LEN).
+    pub fn max_topic_name_len(mut self, len: usize) -> BrokerConfig {
+        self.max_topic_name_len = len;
+        self
+    }
+
+    // Сколько ключей помнит Dedup compaction каждого топика (ZAICHIK_COMPACTION_MAX_KEYS).
+    pub fn compaction_max_keys(mut self, max_keys: usize) -> BrokerConfig {
+        self.compaction_max_keys = max_keys;
+        self
+    }
+
+    // Лимиты retention_ttl и compaction_window топиков (ZAICHIK_MAX_RETENTION_TTL,
+    // ZAICHIK_MAX_COMPACTION_WINDOW и ZAICHIK_TOPIC_LIMIT_POLICY).
+    pub fn topic_limits(mut self, limits: TopicLimits) -> BrokerConfig {
+        self.topic_limits = limits;
+        self
+    }
+
+    // Сколько подписок может быть у одного подключения, 0 - без лимита
+    // (ZAICHIK_MAX_SUBSCRIPTIONS).
+    pub fn max_subscriptions(mut self, max: usize) -> BrokerConfig {
+        self.max_subscriptions = max;
+        self
+    }
+
+    // Добавлять ли к сообщениям заголовки издателя (ZAICHIK_PRODUCER_HEADERS).
+    pub fn producer_headers(mut self, enabled: bool) -> BrokerConfig {
+        self.producer_headers = enabled;
+        self
+    }
+
+    // Хук, которому брокер сообщает о подключениях, подписках и публикациях.
+    // Переменной окружения для него нет, его можно задать, только встроив брокер.
+    pub fn events(mut self, events: Option<Arc<dyn BrokerEvents>>) -> BrokerConfig {
+        self.events = events;
+        self
+    }
+}
+
+// Запускает брокер на addr и обслуживает подключения, пока не случится ошибка
+// или пока в shutdown не придет сигнал остановки. После сигнала новые подключения
+// не принимаются, а текущие закрываются, дописав клиентам то, что уже отправляется.
+pub async fn run_broker(
+    addr: std::net::SocketAddr,
+    config: BrokerConfig,
+    shutdown: broadcast::Sender<()>,
+) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    run_broker_on(listener, config, shutdown).await
+}
+
+// То же, что run_broker, но на уже открытом listener. Так можно занять порт 0
+// и узнать настоящий адрес из local_addr до того, как к брокеру подключатся клиенты.
+pub async fn run_broker_on(
+    listener: tokio::net::TcpListener,
+    config: BrokerConfig,
+    shutdown: broadcast::Sender<()>,
+) -> std::io::Result<()> {
+    let topic_registry = open_topic_registry(&config)?;
+    serve(listener, config, topic_registry, shutdown).await
+}
+
+// База данных топиков, в которой хранятся ссылки на контроллеры топиков.
+fn open_topic_registry(config: &BrokerConfig) -> std::io::Result<Arc<RwLock<TopicRegistry>>> {
+    let topic_registry = TopicRegistry::new()
+        .with_default_buffer_size(config.topic_buffer_size)
+        .with_max_topic_name_len(config.max_topic_name_len)
+        .with_compaction_max_keys(config.compaction_max_keys)
+        .with_topic_limits(config.topic_limits);
+    let topic_registry = match &config.data_dir {
+        Some(data_dir) => topic_registry.open_storage(data_dir.clone())?,
+        None => topic_registry,
+    };
+    Ok(Arc::new(RwLock::new(topic_registry)))
+}
+
+// Принимаем подключения, пока не придет сигнал shutdown.
+async fn serve(
+    mut listener: tokio::net::TcpListener,
+    config: BrokerConfig,
+    topic_registry: Arc<RwLock<TopicRegistry>>,
+    shutdown: broadcast::Sender<()>,
+) -> std::io::Result<()> {
+    let mut unix_listener = match &config.unix_socket {
+        Some(path) => Some(unix_socket::UnixSocketListener::bind(path.clone())?),
+        None => None,
+    };
+    if let Some(metrics_addr) = config.metrics_addr {
+        start_metrics_endpoint(metrics_addr, Arc::clone(&topic_registry));
+    }
+
+    let mut shutdown_receiver = shutdown.subscribe();
+
+    // Подключения, которые сейчас обслуживает брокер. Их показывает AdminConnections.
+    let connections = Arc::new(ConnectionRegistry::new());
+
+    // Каждое подключение держит у себя копию connection_alive. Когда все
+    // копии будут удалены, recv вернет None, значит все подключения закрылись.
+    let (connection_alive, mut connections_closed) = mpsc::channel::<()>(1);
+
+    debug!("Started broker server at {}", listener.local_addr()?);
+    if let Some(unix_listener) = &unix_listener {
+        debug!(
+            "Started broker server at {}",
+            unix_listener.path().display()
+        );
+    }
+
+    loop {
+        let connection = Connection {
+            topic_registry: Arc::clone(&topic_registry),
+            connections: Arc::clone(&connections),
+            config: config.clone(),
+            shutdown: shutdown.subscribe(),
+            alive: connection_alive.clone(),
+        };
+
+        // В peer хранится ip адрес и порт входящего подключения.
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer) = accepted?;
+                connection.spawn(socket, peer);
+            }
+            accepted = unix_socket::accept(&mut unix_listener) => {
+                let (socket, peer) = accepted?;
+                connection.spawn(socket, peer);
+            }
+            _ = shutdown_receiver.recv() => break,
+        };
+    }
+
+    info!("Stopping broker, waiting for connections to close");
+
+    drop(listener);
+    drop(unix_listener);
+    drop(connection_alive);
+
+    if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, connections_closed.recv())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Some connections were not closed in {:?}",
+            SHUTDOWN_GRACE_PERIOD
+        );
+    }
+
+    Ok(())
+}
+
+// Все, что нужно задаче одного входящего подключения, неважно, TCP это или UNIX сокет.
+struct Connection {
+    topic_registry: Arc<RwLock<TopicRegistry>>,
+    connections: Arc<ConnectionRegistry>,
+    config: BrokerConfig,
+    shutdown: broadcast::Receiver<()>,
+    // Пока задача жива, serve ждет ее при остановке брокера.
+    alive: mpsc::Sender<()>,
+}
+
+impl Connection {
+    // Для каждого входящего подключения мы будем создавать отдельную задачу.
+    // TLS рукопожатие тоже делаем в ней, чтобы не задерживать прием подключений.
+    fn spawn<S>(self, socket: S, peer: std::net::SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        tokio::spawn(async move {
+            metrics::METRICS.on_connection_opened();
+
+            match self.config.tls.clone() {
+                Some(acceptor) => match acceptor.accept(socket).await {
+                    Ok(stream) => {
+                        process(
+                            stream,
+                            peer,
+                            self.topic_registry,
+                            self.connections,
+                            self.config,
+                            self.shutdown,
+                        )
+                        .await
+                    }
+                    Err(e) => warn!(
+                        "[{}:{}] TLS handshake failed; error = {:?}",
+                        peer.ip(),
+                        peer.port(),
+                        e
+                    ),
+                },
+                None => {
+                    process(
+                        socket,
+                        peer,
+                        self.topic_registry,
+                        self.connections,
+                        self.config,
+                        self.shutdown,
+                    )
+                    .await
+                }
+            }
+
+            metrics::METRICS.on_connection_closed();
+            drop(self.alive);
+        });
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn start_metrics_endpoint(addr: std::net::SocketAddr, topic_registry: Arc<RwLock<TopicRegistry>>) {
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(addr, topic_registry).await {
+            error!("Failed to serve metrics at {}; error = {:?}", addr, e);
+        }
+    });
+}
+
+#[cfg(not(feature = "metrics"))]
+fn start_metrics_endpoint(addr: std::net::SocketAddr, _topic_registry: Arc<RwLock<TopicRegistry>>) {
+    warn!(
+        "Broker is built without the metrics feature, ignoring metrics address {}",
+        addr
+    );
+}
+
+// Обслуживаем одно подключение. Сокет может быть TcpStream, UnixStream или TLS стримом
+// поверх одного из них, поэтому мы принимаем любой AsyncRead + AsyncWrite.
+async fn process<S>(
+    socket: S,
+    peer: std::net::SocketAddr,
+    topic_registry: Arc<RwLock<TopicRegistry>>,
+    connections: Arc<ConnectionRegistry>,
+    config: BrokerConfig,
+    mut shutdown: broadcast::Receiver<()>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    debug!("New connection from {}:{}", peer.ip(), peer.port());
+
+    let idle_timeout = config.idle_timeout;
+    let codec = protocol::ZaichikCodec::new(config.format);
+    let (read_half, write_half) = tokio::io::split(socket);
+    let write_half: Box<dyn AsyncWrite + Send + Unpin> = Box::new(write_half);
+
+    let mut reader = tokio_util::codec::FramedRead::new(read_half, codec.clone());
+    let mut writer: subscription_manager::ClientConnection =
+        tokio_util::codec::FramedWrite::new(write_half, codec);
+
+    // Первым фреймом клиент обязан прислать Handshake с версией протокола.
+    // Клиентов с неподдерживаемой версией мы сразу отключаем.
+    match next_frame(&mut reader, peer, idle_timeout).await {
+        Some(Ok(protocol::ZaichikFrame::Handshake { protocol_version }))
+            if protocol::is_supported_version(protocol_version) =>
+        {
+            let reply = protocol::ZaichikFrame::Handshake {
+                protocol_version: protocol::PROTOCOL_VERSION,
+            };
+
+            if let Err(e) = writer.send(reply).await {
+                error!(
+                    "[{}:{}] Failed to reply to handshake; error = {:?}",
+                    peer.ip(),
+                    peer.port(),
+                    e
+                );
+                return;
+            }
+        }
+        other => {
+            warn!(
+                "[{}:{}] Rejected connection with handshake {:?}",
+                peer.ip(),
+                peer.port(),
+                other
+            );
+
+            let _ = writer
+                .send(protocol::ZaichikFrame::Error {
+                    code: protocol::ERROR_UNSUPPORTED_PROTOCOL_VERSION,
+                    message: format!(
+                        "Supported protocol version is {}",
+                        protocol::PROTOCOL_VERSION
+                    ),
+                    topic: None,
+                })
+                .await;
+            return;
+        }
+    }
+
+    // Если на брокере включена аутентификация, то следующим фреймом клиент
+    // обязан прислать Authenticate с известным нам токеном.
+    let principal = match &config.auth {
+        Some(authenticator) => {
+            match authenticate(&mut reader, &mut writer, peer, authenticator).await {
+                Some(principal) => Some(principal),
+                None => return,
+            }
+        }
+        None => None,
+    };
+
+    if let Some(events) = &config.events {
+        events.on_connected(peer, principal.as_deref());
+    }
+
+    // Канал, для того, чтобы отправлять сообщения от клиента в управляющий компонент.
+    // Это mpsc, а не broadcast: команды клиента (Subscribe, Commit и т.д.) касаются только
+    // его собственного SubscriptionManager. Общий broadcast раздавал бы их всем подключениям.
+    // Ограниченный размер канала притормаживает чтение из сокета, если менеджер не успевает.
+    let (mut subscription_manager_channel, commands_receiver) = mpsc::channel(1000);
+    let acl = Arc::clone(&config.acl);
+    let events = config.events.clone();
+    let max_subscriptions = config.max_subscriptions;
+    let producer_headers = config.producer_headers;
+
+    // Запись в сокет и управление подписками мы отдадим в отдельную задачу.
+    let manager_task = tokio::spawn(async move {
+        subscription_manager::SubscriptionManager::start_loop(
+            peer,
+            principal,
+            acl,
+            events,
+            max_subscriptions,
+            producer_headers,
+            topic_registry,
+            connections,
+            commands_receiver,
+            writer,
+        )
+        .await
+    });
+
+    // Читаем фреймы, приходящие от клиента из сокета и передаем их в управляющий компонент.
+    // Если брокер останавливается, то перестаем читать, а SubscriptionManager отправит
+    // клиенту CloseConnection и закроет подключение.
+    let mut disconnected = subscription_manager::MessageWrapper::Disconnected;
+    loop {
+        let result = tokio::select! {
+            next = next_frame(&mut reader, peer, idle_timeout) => match next {
+                Some(result) => result,
+                None => break,
+            },
+            _ = shutdown.recv() => {
+                debug!("[{}:{}] Broker is stopping, closing connection", peer.ip(), peer.port());
+                disconnected = subscription_manager::MessageWrapper::Shutdown;
+                break;
+            }
+        };
+
+        let wrapped = match result {
+            Ok(frame) => subscription_manager::MessageWrapper::from_frame(frame),
+            Err(e) => {
+                error!("error on decoding from socket; error = {:?}", e);
+
+                // Сообщаем клиенту, что его фрейм не разобрать.
+                subscription_manager::MessageWrapper::from_frame_error(e)
+            }
+        };
+
+        // SubscriptionManager уже завершился (например, клиент прислал CloseConnection),
+        // так что команды обрабатывать больше некому.
+        if subscription_manager_channel.send(wrapped).await.is_err() {
+            debug!(
+                "[{}:{}] SubscriptionManager is stopped, closing connection",
+                peer.ip(),
+                peer.port()
+            );
+            break;
+        }
+    }
+
+    // Говорим управляющему модулю, что мы больше не работаем с клиентом.
+    let _ = subscription_manager_channel.send(disconnected).await;
+
+    // Ждем, пока SubscriptionManager допишет в сокет то, что уже начал отправлять.
+    let _ = manager_task.await;
+
+    if let Some(events) = &config.events {
+        events.on_disconnected(peer);
+    }
+
+    debug!("[{}:{}] Stopped client", peer.ip(), peer.port());
+}
+
+// Ждем от клиента Authenticate и проверяем токен. Возвращаем имя пользователя,
+// если токен подошел, иначе отправляем клиенту ошибку и возвращаем None.
+async fn authenticate<R>(
+    reader: &mut tokio_util::codec::FramedRead<R, protocol::ZaichikCodec>,
+    writer: &mut subscription_manager::ClientConnection,
+    peer: std::net::SocketAddr,
+    authenticator: &Authenticator,
+) -> Option<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let (code, message) = match next_frame(reader, peer, Some(AUTHENTICATION_TIMEOUT)).await {
+        Some(Ok(protocol::ZaichikFrame::Authenticate { token })) => {
+            match authenticator.authenticate(&token) {
+                Some(principal) => {
+                    let principal = principal.to_string();
+
+                    if let Err(e) = writer.send(protocol::ZaichikFrame::Authenticated).await {
+                        error!(
+                            "[{}:{}] Failed to reply to authentication; error = {:?}",
+                            peer.ip(),
+                            peer.port(),
+                            e
+                        );
+                        return None;
+                    }
+
+                    debug!(
+                        "[{}:{}] Authenticated as {}",
+                        peer.ip(),
+                        peer.port(),
+                        principal
+                    );
+                    return Some(principal);
+                }
+                None => (
+                    protocol::ERROR_AUTHENTICATION_FAILED,
+                    "Unknown token".to_string(),
+                ),
+            }
+        }
+        _ => (
+            protocol::ERROR_AUTHENTICATION_REQUIRED,
+            "Authenticate should be sent right after Handshake".to_string(),
+        ),
+    };
+
+    warn!(
+        "[{}:{}] Rejected connection: {}",
+        peer.ip(),
+        peer.port(),
+        message
+    );
+
+    let _ = writer
+        .send(protocol::ZaichikFrame::Error {
+            code,
+            message,
+            topic: None,
+        })
+        .await;
+    None
+}
+
+// Читаем следующий фрейм от клиента. Если клиент молчит дольше idle_timeout,
+// то считаем соединение мертвым и возвращаем None, как будто сокет закрылся.
+// Чтобы соединение не закрылось, клиент может периодически отправлять Ping.
+async fn next_frame<R>(
+    reader: &mut tokio_util::codec::FramedRead<R, protocol::ZaichikCodec>,
+    peer: std::net::SocketAddr,
+    idle_timeout: Option<time::Duration>,
+) -> Option<Result<protocol::ZaichikFrame, std::io::Error>>
+where
+    R: AsyncRead + Unpin,
+{
+    match idle_timeout {
+        Some(idle_timeout) => match tokio::time::timeout(idle_timeout, reader.next()).await {
+            Ok(next) => next,
+            Err(_) => {
+                info!(
+                    "[{}:{}] No frames for {:?}, closing idle connection",
+                    peer.ip(),
+                    peer.port(),
+                    idle_timeout
+                );
+                None
+            }
+        },
+        None => reader.next().await,
+    }
+}
+
+// Брокер без сокетов: клиенты подключаются к нему через duplex, поэтому ему не нужен
+// свободный порт. Так брокер можно встроить в приложение или запустить в тестах.
+// metrics_addr и unix_socket из настроек такой брокер не использует.
+pub struct InMemoryBroker {
+    topic_registry: Arc<RwLock<TopicRegistry>>,
+    connections: Arc<ConnectionRegistry>,
+    config: BrokerConfig,
+    // Пока отправитель жив, подключения не получают сигнал остановки.
+    shutdown: broadcast::Sender<()>,
+    next_port: std::sync::atomic::AtomicU16,
+}
+
+// Ошибка возможна, только если в настройках есть data_dir и топики с диска не прочитать.
+pub fn spawn_in_memory_broker(config: BrokerConfig) -> std::io::Result<InMemoryBroker> {
+    Ok(InMemoryBroker {
+        topic_registry: open_topic_registry(&config)?,
+        connections: Arc::new(ConnectionRegistry::new()),
+        config,
+        shutdown: broadcast::channel(1).0,
+        next_port: std::sync::atomic::AtomicU16::new(1),
+    })
+}
+
+impl InMemoryBroker {
+    pub async fn connect(&self) -> Result<crate::Client, Box<dyn std::error::Error>> {
+        crate::Client::connect_stream(self.accept()).await
+    }
+
+    // Обслуживает новое подключение и возвращает его клиентский конец, например
+    // для ClientBuilder::connect_stream. Каждое подключение получает свой адрес
+    // 127.0.0.1:<номер>, чтобы ConnectionRegistry их различал.
+    pub fn accept(&self) -> DuplexStream {
+        let port = self
+            .next_port
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let peer = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+        let (client_stream, broker_stream) = duplex(64 * 1024);
+
+        tokio::spawn(process(
+            broker_stream,
+            peer,
+            Arc::clone(&self.topic_registry),
+            Arc::clone(&self.connections),
+            self.config.clone(),
+            self.shutdown.subscribe(),
+        ));
+
+        client_stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{bind_listener, read_publish, NeverClosed, Stalled};
+
+    #[tokio::test]
+    async fn test_in_memory_outbound_buffer_holds_publishes_while_writer_is_stalled() {
+        let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+        let mut consumer = broker.connect().await.unwrap();
+        consumer.set_prefetch(10).await.unwrap();
+        consumer
+            .subscribe_with(
+                "topic".to_string(),
+                crate::SubscribeOptions::new().confirm(true),
+            )
+            .await
+            .unwrap();
+
+        let stalled = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let stream = Stalled {
+            stream: broker.accept(),
+            stalled: Arc::clone(&stalled),
+        };
+        let mut producer = crate::Client::builder()
+            .outbound_buffer(Some(3))
+            .connect_stream(stream)
+            .await
+            .unwrap();
+
+        // Пока запись стоит, publish не ждет ее, а откладывает до трех фреймов.
+        stalled.store(true, std::sync::atomic::Ordering::SeqCst);
+        for payload in 1..=3 {
+            let publish = producer.publish("topic".to_string(), None, vec![payload]);
+            tokio::time::timeout(time::Duration::from_secs(1), publish)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(3, producer.pending_outbound());
+
+        let error = producer
+            .publish("topic".to_string(), None, vec![4])
+            .await
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::WouldBlock, error.kind());
+        assert_eq!(3, producer.pending_outbound());
+
+        // Запись ожила: отложенные фреймы уходят по порядку, и буфер снова принимает новые.
+        stalled.store(false, std::sync::atomic::Ordering::SeqCst);
+        producer
+            .publish("topic".to_string(), None, vec![4])
+            .await
+            .unwrap();
+        producer.flush().await.unwrap();
+        assert_eq!(0, producer.pending_outbound());
+
+        for expected in 1..=4 {
+            let (_id, payload) = read_publish(&mut consumer).await;
+            assert_eq!(vec![expected], payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_dropped_client_closes_connection() {
+        let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+        for split in &[false, true] {
+            let stream = NeverClosed(std::mem::ManuallyDrop::new(broker.accept()));
+            let mut client = crate::Client::connect_stream(stream).await.unwrap();
+            client
+                .subscribe_with(
+                    "topic".to_string(),
+                    crate::SubscribeOptions::new().confirm(true),
+                )
+                .await
+                .unwrap();
+
+            let topic_controller = broker
+                .topic_registry
+                .read()
+                .unwrap()
+                .get_topic("topic")
+                .unwrap();
+            let subscriber_count = || topic_controller.read().unwrap().subscriber_count();
+            assert_eq!(1, subscriber_count());
+
+            // Сокет остается открытым, так что отключение брокер видит
+            // только по CloseConnection, который клиент отправил при удалении.
+            if *split {
+                drop(client.split());
+            } else {
+                drop(client);
+            }
+            tokio::time::timeout(time::Duration::from_secs(1), async {
+                while subscriber_count() != 0 {
+                    tokio::time::delay_for(time::Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("Subscription was not released, split = {}", split));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_disconnect_releases_topic_subscribers() {
+        let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+        for delivery in &[
+            crate::protocol::DeliveryGuarantee::BestEffort,
+            crate::protocol::DeliveryGuarantee::Reliable,
+        ] {
+            let topic = format!("topic.{:?}", delivery);
+            let mut consumer = broker.connect().await.unwrap();
+            consumer
+                .create_topic_with(
+                    topic.clone(),
+                    crate::TopicConfig {
+                        delivery: *delivery,
+                        ..crate::TopicConfig::default()
+                    },
+                )
+                .await
+                .unwrap();
+            consumer
+                .subscribe_with(topic.clone(), crate::SubscribeOptions::new().confirm(true))
+                .await
+                .unwrap();
+
+            let topic_controller = broker
+                .topic_registry
+                .read()
+                .unwrap()
+                .get_topic(&topic)
+                .unwrap();
+            let subscriber_count = || topic_controller.read().unwrap().subscriber_count();
+            assert_eq!(1, subscriber_count(), "{:?}", delivery);
+
+            // Клиент пропадает без CloseConnection, в топик после этого ничего
+            // не публикуют.
+            drop(consumer);
+            tokio::time::timeout(time::Duration::from_secs(5), async {
+                while subscriber_count() != 0 {
+                    tokio::time::delay_for(time::Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("{:?} subscriber was not released", delivery));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_broker_and_closes_connections() {
+        let (listener, addr) = bind_listener().await;
+        let (shutdown, _) = broadcast::channel(1);
+        let broker = tokio::spawn(run_broker_on(
+            listener,
+            BrokerConfig::default(),
+            shutdown.clone(),
+        ));
+
+        let mut client = crate::Client::connect(&addr).await.unwrap();
+        client.subscribe_on("topic".to_string()).await.unwrap();
+        client.list_topics().await.unwrap();
+
+        shutdown.send(()).unwrap();
+
+        // run_broker завершается, а клиент сначала получает CloseConnection
+        // и только потом видит, что брокер закрыл соединение.
+        let stopped = tokio::time::timeout(time::Duration::from_secs(5), broker)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stopped.is_ok());
+
+        let notice = tokio::time::timeout(time::Duration::from_secs(5), client.read_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(crate::ZaichikFrame::CloseConnection), notice);
+
+        let next = tokio::time::timeout(time::Duration::from_secs(5), client.read_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(next.is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_publish_and_consume_over_unix_socket() {
+        let dir =
+            std::env::temp_dir().join(format!("zaichik-test-{}-unix-socket", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("zaichik.sock");
+
+        let config = BrokerConfig::default().unix_socket(Some(path.clone()));
+        let (shutdown, _) = broadcast::channel(1);
+        let (listener, _addr) = bind_listener().await;
+        let broker = tokio::spawn(run_broker_on(listener, config, shutdown.clone()));
+
+        // Брокеру нужно время, чтобы создать сокет, поэтому подключаемся с повторами.
+        let connect = || async {
+            loop {
+                match crate::Client::connect_unix(&path).await {
+                    Ok(client) => return client,
+                    Err(_) => tokio::time::delay_for(time::Duration::from_millis(10)).await,
+                }
+            }
+        };
+        let mut producer = connect().await;
+        producer
+            .create_topic(
+                "topic".to_string(),
+                0,
+                0,
+                0,
+                0,
+                crate::protocol::CompactionMode::Dedup,
+            )
+            .await
+            .unwrap();
+        let mut consumer = connect().await;
+        consumer
+            .subscribe_with(
+                "topic".to_string(),
+                crate::SubscribeOptions::new().confirm(true),
+            )
+            .await
+            .unwrap();
+        producer
+            .publish("topic".to_string(), None, vec![1, 2, 3])
+            .await
+            .unwrap();
+
+        let (_id, payload) = read_publish(&mut consumer).await;
+        assert_eq!(vec![1, 2, 3], payload);
+
+        // После остановки брокера файла сокета не остается.
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(time::Duration::from_secs(10), broker)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(!path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_panic_in_one_task_does_not_poison_broker() {
+        let topic_registry = Arc::new(RwLock::new(TopicRegistry::new()));
+        topic_registry.write().unwrap().create_topic(
+            "topic".to_string(),
+            0,
+            0,
+            0,
+            0,
+            protocol::CompactionMode::Dedup,
+        );
+
+        // Обработчик падает, держа блокировки реестра и топика.
+        let poisoned = Arc::clone(&topic_registry);
+        let panicked = tokio::spawn(async move {
+            let registry = poisoned.write().unwrap();
+            let topic_controller = registry.get_topic("topic").unwrap();
+            let _topic_controller = topic_controller.write().unwrap();
+            panic!("Handler panicked while holding locks");
+        })
+        .await;
+        assert!(panicked.is_err());
+        assert!(topic_registry.is_poisoned());
+
+        let (listener, addr) = bind_listener().await;
+        let (shutdown, _) = broadcast::channel(1);
+        tokio::spawn(serve(
+            listener,
+            BrokerConfig::default(),
+            topic_registry,
+            shutdown,
+        ));
+
+        let mut consumer = crate::Client::connect(&addr).await.unwrap();
+        consumer.subscribe_on("topic".to_string()).await.unwrap();
+        assert_eq!(vec!["topic"], consumer.list_topics().await.unwrap());
+
+        let mut producer = crate::Client::connect(&addr).await.unwrap();
+        producer
+            .publish("topic".to_string(), None, vec![1])
+            .await
+            .unwrap();
+        let (_id, payload) = read_publish(&mut consumer).await;
+        assert_eq!(vec![1], payload);
+    }
+}
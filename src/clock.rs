@@ -0,0 +1,73 @@
+use std::fmt;
+use std::time;
+
+#[cfg(test)]
+use crate::locks::MutexExt;
+#[cfg(test)]
+use std::sync::Mutex;
+
+// Откуда TopicController берет текущее время для retention и compaction.
+// В тестах вместо системных часов подставляется ManualClock, чтобы не ждать
+// через thread::sleep, пока истечет ttl или окно compaction.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> time::Instant;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+}
+
+// Часы, которые стоят на месте, пока их не передвинут через advance.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<time::Instant>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Mutex::new(time::Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, duration: time::Duration) {
+        *self.now.lock_or_recover() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> time::Instant {
+        *self.now.lock_or_recover()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_moves_only_on_advance() {
+        let clock = ManualClock::new();
+        let started_at = clock.now();
+
+        assert_eq!(started_at, clock.now());
+
+        clock.advance(time::Duration::from_secs(60));
+        assert_eq!(started_at + time::Duration::from_secs(60), clock.now());
+    }
+}
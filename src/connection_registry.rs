@@ -0,0 +1,153 @@
+use crate::locks::MutexExt;
+use crate::protocol;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+// Состояние одного подключения, которое видят операторы через AdminConnections.
+#[derive(Debug)]
+struct ConnectionState {
+    principal: Option<String>,
+    connected_at: time::Instant,
+    topics: BTreeSet<String>,
+    in_flight: u64,
+    awaiting_redelivery: u64,
+}
+
+// Реестр активных подключений брокера. Каждый SubscriptionManager записывает
+// сюда свои подписки и неподтвержденные сообщения, а при остановке удаляется.
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    connections: Mutex<HashMap<SocketAddr, ConnectionState>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> ConnectionRegistry {
+        ConnectionRegistry::default()
+    }
+
+    // Подключение остается в реестре, пока жив возвращенный ConnectionGuard,
+    // так что оно пропадет из реестра, даже если его задача запаникует.
+    pub fn register(
+        registry: &Arc<ConnectionRegistry>,
+        peer: SocketAddr,
+        principal: Option<String>,
+    ) -> ConnectionGuard {
+        let state = ConnectionState {
+            principal,
+            connected_at: time::Instant::now(),
+            topics: BTreeSet::new(),
+            in_flight: 0,
+            awaiting_redelivery: 0,
+        };
+        registry.connections.lock_or_recover().insert(peer, state);
+
+        ConnectionGuard {
+            registry: Arc::clone(registry),
+            peer,
+            topics: BTreeSet::new(),
+            in_flight: 0,
+            awaiting_redelivery: 0,
+        }
+    }
+
+    // Снимок всех подключений, отсортированный по адресу клиента.
+    pub fn snapshot(&self) -> Vec<protocol::ConnectionInfo> {
+        let now = time::Instant::now();
+        let mut connections = self
+            .connections
+            .lock_or_recover()
+            .iter()
+            .map(|(peer, state)| protocol::ConnectionInfo {
+                peer: *peer,
+                principal: state.principal.clone(),
+                connected_for: now.saturating_duration_since(state.connected_at),
+                topics: state.topics.iter().cloned().collect(),
+                in_flight: state.in_flight,
+                awaiting_redelivery: state.awaiting_redelivery,
+            })
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|connection| connection.peer);
+        connections
+    }
+}
+
+// Запись подключения в ConnectionRegistry. Помнит, что уже записано в реестр,
+// чтобы не брать блокировку реестра, когда ничего не поменялось.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    registry: Arc<ConnectionRegistry>,
+    peer: SocketAddr,
+    topics: BTreeSet<String>,
+    in_flight: u64,
+    awaiting_redelivery: u64,
+}
+
+impl ConnectionGuard {
+    pub fn update<'a, I>(&mut self, topics: I, in_flight: u64, awaiting_redelivery: u64)
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let topics = topics.into_iter().cloned().collect::<BTreeSet<_>>();
+        let topics_changed = topics != self.topics;
+        if !topics_changed
+            && in_flight == self.in_flight
+            && awaiting_redelivery == self.awaiting_redelivery
+        {
+            return;
+        }
+
+        if topics_changed {
+            self.topics = topics;
+        }
+        self.in_flight = in_flight;
+        self.awaiting_redelivery = awaiting_redelivery;
+
+        if let Some(state) = self
+            .registry
+            .connections
+            .lock_or_recover()
+            .get_mut(&self.peer)
+        {
+            state.topics = self.topics.clone();
+            state.in_flight = in_flight;
+            state.awaiting_redelivery = awaiting_redelivery;
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry
+            .connections
+            .lock_or_recover()
+            .remove(&self.peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_is_listed_until_guard_is_dropped() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+
+        let mut guard = ConnectionRegistry::register(&registry, peer, Some("alice".to_string()));
+        let topics = ["b".to_string(), "a".to_string()];
+        guard.update(topics.iter(), 2, 1);
+
+        let snapshot = registry.snapshot();
+        assert_eq!(1, snapshot.len());
+        assert_eq!(peer, snapshot[0].peer);
+        assert_eq!(Some("alice".to_string()), snapshot[0].principal);
+        assert_eq!(vec!["a".to_string(), "b".to_string()], snapshot[0].topics);
+        assert_eq!(2, snapshot[0].in_flight);
+        assert_eq!(1, snapshot[0].awaiting_redelivery);
+
+        drop(guard);
+        assert!(registry.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::time;
+use tokio::stream::Stream;
+
+use crate::dedup::DedupCache;
+use crate::typed::typed_message;
+use crate::{
+    protocol, Client, ClientBuilder, ClientWriter, SubscribeOptions, TypedError, TypedMessage,
+};
+
+/// Соединение, через которое только читают топики. Публиковать через него нельзя,
+/// для этого есть Producer:
+///
+/// ```compile_fail
+/// async fn publish_from_consumer(consumer: &mut zaichik::Consumer) {
+///     consumer.publish("topic".to_string(), None, vec![1]).await.unwrap();
+/// }
+/// ```
+pub struct Consumer {
+    client: Client,
+    // Отбрасывание повторных доставок, см. set_dedup.
+    dedup: Option<DedupCache>,
+    // Сообщения, которые отданы пользователю и ждут Commit или Nack, по id доставки.
+    // Nack с requeue забывает сообщение, чтобы его повторная доставка не считалась дублем.
+    unacked: HashMap<u64, (String, u64)>,
+}
+
+impl Consumer {
+    pub async fn connect(server_addr: &str) -> Result<Consumer, Box<dyn Error>> {
+        Client::connect(server_addr).await.map(Consumer::from)
+    }
+
+    pub async fn connect_with(
+        server_addr: &str,
+        options: ClientBuilder,
+    ) -> Result<Consumer, Box<dyn Error>> {
+        options.connect(server_addr).await.map(Consumer::from)
+    }
+
+    pub async fn subscribe_on(&mut self, topic: String) -> io::Result<()> {
+        self.client.subscribe_on(topic).await
+    }
+
+    pub async fn subscribe_with(
+        &mut self,
+        topic: String,
+        options: SubscribeOptions,
+    ) -> io::Result<()> {
+        self.client.subscribe_with(topic, options).await
+    }
+
+    pub async fn unsubscribe(&mut self, topic: String) -> io::Result<()> {
+        self.client.unsubscribe(topic).await
+    }
+
+    pub async fn pause(&mut self, topic: String) -> io::Result<()> {
+        self.client.pause(topic).await
+    }
+
+    pub async fn resume(&mut self, topic: String) -> io::Result<()> {
+        self.client.resume(topic).await
+    }
+
+    pub async fn set_prefetch(&mut self, count: u32) -> io::Result<()> {
+        self.client.set_prefetch(count).await
+    }
+
+    pub fn set_keepalive(&mut self, interval: Option<time::Duration>) {
+        self.client.set_keepalive(interval)
+    }
+
+    // Включает отбрасывание сообщений, которые уже приходили в течение window:
+    // при at-least-once доставке брокер может прислать сообщение повторно, например
+    // после переподключения или повторной подписки. Сообщение определяется топиком
+    // и offset, а когда приходит TopicDeleted, сообщения топика забываются.
+    // Помнится не больше max_ids последних сообщений.
+    // Дубль, который брокер прислал с id, подтверждается Commit автоматически.
+    // Работает в read_message, read_message_checked и read_typed, но не в into_stream и split.
+    // None выключает дедупликацию.
+    pub fn set_dedup(&mut self, window: Option<time::Duration>, max_ids: usize) {
+        self.dedup = window.map(|window| DedupCache::new(window, max_ids));
+        self.unacked.clear();
+    }
+
+    pub async fn read_message(&mut self) -> io::Result<Option<protocol::ZaichikFrame>> {
+        loop {
+            let frame = self.client.read_message().await?;
+            if let Some(protocol::ZaichikFrame::Publish {
+                id, topic, offset, ..
+            }) = &frame
+            {
+                if self.is_duplicate(*id, topic, *offset).await? {
+                    continue;
+                }
+            }
+            // Топик, созданный заново, начнет offset с нуля, и его сообщения
+            // не должны считаться дублями сообщений удаленного топика.
+            if let (Some(protocol::ZaichikFrame::TopicDeleted { topic }), Some(dedup)) =
+                (&frame, self.dedup.as_mut())
+            {
+                dedup.forget_topic(topic);
+            }
+
+            return Ok(frame);
+        }
+    }
+
+    async fn is_duplicate(&mut self, id: u64, topic: &str, offset: u64) -> io::Result<bool> {
+        let dedup = match self.dedup.as_mut() {
+            Some(dedup) => dedup,
+            None => return Ok(false),
+        };
+
+        if !dedup.is_duplicate(topic, offset, time::Instant::now()) {
+            if id != 0 {
+                self.unacked.insert(id, (topic.to_string(), offset));
+            }
+            return Ok(false);
+        }
+
+        debug!("Dropping duplicate delivery of {}:{}", topic, offset);
+        // Доставку, которую пользователь еще держит, он подтвердит сам.
+        if id != 0 && !self.unacked.contains_key(&id) {
+            self.client.commit(id).await?;
+        }
+        Ok(true)
+    }
+
+    pub async fn read_message_checked(&mut self) -> io::Result<Option<protocol::ZaichikFrame>> {
+        crate::checked(self.read_message().await?)
+    }
+
+    // Как Client::read_typed, но с дедупликацией, см. set_dedup.
+    pub async fn read_typed<T: serde::de::DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<TypedMessage<T>>, TypedError> {
+        loop {
+            match self.read_message_checked().await? {
+                Some(frame) => {
+                    if let Some(message) = typed_message(self.client.payload_format, frame) {
+                        return message.map(Some);
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    pub fn set_payload_format(&mut self, format: protocol::SerializationFormat) {
+        self.client.set_payload_format(format)
+    }
+
+    pub async fn commit(&mut self, id: u64) -> io::Result<()> {
+        self.unacked.remove(&id);
+        self.client.commit(id).await
+    }
+
+    pub async fn nack(&mut self, id: u64, requeue: bool) -> io::Result<()> {
+        let delivered = self.unacked.remove(&id);
+        if requeue {
+            if let (Some((topic, offset)), Some(dedup)) = (delivered, self.dedup.as_mut()) {
+                dedup.forget(&topic, offset);
+            }
+        }
+        self.client.nack(id, requeue).await
+    }
+
+    pub async fn ping(&mut self) -> io::Result<()> {
+        self.client.ping().await
+    }
+
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.client.close().await
+    }
+
+    pub async fn shutdown(self) -> io::Result<()> {
+        self.client.shutdown().await
+    }
+
+    // См. Client::into_stream: Commit уходит брокеру до того, как сообщение
+    // отдано пользователю.
+    pub fn into_stream(
+        self,
+    ) -> impl Stream<Item = io::Result<protocol::ZaichikFrame>> + Send + Unpin {
+        self.client.into_stream()
+    }
+
+    pub fn split(
+        self,
+    ) -> (
+        ClientWriter,
+        impl Stream<Item = io::Result<protocol::ZaichikFrame>> + Send + Unpin,
+    ) {
+        self.client.split()
+    }
+}
+
+impl From<Client> for Consumer {
+    fn from(client: Client) -> Consumer {
+        Consumer {
+            client,
+            dedup: None,
+            unacked: HashMap::new(),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::topic_controller::Message;
+
+pub type MemberId = u64;
+
+// Группа конкурирующих потребителей топика. В отличие от обычной подписки,
+// где каждый подписчик получает все сообщения, здесь каждое сообщение получает
+// только один участник группы. Участники выбираются по кругу, поэтому в рамках
+// одного участника порядок сообщений топика сохраняется.
+// Группа без участников ничего не хранит, топик удаляет ее, см. TopicController::leave_group.
+#[derive(Debug)]
+pub struct ConsumerGroup {
+    members: Vec<(MemberId, mpsc::Sender<Message>)>,
+    next_member: usize,
+    next_member_id: MemberId,
+    // Размер очереди каждого участника, такой же, как у броадкаста топика.
+    capacity: usize,
+}
+
+impl ConsumerGroup {
+    pub fn new(capacity: usize) -> ConsumerGroup {
+        ConsumerGroup {
+            members: Vec::new(),
+            next_member: 0,
+            next_member_id: 0,
+            capacity,
+        }
+    }
+
+    pub fn join(&mut self) -> (MemberId, mpsc::Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        let member_id = self.next_member_id;
+        self.next_member_id += 1;
+
+        self.members.push((member_id, sender));
+        (member_id, receiver)
+    }
+
+    pub fn member_count(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn leave(&mut self, member_id: MemberId) {
+        self.members.retain(|(id, _sender)| *id != member_id);
+    }
+
+    // Отдаем сообщение следующему по кругу участнику, в очереди которого есть место.
+    // Если места нет ни у кого или участников не осталось, то сообщение возвращается.
+    // Как и try_send, возвращаем само сообщение, а не ошибку.
+    #[allow(clippy::result_large_err)]
+    pub fn dispatch(&mut self, message: Message) -> Result<(), Message> {
+        let mut message = message;
+        let mut tried = 0;
+
+        // Если участник уже отключился, то убираем его из группы
+        // и пробуем отдать сообщение следующему.
+        while tried < self.members.len() {
+            let index = self.next_member % self.members.len();
+
+            match self.members[index].1.try_send(message) {
+                Ok(()) => {
+                    self.next_member = index + 1;
+                    return Ok(());
+                }
+                Err(TrySendError::Full(returned)) => {
+                    message = returned;
+                    self.next_member = index + 1;
+                    tried += 1;
+                }
+                Err(TrySendError::Closed(returned)) => {
+                    message = returned;
+                    self.members.remove(index);
+                }
+            }
+        }
+
+        Err(message)
+    }
+
+    // Есть участники, но ни в одну очередь сейчас не поместится сообщение.
+    // Место проверяем так же, как у Reliable подписчиков, через poll_ready у копии.
+    pub fn is_full(&self) -> bool {
+        let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
+
+        !self.members.is_empty()
+            && self
+                .members
+                .iter()
+                .all(|(_id, sender)| sender.clone().poll_ready(&mut cx).is_pending())
+    }
+
+    // Отправители участников для PendingDelivery, которая ждет места у любого из них.
+    pub fn senders(&self) -> Vec<mpsc::Sender<Message>> {
+        self.members
+            .iter()
+            .map(|(_id, sender)| sender.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time;
+
+    fn message(payload: u8) -> Message {
+        Message::new(
+            None,
+            vec![payload],
+            HashMap::new(),
+            time::Instant::now(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_message_is_returned_without_members() {
+        let mut group = ConsumerGroup::new(4);
+
+        let returned = group.dispatch(message(1)).unwrap_err();
+
+        assert_eq!(vec![1], *returned.payload);
+        assert!(group.is_empty());
+    }
+
+    #[test]
+    fn test_full_member_is_skipped_and_full_group_returns_message() {
+        let mut group = ConsumerGroup::new(1);
+
+        let (_first_id, mut first) = group.join();
+        let (_second_id, mut second) = group.join();
+
+        group.dispatch(message(1)).unwrap();
+        group.dispatch(message(2)).unwrap();
+        assert!(group.is_full());
+
+        let returned = group.dispatch(message(3)).unwrap_err();
+        assert_eq!(vec![3], *returned.payload);
+
+        // Первый участник освободил место, второй нет, поэтому сообщение уходит первому.
+        assert_eq!(vec![1], *first.try_recv().unwrap().payload);
+        assert!(!group.is_full());
+        group.dispatch(message(4)).unwrap();
+
+        assert_eq!(vec![4], *first.try_recv().unwrap().payload);
+        assert_eq!(vec![2], *second.try_recv().unwrap().payload);
+        assert!(second.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_left_member_does_not_receive_messages() {
+        let mut group = ConsumerGroup::new(4);
+
+        let (first_id, mut first) = group.join();
+        let (_second_id, mut second) = group.join();
+        group.leave(first_id);
+
+        group.dispatch(message(1)).unwrap();
+        group.dispatch(message(2)).unwrap();
+
+        assert!(first.try_recv().is_err());
+        assert_eq!(vec![1], *second.try_recv().unwrap().payload);
+        assert_eq!(vec![2], *second.try_recv().unwrap().payload);
+    }
+
+    #[test]
+    fn test_disconnected_member_is_skipped() {
+        let mut group = ConsumerGroup::new(4);
+
+        let (_first_id, first) = group.join();
+        let (_second_id, mut second) = group.join();
+        drop(first);
+
+        group.dispatch(message(1)).unwrap();
+
+        assert_eq!(vec![1], *second.try_recv().unwrap().payload);
+        assert_eq!(1, group.members.len());
+    }
+}
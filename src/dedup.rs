@@ -0,0 +1,141 @@
+use std::collections::{HashMap, VecDeque};
+use std::time;
+
+// Сообщение однозначно определяется топиком и offset: при повторной доставке,
+// в том числе после переподключения, offset у него тот же. id из фрейма Publish
+// для этого не подходит, он свой у каждого соединения, а у auto-ack подписок всегда 0.
+// У топика, созданного заново после удаления, offset снова начинаются с нуля,
+// поэтому при удалении топика его сообщения забываются, см. forget_topic.
+type MessageId = (String, u64);
+
+// Сообщения, которые Consumer уже отдал пользователю, см. Consumer::set_dedup.
+// Сообщение забывается через window после того, как его увидели, или раньше,
+// если запомнено больше max_ids сообщений: тогда забываются самые старые.
+#[derive(Debug)]
+pub(crate) struct DedupCache {
+    window: time::Duration,
+    max_ids: usize,
+    // Для каждого сообщения номер его записи в order. Запись, номер которой
+    // не совпадает, осталась от сообщения, которое уже забыли через forget.
+    seen: HashMap<MessageId, u64>,
+    order: VecDeque<(MessageId, time::Instant, u64)>,
+    next_entry: u64,
+}
+
+impl DedupCache {
+    pub(crate) fn new(window: time::Duration, max_ids: usize) -> DedupCache {
+        DedupCache {
+            window,
+            max_ids: max_ids.max(1),
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            next_entry: 0,
+        }
+    }
+
+    // true, если сообщение уже видели в пределах окна. Иначе запоминает его.
+    pub(crate) fn is_duplicate(&mut self, topic: &str, offset: u64, now: time::Instant) -> bool {
+        self.evict(now);
+
+        let id = (topic.to_string(), offset);
+        if self.seen.contains_key(&id) {
+            return true;
+        }
+
+        let entry = self.next_entry;
+        self.next_entry += 1;
+        self.seen.insert(id.clone(), entry);
+        self.order.push_back((id, now, entry));
+        self.evict(now);
+
+        false
+    }
+
+    // Следующая доставка сообщения уже не будет дублем, например после Nack с requeue.
+    pub(crate) fn forget(&mut self, topic: &str, offset: u64) {
+        self.seen.remove(&(topic.to_string(), offset));
+    }
+
+    // Топик удален, и если его создадут заново, то те же offset будут у новых сообщений.
+    pub(crate) fn forget_topic(&mut self, topic: &str) {
+        self.seen.retain(|(seen_topic, _), _| seen_topic != topic);
+        self.order
+            .retain(|((seen_topic, _), _, _)| seen_topic != topic);
+    }
+
+    fn evict(&mut self, now: time::Instant) {
+        while let Some((_, seen_at, _)) = self.order.front() {
+            let expired = now.duration_since(*seen_at) >= self.window;
+            if !expired && self.order.len() <= self.max_ids {
+                break;
+            }
+
+            let (id, _, entry) = self.order.pop_front().unwrap();
+            if self.seen.get(&id) == Some(&entry) {
+                self.seen.remove(&id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_cache_drops_repeated_offsets_within_window() {
+        let mut cache = DedupCache::new(time::Duration::from_secs(10), 100);
+        let start = time::Instant::now();
+
+        assert!(!cache.is_duplicate("topic", 1, start));
+        assert!(!cache.is_duplicate("other", 1, start));
+        assert!(cache.is_duplicate("topic", 1, start + time::Duration::from_secs(5)));
+
+        // Окно отсчитывается от первой доставки, повтор его не продлевает.
+        assert!(!cache.is_duplicate("topic", 1, start + time::Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_dedup_cache_forgets_oldest_ids_over_limit() {
+        let mut cache = DedupCache::new(time::Duration::from_secs(10), 2);
+        let now = time::Instant::now();
+
+        for offset in 0..3 {
+            assert!(!cache.is_duplicate("topic", offset, now));
+        }
+
+        assert_eq!(2, cache.seen.len());
+        assert_eq!(2, cache.order.len());
+        assert!(cache.is_duplicate("topic", 2, now));
+        assert!(!cache.is_duplicate("topic", 0, now));
+    }
+
+    #[test]
+    fn test_dedup_cache_forgotten_id_is_seen_again() {
+        let mut cache = DedupCache::new(time::Duration::from_secs(10), 2);
+        let now = time::Instant::now();
+
+        assert!(!cache.is_duplicate("topic", 0, now));
+        cache.forget("topic", 0);
+        assert!(!cache.is_duplicate("topic", 0, now));
+        assert!(!cache.is_duplicate("topic", 1, now));
+
+        // Старая запись offset 0 вытесняется первой и не должна забыть новую.
+        assert!(cache.is_duplicate("topic", 0, now));
+        assert!(cache.is_duplicate("topic", 1, now));
+    }
+
+    #[test]
+    fn test_dedup_cache_forgets_deleted_topic() {
+        let mut cache = DedupCache::new(time::Duration::from_secs(10), 100);
+        let now = time::Instant::now();
+
+        assert!(!cache.is_duplicate("topic", 0, now));
+        assert!(!cache.is_duplicate("other", 0, now));
+        cache.forget_topic("topic");
+
+        assert!(!cache.is_duplicate("topic", 0, now));
+        assert!(cache.is_duplicate("other", 0, now));
+        assert_eq!(2, cache.order.len());
+    }
+}
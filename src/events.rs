@@ -0,0 +1,24 @@
+use std::net::SocketAddr;
+
+// Хук, через который внешние системы узнают о подключениях, подписках и публикациях,
+// не разбирая логи брокера. Брокер вызывает методы из задач подключений, поэтому
+// они должны быть быстрыми и не блокировать: долгую работу лучше отдать в свою задачу.
+// Все методы по умолчанию ничего не делают, так что реализовать можно только нужные.
+pub trait BrokerEvents: Send + Sync {
+    // Клиент прошел Handshake и, если она включена, аутентификацию.
+    fn on_connected(&self, _peer: SocketAddr, _principal: Option<&str>) {}
+
+    // Подключение закрыто, все его подписки уже сняты.
+    fn on_disconnected(&self, _peer: SocketAddr) {}
+
+    // Клиент подписался на топик сам или по шаблону.
+    fn on_subscribed(&self, _peer: SocketAddr, _topic: &str) {}
+
+    // Клиент отписался от топика. При отключении клиента не вызывается,
+    // о нем сообщает on_disconnected.
+    fn on_unsubscribed(&self, _peer: SocketAddr, _topic: &str) {}
+
+    // Брокер принял сообщение клиента в топик, в том числе отложенное
+    // или отброшенное compaction как дубль.
+    fn on_published(&self, _peer: SocketAddr, _topic: &str, _payload_len: usize) {}
+}
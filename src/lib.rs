@@ -1,27 +1,998 @@
-use futures::SinkExt;
+use futures::stream::SplitSink;
+use futures::{FutureExt, Sink, SinkExt};
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
-use tokio::stream::StreamExt;
-mod protocol;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::{Context, Poll};
+use std::time;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::stream::{Stream, StreamExt};
+#[cfg(feature = "connection-spans")]
+use tracing::Instrument;
 
+#[macro_use]
+extern crate log;
+
+mod acl;
+mod auth;
+mod backoff;
+pub mod blocking;
+pub mod broker;
+mod clock;
+mod connection_registry;
+mod consumer;
+mod consumer_group;
+mod dedup;
+mod events;
+mod in_memory;
+mod locks;
+mod metrics;
+mod producer;
+pub mod protocol;
+mod reconnecting;
+mod shared;
+mod storage;
+mod subscription_manager;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod topic_controller;
+mod topic_registry;
+mod typed;
+mod unix_socket;
+
+pub use backoff::Backoff;
+pub use consumer::Consumer;
+pub use in_memory::{duplex, DuplexStream};
+pub use producer::Producer;
+pub use protocol::{TopicConfig, ZaichikFrame};
+pub use reconnecting::{ReconnectPolicy, ReconnectingClient};
+pub use shared::SharedClient;
+pub use typed::{TypedError, TypedMessage};
+// Реэкспорт, чтобы пользователь мог собрать RootCertStore для Client::connect_tls.
+pub use tokio_rustls::rustls;
+
+// Сокет клиента: обычный TcpStream, TLS стрим поверх него или UnixStream.
+trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+type Framed = tokio_util::codec::Framed<Box<dyn AsyncStream>, protocol::ZaichikCodec>;
+
+// Сокет соединения. Его делят Connection и задача keepalive, поэтому он лежит под
+// мьютексом. Мьютекс берется только внутри poll_* и не держится между ними.
+struct Socket {
+    framed: Framed,
+    // В буфере кодека есть фреймы, которые еще не сброшены в сокет. Пока это так,
+    // keepalive не пишет: сбросить их должен тот, кто их отправил, а соединение
+    // и так не простаивает.
+    writing: bool,
+    // С прошлой проверки keepalive клиент что-то отправил брокеру.
+    written: bool,
+}
+
+impl Socket {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.framed).poll_ready(cx)
+    }
+
+    fn start_send(&mut self, frame: protocol::ZaichikFrame) -> Result<(), std::io::Error> {
+        self.writing = true;
+        self.written = true;
+        Pin::new(&mut self.framed).start_send(frame)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        futures::ready!(Pin::new(&mut self.framed).poll_flush(cx))?;
+        self.writing = false;
+        Poll::Ready(Ok(()))
+    }
+
+    // Ping, если с прошлой проверки клиент ничего не отправил. Запись не ждем:
+    // если сокет сейчас не готов, то Ping уйдет вместе со следующим фреймом клиента.
+    fn ping_if_idle(&mut self) -> Result<(), std::io::Error> {
+        if std::mem::replace(&mut self.written, false) || self.writing {
+            return Ok(());
+        }
+
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        match self.poll_ready(&mut cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Err(e),
+            Poll::Pending => return Ok(()),
+        }
+        self.start_send(protocol::ZaichikFrame::Ping)?;
+        self.written = false;
+        match self.poll_flush(&mut cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Ok(()),
+        }
+    }
+}
+
+fn lock_socket(socket: &Mutex<Socket>) -> MutexGuard<'_, Socket> {
+    socket
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Раз в interval отправляем брокеру Ping, если за это время клиент ничего ему не отправил,
+// чтобы брокер не закрыл соединение по IDLE_TIMEOUT. Задача не зависит от того, читает
+// ли клиент сообщения, и заканчивается, когда соединение удалено, запись в сокет
+// сломалась или отправитель stop удален.
+fn spawn_keepalive(
+    socket: Weak<Mutex<Socket>>,
+    interval: time::Duration,
+    mut stop: tokio::sync::oneshot::Receiver<()>,
+) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = &mut stop => return,
+            }
+
+            let socket = match socket.upgrade() {
+                Some(socket) => socket,
+                None => return,
+            };
+            let pinged = lock_socket(&socket).ping_if_idle();
+            if let Err(e) = pinged {
+                debug!("Keepalive stopped; error = {}", e);
+                return;
+            }
+        }
+    });
+}
+
+// Соединение клиента с брокером. Если клиент удалили, не закрыв соединение через
+// close или shutdown, то при удалении соединение без ожидания отправляет брокеру
+// CloseConnection. Брокер тогда сразу снимает подписки, не дожидаясь, пока заметит
+// закрытый сокет, а фреймы, которые еще лежат в буфере, уходят вместе с ним.
+// Если сокет не готов принять их сразу, то об отключении брокер узнает по закрытию сокета.
+struct Connection {
+    socket: Arc<Mutex<Socket>>,
+    // CloseConnection уже отправлен, при удалении повторять его не нужно.
+    closed: bool,
+    // Фреймы, которые publish отложил, пока запись в сокет стоит, см. try_send.
+    // Любая другая отправка сначала дожидается, пока они уйдут, так что порядок
+    // фреймов не меняется.
+    outbound: VecDeque<protocol::ZaichikFrame>,
+    // Последний фрейм из outbound еще не сброшен в сокет.
+    unflushed: bool,
+}
+
+impl Connection {
+    fn new(framed: Framed) -> Connection {
+        let socket = Socket {
+            framed,
+            writing: false,
+            written: false,
+        };
+
+        Connection {
+            socket: Arc::new(Mutex::new(socket)),
+            closed: false,
+            outbound: VecDeque::new(),
+            unflushed: false,
+        }
+    }
+
+    // Запускает keepalive, см. spawn_keepalive. Он работает, пока жив возвращенный
+    // отправитель.
+    fn keepalive(&self, interval: time::Duration) -> tokio::sync::oneshot::Sender<()> {
+        let (stop, stopped) = tokio::sync::oneshot::channel();
+        spawn_keepalive(Arc::downgrade(&self.socket), interval, stopped);
+        stop
+    }
+
+    // Отправляет отложенные фреймы по одному: следующий уходит, только когда
+    // предыдущий сброшен в сокет. Так фрейм, который ждет в outbound, еще можно
+    // посчитать, а не теряется в буфере кодека.
+    fn poll_outbound(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        let mut socket = lock_socket(&self.socket);
+
+        loop {
+            if self.unflushed {
+                futures::ready!(socket.poll_flush(cx))?;
+                self.unflushed = false;
+            }
+
+            let frame = match self.outbound.pop_front() {
+                Some(frame) => frame,
+                None => return Poll::Ready(Ok(())),
+            };
+            match socket.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                other => {
+                    self.outbound.push_front(frame);
+                    return other;
+                }
+            }
+            socket.start_send(frame)?;
+            self.unflushed = true;
+        }
+    }
+
+    // Отправляет фрейм, не дожидаясь записи в сокет. Если запись сейчас стоит, то фрейм
+    // ждет в outbound и уйдет при следующей отправке или flush. Когда неотправленных
+    // фреймов уже capacity, новый не принимается и возвращается ошибка WouldBlock.
+    fn try_send(
+        &mut self,
+        frame: protocol::ZaichikFrame,
+        capacity: usize,
+    ) -> Result<(), std::io::Error> {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        if let Poll::Ready(Err(e)) = self.poll_outbound(&mut cx) {
+            return Err(e);
+        }
+        if self.pending_outbound() >= capacity {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                format!("Outbound buffer is full, {} frames are not sent", capacity),
+            ));
+        }
+
+        self.outbound.push_back(frame);
+        match self.poll_outbound(&mut cx) {
+            Poll::Ready(Err(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn pending_outbound(&self) -> usize {
+        self.outbound.len() + self.unflushed as usize
+    }
+}
+
+impl Stream for Connection {
+    type Item = Result<protocol::ZaichikFrame, std::io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut lock_socket(&self.socket).framed).poll_next(cx)
+    }
+}
+
+impl Sink<protocol::ZaichikFrame> for Connection {
+    type Error = std::io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(self.poll_outbound(cx))?;
+        lock_socket(&self.socket).poll_ready(cx)
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        frame: protocol::ZaichikFrame,
+    ) -> Result<(), Self::Error> {
+        if let protocol::ZaichikFrame::CloseConnection = frame {
+            self.closed = true;
+        }
+        lock_socket(&self.socket).start_send(frame)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(self.poll_outbound(cx))?;
+        lock_socket(&self.socket).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(self.poll_outbound(cx))?;
+        let mut socket = lock_socket(&self.socket);
+        futures::ready!(socket.poll_flush(cx))?;
+        Pin::new(&mut socket.framed).poll_close(cx)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if !self.closed {
+            let frame = protocol::ZaichikFrame::CloseConnection {};
+            let _ = self.send(frame).now_or_never();
+        }
+    }
+}
+
+// Заголовок, в котором издатель указывает, чем сжат payload. Брокер payload
+// не трогает и хранит сжатым, а клиент подписчика распаковывает его сам
+// и убирает этот заголовок, так что пользователь получает исходные байты.
+pub const COMPRESSION_HEADER: &str = "zaichik-compression";
+
+// Заголовки запроса в Client::request. По correlation id запрос находит свой ответ
+// в reply топике, а reply-to говорит отвечающему, куда отправить ответ.
+pub const CORRELATION_ID_HEADER: &str = "zaichik-correlation-id";
+pub const REPLY_TO_HEADER: &str = "zaichik-reply-to";
+
+// Номер запроса в этом процессе, часть correlation id.
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+// Correlation id должен отличаться и у запросов других процессов, которые ждут
+// ответа в том же топике, поэтому кроме номера в нем есть pid и время.
+fn next_correlation_id() -> String {
+    let nanos = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+
+    format!(
+        "{}-{}-{}",
+        std::process::id(),
+        nanos,
+        NEXT_REQUEST.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// Чем клиент сжимает payload перед публикацией.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl Compression {
+    fn header_value(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Lz4 => Some("lz4"),
+        }
+    }
+
+    // Сжимаем payload и отмечаем в заголовках, чем он сжат.
+    fn compress(self, payload: Vec<u8>, headers: &mut HashMap<String, String>) -> Vec<u8> {
+        if let Some(value) = self.header_value() {
+            headers.insert(COMPRESSION_HEADER.to_string(), value.to_string());
+        }
+
+        match self {
+            Compression::Lz4 => lz4_flex::compress_prepend_size(&payload),
+            Compression::None => payload,
+        }
+    }
+}
+
+// Клиент, через который можно и публиковать, и читать. Если соединению нужно
+// что-то одно, то лучше взять Producer или Consumer.
 pub struct Client {
-    stream: tokio_util::codec::Framed<tokio::net::TcpStream, protocol::ZaichikCodec>,
+    stream: Connection,
+    // Пока отправитель жив, работает keepalive, см. set_keepalive.
+    keepalive: Option<tokio::sync::oneshot::Sender<()>>,
+    read_timeout: Option<time::Duration>,
+    compression: Compression,
+    payload_format: protocol::SerializationFormat,
+    #[cfg(feature = "connection-spans")]
+    span: Option<tracing::Span>,
+    // Фреймы, которые пришли, пока мы ждали ответа на запрос (например, ListTopics).
+    // read_message отдает их в первую очередь, чтобы ничего не потерялось.
+    pending_frames: VecDeque<protocol::ZaichikFrame>,
+    // Сколько фреймов publish может отложить, пока запись в сокет стоит,
+    // см. ClientBuilder::outbound_buffer. None - publish ждет записи.
+    outbound_capacity: Option<usize>,
+}
+
+// Пишущая половина клиента, которая остается у пользователя после Client::split.
+// Через нее подтверждаются сообщения, полученные из стрима.
+pub struct ClientWriter {
+    sink: SplitSink<Connection, protocol::ZaichikFrame>,
+    // keepalive клиента продолжает работать, пока жива пишущая половина.
+    _keepalive: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+// Настройки подключения к брокеру. Client::connect, connect_with_format
+// и connect_tls - это сокращения для самых частых случаев.
+#[derive(Clone)]
+pub struct ClientBuilder {
+    format: protocol::SerializationFormat,
+    token: Option<String>,
+    tls: Option<(String, rustls::RootCertStore)>,
+    compression: Compression,
+    payload_format: protocol::SerializationFormat,
+    connect_timeout: Option<time::Duration>,
+    read_timeout: Option<time::Duration>,
+    nodelay: bool,
+    outbound_capacity: Option<usize>,
+    #[cfg(feature = "connection-spans")]
+    connection_span: bool,
+}
+
+// Сколько по умолчанию ждем подключения вместе с TLS и Handshake. Без таймаута
+// подключение к неверному адресу висит, пока не сдастся ОС.
+pub const DEFAULT_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+// Ошибка, которую брокер прислал во фрейме Error. Коды описаны в protocol
+// (ERROR_TOPIC_DELETED и другие). read_message_checked кладет ее внутрь
+// io::Error, достать ее можно через get_ref и downcast_ref.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerError {
+    pub code: u16,
+    pub message: String,
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Broker error ({}): {}", self.code, self.message)
+    }
+}
+
+impl Error for BrokerError {}
+
+// Фрейм Error превращается в Err с BrokerError внутри, см. read_message_checked.
+fn checked(
+    frame: Option<protocol::ZaichikFrame>,
+) -> Result<Option<protocol::ZaichikFrame>, std::io::Error> {
+    match frame {
+        Some(protocol::ZaichikFrame::Error { code, message, .. }) => {
+            Err(std::io::Error::other(BrokerError { code, message }))
+        }
+        other => Ok(other),
+    }
+}
+
+// Настройки подписки для Client::subscribe_with. По умолчанию подписка такая же,
+// как у subscribe_on: сначала все retained сообщения топика, потом новые,
+// и каждое сообщение ждет Commit.
+#[derive(Clone, Debug, Default)]
+pub struct SubscribeOptions {
+    group: Option<String>,
+    start: protocol::DeliveryStart,
+    key_filter: Option<Vec<String>>,
+    auto_ack: bool,
+    from_offset: Option<u64>,
+    max_messages_per_sec: Option<u32>,
+    flush_policy: protocol::FlushPolicy,
+    confirm: bool,
+    drain: bool,
+    deliver_expired: bool,
+    partition: Option<u32>,
+}
+
+impl SubscribeOptions {
+    pub fn new() -> SubscribeOptions {
+        SubscribeOptions::default()
+    }
+
+    // С DeliveryStart::Latest подписка получит только сообщения, опубликованные после нее,
+    // без retained истории топика. С SnapshotOnly из retained сообщений брокер пришлет
+    // только последнее по каждому ключу: вместе с drain это снимок текущего состояния
+    // топика, а без drain после снимка придут новые сообщения.
+    pub fn start(mut self, start: protocol::DeliveryStart) -> SubscribeOptions {
+        self.start = start;
+        self
+    }
+
+    // Подписка в составе группы потребителей: каждое сообщение топика
+    // получит только один из подключенных участников группы.
+    pub fn group(mut self, group: String) -> SubscribeOptions {
+        self.group = Some(group);
+        self
+    }
+
+    // Брокер присылает только сообщения с одним из keys. Сообщения без ключа
+    // по такой подписке не приходят.
+    pub fn key_filter(mut self, keys: Vec<String>) -> SubscribeOptions {
+        self.key_filter = Some(keys);
+        self
+    }
+
+    // Подписка без подтверждений: брокер присылает сообщения, не дожидаясь Commit,
+    // и не ограничивает их prefetch. Сообщения приходят с id = 0, подтверждать их
+    // не нужно, а если клиент отключится, не успев их обработать, они потеряются.
+    pub fn auto_ack(mut self, auto_ack: bool) -> SubscribeOptions {
+        self.auto_ack = auto_ack;
+        self
+    }
+
+    // Продолжаем чтение топика после сообщения с этим offset (поле offset в Publish).
+    // Если оно и следующие за ним уже удалены по retention, то читаем с самого старого.
+    pub fn from_offset(mut self, offset: u64) -> SubscribeOptions {
+        self.from_offset = Some(offset);
+        self
+    }
+
+    // Брокер будет присылать сообщения этой подписки не чаще max_messages_per_sec,
+    // даже если prefetch позволяет больше.
+    pub fn max_messages_per_sec(mut self, max_messages_per_sec: u32) -> SubscribeOptions {
+        self.max_messages_per_sec = Some(max_messages_per_sec);
+        self
+    }
+
+    // Batched и OnIdle экономят системные вызовы при большом потоке сообщений,
+    // а Immediate отправляет каждое сообщение сразу.
+    pub fn flush_policy(mut self, flush_policy: protocol::FlushPolicy) -> SubscribeOptions {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    // subscribe_with вернется, только когда брокер ответит Subscribed или ошибкой.
+    pub fn confirm(mut self, confirm: bool) -> SubscribeOptions {
+        self.confirm = confirm;
+        self
+    }
+
+    // Подписка для пакетной обработки: брокер пришлет сообщения, которые есть
+    // в топике сейчас, а после них EndOfStream с этим топиком и снимет подписку.
+    pub fn drain(mut self, drain: bool) -> SubscribeOptions {
+        self.drain = drain;
+        self
+    }
+
+    // Брокер присылает и сообщения, которые истекли, пока ждали отправки. Обычная
+    // подписка такие сообщения пропускает, их видно в TopicStats::expired_drops.
+    pub fn deliver_expired(mut self, deliver_expired: bool) -> SubscribeOptions {
+        self.deliver_expired = deliver_expired;
+        self
+    }
+
+    // Подписка на одну партицию топика, созданного с partitions. Брокер пришлет
+    // только сообщения этой партиции, в порядке их публикации.
+    pub fn partition(mut self, partition: u32) -> SubscribeOptions {
+        self.partition = Some(partition);
+        self
+    }
+
+    pub(crate) fn into_frame(self, topic: String) -> protocol::ZaichikFrame {
+        protocol::ZaichikFrame::Subscribe {
+            topic,
+            group: self.group,
+            start: self.start,
+            key_filter: self.key_filter,
+            auto_ack: self.auto_ack,
+            from_offset: self.from_offset,
+            max_messages_per_sec: self.max_messages_per_sec,
+            flush_policy: self.flush_policy,
+            confirm: self.confirm,
+            drain: self.drain,
+            deliver_expired: self.deliver_expired,
+            partition: self.partition,
+        }
+    }
+}
+
+// Настройки, с которыми работает топик, см. Client::create_topic. Нули значат то же,
+// что и в CreateTopic, а buffer_size уже заменен на размер по умолчанию, если не был задан.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CreatedTopic {
+    pub retention_ttl: u64,
+    pub compaction_window: u64,
+    pub retention_max_messages: u64,
+    pub retention_max_bytes: u64,
+    pub compaction_mode: protocol::CompactionMode,
+    pub buffer_size: u32,
+    pub delivery: protocol::DeliveryGuarantee,
+    pub key_source: protocol::KeySource,
+    pub partitions: u32,
+    pub ordering: protocol::OrderingGuarantee,
+    pub max_message_bytes: u64,
+    // Топик уже был с такими же настройками.
+    pub already_existed: bool,
+}
+
+// Состояние топика на момент запроса, см. Client::topic_stats.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicStats {
+    pub retained_count: u64,
+    pub retained_bytes: u64,
+    pub compaction_keys: u64,
+    pub subscriber_count: u64,
+    // Сообщения, которые истекли до отправки подписчику и были пропущены.
+    pub expired_drops: u64,
+    // Все опубликованные в топик сообщения. Из них duplicate_drops отброшены
+    // Dedup compaction, а kept_messages прошли его и получили offset.
+    pub published_messages: u64,
+    pub duplicate_drops: u64,
+    pub kept_messages: u64,
+}
+
+// Ответ брокера на публикацию, см. Client::publish_acked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishAck {
+    // false, если брокер отклонил сообщение. Причина тогда в reason.
+    pub accepted: bool,
+    pub reason: Option<String>,
+    // Сообщение принято, но compaction отбросил его как дубль ключа.
+    pub dropped_as_duplicate: bool,
+}
+
+impl ClientBuilder {
+    // Формат должен совпадать с тем, с которым запущен брокер (переменная FORMAT).
+    pub fn format(mut self, format: protocol::SerializationFormat) -> ClientBuilder {
+        self.format = format;
+        self
+    }
+
+    // Токен для брокера с включенной аутентификацией (ZAICHIK_AUTH_TOKENS).
+    // Клиент отправит его сразу после Handshake.
+    pub fn token(mut self, token: String) -> ClientBuilder {
+        self.token = Some(token);
+        self
+    }
+
+    // Подключение по TLS. Сертификат брокера проверяется по root_store
+    // и должен быть выдан на server_name.
+    pub fn tls(mut self, server_name: String, root_store: rustls::RootCertStore) -> ClientBuilder {
+        self.tls = Some((server_name, root_store));
+        self
+    }
+
+    // Сжимать payload в publish и publish_with_headers. Полученные сообщения
+    // клиент распаковывает всегда, независимо от этой настройки.
+    pub fn compression(mut self, compression: Compression) -> ClientBuilder {
+        self.compression = compression;
+        self
+    }
+
+    // Формат payload в publish_typed и read_typed, см. Client::set_payload_format.
+    pub fn payload_format(mut self, format: protocol::SerializationFormat) -> ClientBuilder {
+        self.payload_format = format;
+        self
+    }
+
+    // Сколько ждать подключения, включая TLS и Handshake. None - ждать, сколько потребуется.
+    pub fn connect_timeout(mut self, timeout: Option<time::Duration>) -> ClientBuilder {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    // Сколько read_message и запросы вроде list_topics ждут очередного фрейма от брокера,
+    // прежде чем вернуть ошибку TimedOut. По умолчанию ждут сколько угодно. Если брокер
+    // просто давно ничего не присылал, то таймаут тоже сработает, так что для подписчиков
+    // на редкие топики его лучше не включать.
+    pub fn read_timeout(mut self, timeout: Option<time::Duration>) -> ClientBuilder {
+        self.read_timeout = timeout;
+        self
+    }
+
+    // Буфер на capacity фреймов для publish. Если запись в сокет застряла, то publish
+    // не ждет ее, а откладывает фрейм, и он уйдет при следующей отправке или
+    // Client::flush. Когда буфер полон, publish возвращает ошибку с ErrorKind::WouldBlock,
+    // и сообщение можно опубликовать позже. Publish с ack и остальные фреймы по-прежнему
+    // ждут записи, в том числе отложенных фреймов. При переподключении ReconnectingClient
+    // неотправленные фреймы старого соединения теряются. По умолчанию буфера нет.
+    pub fn outbound_buffer(mut self, capacity: Option<usize>) -> ClientBuilder {
+        self.outbound_capacity = capacity;
+        self
+    }
+
+    // TCP_NODELAY, по умолчанию включен, чтобы небольшие фреймы уходили сразу.
+    pub fn nodelay(mut self, nodelay: bool) -> ClientBuilder {
+        self.nodelay = nodelay;
+        self
+    }
+
+    // Создавать ли для подключения tracing span "zaichik_connection" с адресом брокера.
+    // Подключение проходит внутри него, а потом его можно взять через Client::span
+    // и обернуть в него свою обработку сообщений.
+    #[cfg(feature = "connection-spans")]
+    pub fn connection_span(mut self, enabled: bool) -> ClientBuilder {
+        self.connection_span = enabled;
+        self
+    }
+
+    pub async fn connect(self, server_addr: &str) -> Result<Client, Box<dyn Error>> {
+        #[cfg(feature = "connection-spans")]
+        {
+            if self.connection_span {
+                let span = tracing::debug_span!("zaichik_connection", server_addr);
+                let mut client = self
+                    .connect_with_timeout(server_addr)
+                    .instrument(span.clone())
+                    .await?;
+                client.span = Some(span);
+                return Ok(client);
+            }
+        }
+
+        self.connect_with_timeout(server_addr).await
+    }
+
+    // Подключение поверх уже открытого стрима, например одного из концов duplex.
+    // Handshake и Authenticate проходят так же, как при connect, а tls, nodelay
+    // и connect_timeout не используются.
+    pub async fn connect_stream<S>(self, stream: S) -> Result<Client, Box<dyn Error>>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let mut client = Client::handshake(Box::new(stream), self.format, self.token).await?;
+        client.compression = self.compression;
+        client.payload_format = self.payload_format;
+        client.read_timeout = self.read_timeout;
+        client.outbound_capacity = self.outbound_capacity;
+        Ok(client)
+    }
+
+    // Подключение к брокеру на этой же машине через UNIX сокет (ZAICHIK_UNIX_SOCKET
+    // на брокере). tls и nodelay для него не используются.
+    #[cfg(unix)]
+    pub async fn connect_unix<P>(self, path: P) -> Result<Client, Box<dyn Error>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        let path = path.as_ref();
+        let connect_timeout = self.connect_timeout;
+        let read_timeout = self.read_timeout;
+        debug!("Connecting to {}", path.display());
+
+        let establish = async {
+            let stream = tokio::net::UnixStream::connect(path).await?;
+            let mut client = Client::handshake(Box::new(stream), self.format, self.token).await?;
+            client.compression = self.compression;
+            client.payload_format = self.payload_format;
+            client.outbound_capacity = self.outbound_capacity;
+            Ok::<Client, Box<dyn Error>>(client)
+        };
+        let mut client = match connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, establish)
+                .await
+                .map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("Connection to {} timed out", path.display()),
+                    )
+                })??,
+            None => establish.await?,
+        };
+
+        client.read_timeout = read_timeout;
+        debug!("Established connection to {}", path.display());
+        Ok(client)
+    }
+
+    async fn connect_with_timeout(self, server_addr: &str) -> Result<Client, Box<dyn Error>> {
+        let connect_timeout = self.connect_timeout;
+        let read_timeout = self.read_timeout;
+        debug!("Connecting to {}", server_addr);
+
+        let mut client = match connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.establish(server_addr))
+                .await
+                .map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("Connection to {} timed out", server_addr),
+                    )
+                })??,
+            None => self.establish(server_addr).await?,
+        };
+
+        client.read_timeout = read_timeout;
+        debug!("Established connection to {}", server_addr);
+        Ok(client)
+    }
+
+    async fn establish(self, server_addr: &str) -> Result<Client, Box<dyn Error>> {
+        let stream = tokio::net::TcpStream::connect(server_addr).await?;
+        stream.set_nodelay(self.nodelay)?;
+
+        let stream: Box<dyn AsyncStream> = match self.tls {
+            Some((server_name, root_store)) => {
+                let mut config = rustls::ClientConfig::new();
+                config.root_store = root_store;
+                let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+                let domain = tokio_rustls::webpki::DNSNameRef::try_from_ascii_str(&server_name)
+                    .map_err(|_| format!("Invalid server name {}", server_name))?;
+
+                Box::new(connector.connect(domain, stream).await?)
+            }
+            None => Box::new(stream),
+        };
+
+        let mut client = Client::handshake(stream, self.format, self.token).await?;
+        client.compression = self.compression;
+        client.payload_format = self.payload_format;
+        client.outbound_capacity = self.outbound_capacity;
+        Ok(client)
+    }
 }
 
 impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder {
+            format: protocol::SerializationFormat::Bincode,
+            token: None,
+            tls: None,
+            compression: Compression::None,
+            payload_format: protocol::SerializationFormat::Json,
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            read_timeout: None,
+            nodelay: true,
+            outbound_capacity: None,
+            #[cfg(feature = "connection-spans")]
+            connection_span: false,
+        }
+    }
+
     pub async fn connect(server_addr: &str) -> Result<Client, Box<dyn Error>> {
-        // let server_addr = "127.0.0.1:61616";
-        println!("Connecting to {} ...", server_addr);
+        Self::connect_with_format(server_addr, protocol::SerializationFormat::Bincode).await
+    }
 
-        let stream = tokio::net::TcpStream::connect(server_addr).await?;
-        let framed = tokio_util::codec::Framed::new(stream, protocol::ZaichikCodec::new());
+    // Span подключения, если он включен через ClientBuilder::connection_span.
+    #[cfg(feature = "connection-spans")]
+    pub fn span(&self) -> Option<&tracing::Span> {
+        self.span.as_ref()
+    }
 
-        println!("Established connection to {}", server_addr);
+    // Подключение с настройками из Client::builder(), например с другим connect_timeout.
+    pub async fn connect_with(
+        server_addr: &str,
+        options: ClientBuilder,
+    ) -> Result<Client, Box<dyn Error>> {
+        options.connect(server_addr).await
+    }
+
+    // Формат должен совпадать с тем, с которым запущен брокер (переменная FORMAT).
+    pub async fn connect_with_format(
+        server_addr: &str,
+        format: protocol::SerializationFormat,
+    ) -> Result<Client, Box<dyn Error>> {
+        Self::builder().format(format).connect(server_addr).await
+    }
 
-        Ok(Client { stream: framed })
+    // Подключение поверх стрима с настройками по умолчанию, см. ClientBuilder::connect_stream.
+    pub async fn connect_stream<S>(stream: S) -> Result<Client, Box<dyn Error>>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        Self::builder().connect_stream(stream).await
     }
 
-    pub async fn read_message(&mut self) -> Result<protocol::ZaichikFrame, std::io::Error> {
-        self.stream.next().await.unwrap()
+    // Подключение через UNIX сокет с настройками по умолчанию, см. ClientBuilder::connect_unix.
+    #[cfg(unix)]
+    pub async fn connect_unix<P>(path: P) -> Result<Client, Box<dyn Error>>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        Self::builder().connect_unix(path).await
+    }
+
+    // Подключение по TLS. Сертификат брокера проверяется по root_store
+    // и должен быть выдан на server_name.
+    pub async fn connect_tls(
+        server_addr: &str,
+        server_name: &str,
+        root_store: rustls::RootCertStore,
+    ) -> Result<Client, Box<dyn Error>> {
+        Self::builder()
+            .tls(server_name.to_string(), root_store)
+            .connect(server_addr)
+            .await
+    }
+
+    async fn handshake(
+        stream: Box<dyn AsyncStream>,
+        format: protocol::SerializationFormat,
+        token: Option<String>,
+    ) -> Result<Client, Box<dyn Error>> {
+        let mut framed =
+            tokio_util::codec::Framed::new(stream, protocol::ZaichikCodec::new(format));
+
+        // Сообщаем брокеру версию протокола и ждем, что он ее поддерживает.
+        framed
+            .send(protocol::ZaichikFrame::Handshake {
+                protocol_version: protocol::PROTOCOL_VERSION,
+            })
+            .await?;
+
+        match framed.next().await.transpose()? {
+            Some(protocol::ZaichikFrame::Handshake { protocol_version })
+                if protocol::is_supported_version(protocol_version) => {}
+            Some(protocol::ZaichikFrame::Error { code, message, .. }) => {
+                return Err(format!("Handshake rejected by broker ({}): {}", code, message).into())
+            }
+            other => return Err(format!("Unexpected handshake response {:?}", other).into()),
+        }
+
+        if let Some(token) = token {
+            framed
+                .send(protocol::ZaichikFrame::Authenticate { token })
+                .await?;
+
+            match framed.next().await.transpose()? {
+                Some(protocol::ZaichikFrame::Authenticated) => {}
+                Some(protocol::ZaichikFrame::Error { code, message, .. }) => {
+                    return Err(Box::new(BrokerError { code, message }))
+                }
+                other => {
+                    return Err(format!("Unexpected authentication response {:?}", other).into())
+                }
+            }
+        }
+
+        Ok(Client {
+            stream: Connection::new(framed),
+            keepalive: None,
+            read_timeout: None,
+            compression: Compression::None,
+            payload_format: protocol::SerializationFormat::Json,
+            #[cfg(feature = "connection-spans")]
+            span: None,
+            pending_frames: VecDeque::new(),
+            outbound_capacity: None,
+        })
+    }
+
+    // Возвращает Ok(None), когда брокер закрыл соединение, так что
+    // читать сообщения можно в цикле `while let Some(frame) = ...`.
+    pub async fn read_message(&mut self) -> Result<Option<protocol::ZaichikFrame>, std::io::Error> {
+        if let Some(frame) = self.pending_frames.pop_front() {
+            return Ok(Some(frame));
+        }
+
+        self.next_frame().await
+    }
+
+    // То же, что read_message, но фрейм Error от брокера превращается в Err
+    // с BrokerError внутри, так что ошибки можно обрабатывать через `?`.
+    pub async fn read_message_checked(
+        &mut self,
+    ) -> Result<Option<protocol::ZaichikFrame>, std::io::Error> {
+        checked(self.read_message().await?)
+    }
+
+    async fn next_frame(&mut self) -> Result<Option<protocol::ZaichikFrame>, std::io::Error> {
+        match self.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.receive_frame())
+                .await
+                .map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "No frame from broker within read timeout",
+                    )
+                })?,
+            None => self.receive_frame().await,
+        }
+    }
+
+    async fn receive_frame(&mut self) -> Result<Option<protocol::ZaichikFrame>, std::io::Error> {
+        loop {
+            match self.stream.next().await.transpose()? {
+                // Pong это только ответ на наш Ping, пользователю он не нужен.
+                Some(protocol::ZaichikFrame::Pong) => continue,
+                Some(frame) => return decompress(frame).map(Some),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // Читаем фреймы, пока не придет ответ на наш запрос. Все остальное,
+    // что пришло в это время, откладываем для read_message.
+    async fn wait_for_response<F>(
+        &mut self,
+        is_response: F,
+    ) -> Result<protocol::ZaichikFrame, std::io::Error>
+    where
+        F: Fn(&protocol::ZaichikFrame) -> bool,
+    {
+        loop {
+            match self.next_frame().await? {
+                Some(frame) if is_response(&frame) => return Ok(frame),
+                Some(frame) => self.pending_frames.push_back(frame),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "Connection closed before response",
+                    ))
+                }
+            }
+        }
+    }
+
+    // Включает отправку Ping каждые interval, в которые клиент ничего не отправил
+    // брокеру. Ping уходит из фоновой задачи, так что соединение не закроется по
+    // IDLE_TIMEOUT, даже если клиент долго не читает и не пишет. None выключает keepalive.
+    pub fn set_keepalive(&mut self, interval: Option<time::Duration>) {
+        self.keepalive = interval.map(|interval| self.stream.keepalive(interval));
+    }
+
+    pub async fn ping(&mut self) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::Ping {};
+
+        self.stream.send(frame).await
     }
 
     pub async fn create_topic(
@@ -29,21 +1000,373 @@ impl Client {
         topic: String,
         retention_ttl: u64,
         compaction_window: u64,
-    ) -> Result<(), std::io::Error> {
-        let frame = protocol::ZaichikFrame::CreateTopic {
-            topic,
+        retention_max_messages: u64,
+        retention_max_bytes: u64,
+        compaction_mode: protocol::CompactionMode,
+    ) -> Result<CreatedTopic, std::io::Error> {
+        let config = TopicConfig {
             retention_ttl,
             compaction_window,
+            retention_max_messages,
+            retention_max_bytes,
+            compaction_mode,
+            ..TopicConfig::default()
         };
 
-        self.stream.send(frame).await
+        self.create_topic_with(topic, config).await
     }
 
-    pub async fn subscribe_on(&mut self, topic: String) -> Result<(), std::io::Error> {
-        let frame = protocol::ZaichikFrame::Subscribe {
+    // То же, что create_topic, но с размером broadcast канала топика вместо
+    // размера по умолчанию. Подписчик, отставший больше чем на buffer_size
+    // сообщений, пропускает самые старые из них.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_topic_with_buffer_size(
+        &mut self,
+        topic: String,
+        retention_ttl: u64,
+        compaction_window: u64,
+        retention_max_messages: u64,
+        retention_max_bytes: u64,
+        compaction_mode: protocol::CompactionMode,
+        buffer_size: u32,
+    ) -> Result<CreatedTopic, std::io::Error> {
+        let config = TopicConfig {
+            retention_ttl,
+            compaction_window,
+            retention_max_messages,
+            retention_max_bytes,
+            compaction_mode,
+            buffer_size: Some(buffer_size),
+            ..TopicConfig::default()
+        };
+
+        self.create_topic_with(topic, config).await
+    }
+
+    // Топик с DeliveryGuarantee::Reliable: у каждого подписчика очередь на buffer_size
+    // сообщений (0 - размер по умолчанию), и когда она заполнена, publish в топик ждет,
+    // пока подписчик ее разберет. Сообщения при этом не теряются.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_reliable_topic(
+        &mut self,
+        topic: String,
+        retention_ttl: u64,
+        compaction_window: u64,
+        retention_max_messages: u64,
+        retention_max_bytes: u64,
+        compaction_mode: protocol::CompactionMode,
+        buffer_size: u32,
+    ) -> Result<CreatedTopic, std::io::Error> {
+        let config = TopicConfig {
+            retention_ttl,
+            compaction_window,
+            retention_max_messages,
+            retention_max_bytes,
+            compaction_mode,
+            buffer_size: Some(buffer_size),
+            delivery: protocol::DeliveryGuarantee::Reliable,
+            ..TopicConfig::default()
+        };
+
+        self.create_topic_with(topic, config).await
+    }
+
+    // Создает топик со всеми настройками из config, см. TopicConfig.
+    pub async fn create_topic_with(
+        &mut self,
+        topic: String,
+        config: TopicConfig,
+    ) -> Result<CreatedTopic, std::io::Error> {
+        let frame = protocol::ZaichikFrame::CreateTopic {
+            topic,
+            retention_ttl: config.retention_ttl,
+            compaction_window: config.compaction_window,
+            retention_max_messages: config.retention_max_messages,
+            retention_max_bytes: config.retention_max_bytes,
+            compaction_mode: config.compaction_mode,
+            buffer_size: config.buffer_size,
+            delivery: config.delivery,
+            key_source: config.key_source,
+            partitions: config.partitions,
+            ordering: config.ordering,
+            max_message_bytes: config.max_message_bytes,
+        };
+
+        self.send_create_topic(frame).await
+    }
+
+    // Отправляет CreateTopic и ждет TopicCreated с настройками топика. Если топик
+    // уже есть с другими настройками или имя топика не подходит брокеру, то возвращается
+    // Err с BrokerError внутри (ERROR_TOPIC_SETTINGS_CONFLICT, ERROR_INVALID_TOPIC_NAME,
+    // ERROR_INVALID_TOPIC_SETTINGS, если настройки больше лимитов брокера,
+    // или ERROR_ACCESS_DENIED, если пользователю нельзя писать в топик).
+    async fn send_create_topic(
+        &mut self,
+        frame: protocol::ZaichikFrame,
+    ) -> Result<CreatedTopic, std::io::Error> {
+        let topic = match &frame {
+            protocol::ZaichikFrame::CreateTopic { topic, .. } => topic.clone(),
+            _ => unreachable!(),
+        };
+
+        self.stream.send(frame).await?;
+
+        match self
+            .wait_for_response(|frame| match frame {
+                protocol::ZaichikFrame::TopicCreated {
+                    topic: created_topic,
+                    ..
+                } => *created_topic == topic,
+                protocol::ZaichikFrame::Error {
+                    code,
+                    topic: Some(error_topic),
+                    ..
+                } if *error_topic == topic => {
+                    *code == protocol::ERROR_TOPIC_SETTINGS_CONFLICT
+                        || *code == protocol::ERROR_INVALID_TOPIC_NAME
+                        || *code == protocol::ERROR_INVALID_TOPIC_SETTINGS
+                        || *code == protocol::ERROR_ACCESS_DENIED
+                }
+                _ => false,
+            })
+            .await?
+        {
+            protocol::ZaichikFrame::TopicCreated {
+                retention_ttl,
+                compaction_window,
+                retention_max_messages,
+                retention_max_bytes,
+                compaction_mode,
+                buffer_size,
+                delivery,
+                key_source,
+                partitions,
+                ordering,
+                max_message_bytes,
+                already_existed,
+                ..
+            } => Ok(CreatedTopic {
+                retention_ttl,
+                compaction_window,
+                retention_max_messages,
+                retention_max_bytes,
+                compaction_mode,
+                buffer_size,
+                delivery,
+                key_source,
+                partitions,
+                ordering,
+                max_message_bytes,
+                already_existed,
+            }),
+            protocol::ZaichikFrame::Error { code, message, .. } => {
+                Err(std::io::Error::other(BrokerError { code, message }))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub async fn list_topics(&mut self) -> Result<Vec<String>, std::io::Error> {
+        let frame = protocol::ZaichikFrame::ListTopics {};
+
+        self.stream.send(frame).await?;
+
+        match self
+            .wait_for_response(|frame| matches!(frame, protocol::ZaichikFrame::TopicList { .. }))
+            .await?
+        {
+            protocol::ZaichikFrame::TopicList { topics } => Ok(topics),
+            _ => unreachable!(),
+        }
+    }
+
+    // Все подключения к брокеру, включая наше, отсортированные по адресу.
+    // Если пользователь не админ, то возвращается Err с BrokerError внутри.
+    pub async fn admin_connections(
+        &mut self,
+    ) -> Result<Vec<protocol::ConnectionInfo>, std::io::Error> {
+        let frame = protocol::ZaichikFrame::AdminConnections;
+
+        self.stream.send(frame).await?;
+
+        match self
+            .wait_for_response(|frame| {
+                matches!(
+                    frame,
+                    protocol::ZaichikFrame::AdminConnectionsResponse { .. }
+                        | protocol::ZaichikFrame::Error {
+                            code: protocol::ERROR_ACCESS_DENIED,
+                            topic: None,
+                            ..
+                        }
+                )
+            })
+            .await?
+        {
+            protocol::ZaichikFrame::AdminConnectionsResponse { connections } => Ok(connections),
+            protocol::ZaichikFrame::Error { code, message, .. } => {
+                Err(std::io::Error::other(BrokerError { code, message }))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Если топика нет или читать его нельзя, то возвращается Err с BrokerError внутри.
+    pub async fn topic_stats(&mut self, topic: String) -> Result<TopicStats, std::io::Error> {
+        let frame = protocol::ZaichikFrame::TopicStats {
             topic: topic.clone(),
         };
 
+        self.stream.send(frame).await?;
+
+        let response = self
+            .wait_for_response(|frame| match frame {
+                protocol::ZaichikFrame::TopicStatsResponse {
+                    topic: response_topic,
+                    ..
+                } => *response_topic == topic,
+                protocol::ZaichikFrame::Error {
+                    code,
+                    topic: Some(error_topic),
+                    ..
+                } if *error_topic == topic => {
+                    *code == protocol::ERROR_TOPIC_NOT_FOUND
+                        || *code == protocol::ERROR_ACCESS_DENIED
+                }
+                _ => false,
+            })
+            .await?;
+
+        match response {
+            protocol::ZaichikFrame::TopicStatsResponse {
+                retained_count,
+                retained_bytes,
+                compaction_keys,
+                subscriber_count,
+                expired_drops,
+                published_messages,
+                duplicate_drops,
+                kept_messages,
+                ..
+            } => Ok(TopicStats {
+                retained_count,
+                retained_bytes,
+                compaction_keys,
+                subscriber_count,
+                expired_drops,
+                published_messages,
+                duplicate_drops,
+                kept_messages,
+            }),
+            protocol::ZaichikFrame::Error { code, message, .. } => {
+                Err(std::io::Error::other(BrokerError { code, message }))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // После max_delivery_attempts доставок, каждая из которых закончилась Nack,
+    // сообщение уходит в dead_letter_topic, по умолчанию "<topic>.dlq".
+    // max_delivery_attempts = 0 выключает dead-letter для топика.
+    pub async fn set_dead_letter(
+        &mut self,
+        topic: String,
+        max_delivery_attempts: u32,
+        dead_letter_topic: Option<String>,
+    ) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::SetDeadLetter {
+            topic,
+            max_delivery_attempts,
+            dead_letter_topic,
+        };
+
+        self.stream.send(frame).await
+    }
+
+    pub async fn delete_topic(&mut self, topic: String) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::DeleteTopic { topic };
+
+        self.stream.send(frame).await
+    }
+
+    pub async fn subscribe_on(&mut self, topic: String) -> Result<(), std::io::Error> {
+        self.subscribe_with(topic, SubscribeOptions::new()).await
+    }
+
+    // Подписка с настройками из options, см. SubscribeOptions. С confirm возвращается,
+    // только когда брокер завел подписку (Subscribed), и все сообщения по ней придут
+    // после этого. Если брокер отклонил такую подписку, то возвращается Err
+    // с BrokerError внутри.
+    pub async fn subscribe_with(
+        &mut self,
+        topic: String,
+        options: SubscribeOptions,
+    ) -> Result<(), std::io::Error> {
+        let confirm = options.confirm;
+        let frame = options.into_frame(topic.clone());
+
+        if confirm {
+            self.send_confirmed_subscribe(topic, frame).await
+        } else {
+            self.stream.send(frame).await
+        }
+    }
+
+    // Отправляет Subscribe с confirm = true и ждет Subscribed или ошибку по этому топику.
+    async fn send_confirmed_subscribe(
+        &mut self,
+        topic: String,
+        frame: protocol::ZaichikFrame,
+    ) -> Result<(), std::io::Error> {
+        self.stream.send(frame).await?;
+
+        let response = self
+            .wait_for_response(|frame| match frame {
+                protocol::ZaichikFrame::Subscribed {
+                    topic: subscribed_topic,
+                } => *subscribed_topic == topic,
+                protocol::ZaichikFrame::Error {
+                    code,
+                    topic: Some(error_topic),
+                    ..
+                } if *error_topic == topic => {
+                    *code == protocol::ERROR_ACCESS_DENIED
+                        || *code == protocol::ERROR_INVALID_TOPIC_NAME
+                        || *code == protocol::ERROR_INVALID_SUBSCRIPTION
+                        || *code == protocol::ERROR_TOPIC_DELETED
+                        || *code == protocol::ERROR_TOO_MANY_SUBSCRIPTIONS
+                }
+                _ => false,
+            })
+            .await?;
+
+        match response {
+            protocol::ZaichikFrame::Subscribed { .. } => Ok(()),
+            protocol::ZaichikFrame::Error { code, message, .. } => {
+                Err(std::io::Error::other(BrokerError { code, message }))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub async fn unsubscribe(&mut self, topic: String) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::Unsubscribe { topic };
+
+        self.stream.send(frame).await
+    }
+
+    // Брокер перестает присылать сообщения подписки на topic, но не снимает ее:
+    // после resume доставка продолжится с того же места. Сообщения, которые
+    // уже были в пути, могут прийти и после pause.
+    pub async fn pause(&mut self, topic: String) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::Pause { topic };
+
+        self.stream.send(frame).await
+    }
+
+    pub async fn resume(&mut self, topic: String) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::Resume { topic };
+
         self.stream.send(frame).await
     }
 
@@ -53,17 +1376,344 @@ impl Client {
         key: Option<String>,
         payload: Vec<u8>,
     ) -> Result<(), std::io::Error> {
+        self.publish_with_headers(topic, key, payload, HashMap::new())
+            .await
+    }
+
+    // Публикация с заголовками, например content-type или trace id.
+    // Подписчики получат их в том же виде в поле headers фрейма Publish.
+    pub async fn publish_with_headers(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+        headers: HashMap<String, String>,
+    ) -> Result<(), std::io::Error> {
+        self.send_publish(topic, key, payload, headers, None, None, None, false)
+            .await
+    }
+
+    // Подписчики увидят сообщение только через delay после того, как его получит брокер.
+    // Сообщения, опубликованные в топик за это время без задержки, придут раньше него.
+    pub async fn publish_delayed(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+        delay: time::Duration,
+    ) -> Result<(), std::io::Error> {
+        self.send_publish(
+            topic,
+            key,
+            payload,
+            HashMap::new(),
+            Some(delay),
+            None,
+            None,
+            false,
+        )
+        .await
+    }
+
+    // Сообщение истечет через ttl после публикации вместо retention_ttl топика.
+    // Истекшее сообщение не отдается новым подписчикам и не доставляется тем,
+    // кто еще не успел его получить.
+    pub async fn publish_with_ttl(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+        ttl: time::Duration,
+    ) -> Result<(), std::io::Error> {
+        self.send_publish(
+            topic,
+            key,
+            payload,
+            HashMap::new(),
+            None,
+            Some(ttl),
+            None,
+            false,
+        )
+        .await
+    }
+
+    // Sink для непрерывного потока payload в один топик, например через send_all.
+    // Сообщения уходят брокеру в том порядке, в котором попали в sink. send и send_all
+    // сбрасывают буфер соединения на сокет, а feed только кладет сообщение в буфер,
+    // и оно уйдет при следующем flush. Sink пишет в то же соединение, что и publish,
+    // поэтому пока он жив, остальные методы клиента недоступны.
+    pub fn publisher(
+        &mut self,
+        topic: String,
+    ) -> impl futures::Sink<Vec<u8>, Error = std::io::Error> + Unpin + '_ {
+        self.keyed_publisher(topic)
+            .with(|payload| futures::future::ready(Ok::<_, std::io::Error>((None, payload))))
+    }
+
+    // То же, что publisher, но каждое сообщение - это пара (key, payload).
+    pub fn keyed_publisher(
+        &mut self,
+        topic: String,
+    ) -> impl futures::Sink<(Option<String>, Vec<u8>), Error = std::io::Error> + Unpin + '_ {
+        let compression = self.compression;
+
+        (&mut self.stream).with(move |(key, payload): (Option<String>, Vec<u8>)| {
+            let mut headers = HashMap::new();
+            let payload = compression.compress(payload, &mut headers);
+
+            futures::future::ready(Ok::<_, std::io::Error>(protocol::ZaichikFrame::Publish {
+                topic: topic.clone(),
+                key,
+                payload,
+                headers,
+                id: 0,
+                deliver_after: None,
+                ttl: None,
+                offset: 0,
+                create_with: None,
+                ack: false,
+            }))
+        })
+    }
+
+    // Запрос в стиле RPC поверх топиков: публикует payload в topic с заголовками
+    // CORRELATION_ID_HEADER и REPLY_TO_HEADER и ждет в reply_topic сообщение с тем же
+    // correlation id, например отправленное через Client::reply. Возвращает фрейм
+    // Publish ответа. Если ответа нет за timeout, то возвращается ошибка TimedOut.
+    // На reply_topic клиент подписывается только на время запроса, без подтверждений
+    // и только на новые сообщения. Остальные сообщения из reply_topic за это время
+    // отбрасываются, поэтому reply_topic лучше завести свой для каждого клиента и не
+    // подписываться на него отдельно. Подписка снимается и после таймаута, но ответ,
+    // который брокер успел отправить до отписки, может прийти потом в read_message.
+    pub async fn request(
+        &mut self,
+        topic: String,
+        payload: Vec<u8>,
+        reply_topic: String,
+        timeout: time::Duration,
+    ) -> Result<protocol::ZaichikFrame, std::io::Error> {
+        let correlation_id = next_correlation_id();
+
+        let reply = tokio::time::timeout(
+            timeout,
+            self.send_request(topic, payload, reply_topic.clone(), correlation_id),
+        )
+        .await;
+        self.unsubscribe(reply_topic.clone()).await?;
+
+        reply.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("No reply in {} within timeout", reply_topic),
+            )
+        })?
+    }
+
+    async fn send_request(
+        &mut self,
+        topic: String,
+        payload: Vec<u8>,
+        reply_topic: String,
+        correlation_id: String,
+    ) -> Result<protocol::ZaichikFrame, std::io::Error> {
+        // Подписываемся до публикации, иначе быстрый ответ придет раньше подписки
+        // и потеряется.
+        let options = SubscribeOptions::new()
+            .start(protocol::DeliveryStart::Latest)
+            .auto_ack(true)
+            .confirm(true);
+        self.subscribe_with(reply_topic.clone(), options).await?;
+
+        let mut headers = HashMap::new();
+        headers.insert(CORRELATION_ID_HEADER.to_string(), correlation_id.clone());
+        headers.insert(REPLY_TO_HEADER.to_string(), reply_topic.clone());
+        self.publish_with_headers(topic, None, payload, headers)
+            .await?;
+
+        let is_reply = |frame: &protocol::ZaichikFrame| match frame {
+            protocol::ZaichikFrame::Publish { topic, headers, .. } => {
+                *topic == reply_topic && headers.get(CORRELATION_ID_HEADER) == Some(&correlation_id)
+            }
+            _ => false,
+        };
+
+        // Ответы на чужие запросы из reply_topic отбрасываем, остальное
+        // откладываем для read_message, как в wait_for_response.
+        loop {
+            match self.next_frame().await? {
+                Some(frame) if is_reply(&frame) => return Ok(frame),
+                Some(protocol::ZaichikFrame::Publish { topic, .. }) if topic == reply_topic => {}
+                Some(frame) => self.pending_frames.push_back(frame),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "Connection closed before reply",
+                    ))
+                }
+            }
+        }
+    }
+
+    // Ответ на запрос из Client::request: публикует payload в топик из REPLY_TO_HEADER
+    // запроса с его correlation id. Если request - не Publish с этими заголовками,
+    // то возвращается ошибка InvalidInput. Сам запрос нужно подтвердить отдельно.
+    pub async fn reply(
+        &mut self,
+        request: &protocol::ZaichikFrame,
+        payload: Vec<u8>,
+    ) -> Result<(), std::io::Error> {
+        let (reply_topic, correlation_id) = match request {
+            protocol::ZaichikFrame::Publish { headers, .. } => (
+                headers.get(REPLY_TO_HEADER),
+                headers.get(CORRELATION_ID_HEADER),
+            ),
+            _ => (None, None),
+        };
+
+        match (reply_topic, correlation_id) {
+            (Some(reply_topic), Some(correlation_id)) => {
+                let mut headers = HashMap::new();
+                headers.insert(CORRELATION_ID_HEADER.to_string(), correlation_id.clone());
+                self.publish_with_headers(reply_topic.clone(), None, payload, headers)
+                    .await
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Message is not a request, it has no reply-to or correlation id",
+            )),
+        }
+    }
+
+    // Публикация, которая ждет ответа брокера. В отличие от publish, отсюда видно,
+    // записано ли сообщение в топик, отброшено как дубль или отклонено (например,
+    // без доступа к топику). Отклонение - это Ok с accepted = false, а не Err.
+    pub async fn publish_acked(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+    ) -> Result<PublishAck, std::io::Error> {
+        self.send_publish(topic, key, payload, HashMap::new(), None, None, None, true)
+            .await?;
+
+        match self
+            .wait_for_response(|frame| matches!(frame, protocol::ZaichikFrame::PublishAck { .. }))
+            .await?
+        {
+            protocol::ZaichikFrame::PublishAck {
+                accepted,
+                reason,
+                dropped_as_duplicate,
+            } => Ok(PublishAck {
+                accepted,
+                reason,
+                dropped_as_duplicate,
+            }),
+            _ => unreachable!(),
+        }
+    }
+
+    // Если топика еще нет, то брокер создаст его с настройками config, а не с
+    // настройками по умолчанию. Настройки уже существующего топика не меняются.
+    pub async fn publish_creating(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+        config: protocol::TopicConfig,
+    ) -> Result<(), std::io::Error> {
+        self.send_publish(
+            topic,
+            key,
+            payload,
+            HashMap::new(),
+            None,
+            None,
+            Some(config),
+            false,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn send_publish(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+        mut headers: HashMap<String, String>,
+        deliver_after: Option<time::Duration>,
+        ttl: Option<time::Duration>,
+        create_with: Option<protocol::TopicConfig>,
+        ack: bool,
+    ) -> Result<(), std::io::Error> {
+        let payload = self.compression.compress(payload, &mut headers);
+
         let frame = protocol::ZaichikFrame::Publish {
             topic,
             key,
             payload,
+            headers,
+            id: 0,
+            deliver_after,
+            ttl,
+            offset: 0,
+            create_with,
+            ack,
         };
 
+        // Publish с ack ждет ответа брокера, поэтому откладывать его нельзя.
+        match self.outbound_capacity {
+            Some(capacity) if !ack => self.stream.try_send(frame, capacity),
+            _ => self.stream.send(frame).await,
+        }
+    }
+
+    // Ждет, пока все отложенные publish фреймы будут записаны в сокет,
+    // см. ClientBuilder::outbound_buffer.
+    pub async fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.stream.flush().await
+    }
+
+    // Сколько фреймов publish отложено и еще не записано в сокет.
+    pub fn pending_outbound(&self) -> usize {
+        self.stream.pending_outbound()
+    }
+
+    // Публикует все сообщения одним фреймом. Подписчики получат их в том же
+    // порядке, и чужие публикации в этот топик не окажутся между ними.
+    // У сообщений батча нет заголовков, поэтому они не сжимаются.
+    pub async fn publish_batch(
+        &mut self,
+        topic: String,
+        messages: Vec<(Option<String>, Vec<u8>)>,
+    ) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::PublishBatch { topic, messages };
+
         self.stream.send(frame).await
     }
 
-    pub async fn commit(&mut self) -> Result<(), std::io::Error> {
-        let frame = protocol::ZaichikFrame::Commit {};
+    // Подтверждает сообщение с id из фрейма Publish. При prefetch больше 1
+    // сообщения можно подтверждать в любом порядке.
+    pub async fn commit(&mut self, id: u64) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::Commit { id };
+
+        self.stream.send(frame).await
+    }
+
+    // Сообщает брокеру, что сообщение с id не обработано.
+    // С requeue = true брокер доставит его еще раз, иначе оно будет отброшено.
+    pub async fn nack(&mut self, id: u64, requeue: bool) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::Nack { id, requeue };
+
+        self.stream.send(frame).await
+    }
+
+    // Разрешает брокеру присылать до count сообщений, не дожидаясь Commit.
+    // Каждый Commit подтверждает одно сообщение.
+    pub async fn set_prefetch(&mut self, count: u32) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::SetPrefetch { count };
 
         self.stream.send(frame).await
     }
@@ -73,4 +1723,150 @@ impl Client {
 
         self.stream.send(frame).await
     }
+
+    // Закрывает соединение, дождавшись, пока брокер снимет подписки.
+    // Брокер отвечает на CloseConnection тем же фреймом уже после того, как освободил
+    // все, что было связано с клиентом. Сообщения, пришедшие до подтверждения, отбрасываются.
+    // Если ждать подтверждения не нужно, достаточно close().
+    pub async fn shutdown(mut self) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::CloseConnection {};
+
+        self.stream.send(frame).await?;
+        self.stream.flush().await?;
+
+        // Брокер мог закрыть сокет раньше, например при остановке. Это тоже конец соединения.
+        while let Some(frame) = self.stream.next().await {
+            if let protocol::ZaichikFrame::CloseConnection = frame? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Превращает клиента в стрим сообщений с автоматическим коммитом.
+    // Commit отправляется брокеру сразу, как только фрейм получен из сокета,
+    // еще до того, как стрим отдаст его пользователю. Поэтому, если обработка
+    // упадет, брокер уже будет считать сообщение доставленным.
+    pub fn into_stream(
+        self,
+    ) -> impl Stream<Item = Result<protocol::ZaichikFrame, std::io::Error>> + Send + Unpin {
+        let state = (self.stream, self.pending_frames);
+        let stream = futures::stream::unfold(state, |(mut stream, mut pending)| async move {
+            loop {
+                let next = match pending.pop_front() {
+                    Some(frame) => Some(Ok(frame)),
+                    None => stream.next().await,
+                };
+
+                match next {
+                    Some(Ok(protocol::ZaichikFrame::Pong)) => continue,
+                    Some(Ok(frame)) => {
+                        let frame = match decompress(frame) {
+                            Ok(frame) => frame,
+                            Err(e) => return Some((Err(e), (stream, pending))),
+                        };
+                        // Подтверждать нужно только доставленные сообщения.
+                        // Сообщения подписок с auto_ack приходят с id = 0 и Commit не ждут.
+                        let result = match frame {
+                            protocol::ZaichikFrame::Publish { id, .. } if id != 0 => stream
+                                .send(protocol::ZaichikFrame::Commit { id })
+                                .await
+                                .map(|_| frame),
+                            frame => Ok(frame),
+                        };
+                        return Some((result, (stream, pending)));
+                    }
+                    Some(Err(e)) => return Some((Err(e), (stream, pending))),
+                    None => return None,
+                }
+            }
+        });
+
+        Box::pin(stream)
+    }
+
+    // Разделяет клиента на стрим сообщений и ClientWriter для ручного коммита.
+    // Брокер не пришлет следующее сообщение, пока не получит Commit, так что
+    // после обработки каждого сообщения нужно вызвать ClientWriter::commit с его id.
+    pub fn split(
+        self,
+    ) -> (
+        ClientWriter,
+        impl Stream<Item = Result<protocol::ZaichikFrame, std::io::Error>> + Send + Unpin,
+    ) {
+        let pending = tokio::stream::iter(self.pending_frames.into_iter().map(Ok));
+        let (sink, stream) = futures::StreamExt::split(self.stream);
+        let stream = pending.chain(
+            stream
+                .filter(|frame| !matches!(frame, Ok(protocol::ZaichikFrame::Pong)))
+                .map(|frame| frame.and_then(decompress)),
+        );
+
+        let writer = ClientWriter {
+            sink,
+            _keepalive: self.keepalive,
+        };
+        (writer, stream)
+    }
+}
+
+impl ClientWriter {
+    pub async fn commit(&mut self, id: u64) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::Commit { id };
+
+        self.sink.send(frame).await
+    }
+
+    pub async fn close(&mut self) -> Result<(), std::io::Error> {
+        let frame = protocol::ZaichikFrame::CloseConnection {};
+
+        self.sink.send(frame).await
+    }
+}
+
+// Распаковываем payload, если издатель его сжал, и убираем COMPRESSION_HEADER.
+// Остальные фреймы возвращаются без изменений.
+fn decompress(frame: protocol::ZaichikFrame) -> Result<protocol::ZaichikFrame, std::io::Error> {
+    match frame {
+        protocol::ZaichikFrame::Publish {
+            topic,
+            key,
+            payload,
+            mut headers,
+            id,
+            deliver_after,
+            ttl,
+            offset,
+            create_with,
+            ack,
+        } => {
+            let payload = match headers.remove(COMPRESSION_HEADER).as_deref() {
+                None => payload,
+                Some("lz4") => lz4_flex::decompress_size_prepended(&payload).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+                })?,
+                Some(other) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Unsupported compression {}", other),
+                    ))
+                }
+            };
+
+            Ok(protocol::ZaichikFrame::Publish {
+                topic,
+                key,
+                payload,
+                headers,
+                id,
+                deliver_after,
+                ttl,
+                offset,
+                create_with,
+                ack,
+            })
+        }
+        frame => Ok(frame),
+    }
 }
@@ -0,0 +1,67 @@
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// Если обработчик одного соединения запаникует, держа блокировку, то std::sync
+// пометит ее как poisoned, и каждый следующий unwrap тоже будет паниковать, то есть
+// упадут все соединения, которые работают с этим топиком или реестром. Поэтому
+// брокер забирает guard из PoisonError и продолжает работу. Данные под блокировкой
+// при этом могли остаться изменены не до конца, но это лучше, чем остановить брокер.
+pub trait RwLockExt<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> RwLockExt<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(recover)
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(recover)
+    }
+}
+
+pub trait MutexExt<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+    fn get_mut_or_recover(&mut self) -> &mut T;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(recover)
+    }
+
+    fn get_mut_or_recover(&mut self) -> &mut T {
+        self.get_mut().unwrap_or_else(recover)
+    }
+}
+
+// Флаг poisoned со временем не сбрасывается, поэтому пишем в лог на уровне debug,
+// иначе каждое следующее обращение к блокировке засоряло бы лог.
+fn recover<G>(error: PoisonError<G>) -> G {
+    debug!("Recovered a lock poisoned by a panicked task");
+    error.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_poisoned_lock_is_recovered() {
+        let lock = Arc::new(RwLock::new(1));
+        let poisoned = Arc::clone(&lock);
+        let panicked = std::thread::spawn(move || {
+            let mut value = poisoned.write().unwrap();
+            *value = 2;
+            panic!("Handler panicked while holding the lock");
+        })
+        .join();
+
+        assert!(panicked.is_err());
+        assert!(lock.is_poisoned());
+        assert_eq!(2, *lock.read_or_recover());
+        *lock.write_or_recover() = 3;
+        assert_eq!(3, *lock.read_or_recover());
+    }
+}
@@ -1,12 +1,9 @@
-mod protocol;
-mod subscription_manager;
-mod topic_controller;
-mod topic_registry;
-
-use crate::topic_registry::TopicRegistry;
-use std::sync::{Arc, RwLock};
-use tokio::stream::StreamExt;
-use tokio::sync::mpsc;
+// Брокер zaichik: читает настройки из переменных окружения и запускает
+// zaichik::broker::run_broker, пока не придет SIGINT или SIGTERM.
+use std::time;
+use tokio::sync::broadcast;
+use zaichik::broker::{self, AclRules, Authenticator, BrokerConfig};
+use zaichik::protocol;
 
 #[macro_use]
 extern crate log;
@@ -20,76 +17,226 @@ async fn main() {
         .map(|(_key, value)| value)
         .unwrap_or_else(|| "8889".to_string());
 
-    // База данных топиков, в которой хранятся ссылки на контроллеры топиков.
-    let topic_registry = Arc::new(RwLock::new(TopicRegistry::new()));
-
-    let mut listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", port))
-        .await
-        .unwrap();
+    // Через сколько миллисекунд тишины от клиента мы будем закрывать соединение.
+    // 0 означает, что соединения не закрываются по таймауту.
+    let idle_timeout = std::env::vars()
+        .find(|(key, _value)| key == "IDLE_TIMEOUT")
+        .and_then(|(_key, value)| value.parse::<u64>().ok())
+        .filter(|millis| *millis > 0)
+        .map(time::Duration::from_millis);
+
+    // Формат фреймов на проводе: bincode (по умолчанию) или json.
+    let format = match std::env::vars().find(|(key, _value)| key == "FORMAT") {
+        Some((_key, value)) if value == "json" => protocol::SerializationFormat::Json,
+        _ => protocol::SerializationFormat::Bincode,
+    };
+
+    // Адрес, на котором брокер принимает подключения. Например, ZAICHIK_BIND=0.0.0.0:8889
+    // для запуска в контейнере. По умолчанию слушаем 127.0.0.1 и PORT.
+    let bind_addr = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_BIND")
+        .map(|(_key, value)| value)
+        .unwrap_or_else(|| format!("127.0.0.1:{}", port));
+    let bind_addr = bind_addr
+        .parse::<std::net::SocketAddr>()
+        .expect("ZAICHIK_BIND should be an address like 127.0.0.1:8889");
+
+    // Если заданы сертификат и ключ в PEM, то брокер принимает только TLS подключения.
+    let tls_cert = std::env::vars().find(|(key, _value)| key == "ZAICHIK_TLS_CERT");
+    let tls_key = std::env::vars().find(|(key, _value)| key == "ZAICHIK_TLS_KEY");
+    let tls = match (tls_cert, tls_key) {
+        (Some((_, cert_path)), Some((_, key_path))) => Some(
+            broker::load_tls_acceptor(&cert_path, &key_path)
+                .expect("Failed to load TLS certificate"),
+        ),
+        (None, None) => None,
+        _ => panic!("Both ZAICHIK_TLS_CERT and ZAICHIK_TLS_KEY should be set to enable TLS"),
+    };
+
+    // Токены клиентов в виде "alice:token1,bob:token2". Если не заданы,
+    // то подключиться может любой клиент.
+    let auth = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_AUTH_TOKENS")
+        .map(|(_key, value)| {
+            Authenticator::from_spec(&value).expect("Invalid ZAICHIK_AUTH_TOKENS")
+        });
 
-    debug!("Started broker server at 127.0.0.1:{}", port);
+    // Кому можно читать и писать в топики, например "orders:read:alice,bob;orders:write:alice".
+    // Топики без правил открыты всем, а админские фреймы доступны только пользователям
+    // из правила "admin:carol".
+    let acl = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_ACL")
+        .map(|(_key, value)| AclRules::from_spec(&value).expect("Invalid ZAICHIK_ACL"))
+        .unwrap_or_default();
+
+    // Адрес HTTP эндпоинта с метриками, например 127.0.0.1:9100.
+    // Работает, только если брокер собран с фичей metrics.
+    let metrics_addr = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_METRICS_ADDR")
+        .map(|(_key, value)| {
+            value
+                .parse::<std::net::SocketAddr>()
+                .expect("ZAICHIK_METRICS_ADDR should be an address like 127.0.0.1:9100")
+        });
 
-    loop {
-        // В peer хранится ip адрес и порт входящего подключения.
-        let (socket, peer) = listener.accept().await.unwrap();
-        let topic_registry = Arc::clone(&topic_registry);
+    // Директория, где хранятся retained сообщения топиков. Если не задана,
+    // то сообщения живут только в памяти и пропадают при перезапуске.
+    let data_dir = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_DATA_DIR")
+        .map(|(_key, value)| std::path::PathBuf::from(value));
+
+    // Путь к UNIX сокету, на котором брокер принимает локальные подключения
+    // в дополнение к TCP, например /tmp/zaichik.sock.
+    let unix_socket = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_UNIX_SOCKET")
+        .map(|(_key, value)| std::path::PathBuf::from(value));
+
+    // Размер broadcast канала для топиков, в CreateTopic которых он не указан,
+    // см. topic_controller::resolve_buffer_size.
+    // Подписчик, отставший больше чем на столько сообщений, пропускает самые старые.
+    let topic_buffer_size = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_TOPIC_BUFFER_SIZE")
+        .map(|(_key, value)| match value.parse::<u32>() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                warn!(
+                    "Invalid ZAICHIK_TOPIC_BUFFER_SIZE {:?}, using default {}",
+                    value,
+                    broker::DEFAULT_BUFFER_SIZE
+                );
+                broker::DEFAULT_BUFFER_SIZE
+            }
+        })
+        .unwrap_or(broker::DEFAULT_BUFFER_SIZE);
+
+    // Имена топиков длиннее этого (в байтах) брокер отклоняет с ERROR_INVALID_TOPIC_NAME.
+    let max_topic_name_len = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_MAX_TOPIC_NAME_LEN")
+        .and_then(|(_key, value)| value.parse::<usize>().ok())
+        .filter(|len| *len > 0)
+        .unwrap_or(broker::DEFAULT_MAX_TOPIC_NAME_LEN);
+
+    // Сколько ключей помнит Dedup compaction каждого топика. Если ключей больше,
+    // то забываются самые старые, не дожидаясь чистки по compaction_window.
+    let compaction_max_keys = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_COMPACTION_MAX_KEYS")
+        .map(|(_key, value)| match value.parse::<usize>() {
+            Ok(max_keys) if max_keys > 0 => max_keys,
+            _ => {
+                warn!(
+                    "Invalid ZAICHIK_COMPACTION_MAX_KEYS {:?}, using default {}",
+                    value,
+                    broker::DEFAULT_COMPACTION_MAX_KEYS
+                );
+                broker::DEFAULT_COMPACTION_MAX_KEYS
+            }
+        })
+        .unwrap_or(broker::DEFAULT_COMPACTION_MAX_KEYS);
+
+    // Лимиты retention_ttl и compaction_window топиков в миллисекундах, 0 - без лимита.
+    // ZAICHIK_TOPIC_LIMIT_POLICY=reject отклоняет CreateTopic с настройками больше лимитов,
+    // по умолчанию (clamp) они уменьшаются до лимитов.
+    let limit = |name: &str, default: u64| {
+        std::env::vars()
+            .find(|(key, _value)| key == name)
+            .map(|(_key, value)| match value.parse::<u64>() {
+                Ok(limit) => limit,
+                Err(_) => {
+                    warn!("Invalid {} {:?}, using default {}", name, value, default);
+                    default
+                }
+            })
+            .unwrap_or(default)
+    };
+    let topic_limits = broker::TopicLimits {
+        max_retention_ttl: limit(
+            "ZAICHIK_MAX_RETENTION_TTL",
+            broker::DEFAULT_MAX_RETENTION_TTL,
+        ),
+        max_compaction_window: limit(
+            "ZAICHIK_MAX_COMPACTION_WINDOW",
+            broker::DEFAULT_MAX_COMPACTION_WINDOW,
+        ),
+        policy: match std::env::vars().find(|(key, _value)| key == "ZAICHIK_TOPIC_LIMIT_POLICY") {
+            Some((_key, value)) if value == "reject" => broker::LimitPolicy::Reject,
+            Some((_key, value)) if value != "clamp" => {
+                warn!(
+                    "Invalid ZAICHIK_TOPIC_LIMIT_POLICY {:?}, using clamp",
+                    value
+                );
+                broker::LimitPolicy::Clamp
+            }
+            _ => broker::LimitPolicy::Clamp,
+        },
+    };
+
+    // Сколько подписок на топики может быть у одного подключения, 0 - без лимита.
+    // Subscribe сверх лимита брокер отклоняет с ERROR_TOO_MANY_SUBSCRIPTIONS.
+    let max_subscriptions = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_MAX_SUBSCRIPTIONS")
+        .map(|(_key, value)| match value.parse::<usize>() {
+            Ok(max) => max,
+            Err(_) => {
+                warn!(
+                    "Invalid ZAICHIK_MAX_SUBSCRIPTIONS {:?}, using default {}",
+                    value,
+                    broker::DEFAULT_MAX_SUBSCRIPTIONS
+                );
+                broker::DEFAULT_MAX_SUBSCRIPTIONS
+            }
+        })
+        .unwrap_or(broker::DEFAULT_MAX_SUBSCRIPTIONS);
+
+    // Добавлять ли к опубликованным сообщениям заголовки с адресом и пользователем
+    // издателя, см. protocol::PRODUCER_PEER_HEADER. По умолчанию выключено, чтобы
+    // подписчики не узнавали об издателях больше, чем им нужно.
+    let producer_headers = std::env::vars()
+        .find(|(key, _value)| key == "ZAICHIK_PRODUCER_HEADERS")
+        .is_some_and(|(_key, value)| value == "1" || value == "true");
+
+    let config = BrokerConfig::default()
+        .format(format)
+        .idle_timeout(idle_timeout)
+        .tls(tls)
+        .auth(auth)
+        .acl(acl)
+        .metrics_addr(metrics_addr)
+        .data_dir(data_dir)
+        .unix_socket(unix_socket)
+        .topic_buffer_size(topic_buffer_size)
+        .max_topic_name_len(max_topic_name_len)
+        .compaction_max_keys(compaction_max_keys)
+        .topic_limits(topic_limits)
+        .max_subscriptions(max_subscriptions)
+        .producer_headers(producer_headers);
+
+    // По SIGINT или SIGTERM сообщаем всем подключениям, что брокер останавливается.
+    let (shutdown, _) = broadcast::channel(1);
+    let signal_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        info!("Received shutdown signal");
+        let _ = signal_shutdown.send(());
+    });
 
-        // Для каждого входящего подключения мы будем создавать отдельную задачу.
-        tokio::spawn(async move {
-            process(socket, peer, topic_registry).await;
-        });
+    if let Err(e) = broker::run_broker(bind_addr, config, shutdown).await {
+        error!("Failed to run broker at {}; error = {:?}", bind_addr, e);
     }
 }
 
-async fn process(
-    socket: tokio::net::TcpStream,
-    peer: std::net::SocketAddr,
-    topic_registry: Arc<RwLock<TopicRegistry>>,
-) {
-    debug!("New connection from {}:{}", peer.ip(), peer.port());
-
-    let codec = protocol::ZaichikCodec::new();
-    let (read_half, write_half) = socket.into_split();
-
-    let mut reader = tokio_util::codec::FramedRead::new(read_half, codec.clone());
-    let writer = tokio_util::codec::FramedWrite::new(write_half, codec);
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    // Канал, для того, чтобы отправлять сообщения от клиента в управляющий компонент.
-    let (mut subscription_manager_channel, commands_receiver) = mpsc::channel(1000);
-
-    // Запись в сокет и управление подписками мы отдадим в отдельную задачу.
-    tokio::spawn(async move {
-        subscription_manager::SubscriptionManager::start_loop(
-            peer,
-            topic_registry,
-            commands_receiver,
-            writer,
-        )
-        .await
-    });
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
 
-    // Читаем фреймы, приходящие от клиента из сокета и передаем их в управляющий компонент.
-    while let Some(result) = reader.next().await {
-        match result {
-            Ok(frame) => {
-                let wrapped_frame = subscription_manager::MessageWrapper::from_frame(frame);
-                subscription_manager_channel
-                    .send(wrapped_frame)
-                    .await
-                    .unwrap();
-            }
-            Err(e) => {
-                error!("error on decoding from socket; error = {:?}", e);
-            }
-        }
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
     }
+}
 
-    // Говорим управляющему модулю, что мы больше не работаем с клиентом.
-    let _ = subscription_manager_channel
-        .send(subscription_manager::MessageWrapper::from_frame(
-            protocol::ZaichikFrame::CloseConnection {},
-        ))
-        .await;
-
-    debug!("[{}:{}] Stopped client", peer.ip(), peer.port());
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -0,0 +1,296 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+// Общие для всего брокера счетчики. Обновляются из подключений,
+// а читаются только при запросе метрик.
+#[derive(Debug)]
+pub struct BrokerMetrics {
+    // Байты payload, полученные от клиентов в Publish.
+    received_bytes: AtomicU64,
+    // Байты payload, отправленные подписчикам.
+    sent_bytes: AtomicU64,
+    active_connections: AtomicI64,
+    active_subscriptions: AtomicI64,
+}
+
+pub static METRICS: BrokerMetrics = BrokerMetrics {
+    received_bytes: AtomicU64::new(0),
+    sent_bytes: AtomicU64::new(0),
+    active_connections: AtomicI64::new(0),
+    active_subscriptions: AtomicI64::new(0),
+};
+
+impl BrokerMetrics {
+    pub fn on_received(&self, bytes: usize) {
+        self.received_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_sent(&self, bytes: usize) {
+        self.sent_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Подписки меняются во многих местах SubscriptionManager, поэтому
+    // он сообщает разницу между текущим и прошлым количеством.
+    pub fn on_subscriptions_changed(&self, delta: i64) {
+        self.active_subscriptions
+            .fetch_add(delta, Ordering::Relaxed);
+    }
+}
+
+// Счетчики одного топика. Атомарные, чтобы их можно было читать
+// под локом на чтение, пока топик пишется из других подключений.
+#[derive(Debug, Default)]
+pub struct TopicStats {
+    published_messages: AtomicU64,
+    compaction_drops: AtomicU64,
+    // Часть compaction_drops: сообщения, отброшенные Dedup compaction при публикации.
+    duplicate_drops: AtomicU64,
+    // Сообщения, которые прошли compaction и получили offset.
+    kept_messages: AtomicU64,
+    expired_drops: AtomicU64,
+}
+
+impl TopicStats {
+    pub fn on_published(&self) {
+        self.published_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Сообщение отброшено как дубль или вытеснено более новым по тому же ключу.
+    pub fn on_compaction_drop(&self) {
+        self.compaction_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Dedup compaction отбросил сообщение при публикации как дубль ключа.
+    pub fn on_duplicate_drop(&self) {
+        self.duplicate_drops.fetch_add(1, Ordering::Relaxed);
+        self.on_compaction_drop();
+    }
+
+    pub fn on_kept(&self) {
+        self.kept_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Подписчик не получил сообщение, потому что оно истекло, пока ждало отправки.
+    pub fn on_expired_drop(&self) {
+        self.expired_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn published_messages(&self) -> u64 {
+        self.published_messages.load(Ordering::Relaxed)
+    }
+
+    pub fn duplicate_drops(&self) -> u64 {
+        self.duplicate_drops.load(Ordering::Relaxed)
+    }
+
+    pub fn kept_messages(&self) -> u64 {
+        self.kept_messages.load(Ordering::Relaxed)
+    }
+
+    pub fn expired_drops(&self) -> u64 {
+        self.expired_drops.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub use self::endpoint::serve;
+
+// HTTP эндпоинт с метриками в текстовом формате Prometheus.
+#[cfg(feature = "metrics")]
+mod endpoint {
+    use super::{BrokerMetrics, METRICS};
+    use crate::locks::RwLockExt;
+    use crate::topic_controller::TopicController;
+    use crate::topic_registry::TopicRegistry;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use std::convert::Infallible;
+    use std::fmt::Write;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, RwLock};
+
+    // Как получить значение метрики из топика.
+    type TopicValue = fn(&TopicController) -> u64;
+
+    pub async fn serve(
+        addr: std::net::SocketAddr,
+        topic_registry: Arc<RwLock<TopicRegistry>>,
+    ) -> Result<(), hyper::Error> {
+        let make_service = make_service_fn(move |_connection| {
+            let topic_registry = Arc::clone(&topic_registry);
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_request| {
+                    let body = render(&METRICS, &topic_registry);
+                    async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+                }))
+            }
+        });
+
+        debug!("Serving metrics at {}", addr);
+
+        Server::bind(&addr).serve(make_service).await
+    }
+
+    pub fn render(metrics: &BrokerMetrics, topic_registry: &RwLock<TopicRegistry>) -> String {
+        let mut out = String::new();
+
+        let broker_metrics = [
+            (
+                "zaichik_received_bytes_total",
+                "counter",
+                "Payload bytes received from publishers.",
+                metrics.received_bytes.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "zaichik_sent_bytes_total",
+                "counter",
+                "Payload bytes sent to subscribers.",
+                metrics.sent_bytes.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "zaichik_active_connections",
+                "gauge",
+                "Connected clients.",
+                metrics.active_connections.load(Ordering::Relaxed),
+            ),
+            (
+                "zaichik_active_subscriptions",
+                "gauge",
+                "Subscriptions of all connected clients.",
+                metrics.active_subscriptions.load(Ordering::Relaxed),
+            ),
+        ];
+
+        for (name, kind, help, value) in broker_metrics.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let topic_registry = topic_registry.read_or_recover();
+        let topic_names = topic_registry.topic_names();
+
+        let topic_metrics: [(&str, &str, &str, TopicValue); 5] = [
+            (
+                "zaichik_messages_published_total",
+                "counter",
+                "Messages published to the topic.",
+                |topic| topic.stats().published_messages.load(Ordering::Relaxed),
+            ),
+            (
+                "zaichik_compaction_drops_total",
+                "counter",
+                "Messages dropped by compaction.",
+                |topic| topic.stats().compaction_drops.load(Ordering::Relaxed),
+            ),
+            (
+                "zaichik_expired_drops_total",
+                "counter",
+                "Messages not sent to a subscriber because they expired.",
+                |topic| topic.stats().expired_drops(),
+            ),
+            (
+                "zaichik_retained_messages",
+                "gauge",
+                "Messages in the retained buffer.",
+                |topic| topic.retained_len() as u64,
+            ),
+            (
+                "zaichik_retained_bytes",
+                "gauge",
+                "Payload bytes in the retained buffer.",
+                |topic| topic.retained_bytes() as u64,
+            ),
+        ];
+
+        for (name, kind, help, value_of) in topic_metrics.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+
+            for topic_name in &topic_names {
+                if let Some(topic_controller) = topic_registry.get_topic(topic_name) {
+                    let value = value_of(&topic_controller.read_or_recover());
+                    let _ = writeln!(
+                        out,
+                        "{}{{topic=\"{}\"}} {}",
+                        name,
+                        escape_label(topic_name),
+                        value
+                    );
+                }
+            }
+        }
+
+        out
+    }
+
+    // Экранируем значение label по правилам текстового формата Prometheus.
+    fn escape_label(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::protocol::CompactionMode;
+        use std::collections::HashMap;
+
+        // Эндпоинту нужно время, чтобы начать слушать порт, поэтому запрашиваем с повторами.
+        async fn scrape(addr: std::net::SocketAddr) -> String {
+            loop {
+                let uri = format!("http://{}/metrics", addr).parse().unwrap();
+                match hyper::Client::new().get(uri).await {
+                    Ok(response) => {
+                        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                        return String::from_utf8(body.to_vec()).unwrap();
+                    }
+                    Err(_) => tokio::time::delay_for(std::time::Duration::from_millis(10)).await,
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn test_scrape_sees_published_messages() {
+            let topic_registry = Arc::new(RwLock::new(TopicRegistry::new()));
+            topic_registry.write().unwrap().create_topic(
+                "orders".to_string(),
+                0,
+                0,
+                0,
+                0,
+                CompactionMode::Dedup,
+            );
+
+            let addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            tokio::spawn(serve(addr, Arc::clone(&topic_registry)));
+
+            let before = scrape(addr).await;
+            assert!(before.contains("zaichik_messages_published_total{topic=\"orders\"} 0"));
+
+            {
+                let topic_controller = topic_registry.read().unwrap().get_topic("orders").unwrap();
+                let mut topic_controller = topic_controller.write().unwrap();
+                topic_controller.publish(None, vec![1], HashMap::new(), std::time::Instant::now());
+                topic_controller.publish(None, vec![2], HashMap::new(), std::time::Instant::now());
+            }
+
+            let after = scrape(addr).await;
+            assert!(after.contains("zaichik_messages_published_total{topic=\"orders\"} 2"));
+        }
+    }
+}
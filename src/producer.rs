@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::time;
+
+use crate::{protocol, Client, ClientBuilder, CreatedTopic, PublishAck, TypedError};
+
+// Соединение, через которое только публикуют. Подписываться и подтверждать
+// сообщения через него нельзя, для этого есть Consumer.
+pub struct Producer {
+    client: Client,
+}
+
+impl Producer {
+    pub async fn connect(server_addr: &str) -> Result<Producer, Box<dyn Error>> {
+        Client::connect(server_addr).await.map(Producer::from)
+    }
+
+    pub async fn connect_with(
+        server_addr: &str,
+        options: ClientBuilder,
+    ) -> Result<Producer, Box<dyn Error>> {
+        options.connect(server_addr).await.map(Producer::from)
+    }
+
+    pub async fn create_topic(
+        &mut self,
+        topic: String,
+        retention_ttl: u64,
+        compaction_window: u64,
+        retention_max_messages: u64,
+        retention_max_bytes: u64,
+        compaction_mode: protocol::CompactionMode,
+    ) -> io::Result<CreatedTopic> {
+        self.client
+            .create_topic(
+                topic,
+                retention_ttl,
+                compaction_window,
+                retention_max_messages,
+                retention_max_bytes,
+                compaction_mode,
+            )
+            .await
+    }
+
+    pub async fn publish(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+    ) -> io::Result<()> {
+        self.client.publish(topic, key, payload).await
+    }
+
+    pub async fn publish_typed<T: serde::Serialize>(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        value: &T,
+    ) -> Result<(), TypedError> {
+        self.client.publish_typed(topic, key, value).await
+    }
+
+    pub fn set_payload_format(&mut self, format: protocol::SerializationFormat) {
+        self.client.set_payload_format(format)
+    }
+
+    pub async fn publish_acked(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+    ) -> io::Result<PublishAck> {
+        self.client.publish_acked(topic, key, payload).await
+    }
+
+    pub async fn publish_with_headers(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+        headers: HashMap<String, String>,
+    ) -> io::Result<()> {
+        self.client
+            .publish_with_headers(topic, key, payload, headers)
+            .await
+    }
+
+    pub async fn publish_delayed(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+        delay: time::Duration,
+    ) -> io::Result<()> {
+        self.client
+            .publish_delayed(topic, key, payload, delay)
+            .await
+    }
+
+    pub async fn publish_with_ttl(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+        ttl: time::Duration,
+    ) -> io::Result<()> {
+        self.client.publish_with_ttl(topic, key, payload, ttl).await
+    }
+
+    pub async fn publish_creating(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+        config: protocol::TopicConfig,
+    ) -> io::Result<()> {
+        self.client
+            .publish_creating(topic, key, payload, config)
+            .await
+    }
+
+    pub async fn publish_batch(
+        &mut self,
+        topic: String,
+        messages: Vec<(Option<String>, Vec<u8>)>,
+    ) -> io::Result<()> {
+        self.client.publish_batch(topic, messages).await
+    }
+
+    pub fn publisher(
+        &mut self,
+        topic: String,
+    ) -> impl futures::Sink<Vec<u8>, Error = io::Error> + Unpin + '_ {
+        self.client.publisher(topic)
+    }
+
+    pub fn keyed_publisher(
+        &mut self,
+        topic: String,
+    ) -> impl futures::Sink<(Option<String>, Vec<u8>), Error = io::Error> + Unpin + '_ {
+        self.client.keyed_publisher(topic)
+    }
+
+    pub async fn ping(&mut self) -> io::Result<()> {
+        self.client.ping().await
+    }
+
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.client.close().await
+    }
+
+    pub async fn shutdown(self) -> io::Result<()> {
+        self.client.shutdown().await
+    }
+}
+
+impl From<Client> for Producer {
+    fn from(client: Client) -> Producer {
+        Producer { client }
+    }
+}
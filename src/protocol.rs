@@ -1,7 +1,215 @@
+use bytes::{Buf, BufMut};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
 use std::io;
+use std::time;
 use tokio_util::codec::{Decoder, Encoder};
 
+// Версия протокола. Клиент отправляет ее в Handshake сразу после подключения,
+// а брокер отказывает клиентам с другой версией, потому что они не смогут
+// правильно разобрать наши фреймы. Бинкод не умеет пропускать незнакомые поля,
+// поэтому версия увеличивается при каждом изменении состава фреймов.
+// Версия 2: id сообщения в Publish, Commit и Nack, заголовки в Publish.
+// Версия 3: key_filter в Subscribe.
+// Версия 4: buffer_size в CreateTopic.
+// Версия 5: брокер отвечает на CreateTopic фреймом TopicCreated.
+// Версия 6: delivery в CreateTopic и TopicCreated.
+// Версия 7: deliver_after в Publish.
+// Версия 8: ttl в Publish.
+// Версия 9: auto_ack в Subscribe.
+// Версия 10: AdminConnections и AdminConnectionsResponse.
+// Версия 11: offset в Publish, from_offset в Subscribe.
+// Версия 12: create_with в Publish.
+// Версия 13: max_messages_per_sec в Subscribe.
+// Версия 14: flush_policy в Subscribe.
+// Версия 15: ack в Publish и PublishAck.
+// Версия 16: confirm в Subscribe и Subscribed.
+// Версия 17: drain в Subscribe и EndOfStream.
+// Версия 18: key_source в CreateTopic и TopicCreated.
+// Версия 19: deliver_expired в Subscribe, expired_drops в TopicStatsResponse.
+// Версия 20: partitions в CreateTopic и TopicCreated, partition в Subscribe.
+// Версия 21: ordering в CreateTopic и TopicCreated.
+// Версия 22: Pause и Resume.
+// Версия 23: published_messages, duplicate_drops и kept_messages в TopicStatsResponse.
+// Версия 24: DeliveryStart::SnapshotOnly.
+// Версия 25: max_message_bytes в CreateTopic и TopicCreated.
+// Версия 26: topic в Error.
+pub const PROTOCOL_VERSION: u16 = 26;
+
+// Коды ошибок, которые брокер отправляет клиенту во фрейме Error.
+pub const ERROR_UNSUPPORTED_PROTOCOL_VERSION: u16 = 1;
+// Брокер не смог разобрать фрейм от клиента.
+pub const ERROR_MALFORMED_FRAME: u16 = 2;
+// Запрос ссылается на топик, которого нет.
+pub const ERROR_TOPIC_NOT_FOUND: u16 = 3;
+// Подписка на удаленный топик. Топик нужно создать заново через CreateTopic или Publish.
+pub const ERROR_TOPIC_DELETED: u16 = 4;
+// Клиент прислал фрейм, который может отправлять только брокер.
+pub const ERROR_UNEXPECTED_FRAME: u16 = 5;
+// Брокеру не известен токен из Authenticate.
+pub const ERROR_AUTHENTICATION_FAILED: u16 = 6;
+// Брокер требует Authenticate сразу после Handshake, а клиент его не прислал.
+pub const ERROR_AUTHENTICATION_REQUIRED: u16 = 7;
+// Пользователю не разрешено читать или писать в топик. Без topic - пользователь не админ.
+pub const ERROR_ACCESS_DENIED: u16 = 8;
+// Commit или Nack ссылается на сообщение, которое не ждет подтверждения.
+pub const ERROR_UNKNOWN_MESSAGE_ID: u16 = 9;
+// Subscribe с группой, в котором указан шаблон топика или key_filter.
+pub const ERROR_INVALID_SUBSCRIPTION: u16 = 10;
+// CreateTopic для существующего топика с другими настройками.
+pub const ERROR_TOPIC_SETTINGS_CONFLICT: u16 = 11;
+// Publish с deliver_after больше MAX_DELIVER_AFTER.
+pub const ERROR_INVALID_PUBLISH: u16 = 12;
+// Пустое имя топика, имя длиннее, чем разрешает брокер, или с управляющими символами.
+pub const ERROR_INVALID_TOPIC_NAME: u16 = 13;
+// retention_ttl или compaction_window в CreateTopic больше лимита брокера,
+// а брокер настроен отклонять такие топики, а не уменьшать настройки до лимита.
+pub const ERROR_INVALID_TOPIC_SETTINGS: u16 = 14;
+// Subscribe на новый топик, когда у подключения уже столько подписок, сколько разрешает брокер.
+pub const ERROR_TOO_MANY_SUBSCRIPTIONS: u16 = 15;
+// Publish с payload больше max_message_bytes топика. PublishBatch, где такой
+// payload есть хотя бы у одного сообщения, отклоняется целиком.
+pub const ERROR_MESSAGE_TOO_LARGE: u16 = 16;
+// Publish или PublishBatch в Reliable топик, когда у подключения уже заполнена очередь
+// публикаций, которые ждут места в очередях подписчиков. Сообщение не записано в топик.
+pub const ERROR_TOPIC_SATURATED: u16 = 17;
+
+// Дальше, чем на столько, брокер сообщения не откладывает: таймер tokio не умеет
+// ждать дольше пары лет.
+pub const MAX_DELIVER_AFTER: time::Duration = time::Duration::from_secs(365 * 24 * 60 * 60);
+
+// Заголовки, которыми брокер с ZAICHIK_PRODUCER_HEADERS подписывает опубликованные
+// сообщения: адрес подключения издателя (по нему же подключение видно в AdminConnections)
+// и пользователь, под которым издатель прошел аутентификацию.
+pub const PRODUCER_PEER_HEADER: &str = "zaichik-producer-peer";
+pub const PRODUCER_PRINCIPAL_HEADER: &str = "zaichik-producer-principal";
+
+// Режим compaction для топика.
+// Dedup - отбрасываем новые сообщения с тем же ключом в течение compaction_window.
+// KeyLatest - как log compaction в Kafka: в retained буфере остается только
+// последнее сообщение по каждому ключу, и новые подписчики получают именно его.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum CompactionMode {
+    #[default]
+    Dedup,
+    KeyLatest,
+}
+
+// С какого места подписчик начинает получать сообщения топика.
+// Earliest - сначала все retained сообщения, потом новые.
+// Latest - только сообщения, опубликованные после подписки.
+// SnapshotOnly - из retained сообщений только последнее по каждому ключу, потом новые.
+// Сообщения без ключа считаются сообщениями с одним общим ключом, поэтому из них
+// придет только последнее. Лучше всего подходит для топиков с KeyLatest compaction,
+// где retained буфер и так хранит по сообщению на ключ. Вместе с drain подписка
+// закончится на снимке, и новые сообщения не придут.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum DeliveryStart {
+    #[default]
+    Earliest,
+    Latest,
+    SnapshotOnly,
+}
+
+// Когда брокер сбрасывает в сокет сообщения подписки.
+// Immediate - после каждого сообщения, так задержка минимальна.
+// OnIdle - когда брокеру больше нечего отправить прямо сейчас.
+// Batched(n) - после каждых n сообщений, а также когда отправлять больше нечего,
+// чтобы неполная пачка не ждала следующих сообщений.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum FlushPolicy {
+    #[default]
+    Immediate,
+    OnIdle,
+    Batched(u32),
+}
+
+// Что происходит, когда подписчик не успевает читать топик.
+// BestEffort - подписчик, отставший больше чем на buffer_size сообщений,
+// пропускает самые старые из них, а издатель не ждет.
+// Reliable - у каждого подписчика своя очередь на buffer_size сообщений. Когда она
+// заполнена, publish ждет, пока подписчик ее разберет, и сообщения не теряются.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum DeliveryGuarantee {
+    #[default]
+    BestEffort,
+    Reliable,
+}
+
+// Откуда топик берет ключ сообщения для compaction.
+// Explicit - ключ из Publish, как его указал издатель.
+// JsonPointer - payload разбирается как JSON, и ключом становится значение по указателю
+// (например, "/order/id"), а ключ из Publish не используется. Если payload не JSON
+// или по указателю нет строки, числа или bool, то сообщение публикуется без ключа.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub enum KeySource {
+    #[default]
+    Explicit,
+    JsonPointer(String),
+}
+
+// В каком порядке подписчик получает сообщения топика, если он подтверждает их
+// не сразу (prefetch больше 1) или возвращает через Nack. Без Nack сообщения
+// всегда уходят подписчику в порядке публикации, а compaction может только
+// отбросить сообщение, но не переставить его.
+// Strict - пока сообщение топика не подтверждено, следующие сообщения этого топика
+// подписчику не отправляются. Сообщение, возвращенное через Nack, приходит снова
+// раньше всех следующих. Prefetch при этом ускоряет только другие топики.
+// PerKey - то же самое, но отдельно для каждого ключа: сообщения с разными
+// ключами идут параллельно, в пределах prefetch. Сообщения без ключа не ждут друг друга.
+// None - сообщения отправляются, как только есть кредит, а сообщение после Nack
+// приходит снова уже после тех, что были отправлены раньше него.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum OrderingGuarantee {
+    Strict,
+    PerKey,
+    #[default]
+    None,
+}
+
+// Настройки топика для Client::create_topic_with и для Publish, который создаст топик,
+// если его еще нет. Поля значат то же, что и в CreateTopic: времена в миллисекундах,
+// ноль в любом лимите значит, что лимита нет, buffer_size None - размер по умолчанию
+// брокера.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct TopicConfig {
+    pub retention_ttl: u64,
+    pub compaction_window: u64,
+    pub retention_max_messages: u64,
+    pub retention_max_bytes: u64,
+    pub compaction_mode: CompactionMode,
+    #[serde(default)]
+    pub buffer_size: Option<u32>,
+    #[serde(default)]
+    pub delivery: DeliveryGuarantee,
+    #[serde(default)]
+    pub key_source: KeySource,
+    // 0 - одна партиция.
+    #[serde(default)]
+    pub partitions: u32,
+    #[serde(default)]
+    pub ordering: OrderingGuarantee,
+    // Самый большой payload, который примет топик. Publish с большим payload
+    // брокер отклоняет ошибкой ERROR_MESSAGE_TOO_LARGE.
+    #[serde(default)]
+    pub max_message_bytes: u64,
+}
+
+// Подключение к брокеру в ответе на AdminConnections. in_flight - сколько
+// сообщений клиент получил, но еще не подтвердил, awaiting_redelivery - сколько
+// вернулось через Nack и ждет повторной доставки.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer: std::net::SocketAddr,
+    pub principal: Option<String>,
+    pub connected_for: time::Duration,
+    pub topics: Vec<String>,
+    pub in_flight: u64,
+    pub awaiting_redelivery: u64,
+}
+
 // Фрейм нашего протокола. Несмотря на то, что мы используем
 // TCP, где данные передаются просто, как стрим байтов мы
 // можем выделить логические блоки, которые называются фреймами.
@@ -10,37 +218,534 @@ use tokio_util::codec::{Decoder, Encoder};
 // байтов.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub enum ZaichikFrame {
+    // buffer_size - размер broadcast канала топика. None или 0 - размер по умолчанию,
+    // который задается брокеру через ZAICHIK_TOPIC_BUFFER_SIZE.
+    // partitions - на сколько партиций делится топик, 0 - одна. Сообщения с одним
+    // ключом всегда попадают в одну партицию, без ключа - по кругу.
+    // max_message_bytes - самый большой payload, который примет топик, 0 - без лимита.
     CreateTopic {
         topic: String,
         retention_ttl: u64,
         compaction_window: u64,
+        retention_max_messages: u64,
+        retention_max_bytes: u64,
+        compaction_mode: CompactionMode,
+        #[serde(default)]
+        buffer_size: Option<u32>,
+        #[serde(default)]
+        delivery: DeliveryGuarantee,
+        #[serde(default)]
+        key_source: KeySource,
+        #[serde(default)]
+        partitions: u32,
+        #[serde(default)]
+        ordering: OrderingGuarantee,
+        #[serde(default)]
+        max_message_bytes: u64,
     },
+    // id заполняет брокер, когда доставляет сообщение подписчику. Этот id
+    // клиент указывает в Commit и Nack. Издатель отправляет 0, брокер его не смотрит.
+    // headers брокер хранит вместе с сообщением и отдает подписчикам как есть.
+    // В JSON их можно не указывать.
+    // С deliver_after брокер покажет сообщение подписчикам только через это время.
+    // ttl заменяет retention_ttl топика для этого сообщения: оно истекает через ttl
+    // после публикации, даже если у топика retention_ttl нет.
+    // Подписчикам брокер присылает Publish уже без deliver_after и ttl, но с offset -
+    // номером сообщения в топике, см. from_offset в Subscribe. Издатель отправляет 0.
+    // create_with брокер смотрит, только если топика еще нет: тогда топик создается
+    // с этими настройками, а не с настройками по умолчанию. Настройки уже существующего
+    // топика create_with не меняет.
+    // С ack = true брокер отвечает на Publish фреймом PublishAck, в том числе
+    // вместо Error, если отклонил сообщение.
     Publish {
         topic: String,
         key: Option<String>,
         payload: Vec<u8>,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        id: u64,
+        #[serde(default)]
+        deliver_after: Option<time::Duration>,
+        #[serde(default)]
+        ttl: Option<time::Duration>,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default)]
+        create_with: Option<TopicConfig>,
+        #[serde(default)]
+        ack: bool,
     },
+    // Если указана group, то клиент становится участником группы потребителей
+    // и делит сообщения топика с другими ее участниками.
+    // Если указан key_filter, то брокер присылает только сообщения с одним из этих
+    // ключей. Сообщения без ключа под такой фильтр не попадают.
+    // С auto_ack брокер считает сообщение подтвержденным, как только отправил его:
+    // такие сообщения приходят с id = 0, не занимают prefetch и не ждут Commit.
+    // auto_ack нельзя указать вместе с group или шаблоном топика.
+    // С from_offset брокер сначала присылает retained сообщения с offset больше этого,
+    // а start не смотрит. Если таких сообщений уже нет, то начинает с самого старого.
+    // from_offset нельзя указать вместе с шаблоном топика, у группы он не учитывается.
+    // С max_messages_per_sec брокер отправляет сообщения подписки не чаще этого,
+    // даже если у клиента есть свободный prefetch. Сообщения, которые ждут своей
+    // очереди, остаются в канале топика. Нельзя указать вместе с шаблоном топика.
+    // flush_policy задает, как часто брокер сбрасывает сообщения подписки в сокет,
+    // ее тоже нельзя указать вместе с шаблоном.
+    // С confirm = true брокер отвечает Subscribed, когда подписка уже заведена,
+    // и до первого сообщения по ней.
+    // С drain = true подписка получает только сообщения, которые были в топике
+    // в момент подписки, после них брокер присылает EndOfStream и снимает подписку.
+    // drain нельзя указать вместе с шаблоном или группой.
+    // Сообщение, которое истекло, пока ждало отправки подписчику, брокер пропускает
+    // и считает в метрике топика. С deliver_expired = true он отправляет такие
+    // сообщения как обычно. Нельзя указать вместе с шаблоном топика.
+    // С partition подписка получает только сообщения этой партиции топика, без него -
+    // всех партиций. partition нельзя указать вместе с шаблоном или группой.
     Subscribe {
         topic: String,
+        group: Option<String>,
+        start: DeliveryStart,
+        #[serde(default)]
+        key_filter: Option<Vec<String>>,
+        #[serde(default)]
+        auto_ack: bool,
+        #[serde(default)]
+        from_offset: Option<u64>,
+        #[serde(default)]
+        max_messages_per_sec: Option<u32>,
+        #[serde(default)]
+        flush_policy: FlushPolicy,
+        #[serde(default)]
+        confirm: bool,
+        #[serde(default)]
+        drain: bool,
+        #[serde(default)]
+        deliver_expired: bool,
+        #[serde(default)]
+        partition: Option<u32>,
     },
     Unsubscribe {
         topic: String,
     },
+    // Приостанавливает доставку сообщений подписки на topic, не снимая ее. Новые
+    // сообщения ждут в топике: у BestEffort топика подписчик может отстать больше
+    // чем на buffer_size и пропустить самые старые, а Reliable топик перестает
+    // принимать publish, когда очередь подписчика заполнится. Сообщения, которые
+    // клиент уже получил или вернул через Nack, доставляются как обычно.
+    // Resume продолжает доставку с того места, где она остановилась.
+    // Повторная Subscribe на топик снимает паузу.
+    Pause {
+        topic: String,
+    },
+    Resume {
+        topic: String,
+    },
     CloseConnection,
-    Commit,
+    // Клиент обработал сообщение с этим id.
+    Commit {
+        id: u64,
+    },
+    Handshake {
+        protocol_version: u16,
+    },
+    Error {
+        code: u16,
+        message: String,
+        // Топик запроса, на который отвечает ошибка. None у ошибок, которые
+        // не относятся к топику, например ERROR_MALFORMED_FRAME.
+        topic: Option<String>,
+    },
+    Ping,
+    Pong,
+    DeleteTopic {
+        topic: String,
+    },
+    // Уведомление подписчикам о том, что топик был удален.
+    TopicDeleted {
+        topic: String,
+    },
+    ListTopics,
+    TopicList {
+        topics: Vec<String>,
+    },
+    // Сколько неподтвержденных сообщений клиент готов держать у себя.
+    SetPrefetch {
+        count: u32,
+    },
+    // Клиент не смог обработать сообщение с этим id. Если requeue, то брокер доставит его еще раз.
+    Nack {
+        id: u64,
+        requeue: bool,
+    },
+    // Если на брокере включена аутентификация, то клиент присылает токен
+    // первым фреймом после Handshake, а брокер отвечает Authenticated.
+    Authenticate {
+        token: String,
+    },
+    Authenticated,
+    // Несколько сообщений (key, payload) в один топик одним фреймом. Брокер публикует
+    // их по порядку, и другие публикации в топик не могут оказаться между ними.
+    PublishBatch {
+        topic: String,
+        messages: Vec<(Option<String>, Vec<u8>)>,
+    },
+    // Сообщения топика, которые вернули через Nack с requeue max_delivery_attempts раз,
+    // брокер перекладывает в dead_letter_topic (по умолчанию "<topic>.dlq").
+    // max_delivery_attempts = 0 выключает dead-letter для топика.
+    SetDeadLetter {
+        topic: String,
+        max_delivery_attempts: u32,
+        dead_letter_topic: Option<String>,
+    },
+    // Запрос текущего состояния топика, брокер отвечает TopicStatsResponse.
+    TopicStats {
+        topic: String,
+    },
+    // subscriber_count учитывает и обычных подписчиков, и участников групп.
+    // compaction_keys - сколько ключей сейчас помнит Dedup compaction.
+    // expired_drops - сколько сообщений топика подписчики не получили, потому что
+    // они истекли, пока ждали отправки.
+    // published_messages - все сообщения, опубликованные в топик, из них
+    // duplicate_drops отброшены Dedup compaction как дубли, а kept_messages
+    // прошли compaction и получили offset.
+    TopicStatsResponse {
+        topic: String,
+        retained_count: u64,
+        retained_bytes: u64,
+        compaction_keys: u64,
+        subscriber_count: u64,
+        #[serde(default)]
+        expired_drops: u64,
+        #[serde(default)]
+        published_messages: u64,
+        #[serde(default)]
+        duplicate_drops: u64,
+        #[serde(default)]
+        kept_messages: u64,
+    },
+    // Ответ на CreateTopic с настройками, которые действуют у топика: нули уже
+    // заменены на значения по умолчанию, где они есть. already_existed = true, если
+    // топик уже был с точно такими же настройками. Если настройки отличаются,
+    // то брокер вместо TopicCreated отвечает ошибкой ERROR_TOPIC_SETTINGS_CONFLICT.
+    TopicCreated {
+        topic: String,
+        retention_ttl: u64,
+        compaction_window: u64,
+        retention_max_messages: u64,
+        retention_max_bytes: u64,
+        compaction_mode: CompactionMode,
+        buffer_size: u32,
+        delivery: DeliveryGuarantee,
+        key_source: KeySource,
+        partitions: u32,
+        ordering: OrderingGuarantee,
+        max_message_bytes: u64,
+        already_existed: bool,
+    },
+    // Запрос для операторов: какие клиенты сейчас подключены к брокеру
+    // и на что подписаны. Брокер отвечает AdminConnectionsResponse.
+    AdminConnections,
+    AdminConnectionsResponse {
+        connections: Vec<ConnectionInfo>,
+    },
+    // Ответ на Publish с ack = true. accepted = false, если брокер отклонил сообщение,
+    // причина тогда в reason. dropped_as_duplicate = true, если сообщение принято,
+    // но отброшено compaction как дубль ключа. Отложенные сообщения проверяются
+    // на дубли позже, поэтому для них dropped_as_duplicate всегда false.
+    // Publish в Reliable топик, где очередь одного из подписчиков заполнена,
+    // брокер отклоняет сразу, а не ждет подписчика.
+    PublishAck {
+        accepted: bool,
+        reason: Option<String>,
+        dropped_as_duplicate: bool,
+    },
+    // Ответ на Subscribe с confirm = true. topic - топик или шаблон из Subscribe.
+    Subscribed {
+        topic: String,
+    },
+    // Подписка с drain = true получила все сообщения, которые были в топике
+    // в момент подписки, и снята. Новых сообщений по ней не будет.
+    EndOfStream {
+        topic: String,
+    },
+}
+
+pub fn is_supported_version(protocol_version: u16) -> bool {
+    protocol_version == PROTOCOL_VERSION
+}
+
+// Размер префикса, в котором хранится длина фрейма.
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+// Максимальный размер фрейма по умолчанию - 16 MiB. Без такого ограничения
+// клиент мог бы прислать префикс на несколько гигабайт и заставить брокер
+// выделить под него память.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+// Формат, в котором фреймы передаются по сети. По умолчанию используется
+// компактный бинкод, а JSON удобен для отладки и клиентов на других языках.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerializationFormat {
+    Bincode,
+    Json,
+}
+
+// Почему кодек не смог закодировать или разобрать фрейм. Framed работает с io::Error,
+// поэтому Encoder и Decoder кладут CodecError внутрь io::Error, достать ее можно
+// через get_ref и downcast_ref.
+#[derive(Debug)]
+pub enum CodecError {
+    // Соединение закрылось посреди фрейма, и в буфере остались его первые байты.
+    Incomplete { buffered: usize },
+    // Фрейм больше max_frame_len.
+    Oversize { len: usize, max_frame_len: usize },
+    // Фрейм пришел целиком, но это не бинкод ZaichikFrame.
+    Malformed(bincode::Error),
+    // То же для формата JSON.
+    MalformedJson(serde_json::Error),
+    // После битого фрейма декодер пропустил skipped байт, чтобы найти начало
+    // следующего. Следующий вызов decode вернет этот фрейм.
+    Resynced { skipped: usize },
+    Io(io::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Incomplete { buffered } => write!(
+                f,
+                "Connection closed in the middle of a frame, {} bytes left",
+                buffered
+            ),
+            CodecError::Oversize { len, max_frame_len } => write!(
+                f,
+                "Frame of {} bytes exceeds max frame length {}",
+                len, max_frame_len
+            ),
+            CodecError::Malformed(e) => write!(f, "Failed to decode Frame: {}", e),
+            CodecError::MalformedJson(e) => write!(f, "Failed to decode Frame: {}", e),
+            CodecError::Resynced { skipped } => {
+                write!(f, "Skipped {} bytes to find the next frame", skipped)
+            }
+            CodecError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for CodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CodecError::Malformed(e) => Some(e),
+            CodecError::MalformedJson(e) => Some(e),
+            CodecError::Io(e) => Some(e),
+            CodecError::Incomplete { .. }
+            | CodecError::Oversize { .. }
+            | CodecError::Resynced { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(error: io::Error) -> CodecError {
+        CodecError::Io(error)
+    }
+}
+
+impl From<CodecError> for io::Error {
+    fn from(error: CodecError) -> io::Error {
+        match error {
+            CodecError::Io(e) => e,
+            incomplete @ CodecError::Incomplete { .. } => {
+                io::Error::new(io::ErrorKind::UnexpectedEof, incomplete)
+            }
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
 }
 
 // Кодек позволяет нам превратить наш фрейм в байты и обратно.
-// Мы для передачи данных будем использовать бинкод.
+// Мы для передачи данных будем использовать бинкод или JSON.
+// Так как TCP может доставить фрейм по частям, перед каждым фреймом
+// мы пишем его длину в виде 4-х байтов (big-endian). Так декодер
+// всегда знает, пришел ли фрейм целиком.
+//
+// Если префикс длины испорчен, то границам следующих фреймов верить нельзя.
+// Поэтому после битого фрейма декодер ищет в буфере место, где префикс с допустимой
+// длиной стоит перед фреймом, который разбирается, и пропускает байты до него,
+// а о пропуске сообщает ошибкой CodecError::Resynced. Так один битый фрейм
+// не ломает все соединение.
 #[derive(Clone)]
-pub struct ZaichikCodec;
+pub struct ZaichikCodec {
+    format: SerializationFormat,
+    max_frame_len: usize,
+    // Последний фрейм был битым, и следующий надо искать, см. resync.
+    resyncing: bool,
+    // Сколько байт уже пропущено в поисках следующего фрейма.
+    skipped: usize,
+}
 
 impl ZaichikCodec {
-    pub fn new() -> ZaichikCodec {
-        ZaichikCodec {}
+    pub fn new(format: SerializationFormat) -> ZaichikCodec {
+        ZaichikCodec {
+            format,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            resyncing: false,
+            skipped: 0,
+        }
+    }
+
+    pub fn bincode() -> ZaichikCodec {
+        ZaichikCodec::new(SerializationFormat::Bincode)
+    }
+
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> ZaichikCodec {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    fn serialize(&self, item: &ZaichikFrame) -> Result<Vec<u8>, CodecError> {
+        match self.format {
+            SerializationFormat::Bincode => bincode::serialize(item).map_err(CodecError::Malformed),
+            SerializationFormat::Json => {
+                serde_json::to_vec(item).map_err(CodecError::MalformedJson)
+            }
+        }
+    }
+
+    fn deserialize(&self, payload: &[u8]) -> Result<ZaichikFrame, CodecError> {
+        match self.format {
+            SerializationFormat::Bincode => {
+                bincode::deserialize::<ZaichikFrame>(payload).map_err(CodecError::Malformed)
+            }
+            SerializationFormat::Json => {
+                serde_json::from_slice::<ZaichikFrame>(payload).map_err(CodecError::MalformedJson)
+            }
+        }
+    }
+
+    pub fn encode_frame(
+        &mut self,
+        item: ZaichikFrame,
+        buffer: &mut bytes::BytesMut,
+    ) -> Result<(), CodecError> {
+        let encoded = self.serialize(&item)?;
+
+        // Не отправляем фрейм, который другая сторона все равно отвергнет.
+        if encoded.len() > self.max_frame_len {
+            return Err(CodecError::Oversize {
+                len: encoded.len(),
+                max_frame_len: self.max_frame_len,
+            });
+        }
+
+        buffer.reserve(LENGTH_PREFIX_SIZE + encoded.len());
+        buffer.put_u32(encoded.len() as u32);
+        buffer.extend(encoded);
+        Ok(())
+    }
+
+    // Когда сокет закрылся, недочитанный фрейм в буфере уже не допишется.
+    pub fn decode_frame_eof(
+        &mut self,
+        buf: &mut bytes::BytesMut,
+    ) -> Result<Option<ZaichikFrame>, CodecError> {
+        match self.decode_frame(buf)? {
+            Some(frame) => Ok(Some(frame)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(CodecError::Incomplete {
+                buffered: buf.len(),
+            }),
+        }
+    }
+
+    pub fn decode_frame(
+        &mut self,
+        buf: &mut bytes::BytesMut,
+    ) -> Result<Option<ZaichikFrame>, CodecError> {
+        if self.resyncing && !self.resync(buf)? {
+            return Ok(None);
+        }
+
+        // Ждем, пока не придет хотя бы префикс с длиной.
+        if buf.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+
+        let frame_len = read_frame_len(buf);
+
+        // Проверяем длину до того, как начнем накапливать сам фрейм в буфере.
+        if frame_len > self.max_frame_len {
+            self.resyncing = true;
+            return Err(CodecError::Oversize {
+                len: frame_len,
+                max_frame_len: self.max_frame_len,
+            });
+        }
+
+        // Фрейм пришел не полностью, оставляем байты в буфере
+        // и ждем следующую порцию данных из сокета.
+        if buf.len() < LENGTH_PREFIX_SIZE + frame_len {
+            buf.reserve(LENGTH_PREFIX_SIZE + frame_len - buf.len());
+            return Ok(None);
+        }
+
+        buf.advance(LENGTH_PREFIX_SIZE);
+        let payload = buf.split_to(frame_len);
+
+        let frame = self.deserialize(&payload[..]);
+        self.resyncing = frame.is_err();
+        frame.map(Some)
+    }
+
+    // Ищем первое место в буфере, откуда начинается целый фрейм, который удается
+    // разобрать, и пропускаем байты до него. Ok(true) - фрейм в начале буфера и
+    // ничего не пропущено, Ok(false) - фрейма пока нет, ждем еще данных.
+    // Место, где длина допустима, но фрейм пришел не весь, может оказаться началом
+    // настоящего фрейма, поэтому байты с него не выкидываем.
+    fn resync(&mut self, buf: &mut bytes::BytesMut) -> Result<bool, CodecError> {
+        let mut first_incomplete = None;
+        let mut start = 0;
+
+        while buf.len() - start >= LENGTH_PREFIX_SIZE {
+            let frame_len = read_frame_len(&buf[start..]);
+            let end = start + LENGTH_PREFIX_SIZE + frame_len;
+
+            if frame_len > self.max_frame_len {
+                start += 1;
+                continue;
+            }
+            if end > buf.len() {
+                first_incomplete.get_or_insert(start);
+                start += 1;
+                continue;
+            }
+            if self
+                .deserialize(&buf[start + LENGTH_PREFIX_SIZE..end])
+                .is_ok()
+            {
+                buf.advance(start);
+                self.resyncing = false;
+                let skipped = std::mem::replace(&mut self.skipped, 0) + start;
+                return match skipped {
+                    0 => Ok(true),
+                    skipped => Err(CodecError::Resynced { skipped }),
+                };
+            }
+            start += 1;
+        }
+
+        let skipped = first_incomplete.unwrap_or(start);
+        buf.advance(skipped);
+        self.skipped += skipped;
+        Ok(false)
     }
 }
 
+fn read_frame_len(buf: &[u8]) -> usize {
+    let mut length_bytes = [0u8; LENGTH_PREFIX_SIZE];
+    length_bytes.copy_from_slice(&buf[..LENGTH_PREFIX_SIZE]);
+    u32::from_be_bytes(length_bytes) as usize
+}
+
 impl Encoder for ZaichikCodec {
     type Item = ZaichikFrame;
     type Error = io::Error;
@@ -50,9 +755,7 @@ impl Encoder for ZaichikCodec {
         item: ZaichikFrame,
         buffer: &mut bytes::BytesMut,
     ) -> Result<(), io::Error> {
-        let encoded: Vec<u8> = bincode::serialize(&item).unwrap();
-        buffer.extend(encoded);
-        Ok(())
+        self.encode_frame(item, buffer).map_err(io::Error::from)
     }
 }
 
@@ -61,29 +764,11 @@ impl Decoder for ZaichikCodec {
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut bytes::BytesMut) -> Result<Option<ZaichikFrame>, io::Error> {
-        if !buf.is_empty() {
-            match bincode::deserialize::<ZaichikFrame>(&buf[..]) {
-                Ok(decoded) => match bincode::serialized_size(&decoded) {
-                    Ok(already_consumed) => {
-                        let _consumed_frame = buf.split_to(already_consumed as usize);
-                        Ok(Some(decoded))
-                    }
-                    Err(_) => Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Failed to calculate serialized size",
-                    )),
-                },
-                Err(_err) => {
-                    buf.clear();
-                    Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Failed to decode Frame, cleaning buffer",
-                    ))
-                }
-            }
-        } else {
-            Ok(None)
-        }
+        self.decode_frame(buf).map_err(io::Error::from)
+    }
+
+    fn decode_eof(&mut self, buf: &mut bytes::BytesMut) -> Result<Option<ZaichikFrame>, io::Error> {
+        self.decode_frame_eof(buf).map_err(io::Error::from)
     }
 }
 
@@ -97,14 +782,24 @@ mod tests {
             topic: String::from("topic"),
             key: None,
             payload: vec![1, 2, 3, 4, 5],
+            headers: HashMap::new(),
+            id: 0,
+            deliver_after: None,
+            ttl: None,
+            offset: 0,
+            create_with: None,
+            ack: false,
         };
 
         let mut buffer = bytes::BytesMut::new();
-        ZaichikCodec::new()
+        ZaichikCodec::bincode()
             .encode(frame.clone(), &mut buffer)
             .unwrap();
 
-        let decoded = ZaichikCodec::new().decode(&mut buffer).unwrap().unwrap();
+        let decoded = ZaichikCodec::bincode()
+            .decode(&mut buffer)
+            .unwrap()
+            .unwrap();
         assert_eq!(frame, decoded)
     }
 
@@ -114,27 +809,466 @@ mod tests {
             topic: String::from("topic1"),
             key: None,
             payload: vec![1, 2, 3, 4, 5],
+            headers: HashMap::new(),
+            id: 0,
+            deliver_after: None,
+            ttl: None,
+            offset: 0,
+            create_with: None,
+            ack: false,
         };
 
         let frame2 = ZaichikFrame::Publish {
             topic: String::from("topic2"),
             key: None,
             payload: vec![1, 2, 3, 4, 5],
+            headers: HashMap::new(),
+            id: 0,
+            deliver_after: None,
+            ttl: None,
+            offset: 0,
+            create_with: None,
+            ack: false,
         };
 
         let mut buffer = bytes::BytesMut::new();
 
-        ZaichikCodec::new()
+        ZaichikCodec::bincode()
             .encode(frame1.clone(), &mut buffer)
             .unwrap();
-        ZaichikCodec::new()
+        ZaichikCodec::bincode()
             .encode(frame2.clone(), &mut buffer)
             .unwrap();
 
-        let decoded1 = ZaichikCodec::new().decode(&mut buffer).unwrap().unwrap();
-        let decoded2 = ZaichikCodec::new().decode(&mut buffer).unwrap().unwrap();
+        let decoded1 = ZaichikCodec::bincode()
+            .decode(&mut buffer)
+            .unwrap()
+            .unwrap();
+        let decoded2 = ZaichikCodec::bincode()
+            .decode(&mut buffer)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(frame1, decoded1);
         assert_eq!(frame2, decoded2);
     }
+
+    #[test]
+    fn test_frame_decoder_waits_for_whole_frame() {
+        let frame = ZaichikFrame::Publish {
+            topic: String::from("topic"),
+            key: Some(String::from("key")),
+            payload: vec![1, 2, 3, 4, 5],
+            headers: HashMap::new(),
+            id: 0,
+            deliver_after: None,
+            ttl: None,
+            offset: 0,
+            create_with: None,
+            ack: false,
+        };
+
+        let mut encoded = bytes::BytesMut::new();
+        ZaichikCodec::bincode()
+            .encode(frame.clone(), &mut encoded)
+            .unwrap();
+
+        let mut codec = ZaichikCodec::bincode();
+        let mut buffer = bytes::BytesMut::new();
+        let total = encoded.len();
+
+        // Отдаем декодеру по одному байту, как будто TCP порезал фрейм.
+        for (i, byte) in encoded.iter().enumerate() {
+            buffer.put_u8(*byte);
+            let decoded = codec.decode(&mut buffer).unwrap();
+
+            if i + 1 < total {
+                assert_eq!(None, decoded);
+            } else {
+                assert_eq!(Some(frame.clone()), decoded);
+            }
+        }
+
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_handshake_with_mismatched_version() {
+        let frame = ZaichikFrame::Handshake {
+            protocol_version: PROTOCOL_VERSION + 1,
+        };
+
+        let mut buffer = bytes::BytesMut::new();
+        ZaichikCodec::bincode()
+            .encode(frame.clone(), &mut buffer)
+            .unwrap();
+
+        let decoded = ZaichikCodec::bincode()
+            .decode(&mut buffer)
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame, decoded);
+
+        match decoded {
+            ZaichikFrame::Handshake { protocol_version } => {
+                assert!(!is_supported_version(protocol_version))
+            }
+            _ => panic!("Expected Handshake frame"),
+        }
+        assert!(is_supported_version(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn test_frame_decoder_rejects_oversized_frame() {
+        let mut codec = ZaichikCodec::bincode().with_max_frame_len(16);
+        let mut buffer = bytes::BytesMut::new();
+
+        // Префикс обещает фрейм на гигабайт, но самих данных еще нет.
+        buffer.put_u32(1024 * 1024 * 1024);
+
+        let error = codec.decode(&mut buffer).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(buffer.capacity() < 1024);
+    }
+
+    #[test]
+    fn test_codec_errors_tell_apart_incomplete_oversize_and_malformed_frames() {
+        let mut codec = ZaichikCodec::bincode().with_max_frame_len(16);
+
+        // Сокет закрылся, когда от фрейма пришел только префикс и один байт.
+        let mut buffer = bytes::BytesMut::new();
+        buffer.put_u32(8);
+        buffer.put_u8(1);
+        match codec.decode_frame_eof(&mut buffer) {
+            Err(CodecError::Incomplete { buffered }) => assert_eq!(5, buffered),
+            other => panic!("Expected Incomplete, got {:?}", other),
+        }
+
+        let mut buffer = bytes::BytesMut::new();
+        buffer.put_u32(17);
+        match codec.decode_frame(&mut buffer) {
+            Err(CodecError::Oversize { len, max_frame_len }) => {
+                assert_eq!((17, 16), (len, max_frame_len))
+            }
+            other => panic!("Expected Oversize, got {:?}", other),
+        }
+
+        // Несуществующий номер варианта ZaichikFrame. После Oversize кодек ищет
+        // следующий фрейм, поэтому берем новый.
+        let mut codec = ZaichikCodec::bincode().with_max_frame_len(16);
+        let mut buffer = bytes::BytesMut::new();
+        buffer.put_u32(4);
+        buffer.put_u32_le(u32::MAX);
+        match codec.decode_frame(&mut buffer) {
+            Err(CodecError::Malformed(_)) => {}
+            other => panic!("Expected Malformed, got {:?}", other),
+        }
+
+        let mut json_codec = ZaichikCodec::new(SerializationFormat::Json);
+        let mut buffer = bytes::BytesMut::new();
+        buffer.put_u32(2);
+        buffer.extend_from_slice(b"{]");
+        match json_codec.decode_frame(&mut buffer) {
+            Err(CodecError::MalformedJson(_)) => {}
+            other => panic!("Expected MalformedJson, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decoder_resyncs_after_garbage_between_frames() {
+        let first = ZaichikFrame::Unsubscribe {
+            topic: String::from("first"),
+        };
+        let second = ZaichikFrame::Unsubscribe {
+            topic: String::from("second"),
+        };
+
+        for format in &[SerializationFormat::Bincode, SerializationFormat::Json] {
+            let mut codec = ZaichikCodec::new(*format);
+            let mut buffer = bytes::BytesMut::new();
+            codec.encode(first.clone(), &mut buffer).unwrap();
+            // Первые четыре байта мусора читаются как длина больше max_frame_len.
+            buffer.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef, 1, 2, 3]);
+            codec.encode(second.clone(), &mut buffer).unwrap();
+
+            assert_eq!(
+                Some(first.clone()),
+                codec.decode_frame(&mut buffer).unwrap()
+            );
+            match codec.decode_frame(&mut buffer) {
+                Err(CodecError::Oversize { .. }) => {}
+                other => panic!("Expected Oversize, got {:?}", other),
+            }
+            match codec.decode_frame(&mut buffer) {
+                Err(CodecError::Resynced { skipped }) => assert_eq!(7, skipped),
+                other => panic!("Expected Resynced, got {:?}", other),
+            }
+            assert_eq!(
+                Some(second.clone()),
+                codec.decode_frame(&mut buffer).unwrap()
+            );
+            assert!(buffer.is_empty());
+        }
+
+        // Длина правдоподобная, но сам фрейм не разбирается, и следующий фрейм
+        // приходит позже по частям.
+        let mut codec = ZaichikCodec::bincode();
+        let mut buffer = bytes::BytesMut::new();
+        buffer.put_u32(6);
+        buffer.extend_from_slice(&[0xff; 6]);
+        buffer.extend_from_slice(&[0xff; 3]);
+        match codec.decode_frame(&mut buffer) {
+            Err(CodecError::Malformed(_)) => {}
+            other => panic!("Expected Malformed, got {:?}", other),
+        }
+
+        let mut encoded = bytes::BytesMut::new();
+        codec.encode(second.clone(), &mut encoded).unwrap();
+        let rest = encoded.split_off(5);
+        buffer.extend_from_slice(&encoded);
+        assert_eq!(None, codec.decode_frame(&mut buffer).unwrap());
+        buffer.extend_from_slice(&rest);
+        match codec.decode_frame(&mut buffer) {
+            Err(CodecError::Resynced { skipped }) => assert_eq!(3, skipped),
+            other => panic!("Expected Resynced, got {:?}", other),
+        }
+        assert_eq!(Some(second), codec.decode_frame(&mut buffer).unwrap());
+    }
+
+    #[test]
+    fn test_codec_error_is_inside_io_error_from_decoder() {
+        let mut codec = ZaichikCodec::bincode();
+        let mut buffer = bytes::BytesMut::new();
+        buffer.put_u32(4);
+        buffer.put_u32_le(u32::MAX);
+
+        let error = codec.decode(&mut buffer).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        let codec_error = error
+            .get_ref()
+            .and_then(|error| error.downcast_ref::<CodecError>());
+        assert!(matches!(codec_error, Some(CodecError::Malformed(_))));
+
+        let io_error = io::Error::new(io::ErrorKind::BrokenPipe, "closed");
+        let error = io::Error::from(CodecError::from(io_error));
+        assert_eq!(io::ErrorKind::BrokenPipe, error.kind());
+    }
+
+    #[test]
+    fn test_frame_encoder_rejects_oversized_frame() {
+        let frame = ZaichikFrame::Publish {
+            topic: String::from("topic"),
+            key: None,
+            payload: vec![0; 32],
+            headers: HashMap::new(),
+            id: 0,
+            deliver_after: None,
+            ttl: None,
+            offset: 0,
+            create_with: None,
+            ack: false,
+        };
+
+        let mut buffer = bytes::BytesMut::new();
+        let error = ZaichikCodec::bincode()
+            .with_max_frame_len(16)
+            .encode(frame, &mut buffer)
+            .unwrap_err();
+
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(buffer.is_empty());
+    }
+
+    fn all_frames() -> Vec<ZaichikFrame> {
+        vec![
+            ZaichikFrame::CreateTopic {
+                topic: String::from("topic"),
+                retention_ttl: 1000,
+                compaction_window: 2000,
+                retention_max_messages: 10,
+                retention_max_bytes: 1024,
+                compaction_mode: CompactionMode::KeyLatest,
+                buffer_size: Some(16),
+                delivery: DeliveryGuarantee::Reliable,
+                key_source: KeySource::JsonPointer(String::from("/id")),
+                partitions: 4,
+                ordering: OrderingGuarantee::None,
+                max_message_bytes: 4096,
+            },
+            ZaichikFrame::Publish {
+                topic: String::from("topic"),
+                key: Some(String::from("key")),
+                payload: vec![1, 2, 3, 4, 5],
+                headers: vec![(
+                    String::from("content-type"),
+                    String::from("application/json"),
+                )]
+                .into_iter()
+                .collect(),
+                id: 42,
+                deliver_after: Some(time::Duration::from_millis(200)),
+                ttl: Some(time::Duration::from_secs(60)),
+                offset: 0,
+                create_with: Some(TopicConfig {
+                    retention_ttl: 1000,
+                    compaction_window: 0,
+                    retention_max_messages: 10,
+                    retention_max_bytes: 0,
+                    compaction_mode: CompactionMode::KeyLatest,
+                    buffer_size: None,
+                    delivery: DeliveryGuarantee::BestEffort,
+                    key_source: KeySource::Explicit,
+                    partitions: 0,
+                    ordering: OrderingGuarantee::None,
+                    max_message_bytes: 0,
+                }),
+                ack: true,
+            },
+            ZaichikFrame::Subscribe {
+                topic: String::from("topic"),
+                group: Some(String::from("group")),
+                start: DeliveryStart::Latest,
+                key_filter: Some(vec![String::from("key")]),
+                auto_ack: false,
+                from_offset: None,
+                max_messages_per_sec: None,
+                flush_policy: FlushPolicy::Batched(16),
+                confirm: true,
+                drain: true,
+                deliver_expired: true,
+                partition: Some(2),
+            },
+            ZaichikFrame::Unsubscribe {
+                topic: String::from("topic"),
+            },
+            ZaichikFrame::Pause {
+                topic: String::from("topic"),
+            },
+            ZaichikFrame::Resume {
+                topic: String::from("topic"),
+            },
+            ZaichikFrame::CloseConnection,
+            ZaichikFrame::Commit { id: 7 },
+            ZaichikFrame::Handshake {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            ZaichikFrame::Error {
+                code: ERROR_UNSUPPORTED_PROTOCOL_VERSION,
+                message: String::from("error"),
+                topic: Some(String::from("topic")),
+            },
+            ZaichikFrame::Ping,
+            ZaichikFrame::Pong,
+            ZaichikFrame::DeleteTopic {
+                topic: String::from("topic"),
+            },
+            ZaichikFrame::TopicDeleted {
+                topic: String::from("topic"),
+            },
+            ZaichikFrame::ListTopics,
+            ZaichikFrame::TopicList {
+                topics: vec![String::from("first"), String::from("second")],
+            },
+            ZaichikFrame::SetPrefetch { count: 10 },
+            ZaichikFrame::Nack {
+                id: 7,
+                requeue: true,
+            },
+            ZaichikFrame::Authenticate {
+                token: String::from("token"),
+            },
+            ZaichikFrame::Authenticated,
+            ZaichikFrame::PublishBatch {
+                topic: String::from("topic"),
+                messages: vec![(Some(String::from("key")), vec![1, 2]), (None, vec![3])],
+            },
+            ZaichikFrame::SetDeadLetter {
+                topic: String::from("topic"),
+                max_delivery_attempts: 3,
+                dead_letter_topic: Some(String::from("topic.failed")),
+            },
+            ZaichikFrame::TopicStats {
+                topic: String::from("topic"),
+            },
+            ZaichikFrame::TopicStatsResponse {
+                topic: String::from("topic"),
+                retained_count: 3,
+                retained_bytes: 30,
+                compaction_keys: 2,
+                subscriber_count: 1,
+                expired_drops: 4,
+                published_messages: 7,
+                duplicate_drops: 2,
+                kept_messages: 5,
+            },
+            ZaichikFrame::TopicCreated {
+                topic: String::from("topic"),
+                retention_ttl: 1000,
+                compaction_window: 0,
+                retention_max_messages: 10,
+                retention_max_bytes: 0,
+                compaction_mode: CompactionMode::Dedup,
+                buffer_size: 10_000,
+                delivery: DeliveryGuarantee::BestEffort,
+                key_source: KeySource::Explicit,
+                partitions: 1,
+                ordering: OrderingGuarantee::None,
+                max_message_bytes: 0,
+                already_existed: false,
+            },
+            ZaichikFrame::AdminConnections,
+            ZaichikFrame::AdminConnectionsResponse {
+                connections: vec![ConnectionInfo {
+                    peer: "127.0.0.1:4000".parse().unwrap(),
+                    principal: Some(String::from("alice")),
+                    connected_for: time::Duration::from_millis(1500),
+                    topics: vec![String::from("topic")],
+                    in_flight: 2,
+                    awaiting_redelivery: 1,
+                }],
+            },
+            ZaichikFrame::PublishAck {
+                accepted: false,
+                reason: Some(String::from("reason")),
+                dropped_as_duplicate: false,
+            },
+            ZaichikFrame::Subscribed {
+                topic: String::from("topic"),
+            },
+            ZaichikFrame::EndOfStream {
+                topic: String::from("topic"),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_all_frames_round_trip_in_every_format() {
+        for format in &[SerializationFormat::Bincode, SerializationFormat::Json] {
+            for frame in all_frames() {
+                let mut buffer = bytes::BytesMut::new();
+                ZaichikCodec::new(*format)
+                    .encode(frame.clone(), &mut buffer)
+                    .unwrap();
+
+                let decoded = ZaichikCodec::new(*format)
+                    .decode(&mut buffer)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(frame, decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn test_json_frames_are_utf8() {
+        for frame in all_frames() {
+            let mut buffer = bytes::BytesMut::new();
+            ZaichikCodec::new(SerializationFormat::Json)
+                .encode(frame, &mut buffer)
+                .unwrap();
+
+            let json = std::str::from_utf8(&buffer[LENGTH_PREFIX_SIZE..]).unwrap();
+            assert!(serde_json::from_str::<serde_json::Value>(json).is_ok());
+        }
+    }
 }
@@ -0,0 +1,237 @@
+use futures::SinkExt;
+use std::error::Error;
+use std::io;
+use std::time;
+
+use crate::{protocol, Backoff, Client, ClientBuilder, SubscribeOptions};
+
+// Как ReconnectingClient переподключается к брокеру. Между попытками ждем backoff,
+// который после каждой неудачной попытки удваивается, но не больше max_backoff.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    initial_backoff: time::Duration,
+    max_backoff: time::Duration,
+    // None - пытаемся переподключиться бесконечно.
+    max_retries: Option<u32>,
+}
+
+impl ReconnectPolicy {
+    pub fn new() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: time::Duration::from_millis(100),
+            max_backoff: time::Duration::from_secs(5),
+            max_retries: Some(10),
+        }
+    }
+
+    pub fn initial_backoff(mut self, backoff: time::Duration) -> ReconnectPolicy {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, backoff: time::Duration) -> ReconnectPolicy {
+        self.max_backoff = backoff;
+        self
+    }
+
+    pub fn max_retries(mut self, retries: Option<u32>) -> ReconnectPolicy {
+        self.max_retries = retries;
+        self
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy::new()
+    }
+}
+
+// Клиент, который переживает обрыв соединения. Он запоминает свои подписки и prefetch,
+// а когда брокер закрывает соединение, переподключается и отправляет их заново.
+//
+// Доставка после переподключения - at-least-once, а не exactly-once:
+// - сообщения, которые клиент получил, но не успел подтвердить, брокер считает
+//   недоставленными. Группа отдаст их другому участнику или этому же клиенту после
+//   переподключения, а retained сообщения придут еще раз, если подписка была
+//   с DeliveryStart::Earliest. Поэтому обработка должна быть идемпотентной;
+// - id из Publish действуют только в рамках соединения, поэтому Commit сообщения,
+//   полученного до обрыва, после переподключения не отправляется;
+// - сообщения, опубликованные, пока клиента не было, приходят только из retained
+//   буфера топика. Без retention они теряются;
+// - publish после обрыва повторяется на новом соединении, так что если первый фрейм
+//   все-таки дошел до брокера, сообщение будет опубликовано дважды.
+pub struct ReconnectingClient {
+    server_addr: String,
+    options: ClientBuilder,
+    policy: ReconnectPolicy,
+    client: Client,
+    // Subscribe фреймы в том порядке, в котором клиент подписывался.
+    subscriptions: Vec<protocol::ZaichikFrame>,
+    prefetch: Option<u32>,
+}
+
+impl ReconnectingClient {
+    pub async fn connect(
+        server_addr: &str,
+        options: ClientBuilder,
+        policy: ReconnectPolicy,
+    ) -> Result<ReconnectingClient, Box<dyn Error>> {
+        let client = options.clone().connect(server_addr).await?;
+
+        Ok(ReconnectingClient {
+            server_addr: server_addr.to_string(),
+            options,
+            policy,
+            client,
+            subscriptions: Vec::new(),
+            prefetch: None,
+        })
+    }
+
+    // Текущее соединение для запросов, которые ReconnectingClient не оборачивает.
+    // После переподключения это уже будет другой Client.
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    pub async fn subscribe_on(&mut self, topic: String) -> io::Result<()> {
+        self.subscribe_with(topic, SubscribeOptions::new()).await
+    }
+
+    // Подписка запоминается и повторяется после переподключения. confirm
+    // из options не используется: повторные подписки отправляются без ожидания ответа.
+    pub async fn subscribe_with(
+        &mut self,
+        topic: String,
+        options: SubscribeOptions,
+    ) -> io::Result<()> {
+        let frame = options.confirm(false).into_frame(topic);
+        self.client.stream.send(frame.clone()).await?;
+        self.subscriptions.push(frame);
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&mut self, topic: String) -> io::Result<()> {
+        self.subscriptions.retain(|frame| match frame {
+            protocol::ZaichikFrame::Subscribe {
+                topic: subscribed, ..
+            } => *subscribed != topic,
+            _ => true,
+        });
+
+        self.client.unsubscribe(topic).await
+    }
+
+    pub async fn set_prefetch(&mut self, count: u32) -> io::Result<()> {
+        self.prefetch = Some(count);
+        self.client.set_prefetch(count).await
+    }
+
+    // То же, что Client::read_message, но вместо конца стрима клиент переподключается
+    // и продолжает читать. None возвращается только после close. CloseConnection,
+    // которым брокер предупреждает об остановке, тоже ведет к переподключению.
+    pub async fn read_message(&mut self) -> io::Result<Option<protocol::ZaichikFrame>> {
+        loop {
+            match self.client.read_message().await {
+                Ok(Some(protocol::ZaichikFrame::CloseConnection)) => {}
+                Ok(Some(frame)) => return Ok(Some(frame)),
+                Ok(None) => {}
+                Err(e) if is_connection_lost(&e) => {}
+                Err(e) => return Err(e),
+            }
+
+            warn!("Connection to {} is lost, reconnecting", self.server_addr);
+            self.reconnect().await?;
+        }
+    }
+
+    // Подтверждение не повторяется после обрыва, см. описание ReconnectingClient.
+    pub async fn commit(&mut self, id: u64) -> io::Result<()> {
+        self.client.commit(id).await
+    }
+
+    pub async fn publish(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+    ) -> io::Result<()> {
+        match self
+            .client
+            .publish(topic.clone(), key.clone(), payload.clone())
+            .await
+        {
+            Err(e) if is_connection_lost(&e) => {
+                self.reconnect().await?;
+                self.client.publish(topic, key, payload).await
+            }
+            result => result,
+        }
+    }
+
+    pub async fn close(self) -> io::Result<()> {
+        self.client.shutdown().await
+    }
+
+    // Подключаемся заново с backoff и восстанавливаем prefetch и подписки.
+    async fn reconnect(&mut self) -> io::Result<()> {
+        let mut backoff = Backoff::new()
+            .base(self.policy.initial_backoff)
+            .max_delay(self.policy.max_backoff);
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let delay = backoff.next().unwrap();
+            tokio::time::delay_for(delay).await;
+
+            match self.options.clone().connect(&self.server_addr).await {
+                Ok(client) => {
+                    self.client = client;
+                    break;
+                }
+                Err(e) => {
+                    if matches!(self.policy.max_retries, Some(max) if attempt >= max) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotConnected,
+                            format!(
+                                "Failed to reconnect to {} after {} attempts: {}",
+                                self.server_addr, attempt, e
+                            ),
+                        ));
+                    }
+
+                    debug!(
+                        "Reconnect to {} failed, attempt {}; error = {}",
+                        self.server_addr, attempt, e
+                    );
+                }
+            }
+        }
+
+        info!(
+            "Reconnected to {} after {} attempts",
+            self.server_addr, attempt
+        );
+
+        if let Some(count) = self.prefetch {
+            self.client.set_prefetch(count).await?;
+        }
+        for frame in &self.subscriptions {
+            self.client.stream.send(frame.clone()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+// Ошибки, после которых соединение уже не восстановить и нужно подключаться заново.
+fn is_connection_lost(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
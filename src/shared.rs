@@ -0,0 +1,120 @@
+use futures::SinkExt;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::stream::{Stream, StreamExt};
+use tokio::sync::Mutex;
+
+use crate::{protocol, Client, ClientWriter, Compression, SubscribeOptions};
+
+type FrameStream = Pin<Box<dyn Stream<Item = io::Result<protocol::ZaichikFrame>> + Send>>;
+
+// Клиент, которым можно пользоваться из нескольких задач одновременно, например
+// одна задача публикует, а другая читает сообщения того же соединения. Соединение
+// разделено на половины для отправки и для чтения, у каждой свой замок, поэтому
+// publish не ждет, пока read_message в другой задаче дождется сообщения.
+// Клоны SharedClient работают с тем же соединением.
+//
+// Запросов с ответом (list_topics, create_topic и другие) здесь нет: их ответ
+// мог бы забрать read_message в другой задаче. Keepalive и read_timeout клиента
+// тоже не действуют.
+#[derive(Clone)]
+pub struct SharedClient {
+    writer: Arc<Mutex<ClientWriter>>,
+    reader: Arc<Mutex<FrameStream>>,
+    compression: Compression,
+}
+
+impl SharedClient {
+    async fn send(&self, frame: protocol::ZaichikFrame) -> io::Result<()> {
+        self.writer.lock().await.sink.send(frame).await
+    }
+
+    pub async fn publish(
+        &self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+    ) -> io::Result<()> {
+        self.publish_with_headers(topic, key, payload, HashMap::new())
+            .await
+    }
+
+    pub async fn publish_with_headers(
+        &self,
+        topic: String,
+        key: Option<String>,
+        payload: Vec<u8>,
+        mut headers: HashMap<String, String>,
+    ) -> io::Result<()> {
+        let payload = self.compression.compress(payload, &mut headers);
+
+        self.send(protocol::ZaichikFrame::Publish {
+            topic,
+            key,
+            payload,
+            headers,
+            id: 0,
+            deliver_after: None,
+            ttl: None,
+            offset: 0,
+            create_with: None,
+            ack: false,
+        })
+        .await
+    }
+
+    pub async fn subscribe_on(&self, topic: String) -> io::Result<()> {
+        self.subscribe_with(topic, SubscribeOptions::new()).await
+    }
+
+    // Subscribed брокера придет в read_message, как и остальные фреймы: ждать его
+    // здесь нельзя, поэтому confirm из options не используется.
+    pub async fn subscribe_with(&self, topic: String, options: SubscribeOptions) -> io::Result<()> {
+        self.send(options.confirm(false).into_frame(topic)).await
+    }
+
+    pub async fn unsubscribe(&self, topic: String) -> io::Result<()> {
+        self.send(protocol::ZaichikFrame::Unsubscribe { topic })
+            .await
+    }
+
+    pub async fn set_prefetch(&self, count: u32) -> io::Result<()> {
+        self.send(protocol::ZaichikFrame::SetPrefetch { count })
+            .await
+    }
+
+    pub async fn commit(&self, id: u64) -> io::Result<()> {
+        self.send(protocol::ZaichikFrame::Commit { id }).await
+    }
+
+    pub async fn nack(&self, id: u64, requeue: bool) -> io::Result<()> {
+        self.send(protocol::ZaichikFrame::Nack { id, requeue })
+            .await
+    }
+
+    // Как Client::read_message. Если читают несколько задач, то каждый фрейм
+    // получит только одна из них.
+    pub async fn read_message(&self) -> io::Result<Option<protocol::ZaichikFrame>> {
+        self.reader.lock().await.next().await.transpose()
+    }
+
+    pub async fn close(&self) -> io::Result<()> {
+        self.send(protocol::ZaichikFrame::CloseConnection {}).await
+    }
+}
+
+impl Client {
+    // Клиент, которым можно пользоваться из нескольких задач, см. SharedClient.
+    pub fn into_shared(self) -> SharedClient {
+        let compression = self.compression;
+        let (writer, stream) = self.split();
+
+        SharedClient {
+            writer: Arc::new(Mutex::new(writer)),
+            reader: Arc::new(Mutex::new(Box::pin(stream))),
+            compression,
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time;
+
+use crate::protocol::{CompactionMode, DeliveryGuarantee, KeySource, OrderingGuarantee};
+use crate::topic_controller::Message;
+
+// Хранение retained сообщений на диске. Для каждого топика в директории
+// лежат два файла: {topic}.meta с настройками топика в JSON, чтобы при старте
+// создать его заново, и {topic}.log, куда дописываются retained сообщения.
+// Каждая запись в логе - это длина (4 байта, big-endian) и запись в бинкоде.
+
+// Настройки топика в том виде, в котором они пришли в CreateTopic.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TopicMeta {
+    pub topic: String,
+    pub retention_ttl: u64,
+    pub compaction_window: u64,
+    pub retention_max_messages: u64,
+    pub retention_max_bytes: u64,
+    pub compaction_mode: CompactionMode,
+    // 0 - размер по умолчанию из реестра. В старых .meta этого поля нет.
+    #[serde(default)]
+    pub buffer_size: u32,
+    #[serde(default)]
+    pub delivery: DeliveryGuarantee,
+    #[serde(default)]
+    pub key_source: KeySource,
+    #[serde(default)]
+    pub partitions: u32,
+    #[serde(default)]
+    pub ordering: OrderingGuarantee,
+    #[serde(default)]
+    pub max_message_bytes: u64,
+}
+
+// Instant нельзя сохранить на диск, поэтому время храним в миллисекундах
+// от UNIX_EPOCH и переводим обратно в Instant при загрузке.
+#[derive(Serialize, Deserialize, Debug)]
+struct LogRecord {
+    key: Option<String>,
+    payload: Vec<u8>,
+    headers: HashMap<String, String>,
+    received_at: u64,
+    expires_at: Option<u64>,
+    offset: u64,
+}
+
+#[derive(Debug)]
+pub struct TopicLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl TopicLog {
+    pub fn open(dir: &Path, topic: &str) -> io::Result<TopicLog> {
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(format!("{}.log", file_name(topic)));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(TopicLog { path, file })
+    }
+
+    // Дописываем сообщение в конец лога. fsync на каждое сообщение мы не делаем,
+    // так что при падении машины последние записи могут потеряться.
+    pub fn append(&mut self, message: &Message) -> io::Result<()> {
+        let record = encode_record(message)?;
+        self.file.write_all(&record)
+    }
+
+    // Читаем все сообщения из лога, пропуская те, что уже истекли.
+    // Недописанная запись в конце (например, после падения брокера) отбрасывается.
+    pub fn read_all(&self) -> io::Result<Vec<Message>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let now = time::Instant::now();
+        let mut messages = Vec::new();
+
+        loop {
+            let mut length = [0u8; 4];
+            if reader.read_exact(&mut length).is_err() {
+                break;
+            }
+
+            let mut record = vec![0u8; u32::from_be_bytes(length) as usize];
+            if reader.read_exact(&mut record).is_err() {
+                warn!("Truncated record at the end of {:?}, skipping", self.path);
+                break;
+            }
+
+            let record = bincode::deserialize::<LogRecord>(&record)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let message = Message::new(
+                record.key,
+                record.payload,
+                record.headers,
+                from_unix_millis(record.received_at),
+                record.expires_at.map(from_unix_millis),
+            )
+            .with_offset(record.offset);
+
+            if !message.is_expired_at(now) {
+                messages.push(message);
+            }
+        }
+
+        Ok(messages)
+    }
+
+    // Переписываем лог так, чтобы в нем остались только messages. Пишем во
+    // временный файл и подменяем им лог, чтобы не потерять данные посреди записи.
+    pub fn rewrite(&mut self, messages: &[Message]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("log.tmp");
+
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for message in messages {
+                writer.write_all(&encode_record(message)?)?;
+            }
+            writer.flush()?;
+        }
+
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+
+        Ok(())
+    }
+}
+
+pub fn write_meta(dir: &Path, meta: &TopicMeta) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(format!("{}.meta", file_name(&meta.topic)));
+    let json =
+        serde_json::to_vec(meta).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(path, json)
+}
+
+// Настройки всех топиков, сохраненных в директории.
+pub fn read_metas(dir: &Path) -> io::Result<Vec<TopicMeta>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut metas = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) == Some("meta") {
+            let meta = serde_json::from_slice::<TopicMeta>(&fs::read(&path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            metas.push(meta);
+        }
+    }
+
+    Ok(metas)
+}
+
+// Удаляем с диска все, что относится к топику.
+pub fn remove_topic(dir: &Path, topic: &str) -> io::Result<()> {
+    for extension in &["meta", "log"] {
+        let path = dir.join(format!("{}.{}", file_name(topic), extension));
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Имя топика может содержать любые символы, в том числе '/',
+// поэтому все, кроме букв, цифр, '-' и '_', кодируем как %XX.
+fn file_name(topic: &str) -> String {
+    let mut name = String::new();
+    for byte in topic.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    name
+}
+
+fn encode_record(message: &Message) -> io::Result<Vec<u8>> {
+    let record = LogRecord {
+        key: message.key.clone(),
+        payload: message.payload.to_vec(),
+        headers: message.headers.clone(),
+        received_at: to_unix_millis(message.received_at()),
+        expires_at: message.expires_at.map(to_unix_millis),
+        offset: message.offset(),
+    };
+
+    let encoded =
+        bincode::serialize(&record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut buffer = Vec::with_capacity(4 + encoded.len());
+    buffer.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&encoded);
+    Ok(buffer)
+}
+
+fn to_unix_millis(instant: time::Instant) -> u64 {
+    let now = time::Instant::now();
+    let system_now = time::SystemTime::now();
+
+    let system_time = if instant >= now {
+        system_now + (instant - now)
+    } else {
+        system_now - (now - instant)
+    };
+
+    system_time
+        .duration_since(time::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn from_unix_millis(millis: u64) -> time::Instant {
+    let now = time::Instant::now();
+    let system_time = time::UNIX_EPOCH + time::Duration::from_millis(millis);
+
+    match system_time.duration_since(time::SystemTime::now()) {
+        Ok(ahead) => now + ahead,
+        Err(e) => now.checked_sub(e.duration()).unwrap_or(now),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_name_escapes_separators() {
+        assert_eq!("orders", file_name("orders"));
+        assert_eq!("eu%2Forders%20v2", file_name("eu/orders v2"));
+    }
+}
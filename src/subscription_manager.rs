@@ -1,11 +1,47 @@
+use crate::acl::{Access, AclRules};
+use crate::connection_registry::{ConnectionGuard, ConnectionRegistry};
+use crate::consumer_group::MemberId;
+use crate::events::BrokerEvents;
+use crate::locks::RwLockExt;
+use crate::metrics::METRICS;
 use crate::protocol;
-use crate::topic_controller::Message;
-use crate::topic_registry::TopicRegistry;
-use futures::SinkExt;
+use crate::storage::TopicMeta;
+use crate::topic_controller::{
+    DeadLetterPolicy, Message, PendingDelivery, PublishOutcome, TopicController, TopicSettings,
+};
+use crate::topic_registry::{self, CreateTopicOutcome, TopicHandle, TopicName, TopicRegistry};
+use futures::{future, FutureExt, Sink, SinkExt};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 use std::time;
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::stream::{StreamExt, StreamMap};
+use tokio::io::AsyncWrite;
+use tokio::stream::{self, Stream, StreamExt, StreamMap};
+use tokio::sync::broadcast::{self, RecvError};
+use tokio::sync::{mpsc, Notify, OwnedMutexGuard};
+
+// Dead-letter топик, который брокер создает сам, хранит столько последних сообщений,
+// чтобы их можно было разобрать и после того, как они туда попали.
+const DEAD_LETTER_RETAINED_MESSAGES: u64 = 10_000;
+
+// Заголовки, которые брокер добавляет сообщению при переносе в dead-letter топик.
+const DEAD_LETTER_REASON_HEADER: &str = "zaichik-dead-letter-reason";
+const DELIVERY_ATTEMPTS_HEADER: &str = "zaichik-delivery-attempts";
+const ORIGINAL_TOPIC_HEADER: &str = "zaichik-original-topic";
+
+// Сколько публикаций в Reliable топики подключение может держать в PublishQueue.
+// Следующие отклоняются с ERROR_TOPIC_SATURATED, чтобы брокер не копил их в памяти.
+const PUBLISH_QUEUE_SIZE: usize = 64;
+
+// Сообщение, которое публикуем в топик: ключ, payload, заголовки и ttl.
+type OutgoingMessage = (
+    Option<String>,
+    Vec<u8>,
+    HashMap<String, String>,
+    Option<time::Duration>,
+);
 
 // MessageWrapper оборачивает Frame или сообщение от топика Topic, добавляя к нему
 // дополнительную информацию, например, когда он был получен брокером. Создан
@@ -22,6 +58,33 @@ pub enum MessageWrapper {
         topic_name: String,
         message: Message,
     },
+    // Сообщение для подписки с auto_ack, его не нужно подтверждать.
+    AutoAckMessage {
+        topic_name: String,
+        message: Message,
+    },
+    TopicLagged {
+        topic_name: String,
+        skipped: u64,
+    },
+    // Топик был удален, и его стрим закончился.
+    TopicClosed {
+        topic_name: String,
+    },
+    // Из сокета пришли байты, которые не удалось разобрать во фрейм.
+    FrameError {
+        message: String,
+    },
+    // В реестре появился топик. Нужен для подписок по шаблону.
+    TopicCreated {
+        topic_name: String,
+    },
+    // Соединение с клиентом закрылось.
+    // В отличие от CloseConnection от клиента, подтверждение не отправляем.
+    Disconnected,
+    // Брокер останавливается. Клиент получит CloseConnection, чтобы отличить
+    // остановку брокера от обрыва сети.
+    Shutdown,
 }
 
 impl MessageWrapper {
@@ -32,30 +95,494 @@ impl MessageWrapper {
         }
     }
 
+    pub fn from_frame_error(error: std::io::Error) -> MessageWrapper {
+        MessageWrapper::FrameError {
+            message: error.to_string(),
+        }
+    }
+
     pub fn from_topic_message(topic_name: String, message: Message) -> MessageWrapper {
         MessageWrapper::TopicMessage {
             topic_name,
             message,
         }
     }
+
+    pub fn from_topic_result(
+        topic_name: String,
+        result: Result<Message, RecvError>,
+    ) -> MessageWrapper {
+        match result {
+            Ok(message) => MessageWrapper::from_topic_message(topic_name, message),
+            Err(RecvError::Lagged(skipped)) => MessageWrapper::TopicLagged {
+                topic_name,
+                skipped,
+            },
+            Err(RecvError::Closed) => MessageWrapper::TopicClosed { topic_name },
+        }
+    }
+}
+
+// Сообщение, отправленное клиенту. seq - порядковый номер, под которым
+// оно впервые ушло клиенту, он же id во фрейме Publish. При повторной доставке
+// номер сохраняется, чтобы возвращенные через Nack сообщения уходили в исходном
+// порядке, а клиент мог подтвердить их по тому же id.
+// attempts - сколько раз сообщение уже было отправлено клиенту.
+// group - сообщения одной группы клиент получает по одному, см. OrderGroup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delivery<T> {
+    seq: u64,
+    attempts: u32,
+    item: T,
+    group: Option<OrderGroup>,
+}
+
+// Сообщения, которые клиент получает строго по одному и по порядку: следующее
+// уходит только после Commit предыдущего. Для OrderingGuarantee::Strict это
+// топик целиком (ключ None), для PerKey - топик и ключ сообщения.
+pub type OrderGroup = (String, Option<String>);
+
+// Что стало с сообщением после Nack.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Nacked<T> {
+    // Сообщение будет доставлено еще раз.
+    Requeued,
+    // Клиент попросил не доставлять его повторно.
+    Dropped,
+    // Попытки доставки закончились, сообщение нужно отправить в dead-letter топик.
+    Exhausted { item: T, attempts: u32 },
+}
+
+// Кредиты на доставку сообщений клиенту. Клиент может держать у себя
+// до prefetch неподтвержденных сообщений. Каждое отправленное сообщение
+// занимает кредит, а каждый Commit или Nack его возвращает. По умолчанию prefetch
+// равен 1, то есть следующее сообщение уходит только после Commit.
+// Commit и Nack ссылаются на сообщение по id, поэтому при prefetch больше 1
+// клиент может подтверждать сообщения в любом порядке. Id уникальны в пределах
+// подключения, так что они сразу указывают и на подписку, из которой пришло сообщение.
+#[derive(Debug)]
+pub struct DeliveryCredits<T> {
+    prefetch: u32,
+    next_seq: u64,
+    unacked: VecDeque<Delivery<T>>,
+    redelivery: VecDeque<Delivery<T>>,
+    // Сообщения, которые ждут, пока клиент подтвердит предыдущее сообщение
+    // их группы. Они уже взяты из топика, поэтому тоже занимают prefetch.
+    held: VecDeque<Delivery<T>>,
+}
+
+impl<T> DeliveryCredits<T> {
+    pub fn new() -> DeliveryCredits<T> {
+        DeliveryCredits {
+            prefetch: 1,
+            // 0 занят сообщениями подписок с auto_ack, их Commit ничего не подтверждает.
+            next_seq: 1,
+            unacked: VecDeque::new(),
+            redelivery: VecDeque::new(),
+            held: VecDeque::new(),
+        }
+    }
+
+    // Нулевой prefetch остановил бы доставку навсегда, поэтому минимум 1.
+    pub fn set_prefetch(&mut self, prefetch: u32) {
+        self.prefetch = prefetch.max(1);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.unacked.len()
+    }
+
+    pub fn awaiting_redelivery(&self) -> usize {
+        self.redelivery.len()
+    }
+
+    pub fn can_deliver(&self) -> bool {
+        ((self.unacked.len() + self.held.len()) as u32) < self.prefetch
+    }
+
+    // Новое сообщение из топика получает следующий порядковый номер.
+    pub fn track(&mut self, item: T) -> Delivery<T> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        Delivery {
+            seq,
+            attempts: 0,
+            item,
+            group: None,
+        }
+    }
+
+    // То же, что track, но для сообщения из группы. Если у клиента уже есть
+    // неподтвержденное сообщение этой группы, то новое ждет своей очереди
+    // и возвращается None, его потом отдаст next_released.
+    pub fn track_in_group(&mut self, item: T, group: Option<OrderGroup>) -> Option<Delivery<T>> {
+        let mut delivery = self.track(item);
+        delivery.group = group;
+
+        match &delivery.group {
+            Some(group) if self.is_busy(group) => {
+                self.held.push_back(delivery);
+                None
+            }
+            _ => Some(delivery),
+        }
+    }
+
+    // Сообщение из held, предыдущее сообщение группы которого уже подтверждено.
+    // Prefetch оно не проверяет: место под него заняли, когда оно попало в held.
+    pub fn next_released(&mut self) -> Option<Delivery<T>> {
+        let position = (0..self.held.len()).find(|&index| {
+            let group = self.held[index].group.as_ref();
+            !self
+                .unacked
+                .iter()
+                .chain(self.redelivery.iter())
+                .chain(self.held.iter().take(index))
+                .any(|delivery| delivery.group.as_ref() == group)
+        })?;
+        self.held.remove(position)
+    }
+
+    fn is_busy(&self, group: &OrderGroup) -> bool {
+        self.unacked
+            .iter()
+            .chain(self.redelivery.iter())
+            .chain(self.held.iter())
+            .any(|delivery| delivery.group.as_ref() == Some(group))
+    }
+
+    // Сообщение, возвращенное через Nack, которое можно отправить прямо сейчас.
+    pub fn next_redelivery(&mut self) -> Option<Delivery<T>> {
+        if self.can_deliver() {
+            self.redelivery.pop_front()
+        } else {
+            None
+        }
+    }
+
+    pub fn on_delivered(&mut self, mut delivery: Delivery<T>) {
+        delivery.attempts += 1;
+        self.unacked.push_back(delivery);
+    }
+
+    // Забираем все неподтвержденные сообщения и сообщения, ожидающие
+    // повторной доставки или своей очереди, в порядке их первой отправки.
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut deliveries = self
+            .unacked
+            .drain(..)
+            .chain(self.redelivery.drain(..))
+            .chain(self.held.drain(..))
+            .collect::<Vec<_>>();
+        deliveries.sort_by_key(|delivery| delivery.seq);
+        deliveries
+            .into_iter()
+            .map(|delivery| delivery.item)
+            .collect()
+    }
+
+    // Кредит возвращается, только если сообщение с таким id ждет подтверждения.
+    pub fn on_commit(&mut self, id: u64) -> Option<T> {
+        self.take_unacked(id).map(|delivery| delivery.item)
+    }
+
+    // Если requeue, то сообщение встает в очередь на повторную доставку
+    // на место, соответствующее его порядковому номеру. Иначе оно просто
+    // считается подтвержденным. max_attempts возвращает лимит доставок
+    // для сообщения, и после него сообщение больше не доставляется.
+    // Возвращает None, если такого id не ждали.
+    pub fn on_nack<F>(&mut self, id: u64, requeue: bool, max_attempts: F) -> Option<Nacked<T>>
+    where
+        F: FnOnce(&T) -> Option<u32>,
+    {
+        let delivery = self.take_unacked(id)?;
+        if !requeue {
+            return Some(Nacked::Dropped);
+        }
+
+        if let Some(max_attempts) = max_attempts(&delivery.item) {
+            if delivery.attempts >= max_attempts {
+                return Some(Nacked::Exhausted {
+                    item: delivery.item,
+                    attempts: delivery.attempts,
+                });
+            }
+        }
+
+        let position = self
+            .redelivery
+            .iter()
+            .position(|pending| pending.seq > delivery.seq)
+            .unwrap_or(self.redelivery.len());
+        self.redelivery.insert(position, delivery);
+        Some(Nacked::Requeued)
+    }
+
+    fn take_unacked(&mut self, id: u64) -> Option<Delivery<T>> {
+        let position = self
+            .unacked
+            .iter()
+            .position(|delivery| delivery.seq == id)?;
+        self.unacked.remove(position)
+    }
+}
+
+// Пишущая половина подключения клиента. Подключение может быть как обычным TCP,
+// так и TLS, поэтому тип сокета скрыт за AsyncWrite.
+pub type ClientConnection =
+    tokio_util::codec::FramedWrite<Box<dyn AsyncWrite + Send + Unpin>, protocol::ZaichikCodec>;
+
+// Кладем фрейм в буфер подключения, не сбрасывая его в сокет. Это SinkExt::feed,
+// которого еще нет в нашей версии futures.
+async fn feed(connection: &mut ClientConnection, frame: protocol::ZaichikFrame) -> io::Result<()> {
+    future::poll_fn(|cx| Pin::new(&mut *connection).poll_ready(cx)).await?;
+    Pin::new(connection).start_send(frame)
+}
+
+// Стрим сообщений одной подписки: либо broadcast всего топика,
+// либо канал участника группы потребителей.
+type TopicStream = Pin<Box<dyn Stream<Item = Result<Message, RecvError>> + Send>>;
+
+// Ключи из key_filter подписки. None - подписчику нужны все сообщения топика.
+type KeyFilter = Option<Arc<HashSet<String>>>;
+
+// Оставляем в стриме только сообщения с ключами из фильтра. Ошибки (Lagged, Closed)
+// пропускаем всегда, они нужны менеджеру. Фильтруем до кредитов, так что
+// отброшенные сообщения не занимают prefetch.
+fn filter_by_key(topic_stream: TopicStream, key_filter: KeyFilter) -> TopicStream {
+    match key_filter {
+        None => topic_stream,
+        Some(keys) => Box::pin(topic_stream.filter(move |result| match result {
+            Ok(message) => matches!(&message.key, Some(key) if keys.contains(key)),
+            Err(_) => true,
+        })),
+    }
+}
+
+// Пропускаем сообщения подписки не чаще max_messages_per_sec. Это token bucket
+// на один токен: следующее сообщение мы берем из стрима только через 1/max_messages_per_sec
+// после предыдущего, поэтому ожидающие сообщения остаются в канале топика. Ограничение
+// действует вместе с prefetch: сообщению нужен и токен, и свободный кредит.
+fn limit_rate(topic_stream: TopicStream, max_messages_per_sec: Option<u32>) -> TopicStream {
+    let interval = match max_messages_per_sec {
+        Some(max_messages_per_sec) if max_messages_per_sec > 0 => {
+            time::Duration::from_secs(1) / max_messages_per_sec
+        }
+        _ => return topic_stream,
+    };
+
+    Box::pin(futures::stream::unfold(
+        (topic_stream, None),
+        move |(mut topic_stream, next_at): (TopicStream, Option<time::Instant>)| async move {
+            if let Some(next_at) = next_at {
+                tokio::time::delay_until(tokio::time::Instant::from_std(next_at)).await;
+            }
+
+            // Ошибки (Lagged, Closed) токен не тратят.
+            let result = topic_stream.next().await?;
+            let next_at = match result {
+                Ok(_) => Some(time::Instant::now() + interval),
+                Err(_) => next_at,
+            };
+            Some((result, (topic_stream, next_at)))
+        },
+    ))
+}
+
+// Переключатель паузы одной подписки, см. Pause и Resume. changed будит стрим
+// подписки, чтобы тот заново проверил paused.
+#[derive(Default)]
+struct PauseSwitch {
+    paused: AtomicBool,
+    changed: Notify,
+}
+
+impl PauseSwitch {
+    fn set(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        self.changed.notify();
+    }
+}
+
+// Пока подписка на паузе, сообщения из ее стрима не берутся и ждут в топике,
+// а сама подписка остается в StreamMap со своей позицией.
+fn pausable(topic_stream: TopicStream, switch: Arc<PauseSwitch>) -> TopicStream {
+    Box::pin(futures::stream::unfold(
+        (topic_stream, switch),
+        |(mut topic_stream, switch)| async move {
+            loop {
+                if switch.paused.load(Ordering::SeqCst) {
+                    switch.changed.notified().await;
+                    continue;
+                }
+
+                // Pause может прийти, пока мы ждем сообщение, и тогда это сообщение
+                // должно остаться в топике до Resume.
+                tokio::select! {
+                    result = topic_stream.next() => {
+                        return result.map(|result| (result, (topic_stream, switch)));
+                    }
+                    _ = switch.changed.notified() => {}
+                }
+            }
+        },
+    ))
 }
 
 // Наш сабскрипшн менеджер будет асинхронным компонентом, который будет читать из броадкаста
 // и писать в клиентский стрим нужные сообщения.
 // Его задача в основном хранить настройки и координировать действия.
+// Публикации подключения в Reliable топики, которым пришлось ждать своей очереди
+// топика (delivery_order) или места в очередях подписчиков. Их по одной и в порядке
+// получения выполняет отдельная задача, а менеджер тем временем читает следующие
+// фреймы клиента, в том числе Commit, которые эти очереди освобождают. Если бы ждал
+// сам менеджер, то подключение, которое и публикует в топик, и читает его, ждало бы
+// само себя, а вместе с ним и все остальные издатели топика.
+struct PublishQueue {
+    sender: mpsc::Sender<PublishJob>,
+    // Сколько публикаций отправлено в очередь и еще не выполнено.
+    queued: Arc<AtomicUsize>,
+}
+
+enum PublishJob {
+    // Сообщения еще нужно записать в топик, когда до нас дойдет его очередь.
+    Publish {
+        topic_controller: TopicHandle,
+        messages: Vec<OutgoingMessage>,
+        received_at: time::Instant,
+    },
+    // Сообщения уже в топике, осталось дождаться места в очередях подписчиков.
+    // Пока доставка не закончится, turn не пускает в топик других издателей.
+    Deliver {
+        turn: OwnedMutexGuard<()>,
+        pending: Vec<PendingDelivery>,
+    },
+}
+
+impl PublishQueue {
+    fn new() -> PublishQueue {
+        let (sender, mut receiver) = mpsc::channel::<PublishJob>(PUBLISH_QUEUE_SIZE);
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        // Задача заканчивается вместе с менеджером, доделав уже принятые публикации.
+        let done = queued.clone();
+        tokio::spawn(async move {
+            while let Some(job) = receiver.recv().await {
+                match job {
+                    PublishJob::Publish {
+                        topic_controller,
+                        messages,
+                        received_at,
+                    } => {
+                        SubscriptionManager::publish_in_order(
+                            &topic_controller,
+                            |topic_controller| {
+                                publish_messages(topic_controller, messages, received_at)
+                            },
+                        )
+                        .await
+                    }
+                    PublishJob::Deliver { turn, pending } => {
+                        for delivery in pending {
+                            delivery.deliver().await;
+                        }
+                        drop(turn);
+                    }
+                }
+                done.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+
+        PublishQueue { sender, queued }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queued.load(Ordering::SeqCst) == 0
+    }
+
+    // false - в очереди нет места, и публикация не принята.
+    fn push(&mut self, job: PublishJob) -> bool {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        if self.sender.try_send(job).is_err() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+}
+
+// Публикуем сообщения под уже взятым локом топика и возвращаем то, что в Reliable
+// топике еще нужно доставить подписчикам с полными очередями.
+fn publish_messages(
+    topic_controller: &mut TopicController,
+    messages: Vec<OutgoingMessage>,
+    received_at: time::Instant,
+) -> Vec<PendingDelivery> {
+    messages
+        .into_iter()
+        .filter_map(|(key, payload, headers, ttl)| {
+            topic_controller.publish_with_ttl(key, payload, headers, received_at, ttl)
+        })
+        .collect()
+}
+
 pub struct SubscriptionManager {
+    // Имя пользователя, под которым клиент прошел аутентификацию.
+    // None, если аутентификация на брокере выключена.
+    principal: Option<String>,
+    acl: Arc<AclRules>,
+    // Хук событий брокера, см. BrokerEvents. None - о событиях никто не узнает.
+    events: Option<Arc<dyn BrokerEvents>>,
+    // Сколько подписок на топики может быть у подключения одновременно, 0 - без лимита.
+    max_subscriptions: usize,
+    // Подписывать ли сообщения этого клиента его адресом и пользователем,
+    // см. add_producer_headers.
+    producer_headers: bool,
     topic_registry: Arc<RwLock<TopicRegistry>>,
     commands_receiver: tokio::sync::mpsc::Receiver<MessageWrapper>,
-    client_connection: tokio_util::codec::FramedWrite<OwnedWriteHalf, protocol::ZaichikCodec>,
-    waiting_for_next_message: bool,
+    client_connection: ClientConnection,
+    credits: DeliveryCredits<(String, Message)>,
+    // Подписки, сделанные в составе группы: топик -> (группа, наш id в группе).
+    group_memberships: HashMap<String, (String, MemberId)>,
+    // Сколько подписок этого клиента уже учтено в METRICS.
+    reported_subscriptions: usize,
+    // Шаблоны из Subscribe, например "logs.*", и их key_filter. На подходящие
+    // под них топики, в том числе созданные позже, мы подписываемся автоматически.
+    patterns: HashMap<String, KeyFilter>,
+    created_topics: broadcast::Receiver<TopicName>,
+    // Все подключения брокера, нужны для ответа на AdminConnections.
+    connections: Arc<ConnectionRegistry>,
+    // Наша запись в connections, удаляется вместе с менеджером.
+    connection: ConnectionGuard,
+    // Подписки, сообщения которых не сбрасываются в сокет после каждого сообщения.
+    // Для остальных подписок действует FlushPolicy::Immediate.
+    flush_policies: HashMap<String, protocol::FlushPolicy>,
+    // Сколько отправленных сообщений еще лежит в буфере соединения.
+    unflushed: u32,
+    // Подписки с drain. Их стрим заканчивается на снимке retained сообщений,
+    // и вместо TopicDeleted клиент получает EndOfStream.
+    draining: HashSet<String>,
+    // Подписки с deliver_expired, им отправляем и истекшие сообщения.
+    deliver_expired: HashSet<String>,
+    // Переключатели паузы всех подписок, см. pausable.
+    pause_switches: HashMap<String, Arc<PauseSwitch>>,
+    // Публикации в Reliable топики, которые ждут места в очередях подписчиков.
+    publish_queue: PublishQueue,
 }
 
 impl SubscriptionManager {
+    #[allow(clippy::too_many_arguments)]
     pub async fn start_loop(
         peer: std::net::SocketAddr,
+        principal: Option<String>,
+        acl: Arc<AclRules>,
+        events: Option<Arc<dyn BrokerEvents>>,
+        max_subscriptions: usize,
+        producer_headers: bool,
         topic_registry: Arc<RwLock<TopicRegistry>>,
+        connections: Arc<ConnectionRegistry>,
         commands_receiver: tokio::sync::mpsc::Receiver<MessageWrapper>,
-        client_connection: tokio_util::codec::FramedWrite<OwnedWriteHalf, protocol::ZaichikCodec>,
+        client_connection: ClientConnection,
     ) {
         debug!(
             "[{}:{}] Starting SubscriptionManager",
@@ -63,37 +590,97 @@ impl SubscriptionManager {
             peer.port()
         );
 
+        let created_topics = topic_registry.read_or_recover().watch_created_topics();
+        let connection = ConnectionRegistry::register(&connections, peer, principal.clone());
+
         let mut manager = SubscriptionManager {
+            principal,
+            acl,
+            events,
+            max_subscriptions,
+            producer_headers,
             topic_registry,
             commands_receiver,
             client_connection,
-            waiting_for_next_message: false,
+            credits: DeliveryCredits::new(),
+            group_memberships: HashMap::new(),
+            reported_subscriptions: 0,
+            patterns: HashMap::new(),
+            created_topics,
+            connections,
+            connection,
+            flush_policies: HashMap::new(),
+            draining: HashSet::new(),
+            deliver_expired: HashSet::new(),
+            pause_switches: HashMap::new(),
+            publish_queue: PublishQueue::new(),
+            unflushed: 0,
         };
 
-        let mut subscriptions = StreamMap::new();
+        let mut subscriptions: StreamMap<String, TopicStream> = StreamMap::new();
+        // Подписки с auto_ack не занимают кредиты, поэтому читаем их отдельно,
+        // даже когда клиент выбрал весь prefetch.
+        let mut auto_ack_subscriptions: StreamMap<String, TopicStream> = StreamMap::new();
+        // Клиент сам попросил закрыть соединение и ждет подтверждения,
+        // или брокер останавливается и должен предупредить клиента.
+        let mut send_close = false;
 
         // Обрабатываем, как команды от управляющего потока, так и то, что нам прилетает из
         // мультиплексированного стрима всех подписок на топики.
         loop {
-            let message = tokio::select! {
-                Some(message) = manager.commands_receiver.recv() => message,
+            manager.report_subscriptions(subscriptions.len() + auto_ack_subscriptions.len());
+            manager.connection.update(
+                subscriptions.keys().chain(auto_ack_subscriptions.keys()),
+                manager.credits.in_flight() as u64,
+                manager.credits.awaiting_redelivery() as u64,
+            );
 
-                Some((topic_name, Ok(message))) = subscriptions.next(),
-                   if manager.waiting_for_next_message =>
-                     MessageWrapper::from_topic_message(topic_name, message),
+            // Сначала повторно доставляем сообщения, которые клиент вернул через Nack,
+            // потом те, что ждали подтверждения предыдущего сообщения своей группы.
+            if let Some(delivery) = manager.credits.next_redelivery() {
+                manager.deliver(peer, delivery).await;
+                continue;
+            }
+            if let Some(delivery) = manager.credits.next_released() {
+                manager.deliver(peer, delivery).await;
+                continue;
+            }
 
-                else => break,
+            // Пока в буфере соединения есть несброшенные сообщения, сначала проверяем,
+            // готово ли что-то прямо сейчас. Если нет, то менеджер простаивает,
+            // и буфер пора сбросить в сокет.
+            let next = if manager.unflushed == 0 {
+                manager
+                    .next_message(&mut subscriptions, &mut auto_ack_subscriptions)
+                    .await
+            } else {
+                match manager
+                    .next_message(&mut subscriptions, &mut auto_ack_subscriptions)
+                    .now_or_never()
+                {
+                    Some(next) => next,
+                    None => {
+                        manager.flush_pending(peer).await;
+                        manager
+                            .next_message(&mut subscriptions, &mut auto_ack_subscriptions)
+                            .await
+                    }
+                }
+            };
+            let message = match next {
+                Some(message) => message,
+                None => break,
             };
 
             match message {
                 // Эта ветка обрабатывает команды от клиента.
                 MessageWrapper::Frame { frame, received_at } => {
                     debug!(
-                        "[{}:{}] Received broadcast with frame {:?} || Waiting for message: {} || Subscribed on: {:?}",
+                        "[{}:{}] Received broadcast with frame {:?} || Credits: {:?} || Subscribed on: {:?}",
                         peer.ip(),
                         peer.port(),
                         frame,
-                        manager.waiting_for_next_message,
+                        manager.credits,
                         subscriptions.keys().collect::<Vec<_>>()
                     );
 
@@ -102,75 +689,837 @@ impl SubscriptionManager {
                             topic,
                             retention_ttl,
                             compaction_window,
+                            retention_max_messages,
+                            retention_max_bytes,
+                            compaction_mode,
+                            buffer_size,
+                            delivery,
+                            key_source,
+                            partitions,
+                            ordering,
+                            max_message_bytes,
                         } => {
-                            if !Self::topic_exists(&manager.topic_registry, &topic) {
-                                Self::create_topic(
-                                    &manager.topic_registry,
-                                    &topic,
-                                    retention_ttl,
-                                    compaction_window,
+                            // Создать топик может только тот, кому можно в него писать.
+                            if !manager.check_access(peer, &topic, Access::Write).await {
+                                continue;
+                            }
+                            let meta = TopicMeta {
+                                topic: topic.clone(),
+                                retention_ttl,
+                                compaction_window,
+                                retention_max_messages,
+                                retention_max_bytes,
+                                compaction_mode,
+                                buffer_size: buffer_size.unwrap_or(0),
+                                delivery,
+                                key_source,
+                                partitions,
+                                ordering,
+                                max_message_bytes,
+                            };
+                            // Проверка и создание под одной блокировкой, чтобы топик
+                            // не создал одновременно другой клиент.
+                            let outcome = manager
+                                .topic_registry
+                                .write_or_recover()
+                                .create_topic_if_absent(meta);
+
+                            let frame = match outcome {
+                                CreateTopicOutcome::Created(settings) => {
+                                    Self::topic_created_frame(topic, settings, false)
+                                }
+                                CreateTopicOutcome::AlreadyExists(settings) => {
+                                    Self::topic_created_frame(topic, settings, true)
+                                }
+                                CreateTopicOutcome::Conflict(settings) => {
+                                    let message = format!(
+                                        "Topic {} already exists with different settings: {:?}",
+                                        topic, settings
+                                    );
+                                    manager
+                                        .send_error(
+                                            peer,
+                                            Some(&topic),
+                                            protocol::ERROR_TOPIC_SETTINGS_CONFLICT,
+                                            message,
+                                        )
+                                        .await;
+                                    continue;
+                                }
+                                CreateTopicOutcome::InvalidName(message) => {
+                                    manager
+                                        .send_error(
+                                            peer,
+                                            Some(&topic),
+                                            protocol::ERROR_INVALID_TOPIC_NAME,
+                                            message,
+                                        )
+                                        .await;
+                                    continue;
+                                }
+                                CreateTopicOutcome::InvalidSettings(message) => {
+                                    manager
+                                        .send_error(
+                                            peer,
+                                            Some(&topic),
+                                            protocol::ERROR_INVALID_TOPIC_SETTINGS,
+                                            message,
+                                        )
+                                        .await;
+                                    continue;
+                                }
+                            };
+
+                            if let Err(e) = manager.client_connection.send(frame).await {
+                                info!(
+                                    "[{}:{}] TCP connection error:  {}",
+                                    peer.ip(),
+                                    peer.port(),
+                                    e,
                                 );
                             }
                         }
-                        protocol::ZaichikFrame::Subscribe { topic } => {
-                            // Если это наша первая подписка, то отметим, что
-                            // наш клиент готов получать сообщения.
-                            if subscriptions.is_empty() {
-                                manager.waiting_for_next_message = true;
-                            };
+                        protocol::ZaichikFrame::Subscribe {
+                            topic,
+                            group,
+                            start,
+                            key_filter,
+                            auto_ack,
+                            from_offset,
+                            max_messages_per_sec,
+                            flush_policy,
+                            confirm,
+                            drain,
+                            deliver_expired,
+                            partition,
+                        } => {
+                            if !manager.check_access(peer, &topic, Access::Read).await {
+                                continue;
+                            }
+                            if !manager.check_topic_name(peer, &topic).await {
+                                continue;
+                            }
+
+                            // Без подтверждений группе нечего возвращать при отключении
+                            // участника, а подписки по шаблону всегда подтверждаемые.
+                            let is_pattern = topic_registry::is_topic_pattern(&topic);
+                            if is_pattern
+                                && (from_offset.is_some()
+                                    || max_messages_per_sec.is_some()
+                                    || flush_policy != protocol::FlushPolicy::Immediate
+                                    || deliver_expired)
+                            {
+                                // У каждого топика свои offset, так что один на все
+                                // подходящие под шаблон топики не имеет смысла. Лимит
+                                // скорости, flush_policy и deliver_expired тоже задаются
+                                // для подписки на один топик.
+                                let message = format!(
+                                    "Pattern subscription to {} can not use from_offset, rate limit, flush policy or deliver expired",
+                                    topic
+                                );
+                                manager
+                                    .send_error(
+                                        peer,
+                                        Some(&topic),
+                                        protocol::ERROR_INVALID_SUBSCRIPTION,
+                                        message,
+                                    )
+                                    .await;
+                                continue;
+                            }
+                            if drain && (is_pattern || group.is_some()) {
+                                let message = format!(
+                                    "Drain subscription to {} can not use pattern or group",
+                                    topic
+                                );
+                                manager
+                                    .send_error(
+                                        peer,
+                                        Some(&topic),
+                                        protocol::ERROR_INVALID_SUBSCRIPTION,
+                                        message,
+                                    )
+                                    .await;
+                                continue;
+                            }
+                            if partition.is_some() && (is_pattern || group.is_some()) {
+                                let message = format!(
+                                    "Partition subscription to {} can not use pattern or group",
+                                    topic
+                                );
+                                manager
+                                    .send_error(
+                                        peer,
+                                        Some(&topic),
+                                        protocol::ERROR_INVALID_SUBSCRIPTION,
+                                        message,
+                                    )
+                                    .await;
+                                continue;
+                            }
+                            if auto_ack && (is_pattern || group.is_some()) {
+                                let message = format!(
+                                    "Auto ack subscription to {} can not use pattern or group",
+                                    topic
+                                );
+                                manager
+                                    .send_error(
+                                        peer,
+                                        Some(&topic),
+                                        protocol::ERROR_INVALID_SUBSCRIPTION,
+                                        message,
+                                    )
+                                    .await;
+                                continue;
+                            }
+
+                            // Группа делит между участниками все сообщения одного топика,
+                            // поэтому сузить ее подписку шаблоном или фильтром нельзя.
+                            // Лимит скорости задержал бы сообщения, которые группа уже отдала
+                            // участнику, вместо того чтобы отдать их другим участникам.
+                            if group.is_some()
+                                && (is_pattern
+                                    || key_filter.is_some()
+                                    || max_messages_per_sec.is_some())
+                            {
+                                let message = format!(
+                                    "Group subscription to {} can not use pattern, key filter or rate limit",
+                                    topic
+                                );
+                                manager
+                                    .send_error(
+                                        peer,
+                                        Some(&topic),
+                                        protocol::ERROR_INVALID_SUBSCRIPTION,
+                                        message,
+                                    )
+                                    .await;
+                                continue;
+                            }
+                            if !manager.has_room_for(
+                                &subscriptions,
+                                &auto_ack_subscriptions,
+                                &topic,
+                            ) {
+                                let message = format!(
+                                    "Can not subscribe to {}, connection already has {} subscriptions",
+                                    topic, manager.max_subscriptions
+                                );
+                                manager
+                                    .send_error(
+                                        peer,
+                                        Some(&topic),
+                                        protocol::ERROR_TOO_MANY_SUBSCRIPTIONS,
+                                        message,
+                                    )
+                                    .await;
+                                continue;
+                            }
+                            let key_filter: KeyFilter = key_filter
+                                .map(|keys| Arc::new(keys.into_iter().collect::<HashSet<_>>()));
 
-                            // Если у нас нет такого топика, то заведем его с настройками
-                            // по умолчанию.
-                            if !Self::topic_exists(&manager.topic_registry, &topic) {
-                                Self::create_topic_with_defaults(&manager.topic_registry, &topic);
+                            // Подписка по шаблону - это обычные подписки на все подходящие топики.
+                            if is_pattern {
+                                let matching = manager
+                                    .topic_registry
+                                    .read_or_recover()
+                                    .matching_topics(&topic);
+                                for matched in matching {
+                                    manager.subscribe_to_match(
+                                        peer,
+                                        &mut subscriptions,
+                                        &auto_ack_subscriptions,
+                                        matched,
+                                        start,
+                                        key_filter.clone(),
+                                    );
+                                }
+                                if confirm {
+                                    manager.send_subscribed(peer, topic.clone()).await;
+                                }
+                                manager.patterns.insert(topic, key_filter);
+                                continue;
+                            }
+
+                            // На удаленный топик не подписываемся, пока его не создадут заново,
+                            // иначе клиент не узнает, что старых сообщений больше нет.
+                            // Если топика нет, то заведем его с настройками по умолчанию.
+                            let topic_controller =
+                                match Self::topic_for_subscribe(&manager.topic_registry, &topic) {
+                                    Some(topic_controller) => topic_controller,
+                                    None => {
+                                        let message = format!("Topic {} was deleted", topic);
+                                        manager
+                                            .send_error(
+                                                peer,
+                                                Some(&topic),
+                                                protocol::ERROR_TOPIC_DELETED,
+                                                message,
+                                            )
+                                            .await;
+                                        continue;
+                                    }
+                                };
+
+                            let partitions = topic_controller.read_or_recover().partitions();
+                            if let Some(partition) = partition.filter(|p| *p >= partitions) {
+                                let message = format!(
+                                    "Topic {} has no partition {}, it has {} partitions",
+                                    topic, partition, partitions
+                                );
+                                manager
+                                    .send_error(
+                                        peer,
+                                        Some(&topic),
+                                        protocol::ERROR_INVALID_SUBSCRIPTION,
+                                        message,
+                                    )
+                                    .await;
+                                continue;
                             }
 
-                            let topic_registry = manager.topic_registry.read().unwrap();
-                            let topic_controller = topic_registry.topics.get(&topic).unwrap();
+                            // Повторная подписка на топик заменяет старую, поэтому
+                            // выходим из группы, если старая подписка была в группе.
+                            let previous = subscriptions.remove(&topic);
+                            manager.leave_group(&topic, previous, Vec::new());
+                            auto_ack_subscriptions.remove(&topic);
+                            manager.draining.remove(&topic);
 
-                            // Добавляем новую подписку на новый топик.
-                            let topic_controller = topic_controller.read().unwrap();
-                            let topic_stream = topic_controller.subscribe();
-                            subscriptions.insert(topic, Box::pin(topic_stream));
+                            // Добавляем новую подписку на новый топик. Стрим топика заканчивается,
+                            // только когда топик удаляют, поэтому в его конец мы добавляем
+                            // Closed, чтобы узнать об удалении и сообщить клиенту.
+                            // Группа не хранит историю топика, поэтому для нее start не важен.
+                            // Подписка с drain читает только снимок retained сообщений,
+                            // сделанный сейчас, а не очередь топика. Все, что опубликуют
+                            // после этого, до нее не дойдет. Closed в конце снимка
+                            // означает EndOfStream, см. draining.
+                            let topic_stream: TopicStream = match group {
+                                None if drain => {
+                                    let retained = topic_controller
+                                        .read_or_recover()
+                                        .retained_after(start, from_offset, partition);
+                                    manager.draining.insert(topic.clone());
+
+                                    filter_by_key(
+                                        Box::pin(
+                                            stream::iter(retained.into_iter().map(Ok))
+                                                .chain(stream::once(Err(RecvError::Closed))),
+                                        ),
+                                        key_filter,
+                                    )
+                                }
+                                Some(group) => {
+                                    let (member_id, receiver) =
+                                        topic_controller.write_or_recover().join_group(&group);
+                                    manager
+                                        .group_memberships
+                                        .insert(topic.clone(), (group, member_id));
+
+                                    Box::pin(
+                                        receiver
+                                            .map(Ok)
+                                            .chain(stream::once(Err(RecvError::Closed))),
+                                    )
+                                }
+                                None => filter_by_key(
+                                    Box::pin(
+                                        topic_controller
+                                            .read_or_recover()
+                                            .subscribe_partition(start, from_offset, partition)
+                                            .chain(stream::once(Err(RecvError::Closed))),
+                                    ),
+                                    key_filter,
+                                ),
+                            };
+                            let topic_stream = limit_rate(topic_stream, max_messages_per_sec);
+                            let topic_stream = manager.pausable(&topic, topic_stream);
+                            match flush_policy {
+                                protocol::FlushPolicy::Immediate => {
+                                    manager.flush_policies.remove(&topic)
+                                }
+                                flush_policy => {
+                                    manager.flush_policies.insert(topic.clone(), flush_policy)
+                                }
+                            };
+                            if deliver_expired {
+                                manager.deliver_expired.insert(topic.clone());
+                            } else {
+                                manager.deliver_expired.remove(&topic);
+                            }
+                            // Подтверждение уходит до следующего опроса подписок,
+                            // поэтому клиент получит его раньше первого сообщения.
+                            if confirm {
+                                manager.send_subscribed(peer, topic.clone()).await;
+                            }
+                            manager.emit(|events| events.on_subscribed(peer, &topic));
+                            if auto_ack {
+                                auto_ack_subscriptions.insert(topic, topic_stream);
+                            } else {
+                                subscriptions.insert(topic, topic_stream);
+                            }
                         }
                         protocol::ZaichikFrame::Unsubscribe { topic } => {
-                            // Удаляем подписку на топик и ее стрим.
-                            subscriptions.remove(&topic);
+                            // Отписка от шаблона снимает подписки со всех подходящих
+                            // под него топиков, кроме подписок в группах.
+                            if manager.patterns.remove(&topic).is_some() {
+                                let matched = subscriptions
+                                    .keys()
+                                    .filter(|name| topic_registry::topic_matches(&topic, name))
+                                    .filter(|name| !manager.group_memberships.contains_key(*name))
+                                    .cloned()
+                                    .collect::<Vec<_>>();
+                                for name in matched {
+                                    subscriptions.remove(&name);
+                                    manager.pause_switches.remove(&name);
+                                    manager.emit(|events| events.on_unsubscribed(peer, &name));
+                                }
+                                continue;
+                            }
 
-                            // Если мы удалили последнюю подписку, то отметим, что
-                            // клиент больше не готов получать сообщения.
-                            if subscriptions.is_empty() {
-                                manager.waiting_for_next_message = false;
+                            // Удаляем подписку на топик и ее стрим. Сообщения, которые
+                            // мы получили от группы, но не успели отправить, вернутся группе.
+                            let subscription = subscriptions.remove(&topic);
+                            let was_subscribed = subscription.is_some()
+                                || auto_ack_subscriptions.remove(&topic).is_some();
+                            manager.leave_group(&topic, subscription, Vec::new());
+                            manager.flush_policies.remove(&topic);
+                            manager.draining.remove(&topic);
+                            manager.deliver_expired.remove(&topic);
+                            manager.pause_switches.remove(&topic);
+                            if was_subscribed {
+                                manager.emit(|events| events.on_unsubscribed(peer, &topic));
                             }
                         }
+                        protocol::ZaichikFrame::Pause { topic } => {
+                            manager.set_paused(peer, &topic, true);
+                        }
+                        protocol::ZaichikFrame::Resume { topic } => {
+                            manager.set_paused(peer, &topic, false);
+                        }
                         protocol::ZaichikFrame::Publish {
                             topic,
                             key,
                             payload,
+                            mut headers,
+                            deliver_after,
+                            ttl,
+                            create_with,
+                            ack,
+                            ..
+                        } => {
+                            // С ack клиент ждет PublishAck, поэтому об отказе узнает из него.
+                            if let Some((code, message)) = manager.publish_rejection(
+                                &topic,
+                                payload.len(),
+                                deliver_after,
+                                create_with.as_ref(),
+                            ) {
+                                if ack {
+                                    manager
+                                        .send_publish_ack(peer, false, Some(message), false)
+                                        .await;
+                                } else {
+                                    manager.send_error(peer, Some(&topic), code, message).await;
+                                }
+                                continue;
+                            }
+
+                            METRICS.on_received(payload.len());
+                            let payload_len = payload.len();
+                            manager.add_producer_headers(peer, &mut headers);
+
+                            // Если у нас не было такого топика, то добавим его в реестр
+                            // с настройками из create_with или с настройками по умолчанию.
+                            let topic_controller = match create_with {
+                                Some(config) => Self::get_or_create_topic_with(
+                                    &manager.topic_registry,
+                                    &topic,
+                                    config,
+                                ),
+                                None => Self::get_or_create_topic(&manager.topic_registry, &topic),
+                            };
+
+                            let mut dropped_as_duplicate = false;
+                            let mut saturated = false;
+                            match deliver_after {
+                                Some(delay) if delay > time::Duration::from_secs(0) => {
+                                    let deliver_at = received_at + delay;
+                                    topic_controller
+                                        .write_or_recover()
+                                        .schedule(key, payload, headers, deliver_at, ttl);
+                                    Self::release_delayed_at(&topic_controller, deliver_at);
+                                }
+                                // Издатель с ack не ждет медленных подписчиков Reliable
+                                // топика, а сразу узнает из PublishAck, что топик заполнен.
+                                _ if ack => match manager.try_publish(
+                                    &topic_controller,
+                                    (key, payload, headers, ttl),
+                                    received_at,
+                                ) {
+                                    PublishOutcome::Published(_) => {}
+                                    PublishOutcome::Duplicate => dropped_as_duplicate = true,
+                                    PublishOutcome::Saturated => saturated = true,
+                                },
+                                _ => {
+                                    saturated = !manager.publish_to_topic(
+                                        &topic_controller,
+                                        vec![(key, payload, headers, ttl)],
+                                        received_at,
+                                    );
+                                }
+                            }
+
+                            if !saturated {
+                                manager
+                                    .emit(|events| events.on_published(peer, &topic, payload_len));
+                            }
+
+                            if ack {
+                                let reason = if saturated {
+                                    Some(format!("Topic {} is saturated", topic))
+                                } else {
+                                    None
+                                };
+                                manager
+                                    .send_publish_ack(
+                                        peer,
+                                        !saturated,
+                                        reason,
+                                        dropped_as_duplicate,
+                                    )
+                                    .await;
+                            } else if saturated {
+                                manager.send_topic_saturated(peer, &topic).await;
+                            }
+                        }
+                        protocol::ZaichikFrame::PublishBatch { topic, messages } => {
+                            if !manager.check_access(peer, &topic, Access::Write).await {
+                                continue;
+                            }
+                            if !manager.check_topic_name(peer, &topic).await {
+                                continue;
+                            }
+                            // Батч публикуется целиком или не публикуется совсем.
+                            let largest = messages
+                                .iter()
+                                .map(|(_key, payload)| payload.len())
+                                .max()
+                                .unwrap_or(0);
+                            let oversized = Self::oversized_message(
+                                &manager.topic_registry.read_or_recover(),
+                                &topic,
+                                largest,
+                                None,
+                            );
+                            if let Some(message) = oversized {
+                                manager
+                                    .send_error(
+                                        peer,
+                                        Some(&topic),
+                                        protocol::ERROR_MESSAGE_TOO_LARGE,
+                                        message,
+                                    )
+                                    .await;
+                                continue;
+                            }
+
+                            for (_key, payload) in &messages {
+                                METRICS.on_received(payload.len());
+                            }
+
+                            let topic_controller =
+                                Self::get_or_create_topic(&manager.topic_registry, &topic);
+
+                            // Весь батч публикуем под одним локом, поэтому сообщения
+                            // идут подряд, а compaction и retention применяются к каждому из них.
+                            let payload_lens: Vec<usize> = messages
+                                .iter()
+                                .map(|(_key, payload)| payload.len())
+                                .collect();
+                            let messages = messages
+                                .into_iter()
+                                .map(|(key, payload)| {
+                                    let mut headers = HashMap::new();
+                                    manager.add_producer_headers(peer, &mut headers);
+                                    (key, payload, headers, None)
+                                })
+                                .collect();
+                            if !manager.publish_to_topic(&topic_controller, messages, received_at) {
+                                manager.send_topic_saturated(peer, &topic).await;
+                                continue;
+                            }
+                            for payload_len in payload_lens {
+                                manager
+                                    .emit(|events| events.on_published(peer, &topic, payload_len));
+                            }
+                        }
+                        protocol::ZaichikFrame::Commit { id } => {
+                            // Клиент справился с сообщением, возвращаем кредит.
+                            if manager.credits.on_commit(id).is_none() {
+                                manager.send_unknown_message_id(peer, id).await;
+                            }
+                        }
+                        protocol::ZaichikFrame::Nack { id, requeue } => {
+                            // Клиент не смог обработать сообщение. Возвращаем кредит
+                            // и, если нужно, ставим сообщение на повторную доставку.
+                            // Если попытки доставки для топика закончились, то сообщение
+                            // уходит в его dead-letter топик.
+                            let topic_registry = &manager.topic_registry;
+                            let nacked =
+                                manager
+                                    .credits
+                                    .on_nack(id, requeue, |(topic_name, _message)| {
+                                        Self::dead_letter_policy(topic_registry, topic_name)
+                                            .map(|policy| policy.max_delivery_attempts)
+                                    });
+
+                            match nacked {
+                                None => manager.send_unknown_message_id(peer, id).await,
+                                Some(Nacked::Exhausted {
+                                    item: (topic_name, message),
+                                    attempts,
+                                }) => {
+                                    manager
+                                        .move_to_dead_letter(topic_name, message, attempts)
+                                        .await
+                                }
+                                Some(Nacked::Requeued) | Some(Nacked::Dropped) => {}
+                            }
+                        }
+                        protocol::ZaichikFrame::SetDeadLetter {
+                            topic,
+                            max_delivery_attempts,
+                            dead_letter_topic,
                         } => {
-                            // Если у нас не было такого топика, то добавим его в реестр,
-                            // с настройками по умолчанию.
-                            if !Self::topic_exists(&manager.topic_registry, &topic) {
-                                Self::create_topic_with_defaults(&manager.topic_registry, &topic)
+                            if !manager.check_access(peer, &topic, Access::Write).await {
+                                continue;
                             }
+                            if !manager.check_topic_name(peer, &topic).await {
+                                continue;
+                            }
+
+                            let policy = if max_delivery_attempts == 0 {
+                                None
+                            } else {
+                                Some(DeadLetterPolicy {
+                                    max_delivery_attempts,
+                                    topic: dead_letter_topic
+                                        .unwrap_or_else(|| format!("{}.dlq", topic)),
+                                })
+                            };
 
-                            let topic_registry = manager.topic_registry.read().unwrap();
-                            let topic_controller = topic_registry.get_topic(&topic).unwrap();
+                            // Dead-letter топик создается позже, при первом исчерпании попыток,
+                            // поэтому его имя и право писать в него проверяем сразу.
+                            if let Some(policy) = &policy {
+                                if !manager.check_topic_name(peer, &policy.topic).await {
+                                    continue;
+                                }
+                                if !manager
+                                    .check_access(peer, &policy.topic, Access::Write)
+                                    .await
+                                {
+                                    continue;
+                                }
+                            }
 
-                            // Так как топик контроллер должен поддерживать консистентность
-                            // записи мы берем уникальный лок на запись.
-                            let mut topic_controller = topic_controller.write().unwrap();
-                            topic_controller.publish(key, payload, received_at);
+                            Self::get_or_create_topic(&manager.topic_registry, &topic)
+                                .write_or_recover()
+                                .set_dead_letter_policy(policy);
                         }
-                        protocol::ZaichikFrame::Commit => {
-                            // Просто помечаем, что наш клиент справился с предыдущим
-                            // сообщением и готов к приему нового.
-                            manager.waiting_for_next_message = true;
+                        protocol::ZaichikFrame::SetPrefetch { count } => {
+                            manager.credits.set_prefetch(count);
                         }
                         protocol::ZaichikFrame::CloseConnection => {
                             // Завершаем SubscriptionManager. Клиент закрыл соединение.
+                            send_close = true;
                             break;
                         }
+                        protocol::ZaichikFrame::DeleteTopic { topic } => {
+                            if !manager.check_access(peer, &topic, Access::Write).await {
+                                continue;
+                            }
+                            // Подписки на топик, в том числе наша собственная, узнают
+                            // об удалении, когда их стримы закончатся.
+                            let deleted = manager
+                                .topic_registry
+                                .write_or_recover()
+                                .delete_topic(&topic);
+                            if !deleted {
+                                let message = format!("Topic {} does not exist", topic);
+                                manager
+                                    .send_error(
+                                        peer,
+                                        Some(&topic),
+                                        protocol::ERROR_TOPIC_NOT_FOUND,
+                                        message,
+                                    )
+                                    .await;
+                            }
+                        }
+                        protocol::ZaichikFrame::ListTopics => {
+                            let topics = manager.topic_registry.read_or_recover().topic_names();
+                            let frame = protocol::ZaichikFrame::TopicList { topics };
+
+                            if let Err(e) = manager.client_connection.send(frame).await {
+                                info!(
+                                    "[{}:{}] TCP connection error:  {}",
+                                    peer.ip(),
+                                    peer.port(),
+                                    e,
+                                );
+                            }
+                        }
+                        protocol::ZaichikFrame::AdminConnections => {
+                            // Адреса, пользователи и подписки всех клиентов видят только админы.
+                            let principal = manager.principal.clone();
+                            if !manager.acl.is_admin(principal.as_deref()) {
+                                let message = format!(
+                                    "{} is not an admin",
+                                    principal.as_deref().unwrap_or("Anonymous client")
+                                );
+                                manager
+                                    .send_error(peer, None, protocol::ERROR_ACCESS_DENIED, message)
+                                    .await;
+                                continue;
+                            }
+
+                            let connections = manager.connections.snapshot();
+                            let frame =
+                                protocol::ZaichikFrame::AdminConnectionsResponse { connections };
+
+                            if let Err(e) = manager.client_connection.send(frame).await {
+                                info!(
+                                    "[{}:{}] TCP connection error:  {}",
+                                    peer.ip(),
+                                    peer.port(),
+                                    e,
+                                );
+                            }
+                        }
+                        protocol::ZaichikFrame::TopicStats { topic } => {
+                            if !manager.check_access(peer, &topic, Access::Read).await {
+                                continue;
+                            }
+
+                            let response = {
+                                let topic_controller =
+                                    manager.topic_registry.read_or_recover().get_topic(&topic);
+                                topic_controller.map(|topic_controller| {
+                                    let topic_controller = topic_controller.read_or_recover();
+                                    protocol::ZaichikFrame::TopicStatsResponse {
+                                        topic: topic.clone(),
+                                        retained_count: topic_controller.retained_len() as u64,
+                                        retained_bytes: topic_controller.retained_bytes() as u64,
+                                        compaction_keys: topic_controller.compaction_keys() as u64,
+                                        subscriber_count: topic_controller.subscriber_count()
+                                            as u64,
+                                        expired_drops: topic_controller.stats().expired_drops(),
+                                        published_messages: topic_controller
+                                            .stats()
+                                            .published_messages(),
+                                        duplicate_drops: topic_controller.stats().duplicate_drops(),
+                                        kept_messages: topic_controller.stats().kept_messages(),
+                                    }
+                                })
+                            };
+
+                            match response {
+                                Some(frame) => {
+                                    if let Err(e) = manager.client_connection.send(frame).await {
+                                        info!(
+                                            "[{}:{}] TCP connection error:  {}",
+                                            peer.ip(),
+                                            peer.port(),
+                                            e,
+                                        );
+                                    }
+                                }
+                                None => {
+                                    let message = format!("Topic {} does not exist", topic);
+                                    manager
+                                        .send_error(
+                                            peer,
+                                            Some(&topic),
+                                            protocol::ERROR_TOPIC_NOT_FOUND,
+                                            message,
+                                        )
+                                        .await;
+                                }
+                            }
+                        }
+                        protocol::ZaichikFrame::Ping => {
+                            // Клиент проверяет, что соединение живо.
+                            if let Err(e) = manager
+                                .client_connection
+                                .send(protocol::ZaichikFrame::Pong)
+                                .await
+                            {
+                                info!(
+                                    "[{}:{}] TCP connection error:  {}",
+                                    peer.ip(),
+                                    peer.port(),
+                                    e,
+                                );
+                            }
+                        }
+                        protocol::ZaichikFrame::Authenticate { .. } => {
+                            // Аутентификация проходит сразу после Handshake. Если брокер ее
+                            // не требует, то принимаем любой токен, чтобы клиент с токеном
+                            // мог подключиться и к такому брокеру.
+                            match manager.principal {
+                                Some(_) => {
+                                    let message = "Already authenticated".to_string();
+                                    manager
+                                        .send_error(
+                                            peer,
+                                            None,
+                                            protocol::ERROR_UNEXPECTED_FRAME,
+                                            message,
+                                        )
+                                        .await;
+                                }
+                                None => {
+                                    if let Err(e) = manager
+                                        .client_connection
+                                        .send(protocol::ZaichikFrame::Authenticated)
+                                        .await
+                                    {
+                                        info!(
+                                            "[{}:{}] TCP connection error:  {}",
+                                            peer.ip(),
+                                            peer.port(),
+                                            e,
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                        protocol::ZaichikFrame::Handshake { .. }
+                        | protocol::ZaichikFrame::Authenticated
+                        | protocol::ZaichikFrame::Error { .. }
+                        | protocol::ZaichikFrame::Pong
+                        | protocol::ZaichikFrame::TopicDeleted { .. }
+                        | protocol::ZaichikFrame::TopicList { .. }
+                        | protocol::ZaichikFrame::TopicStatsResponse { .. }
+                        | protocol::ZaichikFrame::AdminConnectionsResponse { .. }
+                        | protocol::ZaichikFrame::PublishAck { .. }
+                        | protocol::ZaichikFrame::Subscribed { .. }
+                        | protocol::ZaichikFrame::EndOfStream { .. }
+                        | protocol::ZaichikFrame::TopicCreated { .. } => {
+                            // Handshake уже был обработан при подключении, а ошибки, Pong
+                            // и уведомления отправляет только брокер. Пропускаем такие фреймы
+                            // и сообщаем клиенту, что он делает что-то не то.
+                            debug!(
+                                "[{}:{}] Unexpected frame from client, skipping",
+                                peer.ip(),
+                                peer.port()
+                            );
+
+                            let message = "Frame is not expected from client".to_string();
+                            manager
+                                .send_error(peer, None, protocol::ERROR_UNEXPECTED_FRAME, message)
+                                .await;
+                        }
                     };
                 }
                 MessageWrapper::TopicMessage {
@@ -183,47 +1532,167 @@ impl SubscriptionManager {
                         peer.port(),
                     );
 
-                    if !Self::message_is_out_of_date(&message) {
-                        // Для отправки сообщения обратно на клиент мы
-                        // используем фрейм Publish, можно было бы сделать
-                        // разные кодеки для Sink, Stream.
-                        let frame = protocol::ZaichikFrame::Publish {
-                            topic: topic_name,
-                            key: message.key,
-                            payload: message.payload,
-                        };
-
-                        debug!(
-                            "[{}:{}] Sending Frame to client || {:?}",
-                            peer.ip(),
-                            peer.port(),
-                            frame.clone(),
-                        );
+                    let group = manager.order_group(&topic_name, &message);
+                    if let Some(delivery) =
+                        manager.credits.track_in_group((topic_name, message), group)
+                    {
+                        manager.deliver(peer, delivery).await;
+                    }
+                }
+                MessageWrapper::AutoAckMessage {
+                    topic_name,
+                    message,
+                } => {
+                    // Коммит для такого сообщения не ждем, поэтому отправляем его с id = 0.
+                    manager.send_message(peer, &topic_name, &message, 0).await;
+                }
+                MessageWrapper::TopicLagged {
+                    topic_name,
+                    skipped,
+                } => {
+                    // Клиент не успевает читать, и broadcast канал топика перезаписал
+                    // часть сообщений. Подписка при этом не рвется, стрим продолжится
+                    // с самого старого сообщения, которое еще есть в канале.
+                    warn!(
+                        "[{}:{}] Subscription on {} lagged, skipped {} messages",
+                        peer.ip(),
+                        peer.port(),
+                        topic_name,
+                        skipped
+                    );
+                }
+                MessageWrapper::TopicClosed { topic_name }
+                    if manager.draining.remove(&topic_name) =>
+                {
+                    debug!(
+                        "[{}:{}] Subscription on {} drained, removing it",
+                        peer.ip(),
+                        peer.port(),
+                        topic_name
+                    );
 
-                        match manager.client_connection.send(frame).await {
-                            // Отметим, что отправили сообщение, ждем следующего
-                            // коммита от пользователя.
-                            Ok(_) => manager.waiting_for_next_message = false,
-                            Err(e) => info!(
-                                "[{}:{}] TCP connection error:  {}",
-                                peer.ip(),
-                                peer.port(),
-                                e,
-                            ),
-                        }
+                    // Стрим уже закончился, и StreamMap сам убрал его.
+                    manager.flush_policies.remove(&topic_name);
+                    manager.deliver_expired.remove(&topic_name);
 
-                        debug!("[{}:{}] Frame sending handled", peer.ip(), peer.port(),);
-                    } else {
-                        debug!(
-                            "[{}:{}] Frame is out of date, skipping",
+                    let frame = protocol::ZaichikFrame::EndOfStream { topic: topic_name };
+                    if let Err(e) = manager.client_connection.send(frame).await {
+                        info!(
+                            "[{}:{}] TCP connection error:  {}",
                             peer.ip(),
-                            peer.port()
-                        )
+                            peer.port(),
+                            e,
+                        );
+                    }
+                }
+                MessageWrapper::TopicClosed { topic_name } => {
+                    debug!(
+                        "[{}:{}] Topic {} was deleted, removing subscription",
+                        peer.ip(),
+                        peer.port(),
+                        topic_name
+                    );
+
+                    subscriptions.remove(&topic_name);
+                    auto_ack_subscriptions.remove(&topic_name);
+                    manager.group_memberships.remove(&topic_name);
+                    manager.pause_switches.remove(&topic_name);
+
+                    // Топик могли успеть создать заново, пока мы дочитывали старый стрим,
+                    // и тогда событие о его создании мы уже пропустили.
+                    if let Some(key_filter) = manager.pattern_filter(&topic_name) {
+                        manager.subscribe_to_match(
+                            peer,
+                            &mut subscriptions,
+                            &auto_ack_subscriptions,
+                            topic_name.clone(),
+                            protocol::DeliveryStart::Earliest,
+                            key_filter,
+                        );
+                    }
+
+                    let frame = protocol::ZaichikFrame::TopicDeleted { topic: topic_name };
+                    if let Err(e) = manager.client_connection.send(frame).await {
+                        info!(
+                            "[{}:{}] TCP connection error:  {}",
+                            peer.ip(),
+                            peer.port(),
+                            e,
+                        );
+                    }
+                }
+                MessageWrapper::FrameError { message } => {
+                    manager
+                        .send_error(peer, None, protocol::ERROR_MALFORMED_FRAME, message)
+                        .await;
+                }
+                MessageWrapper::Disconnected => break,
+                MessageWrapper::Shutdown => {
+                    debug!(
+                        "[{}:{}] Broker is stopping, notifying client",
+                        peer.ip(),
+                        peer.port()
+                    );
+                    send_close = true;
+                    break;
+                }
+                MessageWrapper::TopicCreated { topic_name } => {
+                    // Новый топик только что создан, поэтому читаем его с самого начала,
+                    // чтобы не потерять то, что в него уже успели опубликовать.
+                    if let Some(key_filter) = manager.pattern_filter(&topic_name) {
+                        manager.subscribe_to_match(
+                            peer,
+                            &mut subscriptions,
+                            &auto_ack_subscriptions,
+                            topic_name,
+                            protocol::DeliveryStart::Earliest,
+                            key_filter,
+                        );
                     }
                 }
             }
         }
 
+        manager.flush_pending(peer).await;
+
+        // Клиент отключился. Все, что он получил от групп, но не подтвердил,
+        // возвращаем группам, чтобы это доставили другим участникам.
+        let unacked = manager.credits.drain();
+        let group_topics = manager
+            .group_memberships
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for topic in group_topics {
+            let returned = unacked
+                .iter()
+                .filter(|(topic_name, _message)| *topic_name == topic)
+                .map(|(_topic_name, message)| message.clone())
+                .collect::<Vec<_>>();
+            let subscription = subscriptions.remove(&topic);
+            manager.leave_group(&topic, subscription, returned);
+        }
+        manager.unsubscribe_all(&mut subscriptions, &mut auto_ack_subscriptions);
+        manager.report_subscriptions(0);
+
+        // Подписки уже сняты, так что клиент, получив CloseConnection, может быть уверен,
+        // что брокер больше ничего ему не отправит.
+        if send_close {
+            if let Err(e) = manager
+                .client_connection
+                .send(protocol::ZaichikFrame::CloseConnection)
+                .await
+            {
+                debug!(
+                    "[{}:{}] Failed to send CloseConnection: {:?}",
+                    peer.ip(),
+                    peer.port(),
+                    e
+                );
+            }
+        }
+
         debug!(
             "[{}:{}] Stopped SubscriptionManager",
             peer.ip(),
@@ -231,32 +1700,1375 @@ impl SubscriptionManager {
         );
     }
 
-    fn topic_exists(registry: &Arc<RwLock<TopicRegistry>>, topic: &str) -> bool {
-        let reader = registry.read().unwrap();
-        reader.topics.contains_key(topic)
+    // Следующее событие для менеджера: команда клиента, сообщение подписки или новый
+    // топик для подписок по шаблону. None, если ждать больше нечего.
+    async fn next_message(
+        &mut self,
+        subscriptions: &mut StreamMap<String, TopicStream>,
+        auto_ack_subscriptions: &mut StreamMap<String, TopicStream>,
+    ) -> Option<MessageWrapper> {
+        tokio::select! {
+            Some(message) = self.commands_receiver.recv() => Some(message),
+
+            Some((topic_name, result)) = subscriptions.next(),
+               if self.credits.can_deliver() =>
+                 Some(MessageWrapper::from_topic_result(topic_name, result)),
+
+            Some((topic_name, result)) = auto_ack_subscriptions.next() =>
+                 match MessageWrapper::from_topic_result(topic_name, result) {
+                     MessageWrapper::TopicMessage { topic_name, message } =>
+                         Some(MessageWrapper::AutoAckMessage { topic_name, message }),
+                     other => Some(other),
+                 },
+
+            Ok(topic_name) = self.created_topics.recv(),
+               if !self.patterns.is_empty() =>
+                 Some(MessageWrapper::TopicCreated { topic_name }),
+
+            else => None,
+        }
     }
 
-    fn create_topic(
+    // Группа, в которой сообщение ждет подтверждения предыдущих, по OrderingGuarantee топика.
+    // Сообщения без ключа в PerKey топике друг друга не ждут.
+    fn order_group(&self, topic_name: &str, message: &Message) -> Option<OrderGroup> {
+        let ordering = self
+            .topic_registry
+            .read_or_recover()
+            .get_topic(topic_name)
+            .map(|topic_controller| topic_controller.read_or_recover().settings().ordering)
+            .unwrap_or_default();
+
+        match ordering {
+            protocol::OrderingGuarantee::Strict => Some((topic_name.to_string(), None)),
+            protocol::OrderingGuarantee::PerKey => message
+                .key
+                .clone()
+                .map(|key| (topic_name.to_string(), Some(key))),
+            protocol::OrderingGuarantee::None => None,
+        }
+    }
+
+    async fn deliver(&mut self, peer: std::net::SocketAddr, delivery: Delivery<(String, Message)>) {
+        let (topic_name, message) = &delivery.item;
+
+        // Отметим, что отправили сообщение, оно занимает
+        // кредит до коммита от пользователя.
+        if self
+            .send_message(peer, topic_name, message, delivery.seq)
+            .await
+        {
+            self.credits.on_delivered(delivery)
+        }
+    }
+
+    // Отправляет сообщение клиенту. Возвращает true, если сообщение ушло в сокет.
+    async fn send_message(
+        &mut self,
+        peer: std::net::SocketAddr,
+        topic_name: &str,
+        message: &Message,
+        id: u64,
+    ) -> bool {
+        if Self::message_is_out_of_date(message) && !self.deliver_expired.contains(topic_name) {
+            // Подписчик не узнает об этом сообщении, поэтому пропуск видно
+            // в логе и метрике топика.
+            info!(
+                "[{}:{}] Message {} of topic {} expired before delivery, skipping",
+                peer.ip(),
+                peer.port(),
+                message.offset(),
+                topic_name
+            );
+            if let Some(topic_controller) =
+                self.topic_registry.read_or_recover().get_topic(topic_name)
+            {
+                topic_controller.read_or_recover().stats().on_expired_drop();
+            }
+            return false;
+        }
+
+        // Для отправки сообщения обратно на клиент мы
+        // используем фрейм Publish, можно было бы сделать
+        // разные кодеки для Sink, Stream.
+        let frame = protocol::ZaichikFrame::Publish {
+            topic: topic_name.to_string(),
+            key: message.key.clone(),
+            payload: message.payload.to_vec(),
+            headers: message.headers.clone(),
+            id,
+            deliver_after: None,
+            ttl: None,
+            offset: message.offset(),
+            create_with: None,
+            ack: false,
+        };
+
+        debug!(
+            "[{}:{}] Sending Frame to client || {:?}",
+            peer.ip(),
+            peer.port(),
+            frame.clone(),
+        );
+
+        // С Immediate сообщение сразу уходит в сокет вместе со всем, что ждало в буфере.
+        // Иначе оно остается в буфере до flush_pending.
+        let flush_policy = self
+            .flush_policies
+            .get(topic_name)
+            .copied()
+            .unwrap_or_default();
+        let result = match flush_policy {
+            protocol::FlushPolicy::Immediate => {
+                let result = self.client_connection.send(frame).await;
+                self.unflushed = 0;
+                result
+            }
+            protocol::FlushPolicy::OnIdle | protocol::FlushPolicy::Batched(_) => {
+                let result = feed(&mut self.client_connection, frame).await;
+                self.unflushed += 1;
+                result
+            }
+        };
+
+        let sent = match result {
+            Ok(_) => {
+                METRICS.on_sent(message.payload.len());
+                if let protocol::FlushPolicy::Batched(batch_size) = flush_policy {
+                    if self.unflushed >= batch_size {
+                        self.flush_pending(peer).await;
+                    }
+                }
+                true
+            }
+            Err(e) => {
+                info!(
+                    "[{}:{}] TCP connection error:  {}",
+                    peer.ip(),
+                    peer.port(),
+                    e,
+                );
+                false
+            }
+        };
+
+        debug!("[{}:{}] Frame sending handled", peer.ip(), peer.port(),);
+        sent
+    }
+
+    // Сбрасываем в сокет сообщения, которые подписки с OnIdle и Batched оставили в буфере.
+    async fn flush_pending(&mut self, peer: std::net::SocketAddr) {
+        if self.unflushed == 0 {
+            return;
+        }
+
+        self.unflushed = 0;
+        if let Err(e) = self.client_connection.flush().await {
+            info!(
+                "[{}:{}] TCP connection error:  {}",
+                peer.ip(),
+                peer.port(),
+                e,
+            );
+        }
+    }
+
+    fn dead_letter_policy(
         registry: &Arc<RwLock<TopicRegistry>>,
         topic: &str,
-        retention_ttl: u64,
-        compaction_window: u64,
+    ) -> Option<DeadLetterPolicy> {
+        let topic_controller = registry.read_or_recover().get_topic(topic)?;
+        let topic_controller = topic_controller.read_or_recover();
+        topic_controller.dead_letter_policy().cloned()
+    }
+
+    // Перекладываем сообщение, которое клиент так и не смог обработать, в dead-letter
+    // топик. Исходные заголовки сохраняются, к ним добавляется причина и число попыток.
+    async fn move_to_dead_letter(&mut self, topic: String, message: Message, attempts: u32) {
+        let policy = match Self::dead_letter_policy(&self.topic_registry, &topic) {
+            Some(policy) => policy,
+            None => return,
+        };
+
+        warn!(
+            "Message from {} was nacked {} times, moving it to {}",
+            topic, attempts, policy.topic
+        );
+
+        let topic_controller = {
+            let existing = self
+                .topic_registry
+                .read_or_recover()
+                .get_topic(&policy.topic);
+            match existing {
+                Some(topic_controller) => topic_controller,
+                None => {
+                    // Пока мы ждали лок на запись, топик мог создать другой клиент.
+                    let mut registry = self.topic_registry.write_or_recover();
+                    match registry.get_topic(&policy.topic) {
+                        Some(topic_controller) => topic_controller,
+                        None => registry.create_topic(
+                            policy.topic.clone(),
+                            0,
+                            0,
+                            DEAD_LETTER_RETAINED_MESSAGES,
+                            0,
+                            protocol::CompactionMode::Dedup,
+                        ),
+                    }
+                }
+            }
+        };
+
+        let mut headers = message.headers;
+        headers.insert(
+            DEAD_LETTER_REASON_HEADER.to_string(),
+            format!("Nacked {} times", attempts),
+        );
+        headers.insert(DELIVERY_ATTEMPTS_HEADER.to_string(), attempts.to_string());
+        headers.insert(ORIGINAL_TOPIC_HEADER.to_string(), topic);
+
+        let published = self.publish_to_topic(
+            &topic_controller,
+            vec![(message.key, message.payload.to_vec(), headers, None)],
+            time::Instant::now(),
+        );
+        if !published {
+            warn!(
+                "Dead-letter topic {} is saturated, dropping message",
+                policy.topic
+            );
+        }
+    }
+
+    // key_filter первого шаблона, под который подходит топик, или None,
+    // если топик не подходит ни под один шаблон.
+    fn pattern_filter(&self, topic: &str) -> Option<KeyFilter> {
+        self.patterns
+            .iter()
+            .find(|(pattern, _key_filter)| topic_registry::topic_matches(pattern, topic))
+            .map(|(_pattern, key_filter)| key_filter.clone())
+    }
+
+    // Подписка на топик, подошедший под шаблон. Если на топик уже есть подписка
+    // (точная или по другому шаблону), то она остается как есть. Топики, которые
+    // пользователю нельзя читать, шаблон молча пропускает.
+    fn subscribe_to_match(
+        &mut self,
+        peer: std::net::SocketAddr,
+        subscriptions: &mut StreamMap<String, TopicStream>,
+        auto_ack_subscriptions: &StreamMap<String, TopicStream>,
+        topic: String,
+        start: protocol::DeliveryStart,
+        key_filter: KeyFilter,
+    ) {
+        if subscriptions.contains_key(&topic)
+            || !self
+                .acl
+                .is_allowed(self.principal.as_deref(), &topic, Access::Read)
+        {
+            return;
+        }
+        // Клиенту, который не ждет ответа на каждый новый топик, об этом скажет только лог.
+        if !self.has_room_for(subscriptions, auto_ack_subscriptions, &topic) {
+            warn!(
+                "[{}:{}] Not subscribing to {} matching a pattern, connection already has {} subscriptions",
+                peer.ip(),
+                peer.port(),
+                topic,
+                self.max_subscriptions
+            );
+            return;
+        }
+
+        let topic_controller = match self.topic_registry.read_or_recover().get_topic(&topic) {
+            Some(topic_controller) => topic_controller,
+            None => return,
+        };
+
+        let topic_stream: TopicStream = Box::pin(
+            topic_controller
+                .read_or_recover()
+                .subscribe(start)
+                .chain(stream::once(Err(RecvError::Closed))),
+        );
+        let topic_stream = self.pausable(&topic, filter_by_key(topic_stream, key_filter));
+        self.emit(|events| events.on_subscribed(peer, &topic));
+        subscriptions.insert(topic, topic_stream);
+    }
+
+    // Вызывает хук событий, если он задан. Без хука это только проверка на None.
+    fn emit<F>(&self, event: F)
+    where
+        F: FnOnce(&dyn BrokerEvents),
+    {
+        if let Some(events) = &self.events {
+            event(events.as_ref());
+        }
+    }
+
+    // Если так настроен брокер, то добавляет к сообщению адрес и пользователя издателя.
+    // Такие же заголовки от самого клиента заменяются или убираются, чтобы издатель
+    // не мог выдать себя за другого.
+    fn add_producer_headers(
+        &self,
+        peer: std::net::SocketAddr,
+        headers: &mut HashMap<String, String>,
+    ) {
+        if !self.producer_headers {
+            return;
+        }
+
+        headers.insert(protocol::PRODUCER_PEER_HEADER.to_string(), peer.to_string());
+        match &self.principal {
+            Some(principal) => headers.insert(
+                protocol::PRODUCER_PRINCIPAL_HEADER.to_string(),
+                principal.clone(),
+            ),
+            None => headers.remove(protocol::PRODUCER_PRINCIPAL_HEADER),
+        };
+    }
+
+    // Можно ли подписаться на topic, не превысив max_subscriptions. Повторная подписка
+    // на тот же топик заменяет старую, так что новую подписку не добавляет.
+    fn has_room_for(
+        &self,
+        subscriptions: &StreamMap<String, TopicStream>,
+        auto_ack_subscriptions: &StreamMap<String, TopicStream>,
+        topic: &str,
+    ) -> bool {
+        self.max_subscriptions == 0
+            || subscriptions.contains_key(topic)
+            || auto_ack_subscriptions.contains_key(topic)
+            || subscriptions.len() + auto_ack_subscriptions.len() < self.max_subscriptions
+    }
+
+    // Новая подписка на топик всегда начинается без паузы.
+    fn pausable(&mut self, topic: &str, topic_stream: TopicStream) -> TopicStream {
+        let switch = Arc::new(PauseSwitch::default());
+        self.pause_switches
+            .insert(topic.to_string(), Arc::clone(&switch));
+        pausable(topic_stream, switch)
+    }
+
+    // Pause и Resume для топика, на который клиент не подписан, как и Unsubscribe,
+    // ничего не делают.
+    fn set_paused(&self, peer: std::net::SocketAddr, topic: &str, paused: bool) {
+        match self.pause_switches.get(topic) {
+            Some(switch) => switch.set(paused),
+            None => debug!(
+                "[{}:{}] No subscription on {} to pause or resume",
+                peer.ip(),
+                peer.port(),
+                topic
+            ),
+        }
+    }
+
+    // Снимает все оставшиеся подписки отключившегося клиента. Стримы удаляем явно,
+    // а не вместе с менеджером, чтобы топики сразу освободили приемники броадкаста
+    // и очереди Reliable подписок и subscriber_count больше не учитывал клиента.
+    fn unsubscribe_all(
+        &mut self,
+        subscriptions: &mut StreamMap<String, TopicStream>,
+        auto_ack_subscriptions: &mut StreamMap<String, TopicStream>,
     ) {
-        let mut writer = registry.write().unwrap();
-        writer.create_topic(topic.to_string(), retention_ttl, compaction_window);
+        let topics = subscriptions
+            .keys()
+            .chain(auto_ack_subscriptions.keys())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for topic in topics {
+            subscriptions.remove(&topic);
+            auto_ack_subscriptions.remove(&topic);
+
+            if let Some(topic_controller) = self.topic_registry.read_or_recover().get_topic(&topic)
+            {
+                topic_controller
+                    .read_or_recover()
+                    .remove_closed_subscribers();
+            }
+        }
+
+        self.pause_switches.clear();
+        self.flush_policies.clear();
+        self.draining.clear();
+        self.deliver_expired.clear();
+    }
+
+    fn report_subscriptions(&mut self, count: usize) {
+        let delta = count as i64 - self.reported_subscriptions as i64;
+        if delta != 0 {
+            METRICS.on_subscriptions_changed(delta);
+            self.reported_subscriptions = count;
+        }
     }
 
-    fn create_topic_with_defaults(registry: &Arc<RwLock<TopicRegistry>>, topic: &str) {
-        let mut writer = registry.write().unwrap();
-        // По умолчанию не будем включать ни ретеншн, ни компакшн.
-        writer.create_topic(topic.to_string(), 0, 0);
+    // Проверяем, что пользователю можно читать или писать в топик.
+    // Если нельзя, то сообщаем об этом клиенту.
+    async fn check_access(
+        &mut self,
+        peer: std::net::SocketAddr,
+        topic: &str,
+        access: Access,
+    ) -> bool {
+        match self.access_denied(topic, access) {
+            None => true,
+            Some(message) => {
+                self.send_error(peer, Some(topic), protocol::ERROR_ACCESS_DENIED, message)
+                    .await;
+                false
+            }
+        }
+    }
+
+    // Почему пользователю нельзя читать или писать в топик. None, если можно.
+    fn access_denied(&self, topic: &str, access: Access) -> Option<String> {
+        let principal = self.principal.as_deref();
+        if self.acl.is_allowed(principal, topic, access) {
+            return None;
+        }
+
+        Some(format!(
+            "{} has no {:?} access to topic {}",
+            principal.unwrap_or("Anonymous client"),
+            access,
+            topic
+        ))
+    }
+
+    // Код ошибки и причина, по которой брокер не примет Publish. None, если примет.
+    fn publish_rejection(
+        &self,
+        topic: &str,
+        payload_len: usize,
+        deliver_after: Option<time::Duration>,
+        create_with: Option<&protocol::TopicConfig>,
+    ) -> Option<(u16, String)> {
+        if let Some(message) = self.access_denied(topic, Access::Write) {
+            return Some((protocol::ERROR_ACCESS_DENIED, message));
+        }
+
+        let registry = self.topic_registry.read_or_recover();
+        if let Err(message) = registry.validate_topic_name(topic) {
+            return Some((protocol::ERROR_INVALID_TOPIC_NAME, message));
+        }
+
+        // create_with важен, только если топика еще нет.
+        if let Some(config) = create_with.filter(|_| registry.get_topic(topic).is_none()) {
+            let checked =
+                registry.validate_topic_settings(config.retention_ttl, config.compaction_window);
+            if let Err(message) = checked {
+                return Some((protocol::ERROR_INVALID_TOPIC_SETTINGS, message));
+            }
+        }
+
+        // Иначе таймер отложенного сообщения упал бы с паникой.
+        if matches!(deliver_after, Some(delay) if delay > protocol::MAX_DELIVER_AFTER) {
+            let message = format!(
+                "deliver_after should be at most {:?}",
+                protocol::MAX_DELIVER_AFTER
+            );
+            return Some((protocol::ERROR_INVALID_PUBLISH, message));
+        }
+
+        if let Some(message) = Self::oversized_message(&registry, topic, payload_len, create_with) {
+            return Some((protocol::ERROR_MESSAGE_TOO_LARGE, message));
+        }
+
+        None
+    }
+
+    // Причина отказа, если payload больше max_message_bytes топика. Для топика,
+    // которого еще нет, лимит берется из create_with, а без него лимита нет.
+    fn oversized_message(
+        registry: &TopicRegistry,
+        topic: &str,
+        payload_len: usize,
+        create_with: Option<&protocol::TopicConfig>,
+    ) -> Option<String> {
+        let max_message_bytes = match registry.get_topic(topic) {
+            Some(topic_controller) => {
+                topic_controller
+                    .read_or_recover()
+                    .settings()
+                    .max_message_bytes
+            }
+            None => create_with
+                .map(|config| config.max_message_bytes as usize)
+                .filter(|limit| *limit > 0),
+        };
+
+        match max_message_bytes {
+            Some(limit) if payload_len > limit => Some(format!(
+                "Message of {} bytes exceeds max message size {} of topic {}",
+                payload_len, limit, topic
+            )),
+            _ => None,
+        }
+    }
+
+    async fn send_publish_ack(
+        &mut self,
+        peer: std::net::SocketAddr,
+        accepted: bool,
+        reason: Option<String>,
+        dropped_as_duplicate: bool,
+    ) {
+        let frame = protocol::ZaichikFrame::PublishAck {
+            accepted,
+            reason,
+            dropped_as_duplicate,
+        };
+
+        if let Err(e) = self.client_connection.send(frame).await {
+            info!(
+                "[{}:{}] TCP connection error:  {}",
+                peer.ip(),
+                peer.port(),
+                e,
+            );
+        }
+    }
+
+    async fn send_subscribed(&mut self, peer: std::net::SocketAddr, topic: String) {
+        let frame = protocol::ZaichikFrame::Subscribed { topic };
+
+        if let Err(e) = self.client_connection.send(frame).await {
+            info!(
+                "[{}:{}] TCP connection error:  {}",
+                peer.ip(),
+                peer.port(),
+                e,
+            );
+        }
+    }
+
+    // Проверяем имя топика, который запрос может создать. Если имя
+    // не подходит, то сообщаем об этом клиенту.
+    async fn check_topic_name(&mut self, peer: std::net::SocketAddr, topic: &str) -> bool {
+        let checked = self
+            .topic_registry
+            .read_or_recover()
+            .validate_topic_name(topic);
+        match checked {
+            Ok(()) => true,
+            Err(message) => {
+                self.send_error(
+                    peer,
+                    Some(topic),
+                    protocol::ERROR_INVALID_TOPIC_NAME,
+                    message,
+                )
+                .await;
+                false
+            }
+        }
+    }
+
+    // Сообщаем клиенту, почему его запрос не был выполнен. Соединение при этом
+    // остается открытым, клиент может продолжать работу.
+    // topic - топик запроса, на который отвечает ошибка. По нему клиент, который ждет
+    // ответа на свой запрос, отличает его ошибку от ошибок других запросов.
+    async fn send_error(
+        &mut self,
+        peer: std::net::SocketAddr,
+        topic: Option<&str>,
+        code: u16,
+        message: String,
+    ) {
+        let frame = protocol::ZaichikFrame::Error {
+            code,
+            message,
+            topic: topic.map(str::to_string),
+        };
+
+        if let Err(e) = self.client_connection.send(frame).await {
+            info!(
+                "[{}:{}] TCP connection error:  {}",
+                peer.ip(),
+                peer.port(),
+                e,
+            );
+        }
+    }
+
+    // Commit или Nack пришел на сообщение, которое уже подтверждено или не отправлялось.
+    // Кредит при этом не возвращается, иначе клиент мог бы получить больше prefetch сообщений.
+    async fn send_unknown_message_id(&mut self, peer: std::net::SocketAddr, id: u64) {
+        let message = format!("Message {} is not awaiting acknowledgement", id);
+        self.send_error(peer, None, protocol::ERROR_UNKNOWN_MESSAGE_ID, message)
+            .await;
+    }
+
+    // Publish без ack, который не поместился в PublishQueue.
+    async fn send_topic_saturated(&mut self, peer: std::net::SocketAddr, topic: &str) {
+        let message = format!("Topic {} is saturated", topic);
+        self.send_error(peer, Some(topic), protocol::ERROR_TOPIC_SATURATED, message)
+            .await;
+    }
+
+    // Выходим из группы, если подписка на топик была сделана в группе.
+    // returned - неподтвержденные клиентом сообщения, к ним мы добавляем те,
+    // что группа уже положила в канал участника, но мы еще не отправили клиенту.
+    fn leave_group(
+        &mut self,
+        topic: &str,
+        subscription: Option<TopicStream>,
+        returned: Vec<Message>,
+    ) {
+        let (group, member_id) = match self.group_memberships.remove(topic) {
+            Some(membership) => membership,
+            None => return,
+        };
+
+        let topic_controller = match self.topic_registry.read_or_recover().get_topic(topic) {
+            Some(topic_controller) => topic_controller,
+            None => return,
+        };
+        let mut topic_controller = topic_controller.write_or_recover();
+
+        // После выхода из группы канал участника закрыт, поэтому
+        // вычитываем из него все, что там осталось, не дожидаясь новых сообщений.
+        topic_controller.leave_group(&group, member_id);
+
+        let mut returned = returned;
+        if let Some(mut subscription) = subscription {
+            while let Some(Some(Ok(message))) = subscription.next().now_or_never() {
+                returned.push(message);
+            }
+        }
+
+        // Если у оставшихся участников нет места, то не ждем их здесь,
+        // а доставляем сообщения по порядку в отдельной задаче.
+        let pending = topic_controller.return_to_group(&group, returned);
+        if !pending.is_empty() {
+            tokio::spawn(async move {
+                for delivery in pending {
+                    delivery.deliver().await;
+                }
+            });
+        }
+    }
+
+    // Настройки в TopicCreated передаются так же, как в CreateTopic: 0 значит "выключено".
+    fn topic_created_frame(
+        topic: String,
+        settings: TopicSettings,
+        already_existed: bool,
+    ) -> protocol::ZaichikFrame {
+        let millis =
+            |duration: Option<time::Duration>| duration.map_or(0, |d| d.as_millis() as u64);
+        let limit = |limit: Option<usize>| limit.map_or(0, |limit| limit as u64);
+
+        protocol::ZaichikFrame::TopicCreated {
+            topic,
+            retention_ttl: millis(settings.retention_ttl),
+            compaction_window: millis(settings.compaction_window),
+            retention_max_messages: limit(settings.retention_max_messages),
+            retention_max_bytes: limit(settings.retention_max_bytes),
+            compaction_mode: settings.compaction_mode,
+            buffer_size: settings.broadcast_capacity,
+            delivery: settings.delivery,
+            key_source: settings.key_source,
+            partitions: settings.partitions,
+            ordering: settings.ordering,
+            max_message_bytes: limit(settings.max_message_bytes),
+            already_existed,
+        }
+    }
+
+    // Публикуем сообщения в топик под одним его локом. Менеджер здесь никогда не ждет:
+    // если Reliable топику нужно дождаться своей очереди или места в очередях
+    // подписчиков, то публикация уходит в PublishQueue. false - очередь заполнена,
+    // и сообщения не опубликованы.
+    fn publish_to_topic(
+        &mut self,
+        topic_controller: &TopicHandle,
+        messages: Vec<OutgoingMessage>,
+        received_at: time::Instant,
+    ) -> bool {
+        let delivery_order = topic_controller.read_or_recover().delivery_order();
+        let delivery_order = match delivery_order {
+            Some(delivery_order) => delivery_order,
+            None => {
+                // BestEffort топик не ждет подписчиков, его publish ничего не возвращает.
+                publish_messages(
+                    &mut topic_controller.write_or_recover(),
+                    messages,
+                    received_at,
+                );
+                return true;
+            }
+        };
+
+        // Пока у подключения есть ждущие публикации, новые встают за ними,
+        // чтобы не обогнать их.
+        if self.publish_queue.is_empty() {
+            if let Ok(turn) = delivery_order.try_lock_owned() {
+                let pending = publish_messages(
+                    &mut topic_controller.write_or_recover(),
+                    messages,
+                    received_at,
+                );
+                if pending.is_empty() {
+                    return true;
+                }
+                // Очередь пуста, так что место для доставки в ней точно есть.
+                return self
+                    .publish_queue
+                    .push(PublishJob::Deliver { turn, pending });
+            }
+        }
+
+        self.publish_queue.push(PublishJob::Publish {
+            topic_controller: topic_controller.clone(),
+            messages,
+            received_at,
+        })
+    }
+
+    // Publish с ack: если сообщение пришлось бы ждать, то вместо этого возвращаем
+    // Saturated. Ждущие публикации этого подключения тоже считаются, иначе сообщение
+    // с ack обогнало бы их.
+    fn try_publish(
+        &mut self,
+        topic_controller: &TopicHandle,
+        (key, payload, headers, ttl): OutgoingMessage,
+        received_at: time::Instant,
+    ) -> PublishOutcome {
+        let delivery_order = topic_controller.read_or_recover().delivery_order();
+        let _turn = match delivery_order {
+            Some(_) if !self.publish_queue.is_empty() => return PublishOutcome::Saturated,
+            Some(delivery_order) => match delivery_order.try_lock_owned() {
+                Ok(turn) => Some(turn),
+                Err(_) => return PublishOutcome::Saturated,
+            },
+            None => None,
+        };
+
+        topic_controller
+            .write_or_recover()
+            .try_publish(key, payload, headers, received_at, ttl)
+    }
+
+    // Вызываем publish под локом топика на запись. В Reliable топике потом ждем,
+    // пока сообщения поместятся в очереди всех подписчиков: медленный подписчик
+    // замедляет издателя, а не теряет сообщения. Пока мы ждем, остальные издатели
+    // этого топика стоят в очереди delivery_order, чтобы не нарушить порядок.
+    // Ждать можно только вне цикла менеджера, поэтому сюда приходят PublishQueue
+    // и таймеры отложенных сообщений.
+    async fn publish_in_order<F>(topic_controller: &TopicHandle, publish: F)
+    where
+        F: FnOnce(&mut TopicController) -> Vec<PendingDelivery>,
+    {
+        // Так как топик контроллер должен поддерживать консистентность
+        // записи мы берем уникальный лок на запись. Лок реестра к этому
+        // моменту уже отпущен, поэтому publish в другие топики не ждет.
+        let delivery_order = topic_controller.read_or_recover().delivery_order();
+        let _turn = match delivery_order {
+            Some(delivery_order) => Some(delivery_order.lock_owned().await),
+            None => None,
+        };
+
+        let pending = publish(&mut topic_controller.write_or_recover());
+
+        for delivery in pending {
+            delivery.deliver().await;
+        }
+    }
+
+    // Отложенное сообщение уже лежит в топике, здесь мы только заводим таймер, который
+    // опубликует его в deliver_at. Таймер не держит топик: если топик удалят раньше,
+    // то сообщение пропадет вместе с ним.
+    fn release_delayed_at(topic_controller: &TopicHandle, deliver_at: time::Instant) {
+        let topic_controller = Arc::downgrade(topic_controller);
+
+        tokio::spawn(async move {
+            tokio::time::delay_until(tokio::time::Instant::from_std(deliver_at)).await;
+
+            if let Some(topic_controller) = topic_controller.upgrade() {
+                Self::publish_in_order(&topic_controller, |topic_controller| {
+                    topic_controller.release_delayed(time::Instant::now())
+                })
+                .await;
+            }
+        });
+    }
+
+    // То же, что get_or_create_topic, но удаленный топик не создается заново, а
+    // возвращается None. Проверка удаления и поиск топика идут под одним локом реестра,
+    // так что DeleteTopic от другого клиента не окажется между ними.
+    fn topic_for_subscribe(
+        registry: &Arc<RwLock<TopicRegistry>>,
+        topic: &str,
+    ) -> Option<TopicHandle> {
+        {
+            let reader = registry.read_or_recover();
+            if reader.is_deleted(topic) {
+                return None;
+            }
+            if let Some(topic_controller) = reader.get_topic(topic) {
+                return Some(topic_controller);
+            }
+        }
+
+        let mut writer = registry.write_or_recover();
+        if writer.is_deleted(topic) {
+            return None;
+        }
+        Some(Self::get_or_create_with_defaults(&mut writer, topic))
+    }
+
+    // Контроллер топика, который создается с настройками по умолчанию, если топика нет.
+    // Лок реестра отпускается до возврата, лок самого топика берет вызывающий.
+    fn get_or_create_topic(registry: &Arc<RwLock<TopicRegistry>>, topic: &str) -> TopicHandle {
+        let existing = registry.read_or_recover().get_topic(topic);
+        if let Some(topic_controller) = existing {
+            return topic_controller;
+        }
+
+        Self::get_or_create_with_defaults(&mut registry.write_or_recover(), topic)
+    }
+
+    // То же, что get_or_create_topic, но новый топик получает настройки config.
+    fn get_or_create_topic_with(
+        registry: &Arc<RwLock<TopicRegistry>>,
+        topic: &str,
+        config: protocol::TopicConfig,
+    ) -> TopicHandle {
+        let existing = registry.read_or_recover().get_topic(topic);
+        if let Some(topic_controller) = existing {
+            return topic_controller;
+        }
+
+        let mut registry = registry.write_or_recover();
+        match registry.get_topic(topic) {
+            Some(topic_controller) => topic_controller,
+            None => registry.create_topic_from_meta(TopicMeta {
+                topic: topic.to_string(),
+                retention_ttl: config.retention_ttl,
+                compaction_window: config.compaction_window,
+                retention_max_messages: config.retention_max_messages,
+                retention_max_bytes: config.retention_max_bytes,
+                compaction_mode: config.compaction_mode,
+                buffer_size: config.buffer_size.unwrap_or(0),
+                delivery: config.delivery,
+                key_source: config.key_source,
+                partitions: config.partitions,
+                ordering: config.ordering,
+                max_message_bytes: config.max_message_bytes,
+            }),
+        }
+    }
+
+    fn get_or_create_with_defaults(registry: &mut TopicRegistry, topic: &str) -> TopicHandle {
+        // Пока мы ждали лок на запись, топик мог создать другой клиент.
+        match registry.get_topic(topic) {
+            Some(topic_controller) => topic_controller,
+            // По умолчанию не будем включать ни ретеншн, ни компакшн.
+            None => registry.create_topic(
+                topic.to_string(),
+                0,
+                0,
+                0,
+                0,
+                protocol::CompactionMode::Dedup,
+            ),
+        }
     }
 
     fn message_is_out_of_date(message: &Message) -> bool {
-        if message.expires_at.is_none() {
-            false
-        } else {
-            time::Instant::now() > message.expires_at.unwrap()
+        message.is_expired_at(time::Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deliver_next<T>(credits: &mut DeliveryCredits<T>, item: T) -> u64 {
+        assert!(credits.can_deliver());
+        let delivery = credits.track(item);
+        let id = delivery.seq;
+        credits.on_delivered(delivery);
+        id
+    }
+
+    fn no_limit<T>(_item: &T) -> Option<u32> {
+        None
+    }
+
+    #[test]
+    fn test_prefetch_of_one_waits_for_commit() {
+        let mut credits = DeliveryCredits::new();
+
+        let id = deliver_next(&mut credits, "first");
+        assert!(!credits.can_deliver());
+
+        assert_eq!(Some("first"), credits.on_commit(id));
+        assert!(credits.can_deliver());
+    }
+
+    #[test]
+    fn test_prefetch_of_ten_allows_ten_in_flight() {
+        let mut credits = DeliveryCredits::new();
+        credits.set_prefetch(10);
+
+        let ids = (0..10)
+            .map(|i| deliver_next(&mut credits, i))
+            .collect::<Vec<_>>();
+        assert!(!credits.can_deliver());
+
+        // Каждый коммит возвращает ровно один кредит.
+        assert_eq!(Some(0), credits.on_commit(ids[0]));
+        deliver_next(&mut credits, 10);
+        assert!(!credits.can_deliver());
+    }
+
+    #[test]
+    fn test_commits_of_unknown_ids_do_not_grant_extra_credits() {
+        let mut credits = DeliveryCredits::new();
+
+        assert_eq!(None, credits.on_commit(0));
+        assert_eq!(None, credits.on_nack(0, true, no_limit));
+
+        let id = deliver_next(&mut credits, "first");
+        assert!(!credits.can_deliver());
+
+        // Повторный коммит того же id тоже ничего не возвращает.
+        assert_eq!(Some("first"), credits.on_commit(id));
+        deliver_next(&mut credits, "second");
+        assert_eq!(None, credits.on_commit(id));
+        assert!(!credits.can_deliver());
+    }
+
+    #[test]
+    fn test_ids_increase_monotonically() {
+        let mut credits = DeliveryCredits::new();
+        credits.set_prefetch(3);
+
+        let ids = (0..3)
+            .map(|i| deliver_next(&mut credits, i))
+            .collect::<Vec<_>>();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_commit_of_auto_ack_id_does_not_ack_tracked_message() {
+        let mut credits = DeliveryCredits::new();
+
+        let id = deliver_next(&mut credits, "first");
+        assert_ne!(0, id);
+        assert_eq!(None, credits.on_commit(0));
+        assert!(!credits.can_deliver());
+        assert_eq!(Some("first"), credits.on_commit(id));
+    }
+
+    #[test]
+    fn test_out_of_order_commits_within_prefetch_window() {
+        let mut credits = DeliveryCredits::new();
+        credits.set_prefetch(3);
+
+        let first = deliver_next(&mut credits, "first");
+        let second = deliver_next(&mut credits, "second");
+        let third = deliver_next(&mut credits, "third");
+        assert!(!credits.can_deliver());
+
+        // Подтверждение последнего сообщения освобождает ровно его кредит,
+        // а более старые сообщения продолжают ждать своего Commit.
+        assert_eq!(Some("third"), credits.on_commit(third));
+        assert!(credits.can_deliver());
+        let fourth = deliver_next(&mut credits, "fourth");
+        assert!(!credits.can_deliver());
+
+        assert_eq!(Some("first"), credits.on_commit(first));
+        assert_eq!(Some("fourth"), credits.on_commit(fourth));
+        assert_eq!(Some("second"), credits.on_commit(second));
+        assert_eq!(Vec::<&str>::new(), credits.drain());
+    }
+
+    #[test]
+    fn test_nack_of_middle_message_keeps_others_unacked() {
+        let mut credits = DeliveryCredits::new();
+        credits.set_prefetch(3);
+
+        let first = deliver_next(&mut credits, "first");
+        let second = deliver_next(&mut credits, "second");
+        let third = deliver_next(&mut credits, "third");
+
+        assert_eq!(
+            Some(Nacked::Requeued),
+            credits.on_nack(second, true, no_limit)
+        );
+
+        // Повторная доставка сохраняет id, под которым сообщение ушло впервые.
+        let redelivery = credits.next_redelivery().unwrap();
+        assert_eq!(second, redelivery.seq);
+        assert_eq!("second", redelivery.item);
+        credits.on_delivered(redelivery);
+
+        assert_eq!(Some("third"), credits.on_commit(third));
+        assert_eq!(Some("second"), credits.on_commit(second));
+        assert_eq!(Some("first"), credits.on_commit(first));
+    }
+
+    #[test]
+    fn test_nack_redelivers_message_exactly_once() {
+        let mut credits = DeliveryCredits::new();
+
+        let id = deliver_next(&mut credits, "first");
+        assert_eq!(Some(Nacked::Requeued), credits.on_nack(id, true, no_limit));
+
+        let redelivery = credits.next_redelivery().unwrap();
+        assert_eq!("first", redelivery.item);
+        credits.on_delivered(redelivery);
+
+        assert_eq!(Some("first"), credits.on_commit(id));
+        assert!(credits.next_redelivery().is_none());
+    }
+
+    #[test]
+    fn test_nack_after_max_attempts_exhausts_message() {
+        let mut credits = DeliveryCredits::new();
+        let max_attempts = |_item: &&str| Some(2);
+
+        let id = deliver_next(&mut credits, "poison");
+        assert_eq!(
+            Some(Nacked::Requeued),
+            credits.on_nack(id, true, max_attempts)
+        );
+
+        let redelivery = credits.next_redelivery().unwrap();
+        credits.on_delivered(redelivery);
+
+        // Вторая доставка была последней, больше сообщение не вернется.
+        assert_eq!(
+            Some(Nacked::Exhausted {
+                item: "poison",
+                attempts: 2,
+            }),
+            credits.on_nack(id, true, max_attempts)
+        );
+        assert!(credits.next_redelivery().is_none());
+        assert!(credits.can_deliver());
+    }
+
+    #[test]
+    fn test_nack_without_requeue_drops_message() {
+        let mut credits = DeliveryCredits::new();
+
+        let id = deliver_next(&mut credits, "first");
+        assert_eq!(Some(Nacked::Dropped), credits.on_nack(id, false, no_limit));
+
+        assert!(credits.can_deliver());
+        assert!(credits.next_redelivery().is_none());
+    }
+
+    #[test]
+    fn test_nacked_messages_are_redelivered_in_order() {
+        let mut credits = DeliveryCredits::new();
+        credits.set_prefetch(3);
+
+        let first = deliver_next(&mut credits, "first");
+        let second = deliver_next(&mut credits, "second");
+        let third = deliver_next(&mut credits, "third");
+
+        credits.on_nack(first, true, no_limit);
+        credits.on_nack(second, true, no_limit);
+        assert_eq!(Some("third"), credits.on_commit(third));
+
+        // Повторно доставленное first снова возвращают, и оно должно
+        // встать перед second, а не после него.
+        let redelivery = credits.next_redelivery().unwrap();
+        credits.on_delivered(redelivery);
+        credits.on_nack(first, true, no_limit);
+
+        let order = std::iter::from_fn(|| credits.redelivery.pop_front())
+            .map(|delivery| delivery.item)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["first", "second"], order);
+    }
+
+    #[test]
+    fn test_drain_returns_unacked_and_redelivery_in_order() {
+        let mut credits = DeliveryCredits::new();
+        credits.set_prefetch(3);
+
+        let first = deliver_next(&mut credits, "first");
+        deliver_next(&mut credits, "second");
+        deliver_next(&mut credits, "third");
+        credits.on_nack(first, true, no_limit);
+
+        assert_eq!(vec!["first", "second", "third"], credits.drain());
+        assert!(credits.can_deliver());
+        assert!(credits.next_redelivery().is_none());
+    }
+
+    fn group(topic: &str, key: Option<&str>) -> Option<OrderGroup> {
+        Some((topic.to_string(), key.map(str::to_string)))
+    }
+
+    #[test]
+    fn test_group_message_waits_for_commit_of_previous_one() {
+        let mut credits = DeliveryCredits::new();
+        credits.set_prefetch(10);
+
+        let first = credits
+            .track_in_group("first", group("topic", None))
+            .unwrap();
+        let first_id = first.seq;
+        credits.on_delivered(first);
+        assert!(credits
+            .track_in_group("second", group("topic", None))
+            .is_none());
+        assert!(credits.next_released().is_none());
+
+        // Сообщения вне группы и из других групп не ждут.
+        assert!(credits.track_in_group("other", None).is_some());
+        assert!(credits
+            .track_in_group("another topic", group("another", None))
+            .is_some());
+
+        assert_eq!(Some("first"), credits.on_commit(first_id));
+        assert_eq!("second", credits.next_released().unwrap().item);
+    }
+
+    #[test]
+    fn test_held_messages_take_prefetch() {
+        let mut credits = DeliveryCredits::new();
+        credits.set_prefetch(2);
+
+        let first = credits
+            .track_in_group("a1", group("topic", Some("a")))
+            .unwrap();
+        credits.on_delivered(first);
+        assert!(credits
+            .track_in_group("a2", group("topic", Some("a")))
+            .is_none());
+
+        // Брокер не берет из топика больше сообщений, чем позволяет prefetch,
+        // даже если они ждут своей очереди, а не отправлены клиенту.
+        assert!(!credits.can_deliver());
+    }
+
+    #[test]
+    fn test_nacked_group_message_is_redelivered_before_held_ones() {
+        let mut credits = DeliveryCredits::new();
+        credits.set_prefetch(3);
+
+        let first = credits
+            .track_in_group("a1", group("topic", Some("a")))
+            .unwrap();
+        let first_id = first.seq;
+        credits.on_delivered(first);
+        assert!(credits
+            .track_in_group("a2", group("topic", Some("a")))
+            .is_none());
+
+        credits.on_nack(first_id, true, no_limit);
+        assert!(credits.next_released().is_none());
+        let redelivery = credits.next_redelivery().unwrap();
+        assert_eq!("a1", redelivery.item);
+        credits.on_delivered(redelivery);
+        assert!(credits.next_released().is_none());
+
+        assert_eq!(Some("a1"), credits.on_commit(first_id));
+        assert_eq!("a2", credits.next_released().unwrap().item);
+    }
+
+    #[test]
+    fn test_drain_returns_held_messages_in_order() {
+        let mut credits = DeliveryCredits::new();
+        credits.set_prefetch(3);
+
+        let first = credits
+            .track_in_group("first", group("topic", None))
+            .unwrap();
+        credits.on_delivered(first);
+        assert!(credits
+            .track_in_group("second", group("topic", None))
+            .is_none());
+        deliver_next(&mut credits, "third");
+
+        assert_eq!(vec!["first", "second", "third"], credits.drain());
+    }
+
+    #[tokio::test]
+    async fn test_lagged_subscription_recovers() {
+        use crate::protocol::{CompactionMode, DeliveryStart};
+        use crate::topic_controller::TopicController;
+
+        // В broadcast канале топика помещается всего два сообщения.
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 2);
+        let mut subscription = Box::pin(
+            topic_controller
+                .subscribe(DeliveryStart::Earliest)
+                .map(|result| MessageWrapper::from_topic_result("test".to_string(), result)),
+        );
+
+        // Медленный подписчик ничего не читает, пока публикуются пять сообщений.
+        for payload in 1..=5 {
+            topic_controller.publish(None, vec![payload], HashMap::new(), time::Instant::now());
+        }
+
+        let payload_of = |wrapper: Option<MessageWrapper>| match wrapper {
+            Some(MessageWrapper::TopicMessage { message, .. }) => message.payload.to_vec(),
+            other => panic!("Expected topic message, got {:?}", other),
+        };
+
+        assert_eq!(
+            Some(MessageWrapper::TopicLagged {
+                topic_name: "test".to_string(),
+                skipped: 3,
+            }),
+            subscription.next().await
+        );
+        assert_eq!(vec![4], payload_of(subscription.next().await));
+        assert_eq!(vec![5], payload_of(subscription.next().await));
+
+        // После отставания подписка продолжает получать новые сообщения.
+        topic_controller.publish(None, vec![6], HashMap::new(), time::Instant::now());
+        assert_eq!(vec![6], payload_of(subscription.next().await));
+    }
+
+    // Сокет, который считает вызовы write: каждый из них был бы отдельным системным вызовом.
+    #[derive(Clone, Default)]
+    struct CountingWriter {
+        written: Arc<std::sync::Mutex<(Vec<u8>, usize)>>,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let mut written = self.written.lock().unwrap();
+            written.0.extend_from_slice(buf);
+            written.1 += 1;
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    // Подписываемся на топик со 100 retained сообщениями и возвращаем,
+    // за сколько вызовов write менеджер отправил их клиенту.
+    async fn writes_to_deliver_retained(flush_policy: protocol::FlushPolicy) -> usize {
+        use crate::protocol::{CompactionMode, DeliveryStart, SerializationFormat, ZaichikCodec};
+        use tokio_util::codec::Decoder;
+
+        let registry = Arc::new(RwLock::new(TopicRegistry::new()));
+        let topic_controller = registry.write().unwrap().create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+        );
+        for payload in 0..100 {
+            topic_controller.write().unwrap().publish(
+                None,
+                vec![payload],
+                HashMap::new(),
+                time::Instant::now(),
+            );
+        }
+
+        let writer = CountingWriter::default();
+        let (mut commands, commands_receiver) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(SubscriptionManager::start_loop(
+            "127.0.0.1:4000".parse().unwrap(),
+            None,
+            Arc::new(AclRules::new()),
+            None,
+            0,
+            false,
+            registry,
+            Arc::new(ConnectionRegistry::new()),
+            commands_receiver,
+            tokio_util::codec::FramedWrite::new(
+                Box::new(writer.clone()),
+                ZaichikCodec::new(SerializationFormat::Bincode),
+            ),
+        ));
+
+        let frames = vec![
+            protocol::ZaichikFrame::SetPrefetch { count: 100 },
+            protocol::ZaichikFrame::Subscribe {
+                topic: "topic".to_string(),
+                group: None,
+                start: DeliveryStart::Earliest,
+                key_filter: None,
+                auto_ack: false,
+                from_offset: None,
+                max_messages_per_sec: None,
+                flush_policy,
+                confirm: false,
+                drain: false,
+                deliver_expired: false,
+                partition: None,
+            },
+        ];
+        for frame in frames {
+            let received_at = time::Instant::now();
+            let command = MessageWrapper::Frame { frame, received_at };
+            commands.send(command).await.unwrap();
         }
+
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        loop {
+            let (bytes, writes) = writer.written.lock().unwrap().clone();
+            let mut buffer = bytes::BytesMut::from(&bytes[..]);
+            let mut codec = ZaichikCodec::new(SerializationFormat::Bincode);
+            let mut delivered = 0;
+            while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                assert!(matches!(frame, protocol::ZaichikFrame::Publish { .. }));
+                delivered += 1;
+            }
+
+            if delivered == 100 {
+                return writes;
+            }
+            assert!(
+                time::Instant::now() < deadline,
+                "Delivered only {}",
+                delivered
+            );
+            tokio::time::delay_for(time::Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_flush_policy_needs_fewer_writes_than_immediate() {
+        // С Immediate каждое сообщение уходит в сокет отдельно, не дожидаясь следующих.
+        let immediate = writes_to_deliver_retained(protocol::FlushPolicy::Immediate).await;
+        assert_eq!(100, immediate);
+
+        let batched = writes_to_deliver_retained(protocol::FlushPolicy::Batched(10)).await;
+        assert!(batched <= 20, "Batched delivery took {} writes", batched);
+
+        let on_idle = writes_to_deliver_retained(protocol::FlushPolicy::OnIdle).await;
+        assert!(
+            on_idle < batched,
+            "Delivery on idle took {} writes",
+            on_idle
+        );
     }
 }
@@ -0,0 +1,107 @@
+// Общее для тестов брокера: TCP порт для него и клиентские концы подключений,
+// которые ведут себя как сломанный сокет.
+use std::sync::Arc;
+use std::time;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// Порт занимаем сами, так что брокер, которому отдали listener, сразу принимает
+// подключения, а адрес не может занять кто-то другой.
+pub async fn bind_listener() -> (tokio::net::TcpListener, String) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    (listener, addr)
+}
+
+// Клиентский конец, который при удалении не закрывается, как TCP соединение,
+// FIN которого потерялся. Брокер может узнать об отключении только из фреймов.
+pub struct NeverClosed(pub std::mem::ManuallyDrop<crate::DuplexStream>);
+
+impl AsyncRead for NeverClosed {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut *self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for NeverClosed {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut *self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut *self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut *self.0).poll_shutdown(cx)
+    }
+}
+
+// Клиентский конец, запись в который можно остановить, как будто сокет перестал
+// принимать данные. Пока запись стоит, poll_write возвращает Pending.
+pub struct Stalled {
+    pub stream: crate::DuplexStream,
+    pub stalled: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl AsyncRead for Stalled {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Stalled {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        if self.stalled.load(std::sync::atomic::Ordering::SeqCst) {
+            return std::task::Poll::Pending;
+        }
+        std::pin::Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+pub async fn read_publish(client: &mut crate::Client) -> (u64, Vec<u8>) {
+    let frame = tokio::time::timeout(time::Duration::from_secs(5), client.read_message())
+        .await
+        .unwrap()
+        .unwrap();
+
+    match frame {
+        Some(crate::ZaichikFrame::Publish { id, payload, .. }) => (id, payload),
+        other => panic!("Expected published message, got {:?}", other),
+    }
+}
@@ -1,29 +1,154 @@
-use std::collections::HashMap;
+use futures::future::Either;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
 use std::ops::Add;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
 use std::time;
 use tokio::stream::{self, StreamExt};
-use tokio::sync::broadcast;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc};
 
+use crate::clock::{Clock, SystemClock};
+use crate::consumer_group::{ConsumerGroup, MemberId};
+use crate::locks::MutexExt;
+use crate::metrics::TopicStats;
+use crate::protocol::{
+    CompactionMode, DeliveryGuarantee, DeliveryStart, KeySource, OrderingGuarantee,
+};
+use crate::storage::TopicLog;
 use crate::topic_registry::TopicName;
 
-// Сообщение в том в виде, в котором оно хранится в топике.
+// Раз во сколько публикаций мы чистим retained буфер и compaction_map
+// от устаревших записей. Делать это на каждый publish слишком дорого,
+// потому что приходится проходить весь буфер.
+const CLEANUP_EVERY_PUBLISHES: usize = 1000;
+
+// Сообщение в том в виде, в котором оно хранится в топике. Его клонируют для
+// каждого подписчика и каждой retained копии, поэтому payload общий для всех копий.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Message {
     pub key: Option<String>,
-    pub payload: Vec<u8>,
+    pub payload: Arc<[u8]>,
+    // Метаданные издателя, например content-type или trace id. Брокер их не читает
+    // и отдает подписчикам без изменений.
+    pub headers: HashMap<String, String>,
     received_at: time::Instant,
     pub expires_at: Option<time::Instant>,
+    // Номер сообщения в топике. Топик выдает их по возрастанию при публикации,
+    // и по нему подписчик может продолжить чтение с места, где остановился.
+    offset: u64,
+    // Партиция топика, в которую попало сообщение, см. TopicSettings::partitions.
+    partition: u32,
+}
+
+impl Message {
+    pub fn new(
+        key: Option<String>,
+        payload: Vec<u8>,
+        headers: HashMap<String, String>,
+        received_at: time::Instant,
+        expires_at: Option<time::Instant>,
+    ) -> Message {
+        Message {
+            key,
+            payload: payload.into(),
+            headers,
+            received_at,
+            expires_at,
+            offset: 0,
+            partition: 0,
+        }
+    }
+
+    pub fn with_offset(mut self, offset: u64) -> Message {
+        self.offset = offset;
+        self
+    }
+
+    pub fn received_at(&self) -> time::Instant {
+        self.received_at
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn with_partition(mut self, partition: u32) -> Message {
+        self.partition = partition;
+        self
+    }
+
+    // Сообщение без expires_at никогда не истекает.
+    pub fn is_expired_at(&self, now: time::Instant) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+}
+
+// Размер broadcast канала топика, если он не задан при создании.
+// Канал хранит последние buffer_size сообщений. Подписчик, который отстал больше
+// чем на buffer_size сообщений, пропускает самые старые из них (RecvError::Lagged)
+// и продолжает читать с самого старого, что еще осталось в канале.
+pub const DEFAULT_BUFFER_SIZE: u32 = 10_000;
+
+// Размер буфера топика выбирается в одном месте, по приоритету:
+// 1. buffer_size из CreateTopic (или из .meta, когда топик восстанавливается с диска);
+// 2. размер по умолчанию, настроенный для брокера (ZAICHIK_TOPIC_BUFFER_SIZE);
+// 3. DEFAULT_BUFFER_SIZE.
+// 0 на любом уровне значит, что размер там не задан, и берется следующий.
+pub fn resolve_buffer_size(requested: u32, broker_default: u32) -> u32 {
+    if requested != 0 {
+        return requested;
+    }
+
+    let buffer_size = if broker_default != 0 {
+        broker_default
+    } else {
+        DEFAULT_BUFFER_SIZE
+    };
+    debug!("buffer_size is not set, using default {}", buffer_size);
+    buffer_size
 }
 
-#[derive(Clone, Copy, Debug)]
+// Сколько ключей по умолчанию помнит Dedup compaction. Если ключей больше, то
+// забываются те, сообщения с которыми ушли подписчикам раньше всех.
+pub const DEFAULT_COMPACTION_MAX_KEYS: usize = 100_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct TopicSettings {
     pub retention_ttl: Option<time::Duration>,
     pub compaction_window: Option<time::Duration>,
-    pub buffer_size: usize,
+    pub retention_max_messages: Option<usize>,
+    pub retention_max_bytes: Option<usize>,
+    pub compaction_mode: CompactionMode,
+    // Размер broadcast канала, то есть сколько сообщений подписчик может отстать
+    // от издателя, прежде чем пропустит старые, а в Reliable топике размер очереди
+    // подписчика. История для новых подписчиков сюда не относится, ее хранят
+    // retention_* настройки, так что канал не нужно раздувать ради истории.
+    // В CreateTopic и .meta это поле называется buffer_size.
+    pub broadcast_capacity: u32,
+    pub delivery: DeliveryGuarantee,
+    pub compaction_max_keys: usize,
+    pub key_source: KeySource,
+    pub partitions: u32,
+    pub ordering: OrderingGuarantee,
+    // Publish с payload больше этого брокер отклоняет, не доходя до топика.
+    // Это лимит топика, он может быть меньше общего лимита фрейма в кодеке.
+    pub max_message_bytes: Option<usize>,
 }
 
 impl TopicSettings {
-    pub fn new(retention_ttl: u64, compaction_window: u64, buffer_size: usize) -> TopicSettings {
+    pub fn new(
+        retention_ttl: u64,
+        compaction_window: u64,
+        retention_max_messages: u64,
+        retention_max_bytes: u64,
+        compaction_mode: CompactionMode,
+        broadcast_capacity: u32,
+    ) -> TopicSettings {
         let retention_ttl = if retention_ttl == 0 {
             None
         } else {
@@ -34,25 +159,257 @@ impl TopicSettings {
         } else {
             Some(time::Duration::from_millis(compaction_window))
         };
-        let buffer_size = if buffer_size == 0 { 1000 } else { buffer_size } as usize;
+        let retention_max_messages = if retention_max_messages == 0 {
+            None
+        } else {
+            Some(retention_max_messages as usize)
+        };
+        let retention_max_bytes = if retention_max_bytes == 0 {
+            None
+        } else {
+            Some(retention_max_bytes as usize)
+        };
+        let broadcast_capacity = resolve_buffer_size(broadcast_capacity, DEFAULT_BUFFER_SIZE);
 
         TopicSettings {
             retention_ttl,
             compaction_window,
-            buffer_size,
+            retention_max_messages,
+            retention_max_bytes,
+            compaction_mode,
+            broadcast_capacity,
+            delivery: DeliveryGuarantee::BestEffort,
+            compaction_max_keys: DEFAULT_COMPACTION_MAX_KEYS,
+            key_source: KeySource::Explicit,
+            partitions: 1,
+            ordering: OrderingGuarantee::None,
+            max_message_bytes: None,
+        }
+    }
+
+    pub fn with_delivery(mut self, delivery: DeliveryGuarantee) -> TopicSettings {
+        self.delivery = delivery;
+        self
+    }
+
+    // Нулевой лимит не дал бы запомнить даже только что отправленный ключ, поэтому минимум 1.
+    pub fn with_compaction_max_keys(mut self, compaction_max_keys: usize) -> TopicSettings {
+        self.compaction_max_keys = compaction_max_keys.max(1);
+        self
+    }
+
+    pub fn with_key_source(mut self, key_source: KeySource) -> TopicSettings {
+        self.key_source = key_source;
+        self
+    }
+
+    pub fn with_ordering(mut self, ordering: OrderingGuarantee) -> TopicSettings {
+        self.ordering = ordering;
+        self
+    }
+
+    // 0 партиций, как и в CreateTopic, значит одну.
+    pub fn with_partitions(mut self, partitions: u32) -> TopicSettings {
+        self.partitions = partitions.max(1);
+        self
+    }
+
+    // 0, как и в CreateTopic, значит без лимита.
+    pub fn with_max_message_bytes(mut self, max_message_bytes: u64) -> TopicSettings {
+        self.max_message_bytes = if max_message_bytes == 0 {
+            None
+        } else {
+            Some(max_message_bytes as usize)
+        };
+        self
+    }
+}
+
+// Ключи Dedup compaction и время, когда сообщение с ключом последний раз ушло
+// подписчикам. В order те же ключи лежат в порядке отправки, чтобы при превышении
+// лимита быстро найти самый старый, не проходя всю хэшмапу.
+#[derive(Debug, Default)]
+struct CompactionMap {
+    last_sent: HashMap<String, (time::Instant, u64)>,
+    order: BTreeMap<u64, String>,
+    next_seq: u64,
+}
+
+impl CompactionMap {
+    fn get(&self, key: &str) -> Option<time::Instant> {
+        self.last_sent.get(key).map(|(sent_at, _seq)| *sent_at)
+    }
+
+    fn insert(&mut self, key: String, sent_at: time::Instant) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        if let Some((_sent_at, previous_seq)) = self.last_sent.insert(key.clone(), (sent_at, seq)) {
+            self.order.remove(&previous_seq);
+        }
+        self.order.insert(seq, key);
+    }
+
+    fn len(&self) -> usize {
+        self.last_sent.len()
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> bool {
+        self.last_sent.is_empty()
+    }
+
+    // Забываем самые давно отправленные ключи, пока их не станет не больше max_keys.
+    fn evict_oldest(&mut self, max_keys: usize) {
+        while self.last_sent.len() > max_keys {
+            let oldest = match self.order.keys().next() {
+                Some(seq) => *seq,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                self.last_sent.remove(&key);
+            }
+        }
+    }
+
+    // Удаляем ключи, которые отправлялись раньше, чем compaction_window назад.
+    fn remove_outdated(&mut self, compaction_window: time::Duration, now: time::Instant) {
+        let outdated = self
+            .last_sent
+            .iter()
+            .filter(|(_key, (sent_at, _seq))| sent_at.add(compaction_window) < now)
+            .map(|(_key, (_sent_at, seq))| *seq)
+            .collect::<Vec<_>>();
+
+        for seq in outdated {
+            if let Some(key) = self.order.remove(&seq) {
+                self.last_sent.remove(&key);
+            }
+        }
+    }
+}
+
+// Очередь подписчика Reliable топика. Подписчик одной партиции получает только ее сообщения.
+#[derive(Debug)]
+struct ReliableSubscriber {
+    partition: Option<u32>,
+    sender: mpsc::Sender<Message>,
+}
+
+// Сообщение Reliable топика, которому не хватило места в очередях части подписчиков
+// или групп. Издатель доставляет его через deliver, когда уже отпустил лок топика.
+#[derive(Debug)]
+pub struct PendingDelivery {
+    message: Message,
+    subscribers: Vec<mpsc::Sender<Message>>,
+    // Отправители участников каждой группы, у которой не нашлось места.
+    groups: Vec<Vec<mpsc::Sender<Message>>>,
+}
+
+impl PendingDelivery {
+    // Ждем, пока в очереди каждого подписчика освободится место.
+    // Ошибка значит, что подписчик уже отключился, и доставлять ему нечего.
+    // Группе достаточно места у любого из ее участников.
+    pub async fn deliver(self) {
+        for mut subscriber in self.subscribers {
+            let _ = subscriber.send(self.message.clone()).await;
+        }
+
+        for mut members in self.groups {
+            let ready = futures::future::poll_fn(|cx| {
+                let mut connected = false;
+                for (index, member) in members.iter_mut().enumerate() {
+                    match member.poll_ready(cx) {
+                        Poll::Ready(Ok(())) => return Poll::Ready(Some(index)),
+                        Poll::Ready(Err(_)) => {}
+                        Poll::Pending => connected = true,
+                    }
+                }
+
+                if connected {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(None)
+                }
+            })
+            .await;
+
+            // poll_ready уже заняло место в очереди этого участника.
+            if let Some(index) = ready {
+                let _ = members[index].try_send(self.message.clone());
+            }
         }
     }
 }
 
+// Чем закончился publish_checked.
+#[derive(Debug)]
+pub enum PublishOutcome {
+    // Сообщение записано в топик. Для Reliable топика здесь может быть сообщение,
+    // которое еще нужно доставить подписчикам с полными очередями.
+    Published(Option<PendingDelivery>),
+    // Compaction отбросил сообщение как дубль ключа.
+    Duplicate,
+    // try_publish не стал записывать сообщение, потому что очередь одного из
+    // подписчиков Reliable топика заполнена.
+    Saturated,
+}
+
+// Сообщение, опубликованное с deliver_after, которое еще не видно подписчикам.
+#[derive(Debug)]
+struct DelayedMessage {
+    key: Option<String>,
+    payload: Vec<u8>,
+    headers: HashMap<String, String>,
+    ttl: Option<time::Duration>,
+}
+
+// Куда уходят сообщения топика, которые подписчик вернул через Nack
+// max_delivery_attempts раз.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadLetterPolicy {
+    pub max_delivery_attempts: u32,
+    pub topic: TopicName,
+}
+
 // Компонент управляющий топиком.
 // Осуществляет запись в топик, контроль retention и compaction, выдает подписки.
 #[derive(Debug)]
 pub struct TopicController {
     name: TopicName,
-    broadcast_sender: broadcast::Sender<Message>,
+    // Броадкаст каналы партиций, по одному на каждую. Отставший подписчик одной
+    // партиции не заставляет пропускать сообщения подписчиков других.
+    broadcast_senders: Vec<broadcast::Sender<Message>>,
     settings: TopicSettings,
-    compaction_map: HashMap<String, time::Instant>,
+    compaction_map: CompactionMap,
     retained_buffer: Vec<Message>,
+    // Сумма размеров payload всех сообщений в retained_buffer.
+    retained_bytes: usize,
+    publishes_since_cleanup: usize,
+    // Группы потребителей, которые делят между собой сообщения топика.
+    groups: HashMap<String, ConsumerGroup>,
+    stats: TopicStats,
+    // Лог на диске, куда дописываются retained сообщения, если включено хранение.
+    log: Option<TopicLog>,
+    dead_letter: Option<DeadLetterPolicy>,
+    // Очереди подписчиков Reliable топика. subscribe добавляет очередь, имея только
+    // лок на чтение, поэтому список под своим мьютексом.
+    reliable_subscribers: Mutex<Vec<ReliableSubscriber>>,
+    // Издатель Reliable топика держит этот лок, пока не доставит свои сообщения,
+    // чтобы сообщения следующего издателя не обогнали их.
+    delivery_order: Arc<tokio::sync::Mutex<()>>,
+    // Отложенные сообщения по времени, когда их пора опубликовать. Второй элемент
+    // ключа - номер по порядку, чтобы сообщения с одним временем не менялись местами.
+    delayed: BTreeMap<(time::Instant, u64), DelayedMessage>,
+    delayed_seq: u64,
+    // offset, который получит следующее опубликованное сообщение.
+    next_offset: u64,
+    // Партиция для следующего сообщения без ключа, они раскладываются по кругу.
+    next_unkeyed_partition: u32,
+    // Время для чистки по ttl и окну compaction, см. with_clock.
+    clock: Arc<dyn Clock>,
+    // Топик удален, см. close.
+    closed: bool,
 }
 
 impl TopicController {
@@ -60,271 +417,953 @@ impl TopicController {
         name: TopicName,
         retention_ttl: u64,
         compaction_window: u64,
-        buffer_size: u32,
+        retention_max_messages: u64,
+        retention_max_bytes: u64,
+        compaction_mode: CompactionMode,
+        broadcast_capacity: u32,
     ) -> TopicController {
-        let settings = TopicSettings::new(retention_ttl, compaction_window, buffer_size as usize);
-        let (broadcast_sender, _) = broadcast::channel(settings.buffer_size);
-        let compaction_map = HashMap::new();
+        let settings = TopicSettings::new(
+            retention_ttl,
+            compaction_window,
+            retention_max_messages,
+            retention_max_bytes,
+            compaction_mode,
+            broadcast_capacity,
+        );
+        let broadcast_senders = vec![broadcast::channel(settings.broadcast_capacity as usize).0];
+        let compaction_map = CompactionMap::default();
         let retained_buffer = Vec::new();
 
         // Делаем канал
         TopicController {
             name,
-            broadcast_sender,
+            broadcast_senders,
             settings,
             compaction_map,
             retained_buffer,
+            retained_bytes: 0,
+            publishes_since_cleanup: 0,
+            groups: HashMap::new(),
+            stats: TopicStats::default(),
+            log: None,
+            dead_letter: None,
+            reliable_subscribers: Mutex::new(Vec::new()),
+            delivery_order: Arc::new(tokio::sync::Mutex::new(())),
+            delayed: BTreeMap::new(),
+            delayed_seq: 0,
+            next_offset: 0,
+            next_unkeyed_partition: 0,
+            clock: Arc::new(SystemClock),
+            closed: false,
         }
     }
 
-    pub fn publish(&mut self, key: Option<String>, payload: Vec<u8>, received_at: time::Instant) {
-        // Устанавливаем опциональный expires_at, если наш topic поддерживает retention.
-        let message = Message {
-            key,
-            payload,
-            received_at,
-            expires_at: self
-                .settings
-                .retention_ttl
-                .map(|millis| received_at.add(millis)),
-        };
+    // Часы, по которым топик решает, что сообщение истекло или ключ вышел
+    // из окна compaction. По умолчанию системные, другие часы нужны только тестам.
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> TopicController {
+        self.clock = clock;
+        self
+    }
 
-        // Проверяем не дубль ли это сообщения, если у нас включен compaction
-        let is_duplicate = if let Some(compaction_window) = self.settings.compaction_window {
-            Self::check_duplicate_and_update_compaction_map(
-                &message,
-                &mut self.compaction_map,
-                compaction_window,
-            )
-        } else {
-            false
-        };
+    pub fn with_delivery(mut self, delivery: DeliveryGuarantee) -> TopicController {
+        self.settings = self.settings.with_delivery(delivery);
+        self
+    }
 
-        if !is_duplicate {
-            // Отправляем сообщение в броадкаст, его прочитают, если у нас есть
-            // подписчики.
-            match self.broadcast_sender.send(message.clone()) {
-                Ok(count_subscribers) => debug!(
-                    "[TopicController:{}] Sent to {} subscribers",
-                    self.name, count_subscribers,
-                ),
-                Err(_) => debug!(
-                    "[TopicController:{}] No subscribers to receive message",
-                    self.name,
-                ),
-            };
+    pub fn with_compaction_max_keys(mut self, compaction_max_keys: usize) -> TopicController {
+        self.settings = self.settings.with_compaction_max_keys(compaction_max_keys);
+        self
+    }
 
-            // Если мы поддерживаем retention, то сохраним сообщение
-            // в локальный буффер для таких сообщений.
-            if self.settings.retention_ttl.is_some() {
-                self.retained_buffer.push(message);
-            }
-        }
+    pub fn with_key_source(mut self, key_source: KeySource) -> TopicController {
+        self.settings = self.settings.with_key_source(key_source);
+        self
+    }
 
-        // Пройдемся по буфферу и оставим только те элементы, которые
-        // все еще не истекли по времени. Такую работу не очень хорошо
-        // делать на каждом publish сообщения, но мы позволим себе этот
-        // ход для упрощения. Также здесь мы добавим текущее сообщение в
-        // retention_buffer.
-        self.clean_outdated_retained_messages();
+    // Порядок доставки соблюдает SubscriptionManager, топик только хранит настройку.
+    pub fn with_ordering(mut self, ordering: OrderingGuarantee) -> TopicController {
+        self.settings = self.settings.with_ordering(ordering);
+        self
+    }
 
-        // Также, если мы используем compaction для топика, то мы не хотели
-        // бы бесконечно увеличивать размер хэшмапы. Мы наивно будет удалять
-        // ключи, которые уже точно устарели, что не является очень
-        // эффективной и удачной операцией в publish, но в целях упрощения мы оставим
-        // этот код здесь.
-        // Можно было выполнять эту операцию раз в 1000 паблишей, но мы также не будем
-        // усложнять приведенный код.
-        self.clean_outdated_compaction_keys();
+    // Размер сообщений тоже проверяет SubscriptionManager до публикации.
+    pub fn with_max_message_bytes(mut self, max_message_bytes: u64) -> TopicController {
+        self.settings = self.settings.with_max_message_bytes(max_message_bytes);
+        self
     }
 
-    fn clean_outdated_compaction_keys(&mut self) {
-        if self.settings.compaction_window.is_some() {
-            let outdated_keys = self
-                .compaction_map
-                .iter()
-                .filter(|(_key, time)| {
-                    time.add(self.settings.compaction_window.unwrap()) < time::Instant::now()
-                })
-                .map(|(key, _val)| key.to_string())
-                .collect::<Vec<_>>();
-
-            for key in outdated_keys {
-                self.compaction_map.remove(&key);
-            }
-        }
+    // Сообщения с одним ключом всегда попадают в одну партицию и в ней идут в порядке
+    // публикации. Сообщения без ключа раскладываются по партициям по кругу.
+    pub fn with_partitions(mut self, partitions: u32) -> TopicController {
+        self.settings = self.settings.with_partitions(partitions);
+        self.broadcast_senders = (0..self.settings.partitions)
+            .map(|_| broadcast::channel(self.settings.broadcast_capacity as usize).0)
+            .collect();
+        self
     }
 
-    fn clean_outdated_retained_messages(&mut self) {
-        if self.settings.retention_ttl.is_some() {
-            self.retained_buffer
-                .retain(|message| message.expires_at.unwrap() > time::Instant::now());
+    // Подключаем лог на диске. Сообщения из него, которые еще не истекли,
+    // возвращаются в retained буфер, а сам лог переписывается без лишних записей.
+    // Новые сообщения получают offset после самого большого из лога.
+    pub fn attach_log(&mut self, mut log: TopicLog) -> io::Result<()> {
+        // Партиция в логе не хранится. Сообщения с ключом попадут в ту же партицию,
+        // что и до перезапуска, а сообщения без ключа заново разложатся по кругу.
+        for message in log.read_all()? {
+            self.next_offset = self.next_offset.max(message.offset() + 1);
+            let partition = self.next_partition(message.key.as_deref());
+            self.retain(message.with_partition(partition));
         }
+        log.rewrite(&self.retained_buffer)?;
+
+        self.log = Some(log);
+        Ok(())
     }
 
-    // Объединяем retained сообщения и канал Receiver, куда будут поступать сообщения.
-    // Наш брокер гарантирует порядок доставки сообщений в рамках одного топика, поэтому
-    // мы используем chain комбинатор, чтобы вначале отдать старые сообщения, а уже потом
-    // начать слушать текущий stream из топика.
-    pub fn subscribe(
-        &self,
-    ) -> impl tokio::stream::Stream<Item = Result<Message, tokio::sync::broadcast::RecvError>> {
-        let retained_messages = self
-            .retained_buffer
-            .iter()
-            .map(|message| Ok(message.clone()))
-            .collect::<Vec<_>>();
+    pub fn settings(&self) -> &TopicSettings {
+        &self.settings
+    }
 
-        let subscription = self.broadcast_sender.subscribe().into_stream();
+    pub fn set_dead_letter_policy(&mut self, policy: Option<DeadLetterPolicy>) {
+        self.dead_letter = policy;
+    }
 
-        stream::iter(retained_messages).chain(subscription)
+    pub fn dead_letter_policy(&self) -> Option<&DeadLetterPolicy> {
+        self.dead_letter.as_ref()
     }
 
-    fn check_duplicate_and_update_compaction_map(
-        message: &Message,
-        compaction_map: &mut HashMap<String, time::Instant>,
-        compaction_window: time::Duration,
-    ) -> bool {
-        // Если у сообщения нет ключа для compaction, то мы ничего не будет предпринимать.
-        if message.key.is_none() {
-            return false;
+    // Очередь издателей Reliable топика, см. PendingDelivery. У BestEffort топика
+    // publish никогда не ждет, поэтому и очереди нет.
+    pub fn delivery_order(&self) -> Option<Arc<tokio::sync::Mutex<()>>> {
+        match self.settings.delivery {
+            DeliveryGuarantee::BestEffort => None,
+            DeliveryGuarantee::Reliable => Some(self.delivery_order.clone()),
         }
+    }
 
-        let now = time::Instant::now();
-        let key = message.key.as_ref().unwrap();
-
-        match compaction_map.get(key) {
-            Some(last_sent_at) => {
-                let since_last_seen = now.duration_since(*last_sent_at);
-                if since_last_seen < compaction_window {
-                    // Если мы отравляли сообщение не так давно,
-                    // то скажем, что текущее сообщение дубликат.
-                    true
-                } else {
-                    // Здесь мы видим, что можем повторить отправку,
-                    // сообщение ушло давно.
-                    compaction_map.insert(key.to_string(), now);
-                    false
-                }
-            }
-            None => {
-                // Мы еще не встречали такого сообщения,
-                // отправим его и пометим, что оно ушло сейчас.
-                compaction_map.insert(key.to_string(), now);
-                false
-            }
-        }
+    // Нужно ли топику хранить сообщения для новых подписчиков.
+    pub fn retains_messages(&self) -> bool {
+        self.retention_enabled() || self.settings.compaction_mode == CompactionMode::KeyLatest
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub fn stats(&self) -> &TopicStats {
+        &self.stats
+    }
 
-    #[test]
-    fn test_cleaning_compaction_map() {
-        let mut topic_controller_with_small_compaction_window =
-            TopicController::new("test".to_string(), 0, 1, 0);
+    pub fn retained_len(&self) -> usize {
+        self.retained_buffer.len()
+    }
 
-        let mut topic_controller_with_large_compaction_window =
-            TopicController::new("test1".to_string(), 0, 10_000, 0);
+    pub fn retained_bytes(&self) -> usize {
+        self.retained_bytes
+    }
 
-        let in_past = time::Instant::now()
-            .checked_sub(time::Duration::from_millis(5000))
-            .unwrap();
+    pub fn compaction_keys(&self) -> usize {
+        self.compaction_map.len()
+    }
 
-        let message1 = Message {
-            key: Some("same".to_string()),
-            payload: vec![1, 2, 3, 4],
-            received_at: in_past,
-            expires_at: None,
-        };
+    // Обычные подписчики держат приемник броадкаста или свою очередь в Reliable топике,
+    // а участники групп получают сообщения через свои каналы, поэтому считаем всех.
+    // Очереди отключившихся Reliable подписчиков убираются на следующем publish
+    // или в remove_closed_subscribers.
+    pub fn subscriber_count(&self) -> usize {
+        let group_members = self
+            .groups
+            .values()
+            .map(ConsumerGroup::member_count)
+            .sum::<usize>();
+        let reliable_subscribers = self.reliable_subscribers.lock_or_recover().len();
+        let broadcast_receivers = self
+            .broadcast_senders
+            .iter()
+            .map(broadcast::Sender::receiver_count)
+            .sum::<usize>();
 
-        TopicController::check_duplicate_and_update_compaction_map(
-            &message1,
-            &mut topic_controller_with_small_compaction_window.compaction_map,
-            topic_controller_with_small_compaction_window
-                .settings
-                .compaction_window
-                .unwrap(),
-        );
+        broadcast_receivers + reliable_subscribers + group_members
+    }
 
-        TopicController::check_duplicate_and_update_compaction_map(
-            &message1,
-            &mut topic_controller_with_large_compaction_window.compaction_map,
-            topic_controller_with_large_compaction_window
-                .settings
-                .compaction_window
-                .unwrap(),
-        );
+    // Закрывает топик, когда его удаляют из реестра. Контроллер может пережить удаление,
+    // пока у кого-то есть его TopicHandle, поэтому каналы подписчиков и групп закрываем
+    // сами: их стримы дочитают то, что уже получили, и завершатся. Новые подписки на
+    // закрытый топик отдают только retained сообщения, а publish в него ничего не делает.
+    pub fn close(&mut self) {
+        self.closed = true;
+        self.broadcast_senders.clear();
+        self.reliable_subscribers.get_mut_or_recover().clear();
+        self.groups.clear();
+        self.delayed.clear();
+        self.log = None;
+    }
 
-        std::thread::sleep(time::Duration::from_millis(100));
+    // Убирает очереди Reliable подписчиков, которые уже отключились, не дожидаясь
+    // следующего publish. Приемник броадкаста при удалении убирается сам.
+    pub fn remove_closed_subscribers(&self) {
+        let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
 
-        topic_controller_with_small_compaction_window.clean_outdated_compaction_keys();
-        topic_controller_with_large_compaction_window.clean_outdated_compaction_keys();
+        self.reliable_subscribers
+            .lock_or_recover()
+            .retain(|subscriber| {
+                !matches!(
+                    subscriber.sender.clone().poll_ready(&mut cx),
+                    std::task::Poll::Ready(Err(_))
+                )
+            });
+    }
 
-        assert!(topic_controller_with_small_compaction_window
-            .compaction_map
-            .is_empty());
+    // Для Reliable топика возвращает сообщение, которое нужно доставить подписчикам
+    // с полными очередями, см. PendingDelivery.
+    #[cfg(test)]
+    pub fn publish(
+        &mut self,
+        key: Option<String>,
+        payload: Vec<u8>,
+        headers: HashMap<String, String>,
+        received_at: time::Instant,
+    ) -> Option<PendingDelivery> {
+        self.publish_with_ttl(key, payload, headers, received_at, None)
+    }
 
-        assert!(!topic_controller_with_large_compaction_window
-            .compaction_map
-            .is_empty())
+    // ttl сообщения, если он есть, заменяет retention_ttl топика.
+    pub fn publish_with_ttl(
+        &mut self,
+        key: Option<String>,
+        payload: Vec<u8>,
+        headers: HashMap<String, String>,
+        received_at: time::Instant,
+        ttl: Option<time::Duration>,
+    ) -> Option<PendingDelivery> {
+        match self.publish_checked(key, payload, headers, received_at, ttl) {
+            PublishOutcome::Published(pending) => pending,
+            PublishOutcome::Duplicate | PublishOutcome::Saturated => None,
+        }
     }
 
-    #[test]
-    fn test_dedup_works() {
-        let mut compaction_map = HashMap::new();
-        let compaction_window = time::Duration::from_millis(5000);
+    // Как publish_checked, но в Reliable топике не ждет медленных подписчиков:
+    // если чья-то очередь заполнена, то сообщение не публикуется совсем
+    // и возвращается Saturated. BestEffort топик никогда не заполняется, отставший
+    // подписчик там просто пропускает самые старые сообщения.
+    pub fn try_publish(
+        &mut self,
+        key: Option<String>,
+        payload: Vec<u8>,
+        headers: HashMap<String, String>,
+        received_at: time::Instant,
+        ttl: Option<time::Duration>,
+    ) -> PublishOutcome {
+        if self.is_saturated() {
+            return PublishOutcome::Saturated;
+        }
 
-        let in_past = time::Instant::now()
-            .checked_sub(time::Duration::from_millis(5000))
-            .unwrap();
+        self.publish_checked(key, payload, headers, received_at, ttl)
+    }
 
-        let message1 = Message {
-            key: Some("same".to_string()),
-            payload: vec![1, 2, 3, 4],
-            received_at: in_past,
-            expires_at: None,
-        };
-        let message2 = Message {
-            key: Some("same".to_string()),
-            payload: vec![1, 2, 3, 4],
-            received_at: in_past,
-            expires_at: None,
-        };
+    // Есть ли подписчик Reliable топика, в очередь которого сейчас не поместится
+    // сообщение. Место проверяем через poll_ready у копии отправителя: копия
+    // занимает место только для себя и освобождает его, когда ее удаляют.
+    // Группа заполнена, если места нет ни у одного из ее участников.
+    pub fn is_saturated(&self) -> bool {
+        let mut cx = std::task::Context::from_waker(futures::task::noop_waker_ref());
 
-        assert!(!TopicController::check_duplicate_and_update_compaction_map(
-            &message1,
-            &mut compaction_map,
-            compaction_window
-        ));
-        assert!(TopicController::check_duplicate_and_update_compaction_map(
-            &message2,
-            &mut compaction_map,
-            compaction_window
-        )); // Второе сообщение является дубликатом
+        let subscriber_is_full = self
+            .reliable_subscribers
+            .lock_or_recover()
+            .iter()
+            .any(|subscriber| subscriber.sender.clone().poll_ready(&mut cx).is_pending());
+
+        subscriber_is_full
+            || (self.settings.delivery == DeliveryGuarantee::Reliable
+                && self.groups.values().any(ConsumerGroup::is_full))
     }
 
-    #[test]
-    fn test_do_not_dedup_if_too_much_time_passed() {
-        let mut compaction_map = HashMap::new();
-        let compaction_window = time::Duration::from_millis(1);
+    // То же, что publish_with_ttl, но еще сообщает, не отброшено ли сообщение как дубль.
+    pub fn publish_checked(
+        &mut self,
+        key: Option<String>,
+        payload: Vec<u8>,
+        headers: HashMap<String, String>,
+        received_at: time::Instant,
+        ttl: Option<time::Duration>,
+    ) -> PublishOutcome {
+        // Издатель получил TopicHandle до удаления топика. Сообщение некому отдать.
+        if self.closed {
+            return PublishOutcome::Published(None);
+        }
 
-        let in_past = time::Instant::now()
-            .checked_sub(time::Duration::from_millis(5000))
-            .unwrap();
-        let message1 = Message {
-            key: Some("same".to_string()),
-            payload: vec![1, 2, 3, 4],
+        // Устанавливаем опциональный expires_at, если у сообщения есть свой ttl
+        // или наш topic поддерживает retention.
+        // Слишком большой ttl от клиента не должен ронять брокер, такое сообщение
+        // просто не истекает.
+        let expires_at = ttl
+            .or(self.settings.retention_ttl)
+            .and_then(|ttl| received_at.checked_add(ttl));
+        let key = extract_key(&self.settings.key_source, key, &payload);
+        let message = Message::new(key, payload, headers, received_at, expires_at);
+        self.stats.on_published();
+
+        // Проверяем не дубль ли это сообщения, если у нас включен compaction
+        let is_duplicate = match (
+            self.settings.compaction_mode,
+            self.settings.compaction_window,
+        ) {
+            (CompactionMode::Dedup, Some(compaction_window)) => {
+                let is_duplicate = Self::check_duplicate_and_update_compaction_map(
+                    &message,
+                    &mut self.compaction_map,
+                    compaction_window,
+                );
+                // Между чистками по времени хэшмапа не вырастет больше лимита,
+                // сколько бы разных ключей ни публиковали.
+                self.compaction_map
+                    .evict_oldest(self.settings.compaction_max_keys);
+                is_duplicate
+            }
+            _ => false,
+        };
+
+        let mut pending = None;
+        let mut full_subscribers = Vec::new();
+        if is_duplicate {
+            self.stats.on_duplicate_drop();
+        } else {
+            self.stats.on_kept();
+            // Дубли отбрасываются без offset, поэтому у опубликованных сообщений
+            // offset идут подряд.
+            let partition = self.next_partition(message.key.as_deref());
+            let message = message
+                .with_offset(self.next_offset)
+                .with_partition(partition);
+            self.next_offset += 1;
+
+            match self.settings.delivery {
+                // Отправляем сообщение в броадкаст, его прочитают, если у нас есть
+                // подписчики.
+                DeliveryGuarantee::BestEffort => {
+                    let broadcast_sender = &self.broadcast_senders[message.partition as usize];
+                    match broadcast_sender.send(message.clone()) {
+                        Ok(count_subscribers) => debug!(
+                            "[TopicController:{}] Sent to {} subscribers",
+                            self.name, count_subscribers,
+                        ),
+                        Err(_) => debug!(
+                            "[TopicController:{}] No subscribers to receive message",
+                            self.name,
+                        ),
+                    };
+                }
+                DeliveryGuarantee::Reliable => full_subscribers = self.send_reliable(&message),
+            }
+
+            // Каждая группа потребителей получает свою копию сообщения
+            // и отдает ее одному из своих участников. Если места нет ни у кого,
+            // то в Reliable топике сообщение доставит издатель, а в BestEffort
+            // группа его пропускает, как отставший подписчик.
+            let mut full_groups = Vec::new();
+            for (name, group) in self.groups.iter_mut() {
+                if group.dispatch(message.clone()).is_ok() || group.is_empty() {
+                    continue;
+                }
+
+                match self.settings.delivery {
+                    DeliveryGuarantee::BestEffort => debug!(
+                        "[TopicController:{}] Group {} is full, message skipped",
+                        self.name, name,
+                    ),
+                    DeliveryGuarantee::Reliable => full_groups.push(group.senders()),
+                }
+            }
+            // Группа, все участники которой отключились, больше ничего не получит.
+            self.groups.retain(|_name, group| !group.is_empty());
+
+            if !full_subscribers.is_empty() || !full_groups.is_empty() {
+                pending = Some(PendingDelivery {
+                    message: message.clone(),
+                    subscribers: full_subscribers,
+                    groups: full_groups,
+                });
+            }
+
+            // Retained сообщения сначала пишем в лог на диске, если он есть.
+            let is_retained = self.retention_enabled() || self.keeps_latest_by_key(&message);
+            if is_retained {
+                if let Some(log) = self.log.as_mut() {
+                    if let Err(e) = log.append(&message) {
+                        error!(
+                            "[TopicController:{}] Failed to write message to log; error = {:?}",
+                            self.name, e
+                        );
+                    }
+                }
+            }
+
+            self.retain(message);
+        }
+
+        // Раз в CLEANUP_EVERY_PUBLISHES публикаций пройдемся по буфферу и оставим
+        // только те элементы, которые все еще не истекли по времени. Также удалим
+        // из compaction_map ключи, которые уже точно устарели, чтобы хэшмапа не росла
+        // бесконечно. Между чистками в буфере могут лежать истекшие сообщения,
+        // поэтому subscribe сам отфильтровывает их.
+        self.publishes_since_cleanup += 1;
+        if self.publishes_since_cleanup >= CLEANUP_EVERY_PUBLISHES {
+            self.publishes_since_cleanup = 0;
+            self.clean_outdated_retained_messages();
+            self.clean_outdated_compaction_keys();
+        }
+
+        if is_duplicate {
+            PublishOutcome::Duplicate
+        } else {
+            PublishOutcome::Published(pending)
+        }
+    }
+
+    // Кладем сообщение в очереди подписчиков, где есть место, и заодно убираем
+    // очереди отключившихся подписчиков. Возвращаем очереди, где места нет,
+    // им сообщение доставит издатель.
+    fn send_reliable(&mut self, message: &Message) -> Vec<mpsc::Sender<Message>> {
+        let subscribers = self.reliable_subscribers.get_mut_or_recover();
+        let mut connected = Vec::with_capacity(subscribers.len());
+        let mut full = Vec::new();
+
+        for mut subscriber in subscribers.drain(..) {
+            if subscriber
+                .partition
+                .is_some_and(|partition| partition != message.partition)
+            {
+                connected.push(subscriber);
+                continue;
+            }
+
+            match subscriber.sender.try_send(message.clone()) {
+                Ok(()) => connected.push(subscriber),
+                Err(TrySendError::Full(_)) => {
+                    full.push(subscriber.sender.clone());
+                    connected.push(subscriber);
+                }
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+        *subscribers = connected;
+
+        full
+    }
+
+    // Откладываем сообщение до deliver_at. Сообщение занимает место в топике не в момент
+    // schedule, а когда release_delayed его публикует: сообщения, опубликованные сразу
+    // за время ожидания, подписчики получат раньше. retention_ttl и compaction тоже
+    // отсчитываются от момента публикации.
+    pub fn schedule(
+        &mut self,
+        key: Option<String>,
+        payload: Vec<u8>,
+        headers: HashMap<String, String>,
+        deliver_at: time::Instant,
+        ttl: Option<time::Duration>,
+    ) {
+        self.delayed_seq += 1;
+        self.delayed.insert(
+            (deliver_at, self.delayed_seq),
+            DelayedMessage {
+                key,
+                payload,
+                headers,
+                ttl,
+            },
+        );
+    }
+
+    // Публикуем отложенные сообщения, время которых уже наступило, в порядке этого времени.
+    pub fn release_delayed(&mut self, now: time::Instant) -> Vec<PendingDelivery> {
+        let due = self
+            .delayed
+            .range(..=(now, u64::MAX))
+            .map(|(key, _message)| *key)
+            .collect::<Vec<_>>();
+
+        due.into_iter()
+            .filter_map(|key| {
+                let message = self.delayed.remove(&key).unwrap();
+                self.publish_with_ttl(
+                    message.key,
+                    message.payload,
+                    message.headers,
+                    now,
+                    message.ttl,
+                )
+            })
+            .collect()
+    }
+
+    // Группы получают только сообщения, опубликованные после их создания,
+    // retained сообщения им не отдаются.
+    // Очередь каждого участника такого же размера, как броадкаст топика.
+    pub fn join_group(&mut self, group: &str) -> (MemberId, mpsc::Receiver<Message>) {
+        // Без отправителя стрим участника закрытого топика сразу завершится.
+        if self.closed {
+            return (0, mpsc::channel(1).1);
+        }
+
+        let capacity = self.settings.broadcast_capacity as usize;
+        self.groups
+            .entry(group.to_string())
+            .or_insert_with(|| ConsumerGroup::new(capacity))
+            .join()
+    }
+
+    // Группа без участников удаляется вместе с ее именем: сообщения,
+    // опубликованные до следующего join, ей не достанутся.
+    pub fn leave_group(&mut self, group_name: &str, member_id: MemberId) {
+        if let Some(group) = self.groups.get_mut(group_name) {
+            group.leave(member_id);
+            if group.is_empty() {
+                self.groups.remove(group_name);
+            }
+        }
+    }
+
+    // Возвращаем группе сообщения, которые ушедший участник так и не обработал,
+    // чтобы их получили оставшиеся участники. Сообщения, которым не хватило места,
+    // возвращаются для доставки через PendingDelivery::deliver.
+    pub fn return_to_group(&mut self, group: &str, messages: Vec<Message>) -> Vec<PendingDelivery> {
+        let group = match self.groups.get_mut(group) {
+            Some(group) => group,
+            None => return Vec::new(),
+        };
+
+        // После первого сообщения, которому не хватило места, остальные тоже
+        // доставляем через PendingDelivery, чтобы сохранить их порядок.
+        let mut pending = Vec::new();
+        for message in messages {
+            let message = if pending.is_empty() {
+                match group.dispatch(message) {
+                    Ok(()) => continue,
+                    Err(message) => message,
+                }
+            } else {
+                message
+            };
+
+            if group.is_empty() {
+                break;
+            }
+            pending.push(PendingDelivery {
+                message,
+                subscribers: Vec::new(),
+                groups: vec![group.senders()],
+            });
+        }
+
+        pending
+    }
+
+    // Если мы поддерживаем retention, то сохраним сообщение
+    // в локальный буффер для таких сообщений.
+    fn retain(&mut self, message: Message) {
+        if self.settings.compaction_mode == CompactionMode::KeyLatest {
+            // Для KeyLatest храним только последнее сообщение по ключу,
+            // поэтому убираем из буфера предыдущее сообщение с тем же ключом.
+            // Это проход по всему буферу, но мы оставим его для простоты.
+            self.remove_retained_with_key(&message);
+        }
+
+        if self.retention_enabled() || self.keeps_latest_by_key(&message) {
+            self.retained_bytes += message.payload.len();
+            self.retained_buffer.push(message);
+            self.evict_retained_over_limits();
+        }
+    }
+
+    fn retention_enabled(&self) -> bool {
+        self.settings.retention_ttl.is_some()
+            || self.settings.retention_max_messages.is_some()
+            || self.settings.retention_max_bytes.is_some()
+    }
+
+    // В режиме KeyLatest сообщения с ключом сохраняются, даже если
+    // retention для топика не настроен, иначе новым подписчикам нечего отдать.
+    fn keeps_latest_by_key(&self, message: &Message) -> bool {
+        self.settings.compaction_mode == CompactionMode::KeyLatest && message.key.is_some()
+    }
+
+    fn remove_retained_with_key(&mut self, message: &Message) {
+        if message.key.is_none() {
+            return;
+        }
+
+        let mut removed_bytes = 0;
+        let stats = &self.stats;
+        self.retained_buffer.retain(|retained| {
+            if retained.key == message.key {
+                removed_bytes += retained.payload.len();
+                stats.on_compaction_drop();
+                false
+            } else {
+                true
+            }
+        });
+        self.retained_bytes -= removed_bytes;
+    }
+
+    // Если retention ограничен количеством сообщений или суммарным размером,
+    // то выкидываем самые старые сообщения, пока не уложимся в оба лимита.
+    fn evict_retained_over_limits(&mut self) {
+        let mut excess = match self.settings.retention_max_messages {
+            Some(max_messages) => self.retained_buffer.len().saturating_sub(max_messages),
+            None => 0,
+        };
+
+        let mut remaining_bytes = self.retained_bytes
+            - self.retained_buffer[..excess]
+                .iter()
+                .map(|message| message.payload.len())
+                .sum::<usize>();
+
+        if let Some(max_bytes) = self.settings.retention_max_bytes {
+            while remaining_bytes > max_bytes && excess < self.retained_buffer.len() {
+                remaining_bytes -= self.retained_buffer[excess].payload.len();
+                excess += 1;
+            }
+        }
+
+        self.retained_buffer.drain(..excess);
+        self.retained_bytes = remaining_bytes;
+    }
+
+    fn clean_outdated_compaction_keys(&mut self) {
+        if let Some(compaction_window) = self.settings.compaction_window {
+            self.compaction_map
+                .remove_outdated(compaction_window, self.clock.now());
+        }
+    }
+
+    // В буфере могут одновременно лежать сообщения с expires_at и без него
+    // (например, при retention по количеству), поэтому удаляем только истекшие.
+    fn clean_outdated_retained_messages(&mut self) {
+        let now = self.clock.now();
+        self.retained_buffer
+            .retain(|message| !message.is_expired_at(now));
+        self.retained_bytes = self
+            .retained_buffer
+            .iter()
+            .map(|message| message.payload.len())
+            .sum();
+    }
+
+    // Объединяем retained сообщения и канал Receiver, куда будут поступать сообщения.
+    // Наш брокер гарантирует порядок доставки сообщений в рамках одного топика, поэтому
+    // мы используем chain комбинатор, чтобы вначале отдать старые сообщения, а уже потом
+    // начать слушать текущий stream из топика. С DeliveryStart::Latest старые
+    // сообщения пропускаются.
+    pub fn subscribe(
+        &self,
+        start: DeliveryStart,
+    ) -> impl tokio::stream::Stream<Item = Result<Message, tokio::sync::broadcast::RecvError>> {
+        self.subscribe_after(start, None)
+    }
+
+    // С from_offset подписчик получает retained сообщения только после этого offset,
+    // а start не учитывается. Если сообщения после from_offset уже удалены по retention,
+    // то чтение начинается с самого старого из оставшихся.
+    pub fn subscribe_after(
+        &self,
+        start: DeliveryStart,
+        from_offset: Option<u64>,
+    ) -> impl tokio::stream::Stream<Item = Result<Message, tokio::sync::broadcast::RecvError>> {
+        self.subscribe_partition(start, from_offset, None)
+    }
+
+    // Подписка на одну партицию топика или, с None, на все. Порядок сообщений
+    // сохраняется внутри партиции, а сообщения разных партиций подписчик на все
+    // может получить не в том порядке, в котором их опубликовали.
+    pub fn subscribe_partition(
+        &self,
+        start: DeliveryStart,
+        from_offset: Option<u64>,
+        partition: Option<u32>,
+    ) -> impl tokio::stream::Stream<Item = Result<Message, tokio::sync::broadcast::RecvError>> {
+        // Сначала подписываемся на очередь топика и только потом делаем снимок retained
+        // сообщений. Так между ними не остается момента, когда опубликованное сообщение
+        // не попадет ни туда, ни туда. Сообщения, которые попали и в снимок, и в очередь,
+        // отсекаем по offset, поэтому подписчик не получит их дважды и не увидит
+        // сообщение старше уже отданного.
+        let subscription = match self.settings.delivery {
+            DeliveryGuarantee::BestEffort => {
+                let receivers = self
+                    .broadcast_senders
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _sender)| {
+                        partition.is_none_or(|partition| partition as usize == *index)
+                    })
+                    .map(|(_index, sender)| Box::pin(sender.subscribe().into_stream()));
+                Either::Left(futures::stream::select_all(receivers))
+            }
+            DeliveryGuarantee::Reliable => {
+                let (sender, receiver) = mpsc::channel(self.settings.broadcast_capacity as usize);
+                if !self.closed {
+                    self.reliable_subscribers
+                        .lock_or_recover()
+                        .push(ReliableSubscriber { partition, sender });
+                }
+                Either::Right(receiver.map(Ok))
+            }
+        };
+        let retained_messages = self.retained_after(start, from_offset, partition);
+        let last_retained_offset = retained_messages.last().map(Message::offset);
+
+        let subscription =
+            subscription.filter(move |message| match (message, last_retained_offset) {
+                (Ok(message), Some(last_retained_offset)) => message.offset > last_retained_offset,
+                _ => true,
+            });
+
+        stream::iter(retained_messages.into_iter().map(Ok)).chain(subscription)
+    }
+
+    // Retained сообщения, с которых subscribe_after начнет подписку. Это снимок
+    // на момент вызова: опубликованные позже сообщения в него не попадут.
+    pub fn retained_after(
+        &self,
+        start: DeliveryStart,
+        from_offset: Option<u64>,
+        partition: Option<u32>,
+    ) -> Vec<Message> {
+        if from_offset.is_none() && start == DeliveryStart::Latest {
+            return Vec::new();
+        }
+
+        // Буфер чистится не на каждый publish, так что пропускаем сообщения,
+        // которые уже истекли, но еще не были удалены.
+        let now = self.clock.now();
+        let retained = self
+            .retained_buffer
+            .iter()
+            .filter(|message| !message.is_expired_at(now))
+            .filter(|message| from_offset.is_none_or(|offset| message.offset > offset))
+            .filter(|message| partition.is_none_or(|partition| message.partition == partition));
+
+        if start != DeliveryStart::SnapshotOnly {
+            return retained.cloned().collect();
+        }
+
+        // Оставляем последнее сообщение каждого ключа, в порядке offset. Последнее
+        // сообщение буфера всегда попадает в снимок, поэтому subscribe_partition
+        // правильно отсекает по нему очередь топика.
+        let mut latest_by_key = HashMap::new();
+        for message in retained {
+            latest_by_key.insert(message.key.as_deref(), message);
+        }
+        let mut snapshot = latest_by_key.into_values().cloned().collect::<Vec<_>>();
+        snapshot.sort_by_key(Message::offset);
+        snapshot
+    }
+
+    // Партиция для сообщения с этим ключом. Ключ всегда дает одну и ту же партицию,
+    // в том числе после перезапуска брокера, поэтому хэш свой, а не DefaultHasher,
+    // который может поменяться с версией Rust.
+    fn next_partition(&mut self, key: Option<&str>) -> u32 {
+        let partitions = self.settings.partitions;
+        match key {
+            Some(key) => (fnv1a(key.as_bytes()) % u64::from(partitions)) as u32,
+            None => {
+                let partition = self.next_unkeyed_partition % partitions;
+                self.next_unkeyed_partition = (partition + 1) % partitions;
+                partition
+            }
+        }
+    }
+
+    pub fn partitions(&self) -> u32 {
+        self.settings.partitions
+    }
+
+    fn check_duplicate_and_update_compaction_map(
+        message: &Message,
+        compaction_map: &mut CompactionMap,
+        compaction_window: time::Duration,
+    ) -> bool {
+        // Если у сообщения нет ключа для compaction, то мы ничего не будет предпринимать.
+        if message.key.is_none() {
+            return false;
+        }
+
+        // Окно отсчитываем от того, когда брокер получил сообщения, а не от момента
+        // проверки, поэтому результат не зависит от того, как долго сообщение ждало
+        // лока топика или таймера deliver_after.
+        let received_at = message.received_at;
+        let key = message.key.as_ref().unwrap();
+
+        match compaction_map.get(key) {
+            Some(last_sent_at) => {
+                // Сообщение, полученное раньше уже отправленного, считаем дублем.
+                let since_last_seen = received_at.saturating_duration_since(last_sent_at);
+                if since_last_seen < compaction_window {
+                    // Если мы отравляли сообщение не так давно,
+                    // то скажем, что текущее сообщение дубликат.
+                    true
+                } else {
+                    // Здесь мы видим, что можем повторить отправку,
+                    // сообщение ушло давно.
+                    compaction_map.insert(key.to_string(), received_at);
+                    false
+                }
+            }
+            None => {
+                // Мы еще не встречали такого сообщения,
+                // отправим его и пометим, когда оно получено.
+                compaction_map.insert(key.to_string(), received_at);
+                false
+            }
+        }
+    }
+}
+
+// 64-битный FNV-1a.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Ключ сообщения по KeySource топика. Строка по JsonPointer берется как есть, число
+// или bool - в виде JSON. Payload, сжатый клиентом, JSON не разбирается, и такое
+// сообщение тоже остается без ключа.
+fn extract_key(key_source: &KeySource, key: Option<String>, payload: &[u8]) -> Option<String> {
+    match key_source {
+        KeySource::Explicit => key,
+        KeySource::JsonPointer(pointer) => {
+            let payload = serde_json::from_slice::<serde_json::Value>(payload).ok()?;
+            match payload.pointer(pointer)? {
+                serde_json::Value::String(key) => Some(key.clone()),
+                value @ serde_json::Value::Number(_) | value @ serde_json::Value::Bool(_) => {
+                    Some(value.to_string())
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::locks::RwLockExt;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_publish_checked_reports_compacted_duplicates() {
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            0,
+            10_000,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+        let now = time::Instant::now();
+        let mut publish = |key: &str| {
+            topic_controller.publish_checked(
+                Some(key.to_string()),
+                vec![1],
+                HashMap::new(),
+                now,
+                None,
+            )
+        };
+
+        assert!(matches!(publish("a"), PublishOutcome::Published(None)));
+        assert!(matches!(publish("a"), PublishOutcome::Duplicate));
+        assert!(matches!(publish("b"), PublishOutcome::Published(None)));
+    }
+
+    #[test]
+    fn test_cleaning_compaction_map() {
+        let clock = Arc::new(ManualClock::new());
+        let mut topic_controller_with_small_compaction_window =
+            TopicController::new("test".to_string(), 0, 1, 0, 0, CompactionMode::Dedup, 0)
+                .with_clock(clock.clone());
+
+        let mut topic_controller_with_large_compaction_window = TopicController::new(
+            "test1".to_string(),
+            0,
+            10_000,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        )
+        .with_clock(clock.clone());
+
+        let message1 = Message {
+            key: Some("same".to_string()),
+            payload: vec![1, 2, 3, 4].into(),
+            headers: HashMap::new(),
+            received_at: clock.now(),
+            expires_at: None,
+            offset: 0,
+            partition: 0,
+        };
+
+        TopicController::check_duplicate_and_update_compaction_map(
+            &message1,
+            &mut topic_controller_with_small_compaction_window.compaction_map,
+            topic_controller_with_small_compaction_window
+                .settings
+                .compaction_window
+                .unwrap(),
+        );
+
+        TopicController::check_duplicate_and_update_compaction_map(
+            &message1,
+            &mut topic_controller_with_large_compaction_window.compaction_map,
+            topic_controller_with_large_compaction_window
+                .settings
+                .compaction_window
+                .unwrap(),
+        );
+
+        clock.advance(time::Duration::from_millis(100));
+
+        topic_controller_with_small_compaction_window.clean_outdated_compaction_keys();
+        topic_controller_with_large_compaction_window.clean_outdated_compaction_keys();
+
+        assert!(topic_controller_with_small_compaction_window
+            .compaction_map
+            .is_empty());
+
+        assert!(!topic_controller_with_large_compaction_window
+            .compaction_map
+            .is_empty())
+    }
+
+    #[test]
+    fn test_dedup_works() {
+        let mut compaction_map = CompactionMap::default();
+        let compaction_window = time::Duration::from_millis(5000);
+
+        let in_past = time::Instant::now()
+            .checked_sub(time::Duration::from_millis(5000))
+            .unwrap();
+
+        let message1 = Message {
+            key: Some("same".to_string()),
+            payload: vec![1, 2, 3, 4].into(),
+            headers: HashMap::new(),
             received_at: in_past,
             expires_at: None,
+            offset: 0,
+            partition: 0,
         };
         let message2 = Message {
             key: Some("same".to_string()),
-            payload: vec![1, 2, 3, 4],
+            payload: vec![1, 2, 3, 4].into(),
+            headers: HashMap::new(),
             received_at: in_past,
             expires_at: None,
+            offset: 0,
+            partition: 0,
         };
 
         assert!(!TopicController::check_duplicate_and_update_compaction_map(
@@ -332,35 +1371,40 @@ mod tests {
             &mut compaction_map,
             compaction_window
         ));
-
-        std::thread::sleep(time::Duration::from_millis(100));
-
-        assert!(!TopicController::check_duplicate_and_update_compaction_map(
+        assert!(TopicController::check_duplicate_and_update_compaction_map(
             &message2,
             &mut compaction_map,
             compaction_window
-        )); // Второе сообщение не дубликат, прошло много времени
+        )); // Второе сообщение является дубликатом
     }
 
     #[test]
-    fn test_dedup_works_with_different_keys() {
-        let mut compaction_map = HashMap::new();
-        let compaction_window = time::Duration::from_millis(5000);
+    fn test_do_not_dedup_if_too_much_time_passed() {
+        let mut compaction_map = CompactionMap::default();
+        let compaction_window = time::Duration::from_millis(1);
 
         let in_past = time::Instant::now()
             .checked_sub(time::Duration::from_millis(5000))
             .unwrap();
         let message1 = Message {
             key: Some("same".to_string()),
-            payload: vec![1, 2, 3, 4],
+            payload: vec![1, 2, 3, 4].into(),
+            headers: HashMap::new(),
             received_at: in_past,
             expires_at: None,
+            offset: 0,
+            partition: 0,
         };
+        // Окно считается по received_at, поэтому второе сообщение должно быть
+        // получено позже первого, а не просто проверено позже.
         let message2 = Message {
-            key: Some("different".to_string()),
-            payload: vec![1, 2, 3, 4],
-            received_at: in_past,
+            key: Some("same".to_string()),
+            payload: vec![1, 2, 3, 4].into(),
+            headers: HashMap::new(),
+            received_at: in_past + time::Duration::from_millis(100),
             expires_at: None,
+            offset: 0,
+            partition: 0,
         };
 
         assert!(!TopicController::check_duplicate_and_update_compaction_map(
@@ -368,10 +1412,1330 @@ mod tests {
             &mut compaction_map,
             compaction_window
         ));
+
         assert!(!TopicController::check_duplicate_and_update_compaction_map(
             &message2,
             &mut compaction_map,
             compaction_window
-        )); // Второе сообщение прошло, потому что другой ключ
+        )); // Второе сообщение не дубликат, прошло много времени
+    }
+
+    #[test]
+    fn test_dedup_window_follows_received_at() {
+        let mut compaction_map = CompactionMap::default();
+        let compaction_window = time::Duration::from_secs(5);
+        let base = time::Instant::now()
+            .checked_sub(time::Duration::from_secs(60))
+            .unwrap();
+        let received_at = |offset_secs| Message {
+            key: Some("same".to_string()),
+            payload: vec![1].into(),
+            headers: HashMap::new(),
+            received_at: base + time::Duration::from_secs(offset_secs),
+            expires_at: None,
+            offset: 0,
+            partition: 0,
+        };
+
+        // Все проверки идут сразу, через минуту после base, но решение зависит
+        // только от того, когда сообщения были получены.
+        let duplicates = [0, 1, 6, 7, 12, 3]
+            .iter()
+            .map(|offset_secs| {
+                TopicController::check_duplicate_and_update_compaction_map(
+                    &received_at(*offset_secs),
+                    &mut compaction_map,
+                    compaction_window,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // 6 и 12 открывают новое окно, а 3 получено раньше последнего
+        // отправленного сообщения и считается дублем.
+        assert_eq!(vec![false, true, false, true, false, true], duplicates);
+    }
+
+    #[test]
+    fn test_dedup_works_with_different_keys() {
+        let mut compaction_map = CompactionMap::default();
+        let compaction_window = time::Duration::from_millis(5000);
+
+        let in_past = time::Instant::now()
+            .checked_sub(time::Duration::from_millis(5000))
+            .unwrap();
+        let message1 = Message {
+            key: Some("same".to_string()),
+            payload: vec![1, 2, 3, 4].into(),
+            headers: HashMap::new(),
+            received_at: in_past,
+            expires_at: None,
+            offset: 0,
+            partition: 0,
+        };
+        let message2 = Message {
+            key: Some("different".to_string()),
+            payload: vec![1, 2, 3, 4].into(),
+            headers: HashMap::new(),
+            received_at: in_past,
+            expires_at: None,
+            offset: 0,
+            partition: 0,
+        };
+
+        assert!(!TopicController::check_duplicate_and_update_compaction_map(
+            &message1,
+            &mut compaction_map,
+            compaction_window
+        ));
+        assert!(!TopicController::check_duplicate_and_update_compaction_map(
+            &message2,
+            &mut compaction_map,
+            compaction_window
+        )); // Второе сообщение прошло, потому что другой ключ
+    }
+
+    #[test]
+    fn test_dedup_skipped_for_messages_without_key() {
+        let mut compaction_map = CompactionMap::default();
+        let compaction_window = time::Duration::from_millis(5000);
+
+        let message = Message {
+            key: None,
+            payload: vec![1, 2, 3, 4].into(),
+            headers: HashMap::new(),
+            received_at: time::Instant::now(),
+            expires_at: None,
+            offset: 0,
+            partition: 0,
+        };
+
+        assert!(!TopicController::check_duplicate_and_update_compaction_map(
+            &message,
+            &mut compaction_map,
+            compaction_window
+        ));
+        assert!(!TopicController::check_duplicate_and_update_compaction_map(
+            &message,
+            &mut compaction_map,
+            compaction_window
+        )); // Сообщения без ключа не компактятся
+        assert!(compaction_map.is_empty());
+    }
+
+    #[test]
+    fn test_compaction_map_is_capped_by_evicting_oldest_keys() {
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            0,
+            60_000,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        )
+        .with_compaction_max_keys(3);
+
+        for key in 0..10 {
+            topic_controller.publish(
+                Some(key.to_string()),
+                vec![key],
+                HashMap::new(),
+                time::Instant::now(),
+            );
+            assert!(topic_controller.compaction_keys() <= 3);
+        }
+        assert_eq!(3, topic_controller.compaction_keys());
+
+        // Последние ключи все еще отсекают дубли, а самый старый уже забыт.
+        for key in 7..10 {
+            assert!(topic_controller
+                .compaction_map
+                .get(&key.to_string())
+                .is_some());
+        }
+        assert!(topic_controller.compaction_map.get("0").is_none());
+
+        // Забытый ключ снова проходит и вытесняет следующий по старшинству.
+        topic_controller.publish(
+            Some("0".to_string()),
+            vec![0],
+            HashMap::new(),
+            time::Instant::now(),
+        );
+        assert!(topic_controller.compaction_map.get("0").is_some());
+        assert!(topic_controller.compaction_map.get("7").is_none());
+        assert_eq!(3, topic_controller.compaction_keys());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_shares_retained_payloads_instead_of_copying() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 10, 0, CompactionMode::Dedup, 0);
+        for i in 0..4u8 {
+            topic_controller.publish(
+                None,
+                vec![i; 1024 * 1024],
+                HashMap::new(),
+                time::Instant::now(),
+            );
+        }
+
+        // Каждый подписчик получает ссылку на те же байты, что лежат в буфере.
+        for _ in 0..3 {
+            let received = topic_controller
+                .subscribe(DeliveryStart::Earliest)
+                .take(4)
+                .map(|message| message.unwrap())
+                .collect::<Vec<_>>()
+                .await;
+
+            for (retained, received) in topic_controller.retained_buffer.iter().zip(&received) {
+                assert!(Arc::ptr_eq(&retained.payload, &received.payload));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_after_offset_skips_already_received_messages() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 10, 0, CompactionMode::Dedup, 0);
+        for i in 0..5u8 {
+            topic_controller.publish(None, vec![i], HashMap::new(), time::Instant::now());
+        }
+
+        let subscription = topic_controller.subscribe_after(DeliveryStart::Latest, Some(2));
+        topic_controller.publish(None, vec![5], HashMap::new(), time::Instant::now());
+
+        let received = subscription
+            .take(3)
+            .map(|message| {
+                let message = message.unwrap();
+                (message.offset(), message.payload[0])
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(vec![(3, 3), (4, 4), (5, 5)], received);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_after_evicted_offset_starts_from_earliest_retained() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 2, 0, CompactionMode::Dedup, 0);
+        for i in 0..5u8 {
+            topic_controller.publish(None, vec![i], HashMap::new(), time::Instant::now());
+        }
+
+        // Сообщения с offset 1 и 2 уже вытеснены, в буфере остались только 3 и 4.
+        let received = topic_controller
+            .subscribe_after(DeliveryStart::Earliest, Some(0))
+            .take(2)
+            .map(|message| message.unwrap().offset())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(vec![3, 4], received);
+    }
+
+    #[test]
+    fn test_retention_by_message_count_evicts_oldest() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 3, 0, CompactionMode::Dedup, 0);
+
+        for i in 0..5u8 {
+            topic_controller.publish(None, vec![i], HashMap::new(), time::Instant::now());
+        }
+
+        let retained = topic_controller
+            .retained_buffer
+            .iter()
+            .map(|message| message.payload.to_vec())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![vec![2], vec![3], vec![4]], retained);
+    }
+
+    #[test]
+    fn test_retention_by_message_count_with_ttl() {
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            10_000,
+            0,
+            2,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+
+        for i in 0..3u8 {
+            topic_controller.publish(None, vec![i], HashMap::new(), time::Instant::now());
+        }
+
+        assert_eq!(2, topic_controller.retained_buffer.len());
+        assert_eq!(vec![1], *topic_controller.retained_buffer[0].payload);
+        assert_eq!(vec![2], *topic_controller.retained_buffer[1].payload);
+    }
+
+    fn retained_payload_bytes(topic_controller: &TopicController) -> usize {
+        topic_controller
+            .retained_buffer
+            .iter()
+            .map(|message| message.payload.len())
+            .sum()
+    }
+
+    #[test]
+    fn test_retention_by_bytes_stays_within_budget() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 10, CompactionMode::Dedup, 0);
+
+        for i in 0..5u8 {
+            topic_controller.publish(None, vec![i; 4], HashMap::new(), time::Instant::now());
+
+            assert!(topic_controller.retained_bytes <= 10);
+            assert_eq!(
+                retained_payload_bytes(&topic_controller),
+                topic_controller.retained_bytes
+            );
+        }
+
+        // Влезают только два последних сообщения по 4 байта.
+        assert_eq!(2, topic_controller.retained_buffer.len());
+        assert_eq!(vec![3; 4], *topic_controller.retained_buffer[0].payload);
+        assert_eq!(vec![4; 4], *topic_controller.retained_buffer[1].payload);
+    }
+
+    #[test]
+    fn test_retention_by_bytes_consistent_after_ttl_eviction() {
+        let clock = Arc::new(ManualClock::new());
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 1, 0, 0, 100, CompactionMode::Dedup, 0)
+                .with_clock(clock.clone());
+
+        topic_controller.publish(None, vec![1; 10], HashMap::new(), clock.now());
+        assert_eq!(10, topic_controller.retained_bytes);
+
+        clock.advance(time::Duration::from_millis(10));
+        topic_controller.clean_outdated_retained_messages();
+
+        assert!(topic_controller.retained_buffer.is_empty());
+        assert_eq!(0, topic_controller.retained_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_key_latest_keeps_only_latest_message_per_key() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::KeyLatest, 0);
+
+        let now = time::Instant::now();
+        topic_controller.publish(Some("first".to_string()), vec![1], HashMap::new(), now);
+        topic_controller.publish(Some("second".to_string()), vec![2], HashMap::new(), now);
+        topic_controller.publish(Some("first".to_string()), vec![3], HashMap::new(), now);
+
+        // Новый подписчик получает по одному сообщению на ключ, с последним payload.
+        let received = topic_controller
+            .subscribe(DeliveryStart::Earliest)
+            .take(2)
+            .map(|message| {
+                let message = message.unwrap();
+                (message.key.unwrap(), message.payload.to_vec())
+            })
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            vec![
+                ("second".to_string(), vec![2]),
+                ("first".to_string(), vec![3])
+            ],
+            received
+        );
+        assert_eq!(2, topic_controller.retained_buffer.len());
+        assert_eq!(2, topic_controller.retained_bytes);
+    }
+
+    #[test]
+    fn test_explicit_key_source_ignores_payload() {
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            0,
+            10_000,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+        let now = time::Instant::now();
+        let mut publish = |key: Option<&str>, payload: &[u8]| {
+            topic_controller.publish_checked(
+                key.map(str::to_string),
+                payload.to_vec(),
+                HashMap::new(),
+                now,
+                None,
+            )
+        };
+
+        // Одинаковый JSON без ключа не дедуплицируется, а явный ключ - да.
+        let payload = br#"{"id": "a"}"#;
+        assert!(matches!(
+            publish(None, payload),
+            PublishOutcome::Published(None)
+        ));
+        assert!(matches!(
+            publish(None, payload),
+            PublishOutcome::Published(None)
+        ));
+        assert!(matches!(
+            publish(Some("a"), b"1"),
+            PublishOutcome::Published(None)
+        ));
+        assert!(matches!(
+            publish(Some("a"), b"2"),
+            PublishOutcome::Duplicate
+        ));
+    }
+
+    #[test]
+    fn test_stats_count_duplicates_dropped_by_compaction() {
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            60_000,
+            60_000,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+        let now = time::Instant::now();
+
+        // Ключ a публикуем трижды, b дважды, c один раз, и одно сообщение без ключа.
+        for key in &[
+            Some("a"),
+            Some("b"),
+            Some("a"),
+            Some("c"),
+            Some("b"),
+            Some("a"),
+            None,
+        ] {
+            topic_controller.publish(key.map(str::to_string), vec![1], HashMap::new(), now);
+        }
+
+        let stats = topic_controller.stats();
+        assert_eq!(7, stats.published_messages());
+        assert_eq!(3, stats.duplicate_drops());
+        assert_eq!(4, stats.kept_messages());
+        assert_eq!(4, topic_controller.retained_len());
+    }
+
+    #[test]
+    fn test_json_pointer_key_source_dedups_by_payload_field() {
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            0,
+            10_000,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        )
+        .with_key_source(KeySource::JsonPointer("/order/id".to_string()));
+        let now = time::Instant::now();
+        let mut publish = |key: Option<&str>, payload: &[u8]| {
+            topic_controller.publish_checked(
+                key.map(str::to_string),
+                payload.to_vec(),
+                HashMap::new(),
+                now,
+                None,
+            )
+        };
+
+        assert!(matches!(
+            publish(None, br#"{"order": {"id": "a", "n": 1}}"#),
+            PublishOutcome::Published(None)
+        ));
+        // Ключ из payload заменяет явный ключ клиента.
+        assert!(matches!(
+            publish(Some("b"), br#"{"order": {"id": "a", "n": 2}}"#),
+            PublishOutcome::Duplicate
+        ));
+        assert!(matches!(
+            publish(None, br#"{"order": {"id": 7}}"#),
+            PublishOutcome::Published(None)
+        ));
+        assert!(matches!(
+            publish(None, br#"{"order": {"id": 7}}"#),
+            PublishOutcome::Duplicate
+        ));
+    }
+
+    #[test]
+    fn test_json_pointer_key_source_falls_back_to_unkeyed() {
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            0,
+            10_000,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        )
+        .with_key_source(KeySource::JsonPointer("/id".to_string()));
+        let now = time::Instant::now();
+
+        // Не JSON, нет поля, поле не скаляр - сообщение публикуется без ключа
+        // и поэтому никогда не считается дубликатом.
+        for payload in [
+            &b"not json"[..],
+            &br#"{"other": 1}"#[..],
+            &br#"{"id": {"nested": 1}}"#[..],
+            &br#"{"id": null}"#[..],
+            &br#"[1, 2]"#[..],
+        ]
+        .iter()
+        {
+            for _ in 0..2 {
+                let outcome = topic_controller.publish_checked(
+                    Some("explicit".to_string()),
+                    payload.to_vec(),
+                    HashMap::new(),
+                    now,
+                    None,
+                );
+                assert!(matches!(outcome, PublishOutcome::Published(None)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_same_key_messages_land_in_same_partition_in_order() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 0)
+                .with_partitions(4);
+        let mut partitions = (0..4)
+            .map(|partition| {
+                Box::pin(topic_controller.subscribe_partition(
+                    DeliveryStart::Latest,
+                    None,
+                    Some(partition),
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        let keys = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let now = time::Instant::now();
+        for seq in 0..5u8 {
+            for (index, key) in keys.iter().enumerate() {
+                topic_controller.publish(
+                    Some(key.to_string()),
+                    vec![index as u8, seq],
+                    HashMap::new(),
+                    now,
+                );
+            }
+        }
+
+        // Каждый ключ целиком в одной партиции, и его сообщения идут по порядку.
+        let mut partition_of_key = HashMap::new();
+        let mut received = 0;
+        for (partition, stream) in partitions.iter_mut().enumerate() {
+            let mut next_seq = HashMap::new();
+            while received < keys.len() * 5 {
+                let message = match tokio::time::timeout(
+                    time::Duration::from_millis(50),
+                    stream.next(),
+                )
+                .await
+                {
+                    Ok(Some(message)) => message.unwrap(),
+                    _ => break,
+                };
+                received += 1;
+
+                let key = message.key.clone().unwrap();
+                assert_eq!(partition as u32, message.partition);
+                assert_eq!(
+                    partition,
+                    *partition_of_key.entry(key.clone()).or_insert(partition)
+                );
+                let seq = next_seq.entry(key).or_insert(0u8);
+                assert_eq!(*seq, message.payload[1]);
+                *seq += 1;
+            }
+        }
+
+        assert_eq!(keys.len() * 5, received);
+        assert!(partition_of_key.values().collect::<HashSet<_>>().len() > 1);
+    }
+
+    #[test]
+    fn test_unkeyed_messages_are_spread_round_robin() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 100, 0, CompactionMode::Dedup, 0)
+                .with_partitions(3);
+
+        let now = time::Instant::now();
+        for _ in 0..6 {
+            topic_controller.publish(None, vec![1], HashMap::new(), now);
+        }
+
+        let partitions = topic_controller
+            .retained_buffer
+            .iter()
+            .map(|message| message.partition)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 1, 2, 0, 1, 2], partitions);
+        assert_eq!(
+            vec![1, 4],
+            topic_controller
+                .retained_after(DeliveryStart::Earliest, None, Some(1))
+                .iter()
+                .map(Message::offset)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscription_without_partition_receives_all_partitions() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 0)
+                .with_partitions(4);
+        let subscription = topic_controller.subscribe(DeliveryStart::Latest);
+
+        let now = time::Instant::now();
+        for index in 0..10u8 {
+            topic_controller.publish(Some(index.to_string()), vec![index], HashMap::new(), now);
+        }
+
+        let mut offsets = subscription
+            .take(10)
+            .map(|message| message.unwrap().offset())
+            .collect::<Vec<_>>()
+            .await;
+        offsets.sort_unstable();
+        assert_eq!((0..10).collect::<Vec<_>>(), offsets);
+    }
+
+    #[tokio::test]
+    async fn test_reliable_partition_subscriber_receives_only_its_partition() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 0)
+                .with_delivery(DeliveryGuarantee::Reliable)
+                .with_partitions(2);
+        let subscription =
+            topic_controller.subscribe_partition(DeliveryStart::Latest, None, Some(0));
+
+        let now = time::Instant::now();
+        for _ in 0..4 {
+            assert!(topic_controller
+                .publish(None, vec![1], HashMap::new(), now)
+                .is_none());
+        }
+        drop(topic_controller);
+
+        let offsets = subscription
+            .map(|message| message.unwrap().offset())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(vec![0, 2], offsets);
+    }
+
+    fn time_publishes(topic_controller: &mut TopicController, count: usize) -> time::Duration {
+        let started_at = time::Instant::now();
+        for _ in 0..count {
+            topic_controller.publish(None, vec![1], HashMap::new(), time::Instant::now());
+        }
+        started_at.elapsed()
+    }
+
+    #[test]
+    fn test_publish_latency_does_not_scale_with_buffer_length() {
+        let retention_ttl = 60 * 60 * 1000;
+
+        let mut small_topic = TopicController::new(
+            "small".to_string(),
+            retention_ttl,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+        let mut large_topic = TopicController::new(
+            "large".to_string(),
+            retention_ttl,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+
+        // Заполняем буфер большого топика заранее.
+        time_publishes(&mut large_topic, 20_000);
+
+        let with_small_buffer = time_publishes(&mut small_topic, 5_000);
+        let with_large_buffer = time_publishes(&mut large_topic, 5_000);
+
+        // Если бы на каждый publish проходили весь буфер, то большой топик
+        // публиковал бы на порядки медленнее.
+        assert!(
+            with_large_buffer < with_small_buffer * 10 + time::Duration::from_millis(50),
+            "small buffer: {:?}, large buffer: {:?}",
+            with_small_buffer,
+            with_large_buffer
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_skips_expired_messages_before_cleanup() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 1, 0, 0, 0, CompactionMode::Dedup, 0);
+
+        let in_past = time::Instant::now()
+            .checked_sub(time::Duration::from_millis(5000))
+            .unwrap();
+        topic_controller.publish(None, vec![1], HashMap::new(), in_past);
+        topic_controller.publish(
+            None,
+            vec![2],
+            HashMap::new(),
+            time::Instant::now() + time::Duration::from_secs(60),
+        );
+
+        // Истекшее сообщение все еще в буфере, но подписчик его не получит.
+        assert_eq!(2, topic_controller.retained_buffer.len());
+
+        let received = topic_controller
+            .subscribe(DeliveryStart::Earliest)
+            .take(1)
+            .map(|message| message.unwrap().payload.to_vec())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(vec![vec![2]], received);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_from_earliest_replays_retained_messages() {
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+        let now = time::Instant::now();
+
+        topic_controller.publish(None, vec![1], HashMap::new(), now);
+        topic_controller.publish(None, vec![2], HashMap::new(), now);
+
+        let subscription = topic_controller.subscribe(DeliveryStart::Earliest);
+        topic_controller.publish(None, vec![3], HashMap::new(), now);
+
+        let received = subscription
+            .take(3)
+            .map(|message| message.unwrap().payload.to_vec())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(vec![vec![1], vec![2], vec![3]], received);
+    }
+
+    #[tokio::test]
+    async fn test_small_broadcast_capacity_does_not_limit_retained_history() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 100, 0, CompactionMode::Dedup, 2);
+        let now = time::Instant::now();
+
+        let mut live = Box::pin(topic_controller.subscribe(DeliveryStart::Latest));
+        for payload in 0..10 {
+            topic_controller.publish(None, vec![payload], HashMap::new(), now);
+        }
+
+        // Подписчик, который не читал, отстал больше чем на размер канала...
+        assert!(matches!(
+            live.next().await,
+            Some(Err(broadcast::RecvError::Lagged(_)))
+        ));
+
+        // ...а новый подписчик все равно получает всю историю из retained сообщений.
+        let received = topic_controller
+            .subscribe(DeliveryStart::Earliest)
+            .take(10)
+            .map(|message| message.unwrap().payload.to_vec())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            (0..10).map(|payload| vec![payload]).collect::<Vec<_>>(),
+            received
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_from_latest_skips_retained_messages() {
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+        let now = time::Instant::now();
+
+        topic_controller.publish(None, vec![1], HashMap::new(), now);
+        topic_controller.publish(None, vec![2], HashMap::new(), now);
+
+        let subscription = topic_controller.subscribe(DeliveryStart::Latest);
+        topic_controller.publish(None, vec![3], HashMap::new(), now);
+
+        let received = subscription
+            .take(1)
+            .map(|message| message.unwrap().payload.to_vec())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(vec![vec![3]], received);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_subscription_gets_latest_per_key_then_live_messages() {
+        // Retention без compaction хранит все версии ключей.
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+        let now = time::Instant::now();
+        let messages = vec![
+            (Some("a"), 1),
+            (Some("b"), 2),
+            (None, 3),
+            (Some("a"), 4),
+            (None, 5),
+            (Some("a"), 6),
+        ];
+        for (key, payload) in messages {
+            topic_controller.publish(key.map(str::to_string), vec![payload], HashMap::new(), now);
+        }
+
+        let subscription = topic_controller.subscribe(DeliveryStart::SnapshotOnly);
+        topic_controller.publish(Some("b".to_string()), vec![7], HashMap::new(), now);
+
+        let received = subscription
+            .take(4)
+            .map(|message| message.unwrap().payload.to_vec())
+            .collect::<Vec<_>>()
+            .await;
+
+        // Сначала последнее по каждому ключу и последнее без ключа, в порядке offset,
+        // потом опубликованное после подписки.
+        assert_eq!(vec![vec![2], vec![5], vec![6], vec![7]], received);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_during_concurrent_publish_has_no_gaps_or_duplicates() {
+        const MESSAGES: u64 = 2_000;
+        const SUBSCRIBERS: usize = 20;
+
+        let topic_controller = Arc::new(std::sync::RwLock::new(TopicController::new(
+            "test".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+            MESSAGES as u32,
+        )));
+
+        let publisher = {
+            let topic_controller = topic_controller.clone();
+            std::thread::spawn(move || {
+                for i in 0..MESSAGES {
+                    topic_controller.write_or_recover().publish(
+                        None,
+                        i.to_be_bytes().to_vec(),
+                        HashMap::new(),
+                        time::Instant::now(),
+                    );
+                }
+            })
+        };
+
+        // Подписываемся в разные моменты, пока издатель пишет в топик.
+        let mut subscriptions = Vec::new();
+        for _ in 0..SUBSCRIBERS {
+            subscriptions.push(Box::pin(
+                topic_controller
+                    .read_or_recover()
+                    .subscribe(DeliveryStart::Earliest),
+            ));
+            std::thread::yield_now();
+        }
+        publisher.join().unwrap();
+
+        for subscription in subscriptions {
+            let offsets = subscription
+                .take(MESSAGES as usize)
+                .map(|message| message.unwrap().offset())
+                .collect::<Vec<_>>()
+                .await;
+
+            assert_eq!((0..MESSAGES).collect::<Vec<_>>(), offsets);
+        }
+    }
+
+    #[test]
+    fn test_cleaning_retained_buffer_with_mixed_expiry() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 10, 0, CompactionMode::Dedup, 0);
+
+        let now = time::Instant::now();
+        let in_past = now.checked_sub(time::Duration::from_millis(5000)).unwrap();
+
+        let message = |payload: u8, expires_at: Option<time::Instant>| Message {
+            key: None,
+            payload: vec![payload].into(),
+            headers: HashMap::new(),
+            received_at: now,
+            expires_at,
+            offset: 0,
+            partition: 0,
+        };
+
+        topic_controller.retained_buffer = vec![
+            message(1, None),
+            message(2, Some(in_past)),
+            message(3, Some(now + time::Duration::from_secs(60))),
+            message(4, None),
+        ];
+        topic_controller.retained_bytes = 4;
+
+        topic_controller.clean_outdated_retained_messages();
+
+        let survivors = topic_controller
+            .retained_buffer
+            .iter()
+            .map(|message| message.payload[0])
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![1, 3, 4], survivors);
+        assert_eq!(3, topic_controller.retained_bytes);
+    }
+
+    #[test]
+    fn test_group_members_split_messages_evenly() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 0);
+
+        let (_first_id, mut first) = topic_controller.join_group("group");
+        let (_second_id, mut second) = topic_controller.join_group("group");
+
+        for i in 0..100u8 {
+            topic_controller.publish(None, vec![i], HashMap::new(), time::Instant::now());
+        }
+
+        let drain = |receiver: &mut mpsc::Receiver<Message>| {
+            std::iter::from_fn(|| receiver.try_recv().ok())
+                .map(|message| message.payload[0])
+                .collect::<Vec<_>>()
+        };
+        let first_payloads = drain(&mut first);
+        let second_payloads = drain(&mut second);
+
+        assert_eq!(50, first_payloads.len());
+        assert_eq!(50, second_payloads.len());
+
+        let mut all = first_payloads
+            .iter()
+            .chain(second_payloads.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        all.sort();
+        all.dedup();
+        assert_eq!((0..100u8).collect::<Vec<_>>(), all);
+
+        // Внутри одного участника сообщения идут в порядке топика.
+        assert!(first_payloads.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(second_payloads.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_messages_of_left_member_go_to_the_rest_of_group() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 0);
+
+        let (first_id, mut first) = topic_controller.join_group("group");
+        let (_second_id, mut second) = topic_controller.join_group("group");
+
+        topic_controller.publish(None, vec![1], HashMap::new(), time::Instant::now());
+        let unprocessed = first.try_recv().unwrap();
+
+        topic_controller.leave_group("group", first_id);
+        assert!(topic_controller
+            .return_to_group("group", vec![unprocessed])
+            .is_empty());
+
+        assert_eq!(vec![1], *second.try_recv().unwrap().payload);
+    }
+
+    #[test]
+    fn test_group_is_removed_when_last_member_leaves() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 0);
+
+        let (member_id, _member) = topic_controller.join_group("group");
+        assert_eq!(1, topic_controller.subscriber_count());
+
+        topic_controller.leave_group("group", member_id);
+        for i in 0..10u8 {
+            topic_controller.publish(None, vec![i], HashMap::new(), time::Instant::now());
+        }
+
+        // Сообщения без участников нигде не копятся.
+        assert!(topic_controller.groups.is_empty());
+        assert_eq!(0, topic_controller.subscriber_count());
+        assert!(topic_controller.return_to_group("group", vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_group_of_disconnected_members_is_removed_on_publish() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 0);
+
+        let (_member_id, member) = topic_controller.join_group("group");
+        drop(member);
+        topic_controller.publish(None, vec![1], HashMap::new(), time::Instant::now());
+
+        assert!(topic_controller.groups.is_empty());
+    }
+
+    #[test]
+    fn test_best_effort_group_skips_messages_when_members_are_full() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 2);
+
+        let (_member_id, mut member) = topic_controller.join_group("group");
+        for i in 0..5u8 {
+            assert!(topic_controller
+                .publish(None, vec![i], HashMap::new(), time::Instant::now())
+                .is_none());
+        }
+
+        // Очередь участника ограничена размером буфера топика.
+        assert_eq!(vec![0], *member.try_recv().unwrap().payload);
+        assert_eq!(vec![1], *member.try_recv().unwrap().payload);
+        assert!(member.try_recv().is_err());
+        assert!(!topic_controller.is_saturated());
+    }
+
+    #[tokio::test]
+    async fn test_reliable_publish_waits_for_full_group() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 1)
+                .with_delivery(DeliveryGuarantee::Reliable);
+        let (_member_id, mut member) = topic_controller.join_group("group");
+
+        let now = time::Instant::now();
+        assert!(topic_controller
+            .publish(None, vec![1], HashMap::new(), now)
+            .is_none());
+        assert!(topic_controller.is_saturated());
+        assert!(matches!(
+            topic_controller.try_publish(None, vec![2], HashMap::new(), now, None),
+            PublishOutcome::Saturated
+        ));
+
+        let pending = topic_controller
+            .publish(None, vec![3], HashMap::new(), now)
+            .unwrap();
+        let delivered = tokio::spawn(pending.deliver());
+
+        assert_eq!(vec![1], *member.recv().await.unwrap().payload);
+        assert_eq!(vec![3], *member.recv().await.unwrap().payload);
+        delivered.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retained_messages_survive_restart() {
+        let dir = std::env::temp_dir().join(format!(
+            "zaichik-test-{}-controller-restart",
+            std::process::id()
+        ));
+        let now = time::Instant::now();
+
+        {
+            let mut topic_controller = TopicController::new(
+                "test".to_string(),
+                60_000,
+                0,
+                0,
+                0,
+                CompactionMode::Dedup,
+                0,
+            );
+            topic_controller
+                .attach_log(TopicLog::open(&dir, "test").unwrap())
+                .unwrap();
+
+            topic_controller.publish(None, vec![1], HashMap::new(), now);
+            topic_controller.publish(None, vec![2], HashMap::new(), now);
+            // Это сообщение истечет к моменту "перезапуска" и не должно вернуться.
+            topic_controller.publish(
+                None,
+                vec![3],
+                HashMap::new(),
+                now.checked_sub(time::Duration::from_secs(120)).unwrap(),
+            );
+        }
+
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+        topic_controller
+            .attach_log(TopicLog::open(&dir, "test").unwrap())
+            .unwrap();
+        assert_eq!(2, topic_controller.retained_buffer.len());
+
+        let received = topic_controller
+            .subscribe(DeliveryStart::Earliest)
+            .take(2)
+            .map(|message| message.unwrap().payload.to_vec())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(vec![vec![1], vec![2]], received);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reliable_publish_waits_for_full_subscriber_queue() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 0, 0, CompactionMode::Dedup, 1)
+                .with_delivery(DeliveryGuarantee::Reliable);
+        assert!(topic_controller.delivery_order().is_some());
+
+        let mut subscription = Box::pin(topic_controller.subscribe(DeliveryStart::Earliest));
+        assert_eq!(1, topic_controller.subscriber_count());
+
+        // Первое сообщение помещается в очередь, второе издатель доставляет сам.
+        let now = time::Instant::now();
+        assert!(topic_controller
+            .publish(None, vec![1], HashMap::new(), now)
+            .is_none());
+        let pending = topic_controller
+            .publish(None, vec![2], HashMap::new(), now)
+            .unwrap();
+
+        let delivered = tokio::spawn(pending.deliver());
+        assert_eq!(
+            vec![1],
+            *subscription.next().await.unwrap().unwrap().payload
+        );
+        assert_eq!(
+            vec![2],
+            *subscription.next().await.unwrap().unwrap().payload
+        );
+        delivered.await.unwrap();
+
+        // Очередь отключившегося подписчика убирается на следующем publish.
+        drop(subscription);
+        assert!(topic_controller
+            .publish(None, vec![3], HashMap::new(), now)
+            .is_none());
+        assert_eq!(0, topic_controller.subscriber_count());
+    }
+
+    #[tokio::test]
+    async fn test_closed_reliable_subscribers_are_removed_without_publish() {
+        let topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 10, 0, CompactionMode::Dedup, 1)
+                .with_delivery(DeliveryGuarantee::Reliable);
+
+        let first = topic_controller.subscribe(DeliveryStart::Earliest);
+        let _second = topic_controller.subscribe(DeliveryStart::Earliest);
+        assert_eq!(2, topic_controller.subscriber_count());
+
+        drop(first);
+        topic_controller.remove_closed_subscribers();
+        assert_eq!(1, topic_controller.subscriber_count());
+    }
+
+    #[tokio::test]
+    async fn test_close_ends_reliable_and_group_subscriptions() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 10, 0, CompactionMode::Dedup, 10)
+                .with_delivery(DeliveryGuarantee::Reliable);
+        let now = time::Instant::now();
+        let mut subscription = Box::pin(topic_controller.subscribe(DeliveryStart::Earliest));
+        let (_member_id, mut member) = topic_controller.join_group("group");
+        assert!(topic_controller
+            .publish(None, vec![1], HashMap::new(), now)
+            .is_none());
+
+        topic_controller.close();
+        assert!(topic_controller
+            .publish(None, vec![2], HashMap::new(), now)
+            .is_none());
+
+        // Подписчики дочитывают то, что уже получили, и их стримы завершаются.
+        assert_eq!(
+            vec![1],
+            *subscription.next().await.unwrap().unwrap().payload
+        );
+        assert!(subscription.next().await.is_none());
+        assert_eq!(vec![1], *member.recv().await.unwrap().payload);
+        assert!(member.recv().await.is_none());
+
+        // Новая подписка на закрытый топик отдает только retained сообщения.
+        let mut late = Box::pin(topic_controller.subscribe(DeliveryStart::Earliest));
+        assert_eq!(vec![1], *late.next().await.unwrap().unwrap().payload);
+        assert!(late.next().await.is_none());
+        assert_eq!(0, topic_controller.subscriber_count());
+    }
+
+    #[tokio::test]
+    async fn test_try_publish_rejects_message_when_subscriber_queue_is_full() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 10, 0, CompactionMode::Dedup, 1)
+                .with_delivery(DeliveryGuarantee::Reliable);
+        let mut subscription = Box::pin(topic_controller.subscribe(DeliveryStart::Earliest));
+
+        let now = time::Instant::now();
+        let mut try_publish =
+            |payload| topic_controller.try_publish(None, vec![payload], HashMap::new(), now, None);
+        assert!(matches!(try_publish(1), PublishOutcome::Published(None)));
+        assert!(matches!(try_publish(2), PublishOutcome::Saturated));
+
+        // Отклоненное сообщение не записано в топик и не получило offset.
+        assert_eq!(1, topic_controller.retained_len());
+        let received = subscription.next().await.unwrap().unwrap();
+        assert_eq!((vec![1], 0), (received.payload.to_vec(), received.offset()));
+
+        // Подписчик разобрал очередь, и публикация снова проходит.
+        assert!(!topic_controller.is_saturated());
+        assert!(matches!(
+            topic_controller.try_publish(None, vec![3], HashMap::new(), now, None),
+            PublishOutcome::Published(None)
+        ));
+        assert_eq!(1, subscription.next().await.unwrap().unwrap().offset());
+    }
+
+    #[test]
+    fn test_delayed_messages_are_released_in_time_order() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 0, 0, 10, 0, CompactionMode::Dedup, 0);
+        let now = time::Instant::now();
+        let later = now + time::Duration::from_millis(200);
+
+        topic_controller.schedule(None, vec![2], HashMap::new(), later, None);
+        topic_controller.schedule(None, vec![1], HashMap::new(), now, None);
+        topic_controller.schedule(None, vec![3], HashMap::new(), later, None);
+        assert_eq!(3, topic_controller.delayed.len());
+
+        // Раньше своего времени сообщение не публикуется.
+        topic_controller.release_delayed(now);
+        assert_eq!(1, topic_controller.retained_len());
+
+        topic_controller.publish(None, vec![0], HashMap::new(), now);
+        topic_controller.release_delayed(later);
+        assert_eq!(0, topic_controller.delayed.len());
+
+        let payloads = topic_controller
+            .retained_buffer
+            .iter()
+            .map(|message| message.payload[0])
+            .collect::<Vec<_>>();
+        assert_eq!(vec![1, 0, 2, 3], payloads);
+    }
+
+    #[tokio::test]
+    async fn test_message_ttl_overrides_topic_retention_ttl() {
+        let mut topic_controller = TopicController::new(
+            "test".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            CompactionMode::Dedup,
+            0,
+        );
+        let ten_seconds_ago = time::Instant::now()
+            .checked_sub(time::Duration::from_secs(10))
+            .unwrap();
+
+        // Сообщение с retention_ttl топика еще живо, с коротким ttl уже истекло,
+        // а с ttl длиннее retention_ttl переживет сообщения топика.
+        topic_controller.publish(None, vec![1], HashMap::new(), ten_seconds_ago);
+        topic_controller.publish_with_ttl(
+            None,
+            vec![2],
+            HashMap::new(),
+            ten_seconds_ago,
+            Some(time::Duration::from_secs(1)),
+        );
+        topic_controller.publish_with_ttl(
+            None,
+            vec![3],
+            HashMap::new(),
+            ten_seconds_ago,
+            Some(time::Duration::from_secs(3600)),
+        );
+
+        let expires_in = topic_controller
+            .retained_buffer
+            .iter()
+            .map(|message| message.expires_at.unwrap() - ten_seconds_ago)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                time::Duration::from_secs(60),
+                time::Duration::from_secs(1),
+                time::Duration::from_secs(3600)
+            ],
+            expires_in
+        );
+
+        let received = topic_controller
+            .subscribe(DeliveryStart::Earliest)
+            .take(2)
+            .map(|message| message.unwrap().payload.to_vec())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(vec![vec![1], vec![3]], received);
+
+        topic_controller.clean_outdated_retained_messages();
+        assert_eq!(2, topic_controller.retained_len());
+    }
+
+    #[test]
+    fn test_huge_message_ttl_does_not_expire() {
+        let mut topic_controller =
+            TopicController::new("test".to_string(), 1000, 0, 0, 0, CompactionMode::Dedup, 0);
+
+        topic_controller.publish_with_ttl(
+            None,
+            vec![1],
+            HashMap::new(),
+            time::Instant::now(),
+            Some(time::Duration::from_secs(u64::MAX)),
+        );
+
+        assert_eq!(None, topic_controller.retained_buffer[0].expires_at);
     }
 }
@@ -1,40 +1,755 @@
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast;
 
-use crate::topic_controller::TopicController;
+use crate::locks::RwLockExt;
+use crate::protocol::{CompactionMode, DeliveryGuarantee, KeySource, OrderingGuarantee};
+use crate::storage::{self, TopicLog, TopicMeta};
+use crate::topic_controller::{
+    resolve_buffer_size, TopicController, TopicSettings, DEFAULT_BUFFER_SIZE,
+    DEFAULT_COMPACTION_MAX_KEYS,
+};
 
 pub type TopicName = String;
 
+// Максимальная длина имени топика в байтах, если ее не поменяли через
+// ZAICHIK_MAX_TOPIC_NAME_LEN.
+pub const DEFAULT_MAX_TOPIC_NAME_LEN: usize = 255;
+
+// Лимиты retention_ttl и compaction_window в миллисекундах, если их не поменяли
+// через ZAICHIK_MAX_RETENTION_TTL и ZAICHIK_MAX_COMPACTION_WINDOW: 30 и 7 дней.
+// Опечатка вроде 10_000_000_000 иначе дала бы почти вечное хранение и съела бы память.
+pub const DEFAULT_MAX_RETENTION_TTL: u64 = 30 * 24 * 60 * 60 * 1000;
+pub const DEFAULT_MAX_COMPACTION_WINDOW: u64 = 7 * 24 * 60 * 60 * 1000;
+
+// Что брокер делает с retention_ttl или compaction_window больше лимита.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitPolicy {
+    // Уменьшает значение до лимита и пишет предупреждение в лог.
+    Clamp,
+    // Отклоняет CreateTopic и Publish с create_with ошибкой ERROR_INVALID_TOPIC_SETTINGS.
+    Reject,
+}
+
+// Лимиты настроек топика. 0 в лимите значит, что лимита нет. Топики, которые
+// восстанавливаются с диска, всегда уменьшаются до лимитов, а не отклоняются.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TopicLimits {
+    pub max_retention_ttl: u64,
+    pub max_compaction_window: u64,
+    pub policy: LimitPolicy,
+}
+
+impl Default for TopicLimits {
+    fn default() -> TopicLimits {
+        TopicLimits {
+            max_retention_ttl: DEFAULT_MAX_RETENTION_TTL,
+            max_compaction_window: DEFAULT_MAX_COMPACTION_WINDOW,
+            policy: LimitPolicy::Clamp,
+        }
+    }
+}
+
+impl TopicLimits {
+    // Err с описанием первой настройки, которая больше своего лимита.
+    fn check(&self, retention_ttl: u64, compaction_window: u64) -> Result<(), String> {
+        let settings = [
+            ("retention_ttl", retention_ttl, self.max_retention_ttl),
+            (
+                "compaction_window",
+                compaction_window,
+                self.max_compaction_window,
+            ),
+        ];
+
+        for (name, value, max) in settings.iter() {
+            if *max != 0 && value > max {
+                return Err(format!(
+                    "{} is {} ms, should be at most {}",
+                    name, value, max
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn clamp(&self, meta: &mut TopicMeta) {
+        if let Err(message) = self.check(meta.retention_ttl, meta.compaction_window) {
+            warn!("Topic {}: {}, using the limit", meta.topic, message);
+        }
+        if self.max_retention_ttl != 0 {
+            meta.retention_ttl = meta.retention_ttl.min(self.max_retention_ttl);
+        }
+        if self.max_compaction_window != 0 {
+            meta.compaction_window = meta.compaction_window.min(self.max_compaction_window);
+        }
+    }
+}
+
+// Контроллер топика со своей блокировкой. Реестр отдает его по Arc, поэтому
+// блокировку реестра держат только на время поиска топика, а publish в разные
+// топики не ждут друг друга. Порядок внутри топика сохраняет его собственный RwLock.
+pub type TopicHandle = Arc<RwLock<TopicController>>;
+
+// Шаблон топиков для Subscribe - это имя, которое заканчивается на '*'.
+// '*' совпадает с любым непустым окончанием имени, в том числе с точками:
+// "logs.*" подходит для "logs.app1" и "logs.app1.errors", но не для "logs" и "logs.".
+// Звездочка в любом другом месте имени - обычный символ.
+pub fn is_topic_pattern(topic: &str) -> bool {
+    topic.ends_with('*')
+}
+
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => topic.len() > prefix.len() && topic.starts_with(prefix),
+        None => pattern == topic,
+    }
+}
+
+// Чем закончился CreateTopic. В каждом варианте настройки, с которыми работает топик.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CreateTopicOutcome {
+    Created(TopicSettings),
+    // Топик уже есть, и запрошенные настройки совпадают с его настройками.
+    AlreadyExists(TopicSettings),
+    // Топик уже есть, но с другими настройками. Топик при этом не меняется.
+    Conflict(TopicSettings),
+    // Имя топика не прошло validate_topic_name. Топик не создается.
+    InvalidName(String),
+    // Настройки больше лимитов брокера, а политика LimitPolicy::Reject.
+    InvalidSettings(String),
+}
+
 #[derive(Debug)]
 pub struct TopicRegistry {
-    pub topics: HashMap<TopicName, RwLock<TopicController>>,
+    pub topics: HashMap<TopicName, TopicHandle>,
+    // Имена удаленных топиков, которые еще не были созданы заново.
+    deleted_topics: HashSet<TopicName>,
+    // Директория, где хранятся retained сообщения. None - храним только в памяти.
+    storage_dir: Option<PathBuf>,
+    // Имена созданных топиков для подписок по шаблону.
+    created_topics: broadcast::Sender<TopicName>,
+    default_buffer_size: u32,
+    max_topic_name_len: usize,
+    topic_limits: TopicLimits,
+    compaction_max_keys: usize,
 }
 
 impl TopicRegistry {
     pub fn new() -> TopicRegistry {
         TopicRegistry {
             topics: HashMap::new(),
+            deleted_topics: HashSet::new(),
+            storage_dir: None,
+            created_topics: broadcast::channel(1024).0,
+            default_buffer_size: DEFAULT_BUFFER_SIZE,
+            max_topic_name_len: DEFAULT_MAX_TOPIC_NAME_LEN,
+            topic_limits: TopicLimits::default(),
+            compaction_max_keys: DEFAULT_COMPACTION_MAX_KEYS,
         }
     }
 
+    // Размер broadcast канала для топиков, при создании которых он не указан,
+    // см. resolve_buffer_size. 0 значит DEFAULT_BUFFER_SIZE.
+    pub fn with_default_buffer_size(mut self, buffer_size: u32) -> TopicRegistry {
+        self.default_buffer_size = buffer_size;
+        self
+    }
+
+    pub fn with_max_topic_name_len(mut self, max_len: usize) -> TopicRegistry {
+        self.max_topic_name_len = max_len;
+        self
+    }
+
+    // Сколько ключей помнит Dedup compaction каждого топика, см. TopicSettings.
+    pub fn with_compaction_max_keys(mut self, compaction_max_keys: usize) -> TopicRegistry {
+        self.compaction_max_keys = compaction_max_keys;
+        self
+    }
+
+    // Вызывается до open_storage, чтобы лимиты касались и топиков с диска.
+    pub fn with_topic_limits(mut self, topic_limits: TopicLimits) -> TopicRegistry {
+        self.topic_limits = topic_limits;
+        self
+    }
+
+    // Err, если настройки больше лимитов и политика LimitPolicy::Reject.
+    // С LimitPolicy::Clamp такие настройки уменьшаются при создании топика.
+    pub fn validate_topic_settings(
+        &self,
+        retention_ttl: u64,
+        compaction_window: u64,
+    ) -> Result<(), String> {
+        match self.topic_limits.policy {
+            LimitPolicy::Clamp => Ok(()),
+            LimitPolicy::Reject => self.topic_limits.check(retention_ttl, compaction_window),
+        }
+    }
+
+    // Имя топика не должно быть пустым, длиннее max_topic_name_len байт
+    // и не должно содержать управляющих символов. Err описывает, что не так с именем.
+    pub fn validate_topic_name(&self, topic: &str) -> Result<(), String> {
+        if topic.is_empty() {
+            return Err("Topic name should not be empty".to_string());
+        }
+        if topic.len() > self.max_topic_name_len {
+            return Err(format!(
+                "Topic name is {} bytes long, should be at most {}",
+                topic.len(),
+                self.max_topic_name_len
+            ));
+        }
+        if topic.chars().any(char::is_control) {
+            return Err(format!(
+                "Topic name {:?} should not contain control characters",
+                topic
+            ));
+        }
+        Ok(())
+    }
+
+    // Реестр, который хранит retained сообщения в storage_dir. При старте
+    // топики, сохраненные там ранее, создаются заново вместе с их сообщениями.
+    // Вызывается после with_default_buffer_size, чтобы топики из старых .meta,
+    // где размер буфера не записан, получили настроенный размер.
+    pub fn open_storage(mut self, storage_dir: PathBuf) -> io::Result<TopicRegistry> {
+        let metas = storage::read_metas(&storage_dir)?;
+        self.storage_dir = Some(storage_dir);
+
+        for meta in metas {
+            info!("Recovering topic {} from disk", meta.topic);
+            self.create_topic_from_meta(meta);
+        }
+
+        Ok(self)
+    }
+
     pub fn create_topic(
         &mut self,
         topic: TopicName,
         retention_ttl: u64,
         compaction_window: u64,
-    ) -> Option<&RwLock<TopicController>> {
-        let topic_controller = RwLock::new(TopicController::new(
-            topic.clone(),
+        retention_max_messages: u64,
+        retention_max_bytes: u64,
+        compaction_mode: CompactionMode,
+    ) -> TopicHandle {
+        self.create_topic_from_meta(TopicMeta {
+            topic,
             retention_ttl,
             compaction_window,
-            10_000,
+            retention_max_messages,
+            retention_max_bytes,
+            compaction_mode,
+            buffer_size: 0,
+            delivery: DeliveryGuarantee::BestEffort,
+            key_source: KeySource::Explicit,
+            partitions: 0,
+            ordering: OrderingGuarantee::None,
+            max_message_bytes: 0,
+        })
+    }
+
+    // Создает топик, если его еще нет. Если есть, то сравнивает его настройки
+    // с запрошенными после той же нормализации, что проходит новый топик.
+    pub fn create_topic_if_absent(&mut self, mut meta: TopicMeta) -> CreateTopicOutcome {
+        if let Err(message) = self.validate_topic_name(&meta.topic) {
+            return CreateTopicOutcome::InvalidName(message);
+        }
+        if let Err(message) =
+            self.validate_topic_settings(meta.retention_ttl, meta.compaction_window)
+        {
+            return CreateTopicOutcome::InvalidSettings(message);
+        }
+        self.topic_limits.clamp(&mut meta);
+
+        if let Some(topic_controller) = self.topics.get(&meta.topic) {
+            let existing = topic_controller.read_or_recover().settings().clone();
+            let requested = TopicSettings::new(
+                meta.retention_ttl,
+                meta.compaction_window,
+                meta.retention_max_messages,
+                meta.retention_max_bytes,
+                meta.compaction_mode,
+                resolve_buffer_size(meta.buffer_size, self.default_buffer_size),
+            )
+            .with_delivery(meta.delivery)
+            .with_key_source(meta.key_source.clone())
+            .with_partitions(meta.partitions)
+            .with_ordering(meta.ordering)
+            .with_max_message_bytes(meta.max_message_bytes)
+            .with_compaction_max_keys(self.compaction_max_keys);
+
+            return if requested == existing {
+                CreateTopicOutcome::AlreadyExists(existing)
+            } else {
+                CreateTopicOutcome::Conflict(existing)
+            };
+        }
+
+        let topic_controller = self.create_topic_from_meta(meta);
+        let settings = topic_controller.read_or_recover().settings().clone();
+        CreateTopicOutcome::Created(settings)
+    }
+
+    // То же, что create_topic, но с размером буфера. buffer_size = 0 заменяется
+    // на размер по умолчанию, настройки больше лимитов уменьшаются до них,
+    // и в .meta сохраняются уже настоящие значения.
+    pub fn create_topic_from_meta(&mut self, mut meta: TopicMeta) -> TopicHandle {
+        meta.buffer_size = resolve_buffer_size(meta.buffer_size, self.default_buffer_size);
+        self.topic_limits.clamp(&mut meta);
+        let topic = meta.topic.clone();
+
+        let mut topic_controller = TopicController::new(
+            topic.clone(),
+            meta.retention_ttl,
+            meta.compaction_window,
+            meta.retention_max_messages,
+            meta.retention_max_bytes,
+            meta.compaction_mode,
+            meta.buffer_size,
+        )
+        .with_delivery(meta.delivery)
+        .with_key_source(meta.key_source.clone())
+        .with_partitions(meta.partitions)
+        .with_ordering(meta.ordering)
+        .with_max_message_bytes(meta.max_message_bytes)
+        .with_compaction_max_keys(self.compaction_max_keys);
+
+        // Если включено хранение на диске, то сохраняем настройки топика
+        // и подключаем к нему лог. Ошибки диска не мешают работе топика в памяти.
+        if let (Some(storage_dir), true) = (&self.storage_dir, topic_controller.retains_messages())
+        {
+            let attached = storage::write_meta(storage_dir, &meta)
+                .and_then(|_| TopicLog::open(storage_dir, &topic))
+                .and_then(|log| topic_controller.attach_log(log));
+
+            if let Err(e) = attached {
+                error!("Failed to open log for topic {}; error = {:?}", topic, e);
+            }
+        }
+        let topic_controller = Arc::new(RwLock::new(topic_controller));
+
+        self.deleted_topics.remove(&topic);
+        self.topics.insert(topic.clone(), topic_controller.clone());
+        // Ошибка значит только то, что сейчас никто не ждет новых топиков.
+        let _ = self.created_topics.send(topic);
+        topic_controller
+    }
+
+    // Стрим имен топиков, созданных после вызова.
+    pub fn watch_created_topics(&self) -> broadcast::Receiver<TopicName> {
+        self.created_topics.subscribe()
+    }
+
+    // Существующие топики, подходящие под шаблон, в алфавитном порядке.
+    pub fn matching_topics(&self, pattern: &str) -> Vec<TopicName> {
+        self.topic_names()
+            .into_iter()
+            .filter(|topic| topic_matches(pattern, topic))
+            .collect()
+    }
+
+    // Топик ищется по &str, чтобы не создавать String на каждый publish и subscribe.
+    pub fn get_topic(&self, topic: &str) -> Option<TopicHandle> {
+        self.topics.get(topic).cloned()
+    }
+
+    // Имена всех топиков в алфавитном порядке.
+    pub fn topic_names(&self) -> Vec<TopicName> {
+        let mut names = self.topics.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    // Удаляем контроллер топика и закрываем его, поэтому стримы всех текущих
+    // подписчиков на этот топик завершатся, даже если кто-то еще держит его TopicHandle.
+    pub fn delete_topic(&mut self, topic: &str) -> bool {
+        let deleted = match self.topics.remove(topic) {
+            Some(topic_controller) => {
+                topic_controller.write_or_recover().close();
+                true
+            }
+            None => false,
+        };
+        if deleted {
+            self.deleted_topics.insert(topic.to_string());
+
+            if let Some(storage_dir) = &self.storage_dir {
+                if let Err(e) = storage::remove_topic(storage_dir, topic) {
+                    error!("Failed to remove log for topic {}; error = {:?}", topic, e);
+                }
+            }
+        }
+        deleted
+    }
+
+    // Топик был удален и с тех пор не создавался.
+    pub fn is_deleted(&self, topic: &str) -> bool {
+        self.deleted_topics.contains(topic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DeliveryStart;
+    use std::time;
+    use tokio::stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_deleted_topic_is_recreated_empty() {
+        let mut registry = TopicRegistry::new();
+        registry.create_topic("topic".to_string(), 10_000, 0, 0, 0, CompactionMode::Dedup);
+
+        let topic_controller = registry.get_topic("topic").unwrap();
+        topic_controller.write().unwrap().publish(
+            None,
+            vec![1],
+            HashMap::new(),
+            time::Instant::now(),
+        );
+        let mut old_subscription = Box::pin(
+            topic_controller
+                .read()
+                .unwrap()
+                .subscribe(DeliveryStart::Earliest),
+        );
+
+        assert!(registry.delete_topic("topic"));
+        assert!(registry.get_topic("topic").is_none());
+        assert!(registry.is_deleted("topic"));
+        assert!(!registry.delete_topic("topic"));
+
+        // Старый подписчик дочитывает retained сообщения, после чего его стрим завершается.
+        assert_eq!(
+            vec![1],
+            *old_subscription.next().await.unwrap().unwrap().payload
+        );
+        assert!(old_subscription.next().await.is_none());
+
+        // Топик с тем же именем создается заново и уже без старых сообщений.
+        registry.create_topic("topic".to_string(), 10_000, 0, 0, 0, CompactionMode::Dedup);
+        assert!(!registry.is_deleted("topic"));
+        let mut new_subscription = Box::pin(
+            registry
+                .get_topic("topic")
+                .unwrap()
+                .read()
+                .unwrap()
+                .subscribe(DeliveryStart::Earliest),
+        );
+
+        let next =
+            tokio::time::timeout(time::Duration::from_millis(50), new_subscription.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[test]
+    fn test_topic_names() {
+        let mut registry = TopicRegistry::new();
+        assert!(registry.topic_names().is_empty());
+
+        for topic in &["orders", "events", "logs"] {
+            registry.create_topic(topic.to_string(), 0, 0, 0, 0, CompactionMode::Dedup);
+        }
+
+        assert_eq!(vec!["events", "logs", "orders"], registry.topic_names());
+    }
+
+    #[test]
+    fn test_validate_topic_name() {
+        let registry = TopicRegistry::new().with_max_topic_name_len(8);
+
+        assert!(registry.validate_topic_name("orders").is_ok());
+        assert!(registry.validate_topic_name("orders.1").is_ok());
+        assert!(registry.validate_topic_name("logs.*").is_ok());
+
+        assert!(registry.validate_topic_name("").is_err());
+        assert!(registry.validate_topic_name("orders.10").is_err());
+        assert!(registry.validate_topic_name("ord\ners").is_err());
+        assert!(registry.validate_topic_name("ord\u{0}").is_err());
+    }
+
+    #[test]
+    fn test_create_topic_if_absent_rejects_invalid_names() {
+        let mut registry = TopicRegistry::new().with_max_topic_name_len(8);
+        let meta = |topic: &str| TopicMeta {
+            topic: topic.to_string(),
+            retention_ttl: 0,
+            compaction_window: 0,
+            retention_max_messages: 0,
+            retention_max_bytes: 0,
+            compaction_mode: CompactionMode::Dedup,
+            buffer_size: 0,
+            delivery: DeliveryGuarantee::BestEffort,
+            key_source: KeySource::Explicit,
+            partitions: 0,
+            ordering: OrderingGuarantee::None,
+            max_message_bytes: 0,
+        };
+
+        for topic in &["", "too.long.topic"] {
+            match registry.create_topic_if_absent(meta(topic)) {
+                CreateTopicOutcome::InvalidName(_) => {}
+                other => panic!("Expected invalid name for {:?}, got {:?}", topic, other),
+            }
+        }
+        assert!(registry.topic_names().is_empty());
+
+        match registry.create_topic_if_absent(meta("orders")) {
+            CreateTopicOutcome::Created(_) => {}
+            other => panic!("Expected created topic, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_create_topic_if_absent_detects_conflicts() {
+        let mut registry = TopicRegistry::new().with_default_buffer_size(64);
+        let meta = |retention_ttl, buffer_size| TopicMeta {
+            topic: "orders".to_string(),
+            retention_ttl,
+            compaction_window: 0,
+            retention_max_messages: 0,
+            retention_max_bytes: 0,
+            compaction_mode: CompactionMode::Dedup,
+            buffer_size,
+            delivery: DeliveryGuarantee::BestEffort,
+            key_source: KeySource::Explicit,
+            partitions: 0,
+            ordering: OrderingGuarantee::None,
+            max_message_bytes: 0,
+        };
+
+        let settings = match registry.create_topic_if_absent(meta(1000, 0)) {
+            CreateTopicOutcome::Created(settings) => settings,
+            other => panic!("Expected created topic, got {:?}", other),
+        };
+        assert_eq!(64, settings.broadcast_capacity);
+
+        // Размер по умолчанию и тот же размер, указанный явно, не считаются конфликтом.
+        assert_eq!(
+            CreateTopicOutcome::AlreadyExists(settings.clone()),
+            registry.create_topic_if_absent(meta(1000, 0))
+        );
+        assert_eq!(
+            CreateTopicOutcome::AlreadyExists(settings.clone()),
+            registry.create_topic_if_absent(meta(1000, 64))
+        );
+
+        assert_eq!(
+            CreateTopicOutcome::Conflict(settings.clone()),
+            registry.create_topic_if_absent(meta(2000, 0))
+        );
+        assert_eq!(
+            CreateTopicOutcome::Conflict(settings),
+            registry.create_topic_if_absent(meta(1000, 128))
+        );
+    }
+
+    #[test]
+    fn test_buffer_size_resolution() {
+        // (buffer_size в CreateTopic, размер по умолчанию брокера, итоговый размер)
+        let cases: &[(u32, Option<u32>, u32)] = &[
+            (0, None, DEFAULT_BUFFER_SIZE),
+            (16, None, 16),
+            (0, Some(64), 64),
+            (16, Some(64), 16),
+            (0, Some(0), DEFAULT_BUFFER_SIZE),
+            (16, Some(0), 16),
+        ];
+
+        for &(requested, broker_default, expected) in cases {
+            let mut registry = TopicRegistry::new();
+            if let Some(broker_default) = broker_default {
+                registry = registry.with_default_buffer_size(broker_default);
+            }
+            let settings = match registry.create_topic_if_absent(TopicMeta {
+                topic: "orders".to_string(),
+                retention_ttl: 0,
+                compaction_window: 0,
+                retention_max_messages: 0,
+                retention_max_bytes: 0,
+                compaction_mode: CompactionMode::Dedup,
+                buffer_size: requested,
+                delivery: DeliveryGuarantee::BestEffort,
+                key_source: KeySource::Explicit,
+                partitions: 0,
+                ordering: OrderingGuarantee::None,
+                max_message_bytes: 0,
+            }) {
+                CreateTopicOutcome::Created(settings) => settings,
+                other => panic!("Expected created topic, got {:?}", other),
+            };
+
+            assert_eq!(
+                expected, settings.broadcast_capacity,
+                "requested = {}, broker default = {:?}",
+                requested, broker_default
+            );
+        }
+    }
+
+    #[test]
+    fn test_retention_ttl_and_compaction_window_limits() {
+        let limits = |policy| TopicLimits {
+            max_retention_ttl: 1000,
+            max_compaction_window: 500,
+            policy,
+        };
+        // (retention_ttl, compaction_window, настройки топика с Clamp, ошибка с Reject)
+        let cases: &[(u64, u64, (u64, u64), bool)] = &[
+            (999, 499, (999, 499), false),
+            (1000, 500, (1000, 500), false),
+            (1001, 0, (1000, 0), true),
+            (0, 501, (0, 500), true),
+            (10_000_000_000, 10_000_000_000, (1000, 500), true),
+        ];
+
+        for &(retention_ttl, compaction_window, clamped, rejected) in cases {
+            let meta = TopicMeta {
+                topic: "orders".to_string(),
+                retention_ttl,
+                compaction_window,
+                retention_max_messages: 0,
+                retention_max_bytes: 0,
+                compaction_mode: CompactionMode::Dedup,
+                buffer_size: 0,
+                delivery: DeliveryGuarantee::BestEffort,
+                key_source: KeySource::Explicit,
+                partitions: 0,
+                ordering: OrderingGuarantee::None,
+                max_message_bytes: 0,
+            };
+
+            let mut registry = TopicRegistry::new().with_topic_limits(limits(LimitPolicy::Clamp));
+            let settings = match registry.create_topic_if_absent(meta.clone()) {
+                CreateTopicOutcome::Created(settings) => settings,
+                other => panic!("Expected created topic, got {:?}", other),
+            };
+            let millis = |duration: Option<time::Duration>| {
+                duration.map_or(0, |duration| duration.as_millis() as u64)
+            };
+            assert_eq!(
+                clamped,
+                (
+                    millis(settings.retention_ttl),
+                    millis(settings.compaction_window)
+                ),
+                "retention_ttl = {}, compaction_window = {}",
+                retention_ttl,
+                compaction_window
+            );
+
+            let mut registry = TopicRegistry::new().with_topic_limits(limits(LimitPolicy::Reject));
+            let outcome = registry.create_topic_if_absent(meta);
+            assert_eq!(
+                rejected,
+                matches!(outcome, CreateTopicOutcome::InvalidSettings(_)),
+                "retention_ttl = {}, compaction_window = {}: {:?}",
+                retention_ttl,
+                compaction_window,
+                outcome
+            );
+            assert_eq!(rejected, registry.get_topic("orders").is_none());
+        }
+    }
+
+    #[test]
+    fn test_get_topic_by_str() {
+        let mut registry = TopicRegistry::new();
+        registry.create_topic("orders".to_string(), 0, 0, 0, 0, CompactionMode::Dedup);
+
+        let name: &str = "orders";
+        assert!(registry.get_topic(name).is_some());
+        assert!(registry.get_topic(&name[..3]).is_none());
+        assert!(registry.get_topic("unknown").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_storage_recovers_topics() {
+        let dir = std::env::temp_dir().join(format!(
+            "zaichik-test-{}-registry-storage",
+            std::process::id()
         ));
 
-        self.topics.insert(topic.clone(), topic_controller);
-        self.topics.get(&topic)
+        {
+            let mut registry = TopicRegistry::new().open_storage(dir.clone()).unwrap();
+            registry.create_topic("orders".to_string(), 60_000, 0, 0, 0, CompactionMode::Dedup);
+            registry.create_topic(
+                "removed".to_string(),
+                60_000,
+                0,
+                0,
+                0,
+                CompactionMode::Dedup,
+            );
+            // Топик без retention на диск не попадает.
+            registry.create_topic("events".to_string(), 0, 0, 0, 0, CompactionMode::Dedup);
+
+            registry
+                .get_topic("orders")
+                .unwrap()
+                .write()
+                .unwrap()
+                .publish(
+                    Some("key".to_string()),
+                    vec![1],
+                    HashMap::new(),
+                    time::Instant::now(),
+                );
+            assert!(registry.delete_topic("removed"));
+        }
+
+        let registry = TopicRegistry::new().open_storage(dir.clone()).unwrap();
+        assert_eq!(vec!["orders"], registry.topic_names());
+
+        let mut subscription = Box::pin(
+            registry
+                .get_topic("orders")
+                .unwrap()
+                .read()
+                .unwrap()
+                .subscribe(DeliveryStart::Earliest),
+        );
+        assert_eq!(
+            vec![1],
+            *subscription.next().await.unwrap().unwrap().payload
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_topic_pattern_matching() {
+        assert!(is_topic_pattern("logs.*"));
+        assert!(!is_topic_pattern("logs.app1"));
+
+        assert!(topic_matches("logs.*", "logs.app1"));
+        assert!(topic_matches("logs.*", "logs.app1.errors"));
+        assert!(!topic_matches("logs.*", "logs"));
+        assert!(!topic_matches("logs.*", "logs."));
+        assert!(!topic_matches("logs.*", "metrics.app1"));
+        assert!(!topic_matches("logs.*", "applogs.app1"));
+        assert!(topic_matches("*", "anything"));
+        assert!(topic_matches("logs.app1", "logs.app1"));
     }
 
-    pub fn get_topic(&self, topic: &str) -> Option<&RwLock<TopicController>> {
-        self.topics.get(topic)
+    #[tokio::test]
+    async fn test_created_topics_are_announced() {
+        let mut registry = TopicRegistry::new();
+        registry.create_topic("logs.app1".to_string(), 0, 0, 0, 0, CompactionMode::Dedup);
+
+        let mut created = registry.watch_created_topics();
+        registry.create_topic("logs.app2".to_string(), 0, 0, 0, 0, CompactionMode::Dedup);
+        registry.create_topic("metrics".to_string(), 0, 0, 0, 0, CompactionMode::Dedup);
+
+        assert_eq!("logs.app2", created.recv().await.unwrap());
+        assert_eq!("metrics", created.recv().await.unwrap());
+        assert_eq!(
+            vec!["logs.app1", "logs.app2"],
+            registry.matching_topics("logs.*")
+        );
     }
 }
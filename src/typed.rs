@@ -0,0 +1,158 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::{protocol, Client};
+
+// Сообщение, payload которого read_typed уже разобрал в T.
+// id нужен для Commit, как и у фрейма Publish.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypedMessage<T> {
+    pub id: u64,
+    pub topic: String,
+    pub key: Option<String>,
+    pub headers: HashMap<String, String>,
+    pub offset: u64,
+    pub value: T,
+}
+
+// Ошибка publish_typed и read_typed. Ошибки соединения и брокера приходят в Io,
+// как у read_message_checked, а ошибки (де)сериализации payload отдельно от них:
+// после Decode соединение в порядке и можно читать дальше.
+#[derive(Debug)]
+pub enum TypedError {
+    Io(io::Error),
+    // Значение не удалось сериализовать, ничего не отправлено.
+    Encode(Box<dyn Error + Send + Sync>),
+    // Сообщение пришло, но его payload не разбирается как T. Брокер ждет для него
+    // Commit или Nack, как и для любого другого сообщения.
+    Decode {
+        id: u64,
+        topic: String,
+        error: Box<dyn Error + Send + Sync>,
+    },
+}
+
+impl fmt::Display for TypedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypedError::Io(error) => write!(f, "{}", error),
+            TypedError::Encode(error) => write!(f, "Failed to serialize payload: {}", error),
+            TypedError::Decode { id, topic, error } => write!(
+                f,
+                "Failed to deserialize message {} from topic {}: {}",
+                id, topic, error
+            ),
+        }
+    }
+}
+
+impl Error for TypedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TypedError::Io(error) => Some(error),
+            TypedError::Encode(error) => Some(error.as_ref()),
+            TypedError::Decode { error, .. } => Some(error.as_ref()),
+        }
+    }
+}
+
+impl From<io::Error> for TypedError {
+    fn from(error: io::Error) -> TypedError {
+        TypedError::Io(error)
+    }
+}
+
+fn encode<T: Serialize>(
+    format: protocol::SerializationFormat,
+    value: &T,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    match format {
+        protocol::SerializationFormat::Json => Ok(serde_json::to_vec(value)?),
+        protocol::SerializationFormat::Bincode => Ok(bincode::serialize(value)?),
+    }
+}
+
+fn decode<T: DeserializeOwned>(
+    format: protocol::SerializationFormat,
+    payload: &[u8],
+) -> Result<T, Box<dyn Error + Send + Sync>> {
+    match format {
+        protocol::SerializationFormat::Json => Ok(serde_json::from_slice(payload)?),
+        protocol::SerializationFormat::Bincode => Ok(bincode::deserialize(payload)?),
+    }
+}
+
+// Сообщение с payload фрейма Publish, разобранным в T. Для других фреймов None.
+pub(crate) fn typed_message<T: DeserializeOwned>(
+    format: protocol::SerializationFormat,
+    frame: protocol::ZaichikFrame,
+) -> Option<Result<TypedMessage<T>, TypedError>> {
+    match frame {
+        protocol::ZaichikFrame::Publish {
+            id,
+            topic,
+            key,
+            payload,
+            headers,
+            offset,
+            ..
+        } => Some(match decode(format, &payload) {
+            Ok(value) => Ok(TypedMessage {
+                id,
+                topic,
+                key,
+                headers,
+                offset,
+                value,
+            }),
+            Err(error) => Err(TypedError::Decode { id, topic, error }),
+        }),
+        frame => {
+            debug!("Skipping {:?} while waiting for a message", frame);
+            None
+        }
+    }
+}
+
+impl Client {
+    // Формат payload для publish_typed и read_typed, по умолчанию JSON. Он не связан
+    // с форматом фреймов: брокер payload не разбирает, так что издатель и подписчики
+    // должны договориться о нем сами.
+    pub fn set_payload_format(&mut self, format: protocol::SerializationFormat) {
+        self.payload_format = format;
+    }
+
+    // publish, но payload - это value, сериализованный в формате set_payload_format.
+    pub async fn publish_typed<T: Serialize>(
+        &mut self,
+        topic: String,
+        key: Option<String>,
+        value: &T,
+    ) -> Result<(), TypedError> {
+        let payload = encode(self.payload_format, value).map_err(TypedError::Encode)?;
+        self.publish(topic, key, payload).await?;
+        Ok(())
+    }
+
+    // Следующее сообщение из подписок с payload, разобранным в T. Другие фреймы,
+    // например Pong или TopicCreated, пропускаются, а фрейм Error возвращается
+    // как TypedError::Io с BrokerError внутри. Ok(None) - брокер закрыл соединение.
+    pub async fn read_typed<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<TypedMessage<T>>, TypedError> {
+        loop {
+            match self.read_message_checked().await? {
+                Some(frame) => {
+                    if let Some(message) = typed_message(self.payload_format, frame) {
+                        return message.map(Some);
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+// Подключения по UNIX сокету, принятые брокером. Вне unix таких подключений не бывает,
+// но тип нужен, чтобы serve собирался везде одинаково.
+#[cfg(unix)]
+pub type UnixStream = tokio::net::UnixStream;
+#[cfg(not(unix))]
+pub type UnixStream = tokio::net::TcpStream;
+
+// UNIX сокет, на котором брокер принимает локальные подключения в дополнение к TCP.
+// Файл сокета удаляется, когда listener закрывается, в том числе если serve
+// закончился ошибкой, иначе следующий запуск брокера не сможет его занять.
+pub struct UnixSocketListener {
+    #[cfg(unix)]
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+    // У клиентов UNIX сокета нет адреса, а подключения в брокере различаются по адресу,
+    // см. ConnectionRegistry. Поэтому выдаем им 0.0.0.0 с номером по порядку в качестве
+    // порта. TCP клиент с таким адресом подключиться не может.
+    #[cfg(unix)]
+    next_peer_port: u16,
+}
+
+impl UnixSocketListener {
+    #[cfg(unix)]
+    pub fn bind(path: PathBuf) -> io::Result<UnixSocketListener> {
+        let listener = tokio::net::UnixListener::bind(&path)?;
+
+        Ok(UnixSocketListener {
+            listener,
+            path,
+            next_peer_port: 0,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind(path: PathBuf) -> io::Result<UnixSocketListener> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "UNIX sockets are not supported on this platform: {}",
+                path.display()
+            ),
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[cfg(unix)]
+    async fn accept(&mut self) -> io::Result<(UnixStream, SocketAddr)> {
+        let (socket, _addr) = self.listener.accept().await?;
+        self.next_peer_port = self.next_peer_port.wrapping_add(1);
+        let peer = SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, self.next_peer_port));
+
+        Ok((socket, peer))
+    }
+
+    #[cfg(not(unix))]
+    async fn accept(&mut self) -> io::Result<(UnixStream, SocketAddr)> {
+        unreachable!("UNIX socket can't be bound on this platform")
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove UNIX socket {}; error = {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+// Следующее подключение к UNIX сокету. Если сокет не задан, то никогда не завершается,
+// и serve принимает только TCP подключения.
+pub async fn accept(
+    listener: &mut Option<UnixSocketListener>,
+) -> io::Result<(UnixStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => futures::future::pending().await,
+    }
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use common::Broker;
+use std::process::{Command, Stdio};
+
+#[tokio::test]
+async fn test_cli_subscribe_prints_published_message() {
+    let broker = Broker::start().await;
+    let addr = broker.addr();
+
+    let mut client = broker.connect().await;
+    client
+        .create_topic(
+            "cli".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    client
+        .publish("cli".to_string(), None, b"hello from cli test".to_vec())
+        .await
+        .unwrap();
+
+    // Сообщение уже в retained истории, так что подписка с начала топика получит его.
+    // Брокер работает в рантайме теста, поэтому CLI ждем в отдельном потоке.
+    let (sender, output) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let output = Command::new(env!("CARGO_BIN_EXE_zaichik-cli"))
+            .args(["subscribe", "cli", "--count", "1", "--addr", &addr])
+            .stdout(Stdio::piped())
+            .output();
+        let _ = sender.send(output);
+    });
+    let output = output.await.unwrap().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "hello from cli test\n"
+    );
+}
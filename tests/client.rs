@@ -0,0 +1,919 @@
+// Клиенты библиотеки: blocking, reconnecting, typed, request/response и остальные.
+// Сценариям без сокета хватает брокера в памяти, остальные идут через common::Broker.
+mod common;
+
+use common::{handshake, read_publish, Broker};
+use futures::SinkExt;
+use std::time;
+use tokio::stream::StreamExt;
+use zaichik::broker::{
+    spawn_in_memory_broker, AclRules, Authenticator, BrokerConfig, InMemoryBroker,
+};
+use zaichik::protocol;
+
+#[tokio::test]
+async fn test_idle_connection_is_closed() {
+    let broker = Broker::start_with(
+        BrokerConfig::default().idle_timeout(Some(time::Duration::from_millis(100))),
+    )
+    .await;
+    let mut client = broker.connect_raw().await;
+
+    // Ничего не отправляем и ждем, пока брокер сам закроет соединение.
+    let closed = tokio::time::timeout(time::Duration::from_secs(5), client.next())
+        .await
+        .unwrap();
+    assert!(closed.is_none());
+}
+
+#[tokio::test]
+async fn test_keepalive_keeps_connection_open_without_reads() {
+    let broker = Broker::start_with(
+        BrokerConfig::default().idle_timeout(Some(time::Duration::from_millis(100))),
+    )
+    .await;
+
+    let mut client = broker.connect().await;
+    client.set_keepalive(Some(time::Duration::from_millis(20)));
+
+    // Клиент не читает и не пишет дольше idle_timeout, Ping уходят без него.
+    tokio::time::delay_for(time::Duration::from_millis(400)).await;
+
+    let topics = tokio::time::timeout(time::Duration::from_secs(5), client.list_topics())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(topics.is_empty());
+}
+
+// Блокирующий клиент нельзя вызывать изнутри рантайма, поэтому тест обычный,
+// а брокер работает в своем рантайме.
+#[test]
+fn test_blocking_client_publishes_and_reads() {
+    let broker_runtime = tokio::runtime::Runtime::new().unwrap();
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+    let connect = || {
+        let stream = broker_runtime.enter(|| broker.accept());
+        zaichik::blocking::Client::connect_stream(stream).unwrap()
+    };
+
+    let mut consumer = connect();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .unwrap();
+
+    let mut producer = connect();
+    for payload in &[b"1", b"2"] {
+        producer
+            .publish("topic".to_string(), None, payload.to_vec())
+            .unwrap();
+    }
+    producer.close().unwrap();
+
+    for expected in &[b"1", b"2"] {
+        match consumer.read_message().unwrap() {
+            Some(zaichik::ZaichikFrame::Publish { id, payload, .. }) => {
+                assert_eq!(expected.to_vec(), payload);
+                consumer.commit(id).unwrap();
+            }
+            other => panic!("Expected message, got {:?}", other),
+        }
+    }
+    consumer.close().unwrap();
+}
+
+#[tokio::test]
+async fn test_garbage_between_frames_does_not_close_connection() {
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::Encoder;
+
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+    let mut client = handshake(broker.accept()).await;
+
+    // Два ListTopics, между которыми мусор. Префикс мусора читается как длина
+    // больше допустимой.
+    let mut bytes = bytes::BytesMut::new();
+    let mut codec = protocol::ZaichikCodec::bincode();
+    codec
+        .encode(protocol::ZaichikFrame::ListTopics, &mut bytes)
+        .unwrap();
+    bytes.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef, 1, 2, 3]);
+    codec
+        .encode(protocol::ZaichikFrame::ListTopics, &mut bytes)
+        .unwrap();
+    client.get_mut().write_all(&bytes).await.unwrap();
+
+    let mut received = Vec::new();
+    for _ in 0..4 {
+        let frame = tokio::time::timeout(time::Duration::from_secs(5), client.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        received.push(frame);
+    }
+
+    // Брокер сообщает о битом префиксе и о пропущенных байтах, а второй
+    // ListTopics обрабатывает как обычно.
+    let topic_list = protocol::ZaichikFrame::TopicList { topics: Vec::new() };
+    let error = |message: &str| protocol::ZaichikFrame::Error {
+        code: protocol::ERROR_MALFORMED_FRAME,
+        message: message.to_string(),
+        topic: None,
+    };
+    assert_eq!(
+        vec![
+            topic_list.clone(),
+            error("Frame of 3735928559 bytes exceeds max frame length 16777216"),
+            error("Skipped 7 bytes to find the next frame"),
+            topic_list,
+        ],
+        received
+    );
+}
+
+// Отвечает на каждый запрос из topic тем же payload.
+async fn spawn_echo_responder(broker: &InMemoryBroker, topic: &str) {
+    let mut responder = broker.connect().await.unwrap();
+    responder
+        .subscribe_with(
+            topic.to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+
+    tokio::spawn(async move {
+        while let Ok(Some(request)) = responder.read_message().await {
+            if let zaichik::ZaichikFrame::Publish {
+                id, ref payload, ..
+            } = request
+            {
+                responder.reply(&request, payload.clone()).await.unwrap();
+                responder.commit(id).await.unwrap();
+            }
+        }
+    });
+}
+
+#[tokio::test]
+async fn test_request_returns_reply_from_responder() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+    spawn_echo_responder(&broker, "rpc").await;
+
+    let mut client = broker.connect().await.unwrap();
+    for payload in &[b"first".to_vec(), b"second".to_vec()] {
+        let reply = client
+            .request(
+                "rpc".to_string(),
+                payload.clone(),
+                "rpc.replies".to_string(),
+                time::Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+
+        match reply {
+            zaichik::ZaichikFrame::Publish {
+                topic,
+                payload: reply_payload,
+                headers,
+                ..
+            } => {
+                assert_eq!("rpc.replies", topic);
+                assert_eq!(*payload, reply_payload);
+                assert!(headers.contains_key(zaichik::CORRELATION_ID_HEADER));
+            }
+            other => panic!("Expected reply, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_request_without_responder_times_out_and_unsubscribes() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut client = broker.connect().await.unwrap();
+    let error = client
+        .request(
+            "rpc".to_string(),
+            b"ping".to_vec(),
+            "rpc.replies".to_string(),
+            time::Duration::from_millis(100),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::TimedOut, error.kind());
+
+    let stats = client.topic_stats("rpc.replies".to_string()).await.unwrap();
+    assert_eq!(0, stats.subscriber_count);
+}
+
+#[tokio::test]
+async fn test_reply_rejects_message_without_request_headers() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut client = broker.connect().await.unwrap();
+    let message = zaichik::ZaichikFrame::Publish {
+        topic: "rpc".to_string(),
+        key: None,
+        payload: vec![1],
+        headers: std::collections::HashMap::new(),
+        id: 1,
+        deliver_after: None,
+        ttl: None,
+        offset: 0,
+        create_with: None,
+        ack: false,
+    };
+    let error = client.reply(&message, vec![2]).await.unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, error.kind());
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Order {
+    id: u64,
+    items: Vec<String>,
+    total: f64,
+}
+
+#[tokio::test]
+async fn test_typed_messages_round_trip_in_both_formats() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+    let order = Order {
+        id: 7,
+        items: vec!["carrot".to_string(), "cabbage".to_string()],
+        total: 12.5,
+    };
+
+    for format in &[
+        zaichik::protocol::SerializationFormat::Json,
+        zaichik::protocol::SerializationFormat::Bincode,
+    ] {
+        let topic = format!("orders.{:?}", format);
+        let mut consumer = zaichik::Consumer::from(broker.connect().await.unwrap());
+        consumer.set_payload_format(*format);
+        consumer
+            .subscribe_with(
+                topic.clone(),
+                zaichik::SubscribeOptions::new().confirm(true),
+            )
+            .await
+            .unwrap();
+
+        let mut producer = zaichik::Producer::from(broker.connect().await.unwrap());
+        producer.set_payload_format(*format);
+        producer
+            .publish_typed(topic.clone(), Some("7".to_string()), &order)
+            .await
+            .unwrap();
+
+        let message = consumer.read_typed::<Order>().await.unwrap().unwrap();
+        assert_eq!(order, message.value);
+        assert_eq!(topic, message.topic);
+        assert_eq!(Some("7".to_string()), message.key);
+        consumer.commit(message.id).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_read_typed_reports_decode_errors_separately() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+    let order = Order {
+        id: 1,
+        items: Vec::new(),
+        total: 0.0,
+    };
+
+    let mut consumer = zaichik::Consumer::from(broker.connect().await.unwrap());
+    consumer
+        .subscribe_with(
+            "orders".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .publish("orders".to_string(), None, b"not an order".to_vec())
+        .await
+        .unwrap();
+    producer
+        .publish_typed("orders".to_string(), None, &order)
+        .await
+        .unwrap();
+
+    let id = match consumer.read_typed::<Order>().await {
+        Err(zaichik::TypedError::Decode { id, topic, .. }) => {
+            assert_eq!("orders", topic);
+            id
+        }
+        other => panic!("Expected decode error, got {:?}", other),
+    };
+    consumer.commit(id).await.unwrap();
+
+    // После ошибки разбора соединение в порядке, следующее сообщение читается.
+    let message = consumer.read_typed::<Order>().await.unwrap().unwrap();
+    assert_eq!(order, message.value);
+}
+
+#[tokio::test]
+async fn test_consumer_dedup_drops_redelivered_messages() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_topic(
+            "payments".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    for payload in &["first", "second"] {
+        producer
+            .publish("payments".to_string(), None, payload.as_bytes().to_vec())
+            .await
+            .unwrap();
+    }
+
+    let mut consumer = zaichik::Consumer::from(broker.connect().await.unwrap());
+    consumer.set_dedup(Some(time::Duration::from_secs(60)), 100);
+    consumer.set_prefetch(10).await.unwrap();
+
+    let mut received = Vec::new();
+    // Вторая подписка с начала топика доставляет те же retained сообщения еще раз.
+    for _ in 0..2 {
+        consumer
+            .subscribe_with(
+                "payments".to_string(),
+                zaichik::SubscribeOptions::new().confirm(true),
+            )
+            .await
+            .unwrap();
+        producer
+            .publish(
+                "payments".to_string(),
+                None,
+                format!("live {}", received.len()).into_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let expected = received.len() + if received.is_empty() { 3 } else { 1 };
+        while received.len() < expected {
+            match consumer.read_message().await.unwrap() {
+                Some(zaichik::protocol::ZaichikFrame::Publish { id, payload, .. }) => {
+                    received.push(String::from_utf8(payload).unwrap());
+                    consumer.commit(id).await.unwrap();
+                }
+                other => panic!("Expected a message, got {:?}", other),
+            }
+        }
+        consumer.unsubscribe("payments".to_string()).await.unwrap();
+    }
+
+    assert_eq!(vec!["first", "second", "live 0", "live 3"], received);
+
+    // Сообщение, возвращенное через Nack с requeue, приходит снова.
+    consumer
+        .subscribe_with(
+            "payments".to_string(),
+            zaichik::SubscribeOptions::new().start(zaichik::protocol::DeliveryStart::Latest),
+        )
+        .await
+        .unwrap();
+    producer
+        .publish("payments".to_string(), None, b"retry".to_vec())
+        .await
+        .unwrap();
+    for attempt in 0..2 {
+        match consumer.read_message().await.unwrap() {
+            Some(zaichik::protocol::ZaichikFrame::Publish { id, payload, .. }) => {
+                assert_eq!(b"retry".to_vec(), payload);
+                if attempt == 0 {
+                    consumer.nack(id, true).await.unwrap();
+                } else {
+                    consumer.commit(id).await.unwrap();
+                }
+            }
+            other => panic!("Expected a message, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_consumer_dedup_accepts_messages_of_recreated_topic() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+
+    let mut consumer = zaichik::Consumer::from(broker.connect().await.unwrap());
+    consumer.set_dedup(Some(time::Duration::from_secs(60)), 100);
+
+    let mut received = Vec::new();
+    for payload in &["old", "new"] {
+        producer
+            .create_topic(
+                "payments".to_string(),
+                60_000,
+                0,
+                0,
+                0,
+                zaichik::protocol::CompactionMode::Dedup,
+            )
+            .await
+            .unwrap();
+        producer
+            .publish("payments".to_string(), None, payload.as_bytes().to_vec())
+            .await
+            .unwrap();
+        consumer
+            .subscribe_with(
+                "payments".to_string(),
+                zaichik::SubscribeOptions::new().confirm(true),
+            )
+            .await
+            .unwrap();
+
+        // У обоих сообщений offset 0, но второе из нового топика и не дубль.
+        match consumer.read_message().await.unwrap() {
+            Some(zaichik::protocol::ZaichikFrame::Publish {
+                id,
+                payload,
+                offset,
+                ..
+            }) => {
+                assert_eq!(0, offset);
+                received.push(String::from_utf8(payload).unwrap());
+                consumer.commit(id).await.unwrap();
+            }
+            other => panic!("Expected a message, got {:?}", other),
+        }
+
+        producer.delete_topic("payments".to_string()).await.unwrap();
+        match consumer.read_message().await.unwrap() {
+            Some(zaichik::protocol::ZaichikFrame::TopicDeleted { topic }) => {
+                assert_eq!("payments", topic)
+            }
+            other => panic!("Expected TopicDeleted, got {:?}", other),
+        }
+    }
+
+    assert_eq!(vec!["old", "new"], received);
+}
+
+#[tokio::test]
+async fn test_shared_client_publishes_and_reads_concurrently() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut client = broker.connect().await.unwrap();
+    client.set_prefetch(10).await.unwrap();
+    client
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+    let shared = client.into_shared();
+
+    // Читающая задача уже ждет сообщения, когда публикующая начинает отправлять
+    // их через то же соединение.
+    let reader = shared.clone();
+    let consumer = tokio::spawn(async move {
+        let mut payloads = Vec::new();
+        while payloads.len() < 50 {
+            match reader.read_message().await.unwrap() {
+                Some(zaichik::ZaichikFrame::Publish { id, payload, .. }) => {
+                    reader.commit(id).await.unwrap();
+                    payloads.push(payload[0]);
+                }
+                other => panic!("Expected message, got {:?}", other),
+            }
+        }
+        payloads
+    });
+    let producer = tokio::spawn(async move {
+        for payload in 0..50u8 {
+            shared
+                .publish("topic".to_string(), None, vec![payload])
+                .await
+                .unwrap();
+        }
+    });
+
+    let (payloads, published) = tokio::time::timeout(
+        time::Duration::from_secs(5),
+        futures::future::join(consumer, producer),
+    )
+    .await
+    .unwrap();
+    published.unwrap();
+    assert_eq!((0..50u8).collect::<Vec<_>>(), payloads.unwrap());
+}
+
+#[tokio::test]
+async fn test_headers_are_delivered_unchanged() {
+    let broker = Broker::start().await;
+
+    let mut producer = broker.connect().await;
+    let mut consumer = broker.connect().await;
+
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    // ListTopics обрабатывается после Subscribe, так что после ответа подписка уже есть.
+    consumer.list_topics().await.unwrap();
+
+    let headers = vec![
+        ("content-type", "application/json"),
+        ("trace-id", "4bf92f3577b34da6"),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect::<std::collections::HashMap<_, _>>();
+
+    producer
+        .publish_with_headers("topic".to_string(), None, vec![1], headers.clone())
+        .await
+        .unwrap();
+
+    match consumer.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Publish {
+            payload,
+            headers: received,
+            ..
+        }) => {
+            assert_eq!(vec![1], payload);
+            assert_eq!(headers, received);
+        }
+        other => panic!("Expected published message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_compressed_and_plain_payloads_round_trip() {
+    let broker = Broker::start().await;
+
+    let mut consumer = broker.connect().await;
+    consumer.set_prefetch(2).await.unwrap();
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    consumer.list_topics().await.unwrap();
+
+    // Подписчик без клиента библиотеки видит то, что хранит брокер.
+    let mut raw = broker.connect_raw().await;
+    raw.send(protocol::ZaichikFrame::SetPrefetch { count: 2 })
+        .await
+        .unwrap();
+    raw.send(protocol::ZaichikFrame::Subscribe {
+        topic: "topic".to_string(),
+        group: None,
+        start: protocol::DeliveryStart::Earliest,
+        key_filter: None,
+        auto_ack: false,
+        from_offset: None,
+        max_messages_per_sec: None,
+        flush_policy: protocol::FlushPolicy::Immediate,
+        confirm: false,
+        drain: false,
+        deliver_expired: false,
+        partition: None,
+    })
+    .await
+    .unwrap();
+    raw.send(protocol::ZaichikFrame::ListTopics).await.unwrap();
+    assert!(matches!(
+        raw.next().await,
+        Some(Ok(protocol::ZaichikFrame::TopicList { .. }))
+    ));
+
+    let payload = r#"{"order_id": 1, "status": "created"}"#.repeat(100).into_bytes();
+
+    let mut compressed = zaichik::Client::builder()
+        .compression(zaichik::Compression::Lz4)
+        .connect(&broker.addr())
+        .await
+        .unwrap();
+    compressed
+        .publish("topic".to_string(), None, payload.clone())
+        .await
+        .unwrap();
+    consumer
+        .publish("topic".to_string(), None, payload.clone())
+        .await
+        .unwrap();
+
+    for _ in 0..2 {
+        match consumer.read_message().await.unwrap() {
+            Some(zaichik::ZaichikFrame::Publish {
+                payload: received,
+                headers,
+                ..
+            }) => {
+                assert_eq!(payload, received);
+                assert!(!headers.contains_key(zaichik::COMPRESSION_HEADER));
+            }
+            other => panic!("Expected published message, got {:?}", other),
+        }
+    }
+
+    let mut stored = Vec::new();
+    for _ in 0..2 {
+        match raw.next().await {
+            Some(Ok(protocol::ZaichikFrame::Publish {
+                payload, headers, ..
+            })) => stored.push((headers.get(zaichik::COMPRESSION_HEADER).cloned(), payload)),
+            other => panic!("Expected published message, got {:?}", other),
+        }
+    }
+    stored.sort();
+
+    assert_eq!((None, payload.clone()), stored[0]);
+    assert_eq!(Some("lz4".to_string()), stored[1].0);
+    assert!(stored[1].1.len() < payload.len());
+}
+
+#[tokio::test]
+async fn test_producer_and_consumer_handles_share_one_topic() {
+    let broker = Broker::start().await;
+
+    let mut producer = zaichik::Producer::from(broker.connect().await);
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    producer
+        .publish("topic".to_string(), None, vec![1])
+        .await
+        .unwrap();
+
+    // Consumer, подключенный напрямую, а не из готового Client.
+    let mut consumer = zaichik::Consumer::connect(&broker.addr()).await.unwrap();
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+
+    let frame = tokio::time::timeout(
+        time::Duration::from_secs(5),
+        consumer.read_message_checked(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let id = match frame {
+        Some(zaichik::ZaichikFrame::Publish { id, payload, .. }) => {
+            assert_eq!(vec![1], payload);
+            id
+        }
+        other => panic!("Expected published message, got {:?}", other),
+    };
+    consumer.commit(id).await.unwrap();
+
+    producer.shutdown().await.unwrap();
+    consumer.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_publisher_sink_sends_stream_of_payloads_in_order() {
+    let broker = Broker::start().await;
+
+    let mut producer = broker.connect().await;
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+
+    let mut payloads = futures::stream::iter((0..50).map(|payload| Ok(vec![payload])));
+    producer
+        .publisher("topic".to_string())
+        .send_all(&mut payloads)
+        .await
+        .unwrap();
+    let mut keyed = futures::stream::iter(vec![Ok((Some("key".to_string()), vec![50]))]);
+    producer
+        .keyed_publisher("topic".to_string())
+        .send_all(&mut keyed)
+        .await
+        .unwrap();
+    producer.list_topics().await.unwrap();
+
+    let mut consumer = broker.connect().await;
+    consumer.set_prefetch(100).await.unwrap();
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+
+    let received = consumer
+        .into_stream()
+        .take(51)
+        .map(|frame| match frame.unwrap() {
+            zaichik::ZaichikFrame::Publish { payload, .. } => payload,
+            other => panic!("Expected published message, got {:?}", other),
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+    assert_eq!(
+        (0..51).map(|payload| vec![payload]).collect::<Vec<_>>(),
+        received
+    );
+}
+
+#[tokio::test]
+async fn test_publish_batch_is_consumed_in_order() {
+    let broker = Broker::start().await;
+
+    let mut producer = broker.connect().await;
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            10_000,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+
+    // Дубль ключа first в пределах compaction_window будет отброшен.
+    let mut messages = vec![
+        (Some("first".to_string()), vec![0]),
+        (Some("second".to_string()), vec![1]),
+        (Some("first".to_string()), vec![255]),
+    ];
+    messages.extend((2..100).map(|payload| (None, vec![payload])));
+    producer
+        .publish_batch("topic".to_string(), messages)
+        .await
+        .unwrap();
+    producer.list_topics().await.unwrap();
+
+    let mut consumer = broker.connect().await;
+    consumer.set_prefetch(100).await.unwrap();
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+
+    let received = consumer
+        .into_stream()
+        .take(100)
+        .map(|frame| match frame.unwrap() {
+            zaichik::ZaichikFrame::Publish { payload, .. } => payload,
+            other => panic!("Expected published message, got {:?}", other),
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+    assert_eq!(
+        (0..100).map(|payload| vec![payload]).collect::<Vec<_>>(),
+        received
+    );
+}
+
+#[tokio::test]
+async fn test_admin_connections_reports_peers_and_their_subscriptions() {
+    let broker = Broker::start_with(
+        BrokerConfig::default()
+            .auth(Some(
+                Authenticator::from_spec("app:app-token,ops:ops-token").unwrap(),
+            ))
+            .acl(AclRules::from_spec("admin:ops").unwrap()),
+    )
+    .await;
+
+    let mut orders_consumer = broker.connect_with_token("app-token").await.unwrap();
+    orders_consumer
+        .subscribe_on("orders".to_string())
+        .await
+        .unwrap();
+    let mut payments_consumer = broker.connect_with_token("app-token").await.unwrap();
+    payments_consumer
+        .subscribe_on("payments".to_string())
+        .await
+        .unwrap();
+    payments_consumer.list_topics().await.unwrap();
+
+    // Сообщение остается неподтвержденным у подписчика orders.
+    let mut admin = broker.connect_with_token("ops-token").await.unwrap();
+    admin
+        .publish("orders".to_string(), None, vec![1])
+        .await
+        .unwrap();
+    read_publish(&mut orders_consumer).await;
+    orders_consumer.list_topics().await.unwrap();
+
+    let connections = admin.admin_connections().await.unwrap();
+    assert_eq!(3, connections.len());
+
+    let mut subscribed = connections
+        .iter()
+        .filter(|connection| !connection.topics.is_empty())
+        .map(|connection| (connection.topics.clone(), connection.in_flight))
+        .collect::<Vec<_>>();
+    subscribed.sort();
+    assert_eq!(
+        vec![
+            (vec!["orders".to_string()], 1),
+            (vec!["payments".to_string()], 0),
+        ],
+        subscribed
+    );
+    assert!(connections
+        .iter()
+        .all(|connection| connection.peer.ip() == std::net::Ipv4Addr::LOCALHOST));
+
+    // Отключившийся клиент пропадает из ответа.
+    drop(payments_consumer);
+    let mut remaining = 0;
+    for _ in 0..50 {
+        remaining = admin.admin_connections().await.unwrap().len();
+        if remaining == 2 {
+            break;
+        }
+        tokio::time::delay_for(time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(2, remaining);
+}
+
+#[tokio::test]
+async fn test_connect_to_unroutable_address_fails_within_timeout() {
+    let options = zaichik::Client::builder()
+        .connect_timeout(Some(time::Duration::from_millis(200)))
+        .nodelay(false);
+
+    // 10.255.255.1 не отвечает на SYN, так что без таймаута connect висел бы долго.
+    let started_at = time::Instant::now();
+    let result = zaichik::Client::connect_with("10.255.255.1:8889", options).await;
+
+    assert!(result.is_err());
+    assert!(started_at.elapsed() < time::Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_reconnecting_client_resumes_after_broker_restart() {
+    let broker = Broker::start().await;
+
+    let policy = zaichik::ReconnectPolicy::new()
+        .initial_backoff(time::Duration::from_millis(20))
+        .max_retries(Some(100));
+    let mut consumer =
+        zaichik::ReconnectingClient::connect(&broker.addr(), zaichik::Client::builder(), policy)
+            .await
+            .unwrap();
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    consumer.client().list_topics().await.unwrap();
+
+    let mut producer = broker.connect().await;
+    producer
+        .publish("topic".to_string(), None, vec![1])
+        .await
+        .unwrap();
+    match consumer.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Publish { payload, id, .. }) => {
+            assert_eq!(vec![1], payload);
+            consumer.commit(id).await.unwrap();
+        }
+        other => panic!("Expected published message, got {:?}", other),
+    }
+
+    // Брокер останавливается и запускается заново на том же порту.
+    let port = broker.port();
+    broker.stop().await;
+    let broker = Broker::start_on(port, BrokerConfig::default()).await;
+
+    // Издатель публикует, пока подписчик не переподключится и не подпишется заново.
+    // Сообщения без retention, отправленные до этого, до подписчика не доходят.
+    let mut producer = broker.connect().await;
+    tokio::spawn(async move {
+        loop {
+            producer
+                .publish("topic".to_string(), None, vec![2])
+                .await
+                .unwrap();
+            tokio::time::delay_for(time::Duration::from_millis(20)).await;
+        }
+    });
+
+    let next = tokio::time::timeout(time::Duration::from_secs(5), consumer.read_message())
+        .await
+        .unwrap()
+        .unwrap();
+    match next {
+        Some(zaichik::ZaichikFrame::Publish { payload, .. }) => assert_eq!(vec![2], payload),
+        other => panic!("Expected published message, got {:?}", other),
+    }
+}
@@ -0,0 +1,137 @@
+// Общее для интеграционных тестов: брокер на TCP порту в этом же процессе и чтение сообщений.
+// Сценариям, которым сокет не нужен, хватает zaichik::broker::spawn_in_memory_broker.
+// Каждый файл в tests/ пользуется только частью этого, поэтому dead_code здесь разрешен.
+#![allow(dead_code)]
+
+use futures::SinkExt;
+use std::time;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::stream::StreamExt;
+use tokio::sync::broadcast;
+use zaichik::broker::{self, BrokerConfig};
+use zaichik::protocol;
+
+// Соединение без клиента библиотеки, через которое тест отправляет и читает фреймы сам.
+pub type RawClient<S> = tokio_util::codec::Framed<S, protocol::ZaichikCodec>;
+
+// Брокер, запущенный через run_broker_on. Порт занят еще до запуска, поэтому
+// подключаться можно сразу. В конце теста брокер получает сигнал остановки.
+pub struct Broker {
+    addr: std::net::SocketAddr,
+    shutdown: broadcast::Sender<()>,
+    // None после stop.
+    task: Option<tokio::task::JoinHandle<std::io::Result<()>>>,
+}
+
+impl Broker {
+    pub async fn start() -> Broker {
+        Broker::start_with(BrokerConfig::default()).await
+    }
+
+    pub async fn start_with(config: BrokerConfig) -> Broker {
+        Broker::start_on(0, config).await
+    }
+
+    // Запускает брокер на заданном порту, например чтобы перезапустить остановленный.
+    pub async fn start_on(port: u16, config: BrokerConfig) -> Broker {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, _) = broadcast::channel(1);
+        let task = tokio::spawn(broker::run_broker_on(listener, config, shutdown.clone()));
+
+        Broker {
+            addr,
+            shutdown,
+            task: Some(task),
+        }
+    }
+
+    // Останавливает брокер и ждет, пока он закроет подключения и освободит порт.
+    pub async fn stop(mut self) {
+        let _ = self.shutdown.send(());
+        let stopped =
+            tokio::time::timeout(time::Duration::from_secs(10), self.task.take().unwrap())
+                .await
+                .unwrap();
+        stopped.unwrap().unwrap();
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    pub async fn connect(&self) -> zaichik::Client {
+        zaichik::Client::connect(&self.addr()).await.unwrap()
+    }
+
+    pub async fn connect_with_token(
+        &self,
+        token: &str,
+    ) -> Result<zaichik::Client, Box<dyn std::error::Error>> {
+        zaichik::Client::builder()
+            .token(token.to_string())
+            .connect(&self.addr())
+            .await
+    }
+
+    pub async fn connect_raw(&self) -> RawClient<tokio::net::TcpStream> {
+        let socket = tokio::net::TcpStream::connect(self.addr).await.unwrap();
+        handshake(socket).await
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        let _ = self.shutdown.send(());
+    }
+}
+
+// Подключение поверх stream, которое уже прошло Handshake.
+pub async fn handshake<S>(stream: S) -> RawClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client = tokio_util::codec::Framed::new(stream, protocol::ZaichikCodec::bincode());
+
+    let handshake = protocol::ZaichikFrame::Handshake {
+        protocol_version: protocol::PROTOCOL_VERSION,
+    };
+    client.send(handshake.clone()).await.unwrap();
+    assert_eq!(Some(handshake), client.next().await.transpose().unwrap());
+
+    client
+}
+
+pub async fn read_publish(client: &mut zaichik::Client) -> (u64, Vec<u8>) {
+    let frame = tokio::time::timeout(time::Duration::from_secs(5), client.read_message())
+        .await
+        .unwrap()
+        .unwrap();
+
+    match frame {
+        Some(zaichik::ZaichikFrame::Publish { id, payload, .. }) => (id, payload),
+        other => panic!("Expected published message, got {:?}", other),
+    }
+}
+
+// Все сообщения, которые брокер присылает, пока не замолчит на 200 мс.
+pub async fn read_available(client: &mut zaichik::Client) -> Vec<(u64, String)> {
+    let mut messages = Vec::new();
+    while let Ok(frame) =
+        tokio::time::timeout(time::Duration::from_millis(200), client.read_message()).await
+    {
+        match frame.unwrap() {
+            Some(zaichik::ZaichikFrame::Publish { id, payload, .. }) => {
+                messages.push((id, String::from_utf8(payload).unwrap()))
+            }
+            other => panic!("Expected message, got {:?}", other),
+        }
+    }
+    messages
+}
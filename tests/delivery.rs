@@ -0,0 +1,903 @@
+// Доставка сообщений: подтверждения, prefetch, порядок, Reliable топики, TTL и задержки.
+// Сценариям без сокета хватает брокера в памяти, остальные идут через common::Broker.
+mod common;
+
+use common::{read_available, read_publish, Broker};
+use futures::SinkExt;
+use std::time;
+use tokio::stream::StreamExt;
+use zaichik::broker::{spawn_in_memory_broker, BrokerConfig, InMemoryBroker};
+use zaichik::protocol;
+
+// Сообщения топика истекают через 100ms, а подписчик с prefetch 1 держит первое
+// сообщение дольше. Остальные сообщения истекают, пока ждут отправки. Возвращает
+// сообщение, которое подписчик получает после подтверждения первого.
+async fn consume_after_messages_expire(
+    producer: &mut zaichik::Client,
+    consumer: &mut zaichik::Client,
+) -> Vec<u8> {
+    for payload in 1..=3 {
+        producer
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    let (id, payload) = read_publish(consumer).await;
+    assert_eq!(vec![1], payload);
+    tokio::time::delay_for(time::Duration::from_millis(200)).await;
+    consumer.commit(id).await.unwrap();
+
+    producer
+        .publish("topic".to_string(), None, vec![4])
+        .await
+        .unwrap();
+    let (_id, payload) = read_publish(consumer).await;
+    payload
+}
+
+async fn create_expiring_topic(broker: &InMemoryBroker) -> zaichik::Client {
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_topic(
+            "topic".to_string(),
+            100,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    producer
+}
+
+#[tokio::test]
+async fn test_expired_messages_are_skipped_and_counted() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+    let mut producer = create_expiring_topic(&broker).await;
+
+    let mut consumer = broker.connect().await.unwrap();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+
+    // Сообщения 2 и 3 истекли, следующим подписчик получает 4.
+    let payload = consume_after_messages_expire(&mut producer, &mut consumer).await;
+    assert_eq!(vec![4], payload);
+
+    let stats = consumer.topic_stats("topic".to_string()).await.unwrap();
+    assert_eq!(2, stats.expired_drops);
+}
+
+#[tokio::test]
+async fn test_deliver_expired_subscription_receives_expired_messages() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+    let mut producer = create_expiring_topic(&broker).await;
+
+    let mut consumer = broker.connect().await.unwrap();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().deliver_expired(true),
+        )
+        .await
+        .unwrap();
+    consumer.list_topics().await.unwrap();
+
+    let payload = consume_after_messages_expire(&mut producer, &mut consumer).await;
+    assert_eq!(vec![2], payload);
+
+    let stats = consumer.topic_stats("topic".to_string()).await.unwrap();
+    assert_eq!(0, stats.expired_drops);
+}
+
+#[tokio::test]
+async fn test_ordering_guarantee_limits_messages_in_flight() {
+    use zaichik::protocol::OrderingGuarantee;
+
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+    let payloads = |messages: &[(u64, String)]| {
+        messages
+            .iter()
+            .map(|(_id, payload)| payload.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // (порядок, что приходит сразу, что приходит после Commit первого сообщения)
+    let cases = vec![
+        (
+            OrderingGuarantee::None,
+            vec!["a1", "b1", "a2", "b2"],
+            vec![],
+        ),
+        (OrderingGuarantee::PerKey, vec!["a1", "b1"], vec!["a2"]),
+        (OrderingGuarantee::Strict, vec!["a1"], vec!["b1"]),
+    ];
+
+    for (ordering, first_batch, after_commit) in cases {
+        let topic = format!("orders.{:?}", ordering);
+        let mut producer = broker.connect().await.unwrap();
+        let created = producer
+            .create_topic_with(
+                topic.clone(),
+                zaichik::TopicConfig {
+                    retention_ttl: 60_000,
+                    ordering,
+                    ..zaichik::TopicConfig::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(ordering, created.ordering);
+        for payload in &["a1", "b1", "a2", "b2"] {
+            producer
+                .publish(
+                    topic.clone(),
+                    Some(payload[..1].to_string()),
+                    payload.as_bytes().to_vec(),
+                )
+                .await
+                .unwrap();
+        }
+        producer.list_topics().await.unwrap();
+
+        let mut consumer = broker.connect().await.unwrap();
+        consumer.set_prefetch(4).await.unwrap();
+        consumer.subscribe_on(topic).await.unwrap();
+
+        let received = read_available(&mut consumer).await;
+        assert_eq!(first_batch, payloads(&received), "{:?}", ordering);
+
+        // Сообщение после Nack приходит снова раньше, чем следующие за ним
+        // сообщения его группы.
+        let (first_id, _) = received[0];
+        consumer.nack(first_id, true).await.unwrap();
+        assert_eq!(
+            vec!["a1"],
+            payloads(&read_available(&mut consumer).await),
+            "{:?}",
+            ordering
+        );
+
+        consumer.commit(first_id).await.unwrap();
+        assert_eq!(
+            after_commit,
+            payloads(&read_available(&mut consumer).await),
+            "{:?}",
+            ordering
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_paused_subscription_receives_messages_after_resume() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    for delivery in &[
+        zaichik::protocol::DeliveryGuarantee::BestEffort,
+        zaichik::protocol::DeliveryGuarantee::Reliable,
+    ] {
+        let topic = format!("topic.{:?}", delivery);
+        let mut producer = broker.connect().await.unwrap();
+        producer
+            .create_topic_with(
+                topic.clone(),
+                zaichik::TopicConfig {
+                    delivery: *delivery,
+                    ..zaichik::TopicConfig::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let mut consumer = broker.connect().await.unwrap();
+        consumer.set_prefetch(10).await.unwrap();
+        consumer
+            .subscribe_with(
+                topic.clone(),
+                zaichik::SubscribeOptions::new().confirm(true),
+            )
+            .await
+            .unwrap();
+        consumer.pause(topic.clone()).await.unwrap();
+        // Ответ на list_topics приходит после того, как брокер обработал Pause.
+        consumer.list_topics().await.unwrap();
+
+        for payload in &["1", "2", "3"] {
+            producer
+                .publish(topic.clone(), None, payload.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+        producer.list_topics().await.unwrap();
+        assert!(read_available(&mut consumer).await.is_empty());
+
+        consumer.resume(topic.clone()).await.unwrap();
+        let payloads = read_available(&mut consumer)
+            .await
+            .into_iter()
+            .map(|(_id, payload)| payload)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["1", "2", "3"], payloads, "{:?}", delivery);
+    }
+}
+
+#[tokio::test]
+async fn test_publish_ack_reports_saturated_reliable_topic() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_reliable_topic(
+            "topic".to_string(),
+            0,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+            1,
+        )
+        .await
+        .unwrap();
+    let mut consumer = broker.connect().await.unwrap();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+
+    // Подписчик ничего не подтверждает, поэтому его очередь скоро заполняется,
+    // и издатель узнает об этом из PublishAck, а не теряет сообщения молча.
+    let mut accepted = Vec::new();
+    let mut rejection = None;
+    for payload in 0..10u8 {
+        let ack = producer
+            .publish_acked("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+        if ack.accepted {
+            accepted.push(vec![payload]);
+        } else {
+            rejection = ack.reason;
+            break;
+        }
+    }
+    assert!(rejection.unwrap().contains("saturated"));
+
+    // Все принятые сообщения доходят до подписчика.
+    let mut received = Vec::new();
+    for _ in 0..accepted.len() {
+        let (id, payload) = read_publish(&mut consumer).await;
+        consumer.commit(id).await.unwrap();
+        received.push(payload);
+    }
+    assert_eq!(accepted, received);
+}
+
+#[tokio::test]
+async fn test_next_message_is_delivered_after_commit() {
+    let broker = Broker::start().await;
+    let mut client = broker.connect_raw().await;
+
+    client
+        .send(protocol::ZaichikFrame::CreateTopic {
+            topic: "retained".to_string(),
+            retention_ttl: 60_000,
+            compaction_window: 0,
+            retention_max_messages: 0,
+            retention_max_bytes: 0,
+            compaction_mode: protocol::CompactionMode::Dedup,
+            buffer_size: None,
+            delivery: protocol::DeliveryGuarantee::BestEffort,
+            key_source: protocol::KeySource::Explicit,
+            partitions: 0,
+            ordering: protocol::OrderingGuarantee::None,
+            max_message_bytes: 0,
+        })
+        .await
+        .unwrap();
+    match client.next().await {
+        Some(Ok(protocol::ZaichikFrame::TopicCreated { .. })) => {}
+        other => panic!("Expected TopicCreated, got {:?}", other),
+    }
+    for payload in 1..=2 {
+        client
+            .send(protocol::ZaichikFrame::Publish {
+                topic: "retained".to_string(),
+                key: None,
+                payload: vec![payload],
+                headers: std::collections::HashMap::new(),
+                id: 0,
+                deliver_after: None,
+                ttl: None,
+                offset: 0,
+                create_with: None,
+                ack: false,
+            })
+            .await
+            .unwrap();
+    }
+    client
+        .send(protocol::ZaichikFrame::Subscribe {
+            topic: "retained".to_string(),
+            group: None,
+            start: protocol::DeliveryStart::Earliest,
+            key_filter: None,
+            auto_ack: false,
+            from_offset: None,
+            max_messages_per_sec: None,
+            flush_policy: protocol::FlushPolicy::Immediate,
+            confirm: false,
+            drain: false,
+            deliver_expired: false,
+            partition: None,
+        })
+        .await
+        .unwrap();
+
+    let next_message = |frame: Option<protocol::ZaichikFrame>| match frame {
+        Some(protocol::ZaichikFrame::Publish { payload, id, .. }) => (id, payload),
+        other => panic!("Expected published message, got {:?}", other),
+    };
+
+    let (first_id, first) = next_message(client.next().await.transpose().unwrap());
+    assert_eq!(vec![1], first);
+
+    // Пока нет Commit, следующее сообщение не приходит.
+    let not_yet = tokio::time::timeout(time::Duration::from_millis(100), client.next()).await;
+    assert!(not_yet.is_err());
+
+    // Commit с чужим id не возвращает кредит.
+    client
+        .send(protocol::ZaichikFrame::Commit { id: first_id + 1 })
+        .await
+        .unwrap();
+    match client.next().await.transpose().unwrap() {
+        Some(protocol::ZaichikFrame::Error { code, .. }) => {
+            assert_eq!(protocol::ERROR_UNKNOWN_MESSAGE_ID, code)
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    client
+        .send(protocol::ZaichikFrame::Commit { id: first_id })
+        .await
+        .unwrap();
+    let (second_id, second) = next_message(client.next().await.transpose().unwrap());
+    assert_eq!(vec![2], second);
+    assert!(second_id > first_id);
+}
+
+#[tokio::test]
+async fn test_commit_of_one_client_does_not_affect_another() {
+    let broker = Broker::start().await;
+
+    let mut first = broker.connect().await;
+    let mut second = broker.connect().await;
+
+    first
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    for payload in 1..=2 {
+        first
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+    // ListTopics обрабатывается после публикаций, так что после ответа
+    // сообщения уже лежат в топике.
+    first.list_topics().await.unwrap();
+
+    first.subscribe_on("topic".to_string()).await.unwrap();
+    second.subscribe_on("topic".to_string()).await.unwrap();
+
+    let message_of = |frame: Option<zaichik::ZaichikFrame>| match frame {
+        Some(zaichik::ZaichikFrame::Publish { payload, id, .. }) => (id, payload),
+        other => panic!("Expected published message, got {:?}", other),
+    };
+
+    let (id, payload) = message_of(first.read_message().await.unwrap());
+    assert_eq!(vec![1], payload);
+    assert_eq!(vec![1], message_of(second.read_message().await.unwrap()).1);
+
+    first.commit(id).await.unwrap();
+    assert_eq!(vec![2], message_of(first.read_message().await.unwrap()).1);
+
+    // Второй клиент ничего не подтверждал, поэтому следующее сообщение ему не приходит.
+    let not_yet =
+        tokio::time::timeout(time::Duration::from_millis(100), second.read_message()).await;
+    assert!(not_yet.is_err());
+}
+
+#[tokio::test]
+async fn test_message_nacked_too_many_times_goes_to_dead_letter_topic() {
+    let broker = Broker::start().await;
+
+    let mut consumer = broker.connect().await;
+    consumer
+        .set_dead_letter("orders".to_string(), 2, None)
+        .await
+        .unwrap();
+    consumer.subscribe_on("orders".to_string()).await.unwrap();
+    consumer.list_topics().await.unwrap();
+
+    let mut producer = broker.connect().await;
+    let headers = vec![("trace-id".to_string(), "42".to_string())]
+        .into_iter()
+        .collect::<std::collections::HashMap<_, _>>();
+    producer
+        .publish_with_headers("orders".to_string(), None, vec![1], headers)
+        .await
+        .unwrap();
+
+    for _ in 0..2 {
+        match consumer.read_message().await.unwrap() {
+            Some(zaichik::ZaichikFrame::Publish { id, payload, .. }) => {
+                assert_eq!(vec![1], payload);
+                consumer.nack(id, true).await.unwrap();
+            }
+            other => panic!("Expected published message, got {:?}", other),
+        }
+    }
+
+    // После второго Nack сообщение больше не доставляется из исходного топика.
+    let redelivered =
+        tokio::time::timeout(time::Duration::from_millis(100), consumer.read_message()).await;
+    assert!(redelivered.is_err());
+
+    consumer
+        .subscribe_on("orders.dlq".to_string())
+        .await
+        .unwrap();
+    match consumer.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Publish {
+            topic,
+            payload,
+            headers,
+            ..
+        }) => {
+            assert_eq!("orders.dlq", topic);
+            assert_eq!(vec![1], payload);
+            assert_eq!(Some("42"), headers.get("trace-id").map(String::as_str));
+            assert_eq!(
+                Some("2"),
+                headers.get("zaichik-delivery-attempts").map(String::as_str)
+            );
+            assert_eq!(
+                Some("orders"),
+                headers.get("zaichik-original-topic").map(String::as_str)
+            );
+            assert!(headers.contains_key("zaichik-dead-letter-reason"));
+        }
+        other => panic!("Expected dead-lettered message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_subscriber_lagging_behind_small_buffer_skips_oldest_messages() {
+    let broker = Broker::start().await;
+
+    let mut producer = broker.connect().await;
+    producer
+        .create_topic_with_buffer_size(
+            "topic".to_string(),
+            0,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+            2,
+        )
+        .await
+        .unwrap();
+
+    let mut consumer = broker.connect().await;
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    consumer.list_topics().await.unwrap();
+
+    producer
+        .publish("topic".to_string(), None, vec![0])
+        .await
+        .unwrap();
+    let (first_id, first) = read_publish(&mut consumer).await;
+
+    // Пока первое сообщение не подтверждено, брокер не читает подписку, и в
+    // канале на два сообщения остаются только последние из опубликованных.
+    for payload in 1..10 {
+        producer
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+    consumer.commit(first_id).await.unwrap();
+
+    let mut received = vec![first];
+    for _ in 0..2 {
+        let (id, payload) = read_publish(&mut consumer).await;
+        consumer.commit(id).await.unwrap();
+        received.push(payload);
+    }
+
+    assert_eq!(vec![vec![0], vec![8], vec![9]], received);
+}
+
+#[tokio::test]
+async fn test_reliable_topic_queues_publishes_instead_of_dropping_messages() {
+    const MESSAGES: u8 = 50;
+
+    let broker = Broker::start().await;
+
+    let mut producer = broker.connect().await;
+    let created = producer
+        .create_reliable_topic(
+            "topic".to_string(),
+            0,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+            2,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        zaichik::protocol::DeliveryGuarantee::Reliable,
+        created.delivery
+    );
+
+    let mut consumer = broker.connect().await;
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    consumer.list_topics().await.unwrap();
+
+    // ListTopics после всех publish отвечает, только когда брокер их обработал.
+    let (published, all_published) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        for payload in 0..MESSAGES {
+            producer
+                .publish("topic".to_string(), None, vec![payload])
+                .await
+                .unwrap();
+        }
+        producer.list_topics().await.unwrap();
+        let _ = published.send(());
+    });
+
+    // Подписчик медленный: его очередь на два сообщения заполнена, и остальные
+    // сообщения ждут в очереди публикаций издателя, но не теряются.
+    let mut received = Vec::new();
+    for _ in 0..MESSAGES {
+        let (id, payload) = read_publish(&mut consumer).await;
+        tokio::time::delay_for(time::Duration::from_millis(5)).await;
+        consumer.commit(id).await.unwrap();
+        received.push(payload[0]);
+    }
+
+    assert_eq!((0..MESSAGES).collect::<Vec<_>>(), received);
+    tokio::time::timeout(time::Duration::from_secs(5), all_published)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_reliable_publisher_reading_own_topic_does_not_wait_for_itself() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut client = broker.connect().await.unwrap();
+    client
+        .create_reliable_topic(
+            "topic".to_string(),
+            0,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+            1,
+        )
+        .await
+        .unwrap();
+    client
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+
+    // Очередь подписки на одно сообщение, а клиент ничего не подтверждает, пока
+    // публикует. Брокер все равно продолжает отвечать ему.
+    for payload in 0..5u8 {
+        client
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+    tokio::time::timeout(time::Duration::from_secs(5), client.list_topics())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Другой издатель топика не ждет, а узнает, что топик заполнен.
+    let mut other = broker.connect().await.unwrap();
+    let ack = tokio::time::timeout(
+        time::Duration::from_secs(5),
+        other.publish_acked("topic".to_string(), None, vec![5]),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(!ack.accepted);
+
+    let mut received = Vec::new();
+    for _ in 0..5 {
+        let (id, payload) = read_publish(&mut client).await;
+        client.commit(id).await.unwrap();
+        received.push(payload[0]);
+    }
+    assert_eq!(vec![0, 1, 2, 3, 4], received);
+}
+
+#[tokio::test]
+async fn test_publish_to_saturated_reliable_topic_is_rejected() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_reliable_topic(
+            "topic".to_string(),
+            0,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+            1,
+        )
+        .await
+        .unwrap();
+    let mut consumer = broker.connect().await.unwrap();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+
+    // Подписчик ничего не подтверждает, поэтому очередь публикаций издателя
+    // заполняется, и следующие publish брокер отклоняет.
+    for payload in 0..200u8 {
+        producer
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+
+    let received = tokio::time::timeout(time::Duration::from_secs(5), producer.read_message())
+        .await
+        .unwrap()
+        .unwrap();
+    match received {
+        Some(zaichik::ZaichikFrame::Error {
+            code,
+            message,
+            topic,
+        }) => {
+            assert_eq!(zaichik::protocol::ERROR_TOPIC_SATURATED, code);
+            assert_eq!(Some("topic".to_string()), topic);
+            assert!(message.contains("saturated"));
+        }
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_delayed_message_is_not_delivered_before_its_time() {
+    let broker = Broker::start().await;
+
+    let mut consumer = broker.connect().await;
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    consumer.set_prefetch(10).await.unwrap();
+    consumer.list_topics().await.unwrap();
+
+    let mut producer = broker.connect().await;
+    let published_at = time::Instant::now();
+    producer
+        .publish_delayed(
+            "topic".to_string(),
+            None,
+            vec![1],
+            time::Duration::from_millis(200),
+        )
+        .await
+        .unwrap();
+    producer
+        .publish("topic".to_string(), None, vec![2])
+        .await
+        .unwrap();
+
+    // Сообщение без задержки приходит сразу, отложенное - не раньше, чем через 200ms.
+    let (_id, payload) = read_publish(&mut consumer).await;
+    assert_eq!(vec![2], payload);
+
+    let early =
+        tokio::time::timeout(time::Duration::from_millis(100), consumer.read_message()).await;
+    assert!(early.is_err());
+
+    let (_id, payload) = read_publish(&mut consumer).await;
+    assert_eq!(vec![1], payload);
+    assert!(published_at.elapsed() >= time::Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn test_message_ttl_expires_before_topic_retention_ttl() {
+    let broker = Broker::start().await;
+
+    let mut producer = broker.connect().await;
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    producer
+        .publish_with_ttl(
+            "topic".to_string(),
+            None,
+            vec![1],
+            time::Duration::from_millis(100),
+        )
+        .await
+        .unwrap();
+    producer
+        .publish("topic".to_string(), None, vec![2])
+        .await
+        .unwrap();
+    producer.list_topics().await.unwrap();
+
+    tokio::time::delay_for(time::Duration::from_millis(200)).await;
+
+    // Новый подписчик получает только сообщение с retention_ttl топика.
+    let mut consumer = broker.connect().await;
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    let (_id, payload) = read_publish(&mut consumer).await;
+    assert_eq!(vec![2], payload);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_concurrent_publishes_to_many_topics() {
+    const TOPICS: u8 = 8;
+    const PUBLISHERS_PER_TOPIC: u8 = 4;
+    const MESSAGES_PER_PUBLISHER: u8 = 50;
+
+    let broker = Broker::start().await;
+
+    // Подписчики подключаются заранее, ListTopics подтверждает, что подписка уже есть.
+    let mut consumers = Vec::new();
+    for topic in 0..TOPICS {
+        let mut consumer = broker.connect().await;
+        consumer
+            .subscribe_on(format!("topic{}", topic))
+            .await
+            .unwrap();
+        consumer.list_topics().await.unwrap();
+        consumers.push(consumer);
+    }
+
+    let mut producers = Vec::new();
+    for _ in 0..TOPICS * PUBLISHERS_PER_TOPIC {
+        producers.push(broker.connect().await);
+    }
+
+    let mut tasks = Vec::new();
+    for (index, mut producer) in producers.into_iter().enumerate() {
+        let topic = format!("topic{}", index as u8 % TOPICS);
+        let publisher = index as u8 / TOPICS;
+        tasks.push(tokio::spawn(async move {
+            for seq in 0..MESSAGES_PER_PUBLISHER {
+                producer
+                    .publish(topic.clone(), None, vec![publisher, seq])
+                    .await
+                    .unwrap();
+            }
+        }));
+    }
+
+    // Каждый подписчик получает все сообщения своего топика, и сообщения
+    // одного издателя приходят в том порядке, в котором он их публиковал.
+    for mut consumer in consumers {
+        tasks.push(tokio::spawn(async move {
+            let mut next_seq = vec![0; PUBLISHERS_PER_TOPIC as usize];
+            for _ in 0..PUBLISHERS_PER_TOPIC as usize * MESSAGES_PER_PUBLISHER as usize {
+                let (id, payload) = read_publish(&mut consumer).await;
+                let publisher = payload[0] as usize;
+                assert_eq!(next_seq[publisher], payload[1]);
+                next_seq[publisher] += 1;
+                consumer.commit(id).await.unwrap();
+            }
+        }));
+    }
+
+    let all_done = futures::future::join_all(tasks);
+    for result in tokio::time::timeout(time::Duration::from_secs(10), all_done)
+        .await
+        .unwrap()
+    {
+        result.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_out_of_order_commits_under_prefetch() {
+    let broker = Broker::start().await;
+
+    let mut client = broker.connect().await;
+    client
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    for payload in 1..=3 {
+        client
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+    client.set_prefetch(2).await.unwrap();
+    client.subscribe_on("topic".to_string()).await.unwrap();
+
+    let message_of = |frame: Option<zaichik::ZaichikFrame>| match frame {
+        Some(zaichik::ZaichikFrame::Publish { payload, id, .. }) => (id, payload),
+        other => panic!("Expected published message, got {:?}", other),
+    };
+
+    let (first_id, first) = message_of(client.read_message().await.unwrap());
+    let (second_id, second) = message_of(client.read_message().await.unwrap());
+    assert_eq!((vec![1], vec![2]), (first, second));
+
+    // Подтверждаем второе сообщение раньше первого. Освобождается один
+    // кредит, и приходит третье сообщение.
+    client.commit(second_id).await.unwrap();
+    let (third_id, third) = message_of(client.read_message().await.unwrap());
+    assert_eq!(vec![3], third);
+
+    // Первое все еще не подтверждено, поэтому окно снова заполнено.
+    client
+        .publish("topic".to_string(), None, vec![4])
+        .await
+        .unwrap();
+    let not_yet =
+        tokio::time::timeout(time::Duration::from_millis(100), client.read_message()).await;
+    assert!(not_yet.is_err());
+
+    client.commit(first_id).await.unwrap();
+    assert_eq!(vec![4], message_of(client.read_message().await.unwrap()).1);
+    client.commit(third_id).await.unwrap();
+}
@@ -0,0 +1,89 @@
+// Хук событий брокера, который приложение задает через BrokerConfig::events.
+use std::sync::Arc;
+use std::time;
+use zaichik::broker::{spawn_in_memory_broker, BrokerConfig, BrokerEvents};
+
+// Хук событий, который запоминает события по порядку.
+#[derive(Default)]
+struct RecordingEvents {
+    events: std::sync::Mutex<Vec<String>>,
+}
+
+impl RecordingEvents {
+    fn record(&self, peer: std::net::SocketAddr, event: String) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", peer.port(), event));
+    }
+
+    fn events(&self) -> Vec<String> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl BrokerEvents for RecordingEvents {
+    fn on_connected(&self, peer: std::net::SocketAddr, principal: Option<&str>) {
+        self.record(peer, format!("connected {:?}", principal));
+    }
+
+    fn on_disconnected(&self, peer: std::net::SocketAddr) {
+        self.record(peer, "disconnected".to_string());
+    }
+
+    fn on_subscribed(&self, peer: std::net::SocketAddr, topic: &str) {
+        self.record(peer, format!("subscribed {}", topic));
+    }
+
+    fn on_unsubscribed(&self, peer: std::net::SocketAddr, topic: &str) {
+        self.record(peer, format!("unsubscribed {}", topic));
+    }
+
+    fn on_published(&self, peer: std::net::SocketAddr, topic: &str, payload_len: usize) {
+        self.record(peer, format!("published {} {}", topic, payload_len));
+    }
+}
+
+#[tokio::test]
+async fn test_events_hook_sees_connection_lifecycle() {
+    let events = Arc::new(RecordingEvents::default());
+    let broker = spawn_in_memory_broker(
+        BrokerConfig::default().events(Some(Arc::clone(&events) as Arc<dyn BrokerEvents>)),
+    )
+    .unwrap();
+
+    let mut client = broker.connect().await.unwrap();
+    client
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+    client
+        .publish("topic".to_string(), None, b"hello".to_vec())
+        .await
+        .unwrap();
+    client.read_message().await.unwrap();
+    client.unsubscribe("topic".to_string()).await.unwrap();
+    client.shutdown().await.unwrap();
+
+    tokio::time::timeout(time::Duration::from_secs(5), async {
+        while events.events().len() < 5 {
+            tokio::time::delay_for(time::Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("Missing events: {:?}", events.events()));
+
+    assert_eq!(
+        vec![
+            "1 connected None",
+            "1 subscribed topic",
+            "1 published topic 5",
+            "1 unsubscribed topic",
+            "1 disconnected",
+        ],
+        events.events()
+    );
+}
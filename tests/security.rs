@@ -0,0 +1,404 @@
+// TLS, аутентификация по токенам, ACL и заголовки издателя.
+// Сценариям без сокета хватает брокера в памяти, остальные идут через common::Broker.
+mod common;
+
+use common::Broker;
+use std::time;
+use zaichik::broker::{self, spawn_in_memory_broker, AclRules, Authenticator, BrokerConfig};
+use zaichik::protocol;
+
+#[tokio::test]
+async fn test_consumer_sees_producer_headers() {
+    let broker = spawn_in_memory_broker(
+        BrokerConfig::default()
+            .auth(Some(Authenticator::from_spec("alice:secret").unwrap()))
+            .producer_headers(true),
+    )
+    .unwrap();
+
+    let connect = |token: &str| {
+        zaichik::Client::builder()
+            .token(token.to_string())
+            .connect_stream(broker.accept())
+    };
+
+    let mut consumer = connect("secret").await.unwrap();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+
+    // Заголовок с чужим пользователем, который прислал сам издатель, брокер заменяет.
+    let mut producer = connect("secret").await.unwrap();
+    let mut headers = std::collections::HashMap::new();
+    headers.insert(
+        protocol::PRODUCER_PRINCIPAL_HEADER.to_string(),
+        "mallory".to_string(),
+    );
+    producer
+        .publish_with_headers("topic".to_string(), None, vec![1], headers)
+        .await
+        .unwrap();
+    producer
+        .publish_batch("topic".to_string(), vec![(None, vec![2])])
+        .await
+        .unwrap();
+
+    for expected in &[1, 2] {
+        let received = tokio::time::timeout(time::Duration::from_secs(5), consumer.read_message())
+            .await
+            .unwrap()
+            .unwrap();
+        match received {
+            Some(zaichik::ZaichikFrame::Publish {
+                id,
+                payload,
+                headers,
+                ..
+            }) => {
+                assert_eq!(vec![*expected], payload);
+                assert_eq!(
+                    Some("alice"),
+                    headers
+                        .get(protocol::PRODUCER_PRINCIPAL_HEADER)
+                        .map(String::as_str)
+                );
+                // Адреса подключений in-memory брокера отличаются только портом.
+                let peer = headers.get(protocol::PRODUCER_PEER_HEADER).unwrap();
+                assert!(peer.starts_with("127.0.0.1:"), "{}", peer);
+                consumer.commit(id).await.unwrap();
+            }
+            other => panic!("Expected published message, got {:?}", other),
+        }
+    }
+
+    // Без настройки брокер заголовки не добавляет.
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+    let mut consumer = broker.connect().await.unwrap();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .publish("topic".to_string(), None, vec![3])
+        .await
+        .unwrap();
+    match consumer.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Publish { headers, .. }) => {
+            assert!(headers.is_empty(), "{:?}", headers)
+        }
+        other => panic!("Expected published message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_publish_and_consume_over_tls() {
+    let fixture = |name: &str| format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+
+    let broker = Broker::start_with(BrokerConfig::default().tls(Some(
+        broker::load_tls_acceptor(&fixture("server.pem"), &fixture("server.key")).unwrap(),
+    )))
+    .await;
+
+    // Сертификат брокера подписан тестовым CA, которому мы и доверяем.
+    let mut root_store = zaichik::rustls::RootCertStore::empty();
+    let mut ca = std::io::BufReader::new(std::fs::File::open(fixture("ca.pem")).unwrap());
+    root_store.add_pem_file(&mut ca).unwrap();
+
+    let mut client = zaichik::Client::connect_tls(&broker.addr(), "localhost", root_store)
+        .await
+        .unwrap();
+
+    client.subscribe_on("topic".to_string()).await.unwrap();
+    client
+        .publish("topic".to_string(), None, vec![1, 2, 3])
+        .await
+        .unwrap();
+
+    let received = tokio::time::timeout(time::Duration::from_secs(5), client.read_message())
+        .await
+        .unwrap()
+        .unwrap();
+
+    match received {
+        Some(zaichik::ZaichikFrame::Publish { payload, .. }) => {
+            assert_eq!(vec![1, 2, 3], payload)
+        }
+        other => panic!("Expected published message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_authentication_with_known_token() {
+    let broker = Broker::start_with(
+        BrokerConfig::default().auth(Some(Authenticator::from_spec("alice:secret").unwrap())),
+    )
+    .await;
+
+    let mut client = broker.connect_with_token("secret").await.unwrap();
+
+    client.subscribe_on("topic".to_string()).await.unwrap();
+    client
+        .publish("topic".to_string(), None, vec![1])
+        .await
+        .unwrap();
+
+    match client.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Publish { payload, .. }) => assert_eq!(vec![1], payload),
+        other => panic!("Expected published message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_authentication_with_unknown_token() {
+    let broker = Broker::start_with(
+        BrokerConfig::default().auth(Some(Authenticator::from_spec("alice:secret").unwrap())),
+    )
+    .await;
+
+    let rejected = broker.connect_with_token("wrong").await.err().unwrap();
+    let rejected = rejected.downcast_ref::<zaichik::BrokerError>().unwrap();
+
+    assert_eq!(
+        zaichik::protocol::ERROR_AUTHENTICATION_FAILED,
+        rejected.code
+    );
+}
+
+#[tokio::test]
+async fn test_connection_without_authentication_is_closed() {
+    let broker = Broker::start_with(
+        BrokerConfig::default().auth(Some(Authenticator::from_spec("alice:secret").unwrap())),
+    )
+    .await;
+
+    // Handshake проходит, но вместо Authenticate клиент сразу подписывается.
+    let mut client = broker.connect().await;
+    client.subscribe_on("topic".to_string()).await.unwrap();
+
+    match client.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Error { code, .. }) => {
+            assert_eq!(zaichik::protocol::ERROR_AUTHENTICATION_REQUIRED, code)
+        }
+        other => panic!("Expected error frame, got {:?}", other),
+    }
+    assert!(client.read_message().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_acl_separates_read_and_write_access() {
+    let broker = Broker::start_with(
+        BrokerConfig::default()
+            .auth(Some(
+                Authenticator::from_spec("reader:reader-token,writer:writer-token").unwrap(),
+            ))
+            .acl(AclRules::from_spec("orders:read:reader;orders:write:writer").unwrap()),
+    )
+    .await;
+
+    let mut reader = broker.connect_with_token("reader-token").await.unwrap();
+    let mut writer = broker.connect_with_token("writer-token").await.unwrap();
+
+    let expect_access_denied = |frame: Option<zaichik::ZaichikFrame>| match frame {
+        Some(zaichik::ZaichikFrame::Error { code, .. }) => {
+            assert_eq!(zaichik::protocol::ERROR_ACCESS_DENIED, code)
+        }
+        other => panic!("Expected access denied, got {:?}", other),
+    };
+
+    // Читатель может подписаться, но не может писать.
+    reader.subscribe_on("orders".to_string()).await.unwrap();
+    reader
+        .publish("orders".to_string(), None, vec![0])
+        .await
+        .unwrap();
+    expect_access_denied(reader.read_message().await.unwrap());
+
+    // Писатель может писать, но не может подписаться.
+    writer.subscribe_on("orders".to_string()).await.unwrap();
+    expect_access_denied(writer.read_message().await.unwrap());
+    writer
+        .publish("orders".to_string(), None, vec![1])
+        .await
+        .unwrap();
+
+    match reader.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Publish { payload, .. }) => assert_eq!(vec![1], payload),
+        other => panic!("Expected published message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_acl_denies_creating_and_deleting_topic_without_write_access() {
+    let broker = Broker::start_with(
+        BrokerConfig::default()
+            .auth(Some(
+                Authenticator::from_spec("reader:reader-token,writer:writer-token").unwrap(),
+            ))
+            .acl(AclRules::from_spec("orders:read:reader;orders:write:writer").unwrap()),
+    )
+    .await;
+
+    let mut reader = broker.connect_with_token("reader-token").await.unwrap();
+    let mut writer = broker.connect_with_token("writer-token").await.unwrap();
+
+    // Читатель не может создать топик.
+    let error = reader
+        .create_topic(
+            "orders".to_string(),
+            0,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap_err();
+    let error = error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<zaichik::BrokerError>())
+        .unwrap();
+    assert_eq!(zaichik::protocol::ERROR_ACCESS_DENIED, error.code);
+    assert!(reader.list_topics().await.unwrap().is_empty());
+
+    // И не может удалить топик, который создал писатель.
+    writer
+        .create_topic(
+            "orders".to_string(),
+            0,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    reader.delete_topic("orders".to_string()).await.unwrap();
+    match reader.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Error { code, .. }) => {
+            assert_eq!(zaichik::protocol::ERROR_ACCESS_DENIED, code)
+        }
+        other => panic!("Expected access denied, got {:?}", other),
+    }
+    assert_eq!(
+        vec!["orders".to_string()],
+        reader.list_topics().await.unwrap()
+    );
+
+    // Писатель удаляет топик.
+    writer.delete_topic("orders".to_string()).await.unwrap();
+    assert!(writer.list_topics().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_subscribe_confirmed_keeps_errors_of_other_topics() {
+    let broker = Broker::start_with(
+        BrokerConfig::default()
+            .auth(Some(
+                Authenticator::from_spec("reader:reader-token").unwrap(),
+            ))
+            .acl(AclRules::from_spec("orders:write:writer").unwrap()),
+    )
+    .await;
+
+    let mut reader = broker.connect_with_token("reader-token").await.unwrap();
+
+    // Ошибка publish без ack приходит, пока клиент ждет подтверждения подписки
+    // на другой топик. Подписка не считает ее своей, и ошибка достается read_message.
+    reader
+        .publish("orders".to_string(), None, vec![0])
+        .await
+        .unwrap();
+    reader
+        .subscribe_with(
+            "events".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+
+    match reader.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Error { code, topic, .. }) => {
+            assert_eq!(zaichik::protocol::ERROR_ACCESS_DENIED, code);
+            assert_eq!(Some("orders".to_string()), topic);
+        }
+        other => panic!("Expected access denied, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_acl_denies_dead_letter_topic_without_write_access() {
+    let broker = Broker::start_with(
+        BrokerConfig::default()
+            .auth(Some(
+                Authenticator::from_spec("writer:writer-token,auditor:auditor-token").unwrap(),
+            ))
+            .acl(AclRules::from_spec("orders:write:writer;audit:write:auditor").unwrap()),
+    )
+    .await;
+
+    let mut writer = broker.connect_with_token("writer-token").await.unwrap();
+
+    // Иначе брокер сам опубликовал бы в audit сообщения, которые писатель не смог обработать.
+    writer
+        .set_dead_letter("orders".to_string(), 1, Some("audit".to_string()))
+        .await
+        .unwrap();
+    match writer.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Error { code, topic, .. }) => {
+            assert_eq!(zaichik::protocol::ERROR_ACCESS_DENIED, code);
+            assert_eq!(Some("audit".to_string()), topic);
+        }
+        other => panic!("Expected access denied, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_admin_connections_are_denied_to_non_admins() {
+    let broker = spawn_in_memory_broker(
+        BrokerConfig::default()
+            .auth(Some(
+                Authenticator::from_spec("app:app-token,ops:ops-token").unwrap(),
+            ))
+            .acl(AclRules::from_spec("orders:write:app;admin:ops").unwrap()),
+    )
+    .unwrap();
+
+    let connect = |token: &str| {
+        zaichik::Client::builder()
+            .token(token.to_string())
+            .connect_stream(broker.accept())
+    };
+    let mut app = connect("app-token").await.unwrap();
+    let mut ops = connect("ops-token").await.unwrap();
+
+    // Право писать в топик не делает пользователя админом.
+    let error = app.admin_connections().await.unwrap_err();
+    let error = error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<zaichik::BrokerError>())
+        .unwrap();
+    assert_eq!(zaichik::protocol::ERROR_ACCESS_DENIED, error.code);
+
+    assert_eq!(2, ops.admin_connections().await.unwrap().len());
+}
+
+#[tokio::test]
+async fn test_admin_connections_are_denied_without_admin_rules() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+    let mut client = broker.connect().await.unwrap();
+
+    let error = client.admin_connections().await.unwrap_err();
+    let error = error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<zaichik::BrokerError>())
+        .unwrap();
+    assert_eq!(zaichik::protocol::ERROR_ACCESS_DENIED, error.code);
+}
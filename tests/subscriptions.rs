@@ -0,0 +1,839 @@
+// Подписки: подтверждение, снимки, партиции, шаблоны, фильтры и лимиты.
+// Сценариям без сокета хватает брокера в памяти, остальные идут через common::Broker.
+mod common;
+
+use common::{handshake, read_available, read_publish, Broker};
+use futures::SinkExt;
+use std::time;
+use tokio::stream::StreamExt;
+use zaichik::broker::{spawn_in_memory_broker, BrokerConfig};
+use zaichik::protocol;
+
+#[tokio::test]
+async fn test_drain_subscription_ends_after_retained_messages() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    for payload in 1..=2 {
+        producer
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    let mut consumer = broker.connect().await.unwrap();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().drain(true),
+        )
+        .await
+        .unwrap();
+    consumer.list_topics().await.unwrap();
+
+    // Опубликовано уже после подписки, поэтому в снимок не попадает.
+    producer
+        .publish("topic".to_string(), None, vec![3])
+        .await
+        .unwrap();
+    producer.list_topics().await.unwrap();
+
+    for expected in 1..=2 {
+        let (id, payload) = read_publish(&mut consumer).await;
+        assert_eq!(vec![expected], payload);
+        consumer.commit(id).await.unwrap();
+    }
+    let received = tokio::time::timeout(time::Duration::from_secs(5), consumer.read_message())
+        .await
+        .unwrap()
+        .unwrap();
+    match received {
+        Some(zaichik::ZaichikFrame::EndOfStream { topic }) => assert_eq!("topic", topic),
+        other => panic!("Expected EndOfStream, got {:?}", other),
+    }
+
+    // Подписка снята, так что больше ничего не приходит.
+    let next =
+        tokio::time::timeout(time::Duration::from_millis(200), consumer.read_message()).await;
+    assert!(next.is_err());
+}
+
+#[tokio::test]
+async fn test_snapshot_subscription_delivers_latest_value_per_key() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_topic(
+            "config".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::KeyLatest,
+        )
+        .await
+        .unwrap();
+    let messages = vec![
+        (Some("timeout"), 1),
+        (Some("retries"), 2),
+        (Some("timeout"), 3),
+        (None, 4),
+        (Some("retries"), 5),
+        (None, 6),
+        (Some("timeout"), 7),
+    ];
+    for (key, payload) in messages {
+        producer
+            .publish("config".to_string(), key.map(str::to_string), vec![payload])
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    // Без live подписка заканчивается на снимке.
+    let mut snapshot = broker.connect().await.unwrap();
+    snapshot.set_prefetch(10).await.unwrap();
+    snapshot
+        .subscribe_with(
+            "config".to_string(),
+            zaichik::SubscribeOptions::new()
+                .start(zaichik::protocol::DeliveryStart::SnapshotOnly)
+                .drain(true),
+        )
+        .await
+        .unwrap();
+    for expected in &[5, 6, 7] {
+        let (_id, payload) = read_publish(&mut snapshot).await;
+        assert_eq!(vec![*expected], payload);
+    }
+    let received = tokio::time::timeout(time::Duration::from_secs(5), snapshot.read_message())
+        .await
+        .unwrap()
+        .unwrap();
+    match received {
+        Some(zaichik::ZaichikFrame::EndOfStream { topic }) => assert_eq!("config", topic),
+        other => panic!("Expected EndOfStream, got {:?}", other),
+    }
+
+    // С live после снимка приходят новые значения.
+    let mut live = broker.connect().await.unwrap();
+    live.set_prefetch(10).await.unwrap();
+    live.subscribe_with(
+        "config".to_string(),
+        zaichik::SubscribeOptions::new()
+            .start(zaichik::protocol::DeliveryStart::SnapshotOnly)
+            .drain(false),
+    )
+    .await
+    .unwrap();
+    live.list_topics().await.unwrap();
+    producer
+        .publish("config".to_string(), Some("retries".to_string()), vec![8])
+        .await
+        .unwrap();
+    for expected in &[5, 6, 7, 8] {
+        let (_id, payload) = read_publish(&mut live).await;
+        assert_eq!(vec![*expected], payload);
+    }
+}
+
+#[tokio::test]
+async fn test_partition_subscriptions_split_keys_between_partitions() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    let created = producer
+        .create_topic_with(
+            "topic".to_string(),
+            zaichik::TopicConfig {
+                retention_ttl: 60_000,
+                partitions: 2,
+                ..zaichik::TopicConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(2, created.partitions);
+    for payload in 0..20u8 {
+        let key = ["a", "b", "c", "d"][payload as usize % 4];
+        producer
+            .publish("topic".to_string(), Some(key.to_string()), vec![payload])
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    // Подписки на разные партиции вместе получают все сообщения, сообщения
+    // одного ключа приходят только в одну из них и в порядке публикации.
+    let mut received = Vec::new();
+    for partition in 0..2 {
+        let mut consumer = broker.connect().await.unwrap();
+        consumer
+            .subscribe_with(
+                "topic".to_string(),
+                zaichik::SubscribeOptions::new().partition(partition),
+            )
+            .await
+            .unwrap();
+
+        let mut payloads = Vec::new();
+        while let Ok(frame) =
+            tokio::time::timeout(time::Duration::from_millis(200), consumer.read_message()).await
+        {
+            match frame.unwrap() {
+                Some(zaichik::ZaichikFrame::Publish { id, payload, .. }) => {
+                    consumer.commit(id).await.unwrap();
+                    payloads.push(payload[0]);
+                }
+                other => panic!("Expected message, got {:?}", other),
+            }
+        }
+        received.push(payloads);
+    }
+
+    assert_eq!(20, received[0].len() + received[1].len());
+    for payloads in &received {
+        let mut sorted = payloads.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, *payloads);
+    }
+    let keys = |payloads: &Vec<u8>| -> std::collections::HashSet<u8> {
+        payloads.iter().map(|payload| payload % 4).collect()
+    };
+    assert!(keys(&received[0]).is_disjoint(&keys(&received[1])));
+
+    let mut consumer = broker.connect().await.unwrap();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().partition(2),
+        )
+        .await
+        .unwrap();
+    match consumer.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Error { code, .. }) => {
+            assert_eq!(zaichik::protocol::ERROR_INVALID_SUBSCRIPTION, code)
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_subscriptions_over_limit_are_rejected() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default().max_subscriptions(2)).unwrap();
+    let mut client = broker.connect().await.unwrap();
+    client.set_prefetch(10).await.unwrap();
+
+    for topic in &["a", "b"] {
+        client
+            .subscribe_with(
+                topic.to_string(),
+                zaichik::SubscribeOptions::new().confirm(true),
+            )
+            .await
+            .unwrap();
+    }
+    for topic in &["c", "logs.*"] {
+        let error = client
+            .subscribe_with(
+                topic.to_string(),
+                zaichik::SubscribeOptions::new().confirm(true),
+            )
+            .await
+            .unwrap_err();
+        let error = error
+            .get_ref()
+            .and_then(|error| error.downcast_ref::<zaichik::BrokerError>())
+            .unwrap();
+        assert_eq!(
+            zaichik::protocol::ERROR_TOO_MANY_SUBSCRIPTIONS,
+            error.code,
+            "{}",
+            topic
+        );
+    }
+    // Повторная подписка заменяет старую и лимит не занимает.
+    client
+        .subscribe_with(
+            "a".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+
+    // Подписки, сделанные до лимита, работают как раньше.
+    let mut producer = broker.connect().await.unwrap();
+    for topic in &["a", "b", "c"] {
+        producer
+            .publish(topic.to_string(), None, topic.as_bytes().to_vec())
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+    let mut payloads = read_available(&mut client)
+        .await
+        .into_iter()
+        .map(|(_id, payload)| payload)
+        .collect::<Vec<_>>();
+    payloads.sort();
+    assert_eq!(vec!["a", "b"], payloads);
+
+    // После отписки место освобождается.
+    client.unsubscribe("b".to_string()).await.unwrap();
+    client
+        .subscribe_with(
+            "c".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_broker_accepts_clients() {
+    let broker = Broker::start().await;
+    let mut client = broker.connect().await;
+
+    client.subscribe_on("topic".to_string()).await.unwrap();
+    client
+        .publish("topic".to_string(), None, vec![1, 2, 3])
+        .await
+        .unwrap();
+
+    let received = tokio::time::timeout(time::Duration::from_secs(5), client.read_message())
+        .await
+        .unwrap()
+        .unwrap();
+
+    match received {
+        Some(zaichik::ZaichikFrame::Publish { topic, payload, .. }) => {
+            assert_eq!("topic", topic);
+            assert_eq!(vec![1, 2, 3], payload);
+        }
+        other => panic!("Expected published message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_subscribe_on_deleted_topic_returns_error() {
+    let broker = Broker::start().await;
+    let mut client = broker.connect_raw().await;
+
+    client
+        .send(protocol::ZaichikFrame::CreateTopic {
+            topic: "topic".to_string(),
+            retention_ttl: 0,
+            compaction_window: 0,
+            retention_max_messages: 0,
+            retention_max_bytes: 0,
+            compaction_mode: protocol::CompactionMode::Dedup,
+            buffer_size: None,
+            delivery: protocol::DeliveryGuarantee::BestEffort,
+            key_source: protocol::KeySource::Explicit,
+            partitions: 0,
+            ordering: protocol::OrderingGuarantee::None,
+            max_message_bytes: 0,
+        })
+        .await
+        .unwrap();
+    match client.next().await {
+        Some(Ok(protocol::ZaichikFrame::TopicCreated { .. })) => {}
+        other => panic!("Expected TopicCreated, got {:?}", other),
+    }
+    client
+        .send(protocol::ZaichikFrame::DeleteTopic {
+            topic: "topic".to_string(),
+        })
+        .await
+        .unwrap();
+    client
+        .send(protocol::ZaichikFrame::Subscribe {
+            topic: "topic".to_string(),
+            group: None,
+            start: protocol::DeliveryStart::Earliest,
+            key_filter: None,
+            auto_ack: false,
+            from_offset: None,
+            max_messages_per_sec: None,
+            flush_policy: protocol::FlushPolicy::Immediate,
+            confirm: false,
+            drain: false,
+            deliver_expired: false,
+            partition: None,
+        })
+        .await
+        .unwrap();
+
+    let reply = tokio::time::timeout(time::Duration::from_secs(5), client.next())
+        .await
+        .unwrap()
+        .transpose()
+        .unwrap();
+
+    match reply {
+        Some(protocol::ZaichikFrame::Error { code, .. }) => {
+            assert_eq!(protocol::ERROR_TOPIC_DELETED, code)
+        }
+        other => panic!("Expected error frame, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_subscribed_arrives_before_retained_messages() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    producer
+        .publish("topic".to_string(), None, vec![1])
+        .await
+        .unwrap();
+    producer.list_topics().await.unwrap();
+
+    let mut client = handshake(broker.accept()).await;
+    client
+        .send(protocol::ZaichikFrame::Subscribe {
+            topic: "topic".to_string(),
+            group: None,
+            start: protocol::DeliveryStart::Earliest,
+            key_filter: None,
+            auto_ack: false,
+            from_offset: None,
+            max_messages_per_sec: None,
+            flush_policy: protocol::FlushPolicy::Immediate,
+            confirm: true,
+            drain: false,
+            deliver_expired: false,
+            partition: None,
+        })
+        .await
+        .unwrap();
+
+    let reply = tokio::time::timeout(time::Duration::from_secs(5), client.next())
+        .await
+        .unwrap()
+        .transpose()
+        .unwrap();
+    match reply {
+        Some(protocol::ZaichikFrame::Subscribed { topic }) => assert_eq!("topic", topic),
+        other => panic!("Expected Subscribed, got {:?}", other),
+    }
+    let reply = tokio::time::timeout(time::Duration::from_secs(5), client.next())
+        .await
+        .unwrap()
+        .transpose()
+        .unwrap();
+    match reply {
+        Some(protocol::ZaichikFrame::Publish { payload, .. }) => assert_eq!(vec![1], payload),
+        other => panic!("Expected published message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_subscribe_confirmed_returns_before_messages_or_error() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    producer
+        .publish("topic".to_string(), None, vec![1])
+        .await
+        .unwrap();
+    producer.list_topics().await.unwrap();
+
+    let mut consumer = broker.connect().await.unwrap();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+    let (_id, payload) = read_publish(&mut consumer).await;
+    assert_eq!(vec![1], payload);
+
+    let error = consumer
+        .subscribe_with(
+            String::new(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap_err();
+    let error = error
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<zaichik::BrokerError>())
+        .unwrap();
+    assert_eq!(protocol::ERROR_INVALID_TOPIC_NAME, error.code);
+}
+
+#[tokio::test]
+async fn test_pattern_subscription_follows_matching_topics() {
+    let broker = Broker::start().await;
+
+    async fn create(producer: &mut zaichik::Client, topic: &str) -> std::io::Result<()> {
+        producer
+            .create_topic(
+                topic.to_string(),
+                60_000,
+                0,
+                0,
+                0,
+                zaichik::protocol::CompactionMode::Dedup,
+            )
+            .await
+            .map(|_created| ())
+    }
+
+    let mut producer = broker.connect().await;
+    for topic in &["logs.app1", "logs", "metrics.app1"] {
+        create(&mut producer, topic).await.unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    let mut consumer = broker.connect().await;
+    consumer.set_prefetch(10).await.unwrap();
+    consumer.subscribe_on("logs.*".to_string()).await.unwrap();
+    consumer.list_topics().await.unwrap();
+
+    // Топик создан уже после подписки, но тоже подходит под шаблон.
+    create(&mut producer, "logs.app2").await.unwrap();
+    for topic in &["logs.app1", "logs", "metrics.app1", "logs.app2"] {
+        producer
+            .publish(topic.to_string(), None, topic.as_bytes().to_vec())
+            .await
+            .unwrap();
+    }
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        match consumer.read_message().await.unwrap() {
+            Some(zaichik::ZaichikFrame::Publish { topic, payload, .. }) => {
+                assert_eq!(topic.as_bytes(), &payload[..]);
+                received.push(topic);
+            }
+            other => panic!("Expected published message, got {:?}", other),
+        }
+    }
+    received.sort();
+    assert_eq!(vec!["logs.app1", "logs.app2"], received);
+
+    // Сообщения из logs и metrics.app1 не приходят.
+    let not_matched =
+        tokio::time::timeout(time::Duration::from_millis(100), consumer.read_message()).await;
+    assert!(not_matched.is_err());
+}
+
+#[tokio::test]
+async fn test_key_filter_forwards_only_matching_keys() {
+    let broker = Broker::start().await;
+
+    let mut consumer = broker.connect().await;
+    consumer.set_prefetch(10).await.unwrap();
+    consumer
+        .subscribe_with(
+            "orders".to_string(),
+            zaichik::SubscribeOptions::new().key_filter(vec!["eu".to_string(), "us".to_string()]),
+        )
+        .await
+        .unwrap();
+    consumer.list_topics().await.unwrap();
+
+    let mut producer = broker.connect().await;
+    let messages = vec![
+        (Some("eu".to_string()), vec![1]),
+        (Some("asia".to_string()), vec![2]),
+        (None, vec![3]),
+        (Some("us".to_string()), vec![4]),
+    ];
+    for (key, payload) in messages {
+        producer
+            .publish("orders".to_string(), key, payload)
+            .await
+            .unwrap();
+    }
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        match consumer.read_message().await.unwrap() {
+            Some(zaichik::ZaichikFrame::Publish { key, payload, .. }) => {
+                received.push((key.unwrap(), payload))
+            }
+            other => panic!("Expected published message, got {:?}", other),
+        }
+    }
+    assert_eq!(
+        vec![("eu".to_string(), vec![1]), ("us".to_string(), vec![4])],
+        received
+    );
+
+    // Сообщения с другим ключом и без ключа не приходят.
+    let filtered =
+        tokio::time::timeout(time::Duration::from_millis(100), consumer.read_message()).await;
+    assert!(filtered.is_err());
+}
+
+#[tokio::test]
+async fn test_auto_ack_subscriber_receives_live_messages_without_commits() {
+    let broker = Broker::start().await;
+
+    let mut producer = broker.connect().await;
+    producer
+        .create_topic_with_buffer_size(
+            "topic".to_string(),
+            0,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+            256,
+        )
+        .await
+        .unwrap();
+    producer
+        .publish("topic".to_string(), None, vec![0])
+        .await
+        .unwrap();
+    producer.list_topics().await.unwrap();
+
+    let mut consumer = broker.connect().await;
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new()
+                .start(zaichik::protocol::DeliveryStart::Latest)
+                .auto_ack(true),
+        )
+        .await
+        .unwrap();
+    consumer.list_topics().await.unwrap();
+
+    for payload in 1..=100 {
+        producer
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+
+    // Ни одного Commit не отправляем, а сохраненное до подписки сообщение не приходит.
+    for expected in 1..=100 {
+        let (id, payload) = read_publish(&mut consumer).await;
+        assert_eq!(0, id);
+        assert_eq!(vec![expected], payload);
+    }
+}
+
+#[tokio::test]
+async fn test_subscribe_from_offset_resumes_after_received_messages() {
+    let broker = Broker::start().await;
+
+    let mut producer = broker.connect().await;
+    producer
+        .create_topic(
+            "topic".to_string(),
+            0,
+            0,
+            10,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    for payload in 0..5 {
+        producer
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    // Потребитель уже обработал сообщения до offset 2 и переподключился.
+    let mut consumer = broker.connect().await;
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().from_offset(2),
+        )
+        .await
+        .unwrap();
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        let frame = tokio::time::timeout(time::Duration::from_secs(5), consumer.read_message())
+            .await
+            .unwrap()
+            .unwrap();
+        match frame {
+            Some(zaichik::ZaichikFrame::Publish {
+                id,
+                payload,
+                offset,
+                ..
+            }) => {
+                consumer.commit(id).await.unwrap();
+                received.push((offset, payload));
+            }
+            other => panic!("Expected published message, got {:?}", other),
+        }
+    }
+
+    assert_eq!(vec![(3, vec![3]), (4, vec![4])], received);
+}
+
+#[tokio::test]
+async fn test_rate_limited_subscription_stays_under_the_limit() {
+    let broker = Broker::start().await;
+
+    let mut producer = broker.connect().await;
+    producer
+        .create_topic(
+            "topic".to_string(),
+            0,
+            0,
+            100,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    for payload in 0..50 {
+        producer
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    // prefetch не мешает, так что скорость доставки задает только лимит.
+    let mut consumer = broker.connect().await;
+    consumer.set_prefetch(100).await.unwrap();
+    consumer
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().max_messages_per_sec(10),
+        )
+        .await
+        .unwrap();
+
+    let window = time::Duration::from_millis(1000);
+    let started_at = time::Instant::now();
+    let mut received = Vec::new();
+    while let Ok(frame) = tokio::time::timeout_at(
+        tokio::time::Instant::from_std(started_at + window),
+        consumer.read_message(),
+    )
+    .await
+    {
+        match frame.unwrap() {
+            Some(zaichik::ZaichikFrame::Publish { id, payload, .. }) => {
+                consumer.commit(id).await.unwrap();
+                received.push(payload[0]);
+            }
+            other => panic!("Expected published message, got {:?}", other),
+        }
+    }
+
+    // За секунду не больше 10 сообщений и еще одного на границе окна.
+    assert!(received.len() >= 5, "received {:?}", received);
+    assert!(received.len() <= 11, "received {:?}", received);
+    assert_eq!((0..received.len() as u8).collect::<Vec<_>>(), received);
+}
+
+#[tokio::test]
+async fn test_shutdown_leaves_group_before_returning() {
+    let broker = Broker::start().await;
+
+    let mut producer = broker.connect().await;
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+
+    let mut leaving = broker.connect().await;
+    leaving
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().group("group".to_string()),
+        )
+        .await
+        .unwrap();
+    leaving.shutdown().await.unwrap();
+
+    // Если бы брокер еще держал подписку ушедшего клиента, группа отдала бы ему
+    // часть сообщений, и оставшийся участник получил бы не все.
+    let mut staying = broker.connect().await;
+    staying.set_prefetch(10).await.unwrap();
+    staying
+        .subscribe_with(
+            "topic".to_string(),
+            zaichik::SubscribeOptions::new().group("group".to_string()),
+        )
+        .await
+        .unwrap();
+    staying.list_topics().await.unwrap();
+
+    for payload in 0..10 {
+        producer
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+
+    let received = tokio::time::timeout(
+        time::Duration::from_secs(5),
+        staying
+            .into_stream()
+            .take(10)
+            .map(|frame| match frame.unwrap() {
+                zaichik::ZaichikFrame::Publish { payload, .. } => payload,
+                other => panic!("Expected published message, got {:?}", other),
+            })
+            .collect::<Vec<_>>(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        (0..10).map(|payload| vec![payload]).collect::<Vec<_>>(),
+        received
+    );
+}
@@ -0,0 +1,697 @@
+// Топики: создание и его настройки, retention, compaction, лимиты и статистика.
+// Сценариям без сокета хватает брокера в памяти, остальные идут через common::Broker.
+mod common;
+
+use common::{read_publish, Broker};
+use std::time;
+use zaichik::broker::{spawn_in_memory_broker, BrokerConfig, LimitPolicy, TopicLimits};
+use zaichik::protocol;
+
+#[tokio::test]
+async fn test_topic_keeps_last_retained_messages() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            2,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    for payload in 1..=3 {
+        producer
+            .publish("topic".to_string(), None, vec![payload])
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    // retention_max_messages = 2, поэтому первое сообщение уже удалено.
+    let mut consumer = broker.connect().await.unwrap();
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    let (id, payload) = read_publish(&mut consumer).await;
+    assert_eq!(vec![2], payload);
+    consumer.commit(id).await.unwrap();
+    let (_id, payload) = read_publish(&mut consumer).await;
+    assert_eq!(vec![3], payload);
+}
+
+#[tokio::test]
+async fn test_dedup_compaction_drops_repeated_keys() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            60_000,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    let messages = vec![("a", 1), ("a", 2), ("b", 3)];
+    for (key, payload) in messages {
+        producer
+            .publish("topic".to_string(), Some(key.to_string()), vec![payload])
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    let mut consumer = broker.connect().await.unwrap();
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    let (id, payload) = read_publish(&mut consumer).await;
+    assert_eq!(vec![1], payload);
+    consumer.commit(id).await.unwrap();
+    let (_id, payload) = read_publish(&mut consumer).await;
+    assert_eq!(vec![3], payload);
+}
+
+#[tokio::test]
+async fn test_key_latest_compaction_keeps_last_value_per_key() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            60_000,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::KeyLatest,
+        )
+        .await
+        .unwrap();
+    let messages = vec![("a", 1), ("b", 2), ("a", 3)];
+    for (key, payload) in messages {
+        producer
+            .publish("topic".to_string(), Some(key.to_string()), vec![payload])
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    let mut consumer = broker.connect().await.unwrap();
+    consumer.set_prefetch(10).await.unwrap();
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    let (_id, first) = read_publish(&mut consumer).await;
+    let (_id, second) = read_publish(&mut consumer).await;
+    assert_eq!(vec![vec![2], vec![3]], vec![first, second]);
+}
+
+#[tokio::test]
+async fn test_key_latest_compaction_by_json_pointer() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    let created = producer
+        .create_topic_with(
+            "topic".to_string(),
+            zaichik::TopicConfig {
+                retention_ttl: 60_000,
+                compaction_mode: zaichik::protocol::CompactionMode::KeyLatest,
+                key_source: zaichik::protocol::KeySource::JsonPointer("/id".to_string()),
+                ..zaichik::TopicConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        zaichik::protocol::KeySource::JsonPointer("/id".to_string()),
+        created.key_source
+    );
+    let payloads = vec![
+        br#"{"id": "a", "v": 1}"#.to_vec(),
+        br#"{"id": "b", "v": 2}"#.to_vec(),
+        br#"{"id": "a", "v": 3}"#.to_vec(),
+    ];
+    for payload in payloads {
+        producer
+            .publish("topic".to_string(), None, payload)
+            .await
+            .unwrap();
+    }
+    producer.list_topics().await.unwrap();
+
+    let mut consumer = broker.connect().await.unwrap();
+    consumer.set_prefetch(10).await.unwrap();
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    let (_id, first) = read_publish(&mut consumer).await;
+    let (_id, second) = read_publish(&mut consumer).await;
+    assert_eq!(
+        vec![
+            br#"{"id": "b", "v": 2}"#.to_vec(),
+            br#"{"id": "a", "v": 3}"#.to_vec()
+        ],
+        vec![first, second]
+    );
+}
+
+#[tokio::test]
+async fn test_topic_settings_over_limits_are_rejected() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default().topic_limits(TopicLimits {
+        max_retention_ttl: 60_000,
+        max_compaction_window: 60_000,
+        policy: LimitPolicy::Reject,
+    }))
+    .unwrap();
+    let mut client = broker.connect().await.unwrap();
+
+    let error = client
+        .create_topic_with(
+            "topic".to_string(),
+            zaichik::TopicConfig {
+                retention_ttl: 10_000_000_000,
+                ..zaichik::TopicConfig::default()
+            },
+        )
+        .await
+        .unwrap_err();
+    let error = error
+        .get_ref()
+        .and_then(|error| error.downcast_ref::<zaichik::BrokerError>())
+        .unwrap();
+    assert_eq!(zaichik::protocol::ERROR_INVALID_TOPIC_SETTINGS, error.code);
+
+    // Publish, который создал бы такой топик, тоже отклоняется.
+    client
+        .publish_creating(
+            "topic".to_string(),
+            None,
+            vec![1],
+            zaichik::protocol::TopicConfig {
+                retention_ttl: 0,
+                compaction_window: 60_001,
+                retention_max_messages: 0,
+                retention_max_bytes: 0,
+                compaction_mode: zaichik::protocol::CompactionMode::Dedup,
+                buffer_size: None,
+                delivery: zaichik::protocol::DeliveryGuarantee::BestEffort,
+                key_source: zaichik::protocol::KeySource::Explicit,
+                partitions: 0,
+                ordering: zaichik::protocol::OrderingGuarantee::None,
+                max_message_bytes: 0,
+            },
+        )
+        .await
+        .unwrap();
+    match client.read_message().await.unwrap() {
+        Some(zaichik::ZaichikFrame::Error { code, .. }) => {
+            assert_eq!(zaichik::protocol::ERROR_INVALID_TOPIC_SETTINGS, code)
+        }
+        other => panic!("Expected error, got {:?}", other),
+    }
+
+    // Настройки на лимите принимаются.
+    let created = client
+        .create_topic_with(
+            "topic".to_string(),
+            zaichik::TopicConfig {
+                retention_ttl: 60_000,
+                compaction_window: 60_000,
+                ..zaichik::TopicConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(60_000, created.retention_ttl);
+    assert!(client
+        .list_topics()
+        .await
+        .unwrap()
+        .contains(&"topic".to_string()));
+}
+
+#[tokio::test]
+async fn test_topic_created_with_full_config() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut client = broker.connect().await.unwrap();
+    let created = client
+        .create_topic_with(
+            "topic".to_string(),
+            zaichik::TopicConfig {
+                retention_ttl: 60_000,
+                retention_max_messages: 2,
+                compaction_mode: zaichik::protocol::CompactionMode::KeyLatest,
+                buffer_size: Some(16),
+                delivery: zaichik::protocol::DeliveryGuarantee::Reliable,
+                ..zaichik::TopicConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        zaichik::CreatedTopic {
+            retention_ttl: 60_000,
+            compaction_window: 0,
+            retention_max_messages: 2,
+            retention_max_bytes: 0,
+            compaction_mode: zaichik::protocol::CompactionMode::KeyLatest,
+            buffer_size: 16,
+            delivery: zaichik::protocol::DeliveryGuarantee::Reliable,
+            key_source: zaichik::protocol::KeySource::Explicit,
+            partitions: 1,
+            ordering: zaichik::protocol::OrderingGuarantee::None,
+            max_message_bytes: 0,
+            already_existed: false,
+        },
+        created
+    );
+
+    // KeyLatest оставляет одно сообщение на ключ, а лимит - только два последних.
+    for (key, payload) in &[("a", 1), ("b", 2), ("a", 3), ("c", 4)] {
+        client
+            .publish("topic".to_string(), Some(key.to_string()), vec![*payload])
+            .await
+            .unwrap();
+    }
+
+    let stats = client.topic_stats("topic".to_string()).await.unwrap();
+    assert_eq!(2, stats.retained_count);
+    assert_eq!(2, stats.retained_bytes);
+}
+
+#[tokio::test]
+async fn test_publish_ack_reports_compacted_duplicate() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    producer
+        .create_topic(
+            "topic".to_string(),
+            0,
+            60_000,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+
+    let written = producer
+        .publish_acked("topic".to_string(), Some("key".to_string()), vec![1])
+        .await
+        .unwrap();
+    assert!(written.accepted);
+    assert!(!written.dropped_as_duplicate);
+
+    let duplicate = producer
+        .publish_acked("topic".to_string(), Some("key".to_string()), vec![2])
+        .await
+        .unwrap();
+    assert!(duplicate.accepted);
+    assert!(duplicate.dropped_as_duplicate);
+
+    // Отклоненная публикация - это PublishAck с причиной, а не фрейм Error.
+    let rejected = producer
+        .publish_acked(String::new(), None, vec![3])
+        .await
+        .unwrap();
+    assert!(!rejected.accepted);
+    assert!(rejected.reason.is_some());
+}
+
+#[tokio::test]
+async fn test_publish_ack_rejects_messages_over_topic_max_size() {
+    let broker = spawn_in_memory_broker(BrokerConfig::default()).unwrap();
+
+    let mut producer = broker.connect().await.unwrap();
+    let created = producer
+        .create_topic_with(
+            "control".to_string(),
+            zaichik::TopicConfig {
+                max_message_bytes: 4,
+                ..zaichik::TopicConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(4, created.max_message_bytes);
+
+    let mut consumer = broker.connect().await.unwrap();
+    consumer
+        .subscribe_with(
+            "control".to_string(),
+            zaichik::SubscribeOptions::new().confirm(true),
+        )
+        .await
+        .unwrap();
+
+    let rejected = producer
+        .publish_acked("control".to_string(), None, vec![0; 5])
+        .await
+        .unwrap();
+    assert!(!rejected.accepted);
+    assert!(rejected
+        .reason
+        .unwrap()
+        .contains("exceeds max message size 4"));
+
+    // Без ack об отказе сообщает фрейм Error, а батч с одним большим сообщением
+    // отклоняется целиком.
+    producer
+        .publish("control".to_string(), None, vec![0; 5])
+        .await
+        .unwrap();
+    producer
+        .publish_batch(
+            "control".to_string(),
+            vec![(None, vec![1]), (None, vec![0; 1024])],
+        )
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        match producer.read_message().await.unwrap() {
+            Some(zaichik::protocol::ZaichikFrame::Error { code, .. }) => {
+                assert_eq!(zaichik::protocol::ERROR_MESSAGE_TOO_LARGE, code)
+            }
+            other => panic!("Expected message too large error, got {:?}", other),
+        }
+    }
+
+    let accepted = producer
+        .publish_acked("control".to_string(), None, vec![1, 2, 3, 4])
+        .await
+        .unwrap();
+    assert!(accepted.accepted);
+
+    // Первым подписчик получает сообщение, которое уложилось в лимит, так что
+    // из отклоненного батча не опубликовано ничего.
+    let (id, payload) = read_publish(&mut consumer).await;
+    assert_eq!(vec![1, 2, 3, 4], payload);
+    consumer.commit(id).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_invalid_topic_names_are_rejected() {
+    let broker = Broker::start_with(BrokerConfig::default().max_topic_name_len(16)).await;
+
+    let mut client = broker.connect().await;
+
+    let created = client
+        .create_topic(
+            String::new(),
+            0,
+            0,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await;
+    assert!(created.is_err());
+
+    let long_name = "a".repeat(17);
+    client
+        .publish(long_name.clone(), None, vec![1])
+        .await
+        .unwrap();
+    client
+        .publish("bad\ntopic".to_string(), None, vec![1])
+        .await
+        .unwrap();
+    client.publish("a".repeat(16), None, vec![1]).await.unwrap();
+
+    for _ in 0..2 {
+        match client.read_message().await.unwrap() {
+            Some(zaichik::ZaichikFrame::Error { code, .. }) => {
+                assert_eq!(zaichik::protocol::ERROR_INVALID_TOPIC_NAME, code)
+            }
+            other => panic!("Expected error, got {:?}", other),
+        }
+    }
+
+    // Топик создается только для имени допустимой длины.
+    assert_eq!(vec!["a".repeat(16)], client.list_topics().await.unwrap());
+}
+
+#[tokio::test]
+async fn test_topic_stats_report_retained_messages_and_subscribers() {
+    let broker = Broker::start().await;
+
+    let mut client = broker.connect().await;
+    client
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            60_000,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+    client.subscribe_on("topic".to_string()).await.unwrap();
+
+    // Дубль ключа first не попадает в retained буфер.
+    for (key, payload) in &[
+        ("first", vec![1, 2]),
+        ("second", vec![3]),
+        ("first", vec![4]),
+    ] {
+        client
+            .publish("topic".to_string(), Some(key.to_string()), payload.clone())
+            .await
+            .unwrap();
+    }
+    client
+        .publish("topic".to_string(), None, vec![5, 6, 7])
+        .await
+        .unwrap();
+
+    let stats = client.topic_stats("topic".to_string()).await.unwrap();
+    assert_eq!(
+        zaichik::TopicStats {
+            retained_count: 3,
+            retained_bytes: 6,
+            compaction_keys: 2,
+            subscriber_count: 1,
+            expired_drops: 0,
+            published_messages: 4,
+            duplicate_drops: 1,
+            kept_messages: 3,
+        },
+        stats
+    );
+
+    let error = client.topic_stats("unknown".to_string()).await.unwrap_err();
+    let broker_error = error
+        .get_ref()
+        .and_then(|error| error.downcast_ref::<zaichik::BrokerError>())
+        .unwrap();
+    assert_eq!(protocol::ERROR_TOPIC_NOT_FOUND, broker_error.code);
+}
+
+#[tokio::test]
+async fn test_publish_to_new_topic_creates_it_with_given_settings() {
+    let broker = Broker::start().await;
+
+    let config = zaichik::protocol::TopicConfig {
+        retention_ttl: 0,
+        compaction_window: 0,
+        retention_max_messages: 10,
+        retention_max_bytes: 0,
+        compaction_mode: zaichik::protocol::CompactionMode::Dedup,
+        buffer_size: None,
+        delivery: zaichik::protocol::DeliveryGuarantee::BestEffort,
+        key_source: zaichik::protocol::KeySource::Explicit,
+        partitions: 0,
+        ordering: zaichik::protocol::OrderingGuarantee::None,
+        max_message_bytes: 0,
+    };
+    let mut producer = broker.connect().await;
+    producer
+        .publish_creating("topic".to_string(), None, vec![1], config.clone())
+        .await
+        .unwrap();
+    // У топика уже есть настройки, так что другой create_with их не меняет.
+    producer
+        .publish_creating(
+            "topic".to_string(),
+            None,
+            vec![2],
+            zaichik::protocol::TopicConfig {
+                retention_max_messages: 1,
+                ..config
+            },
+        )
+        .await
+        .unwrap();
+    producer.list_topics().await.unwrap();
+
+    // Подписчик, пришедший после публикации, получает сохраненные сообщения.
+    let mut consumer = broker.connect().await;
+    consumer.subscribe_on("topic".to_string()).await.unwrap();
+    let (id, first) = read_publish(&mut consumer).await;
+    consumer.commit(id).await.unwrap();
+    let (_id, second) = read_publish(&mut consumer).await;
+    assert_eq!((vec![1], vec![2]), (first, second));
+}
+
+#[tokio::test]
+async fn test_create_topic_returns_normalized_settings() {
+    // Размер буфера топиков, в CreateTopic которых он не указан.
+    let broker = Broker::start_with(BrokerConfig::default().topic_buffer_size(64)).await;
+
+    let mut client = broker.connect().await;
+    let created = client
+        .create_topic(
+            "topic".to_string(),
+            60_000,
+            0,
+            100,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        zaichik::CreatedTopic {
+            retention_ttl: 60_000,
+            compaction_window: 0,
+            retention_max_messages: 100,
+            retention_max_bytes: 0,
+            compaction_mode: zaichik::protocol::CompactionMode::Dedup,
+            buffer_size: 64,
+            delivery: zaichik::protocol::DeliveryGuarantee::BestEffort,
+            key_source: zaichik::protocol::KeySource::Explicit,
+            partitions: 1,
+            ordering: zaichik::protocol::OrderingGuarantee::None,
+            max_message_bytes: 0,
+            already_existed: false,
+        },
+        created
+    );
+
+    // Повторный CreateTopic с теми же настройками проходит, в том числе если размер
+    // буфера указан явно и совпадает с размером по умолчанию.
+    let existing = client
+        .create_topic_with_buffer_size(
+            "topic".to_string(),
+            60_000,
+            0,
+            100,
+            0,
+            zaichik::protocol::CompactionMode::Dedup,
+            64,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        zaichik::CreatedTopic {
+            already_existed: true,
+            ..created
+        },
+        existing
+    );
+
+    // С другими настройками брокер сообщает о конфликте и не трогает топик.
+    let error = client
+        .create_topic(
+            "topic".to_string(),
+            0,
+            5_000,
+            0,
+            0,
+            zaichik::protocol::CompactionMode::KeyLatest,
+        )
+        .await
+        .unwrap_err();
+    let broker_error = error
+        .get_ref()
+        .and_then(|error| error.downcast_ref::<zaichik::BrokerError>())
+        .unwrap();
+    assert_eq!(protocol::ERROR_TOPIC_SETTINGS_CONFLICT, broker_error.code);
+
+    let stats = client.topic_stats("topic".to_string()).await.unwrap();
+    assert_eq!(0, stats.compaction_keys);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_concurrent_create_delete_and_subscribe_keep_broker_running() {
+    const TOPICS: usize = 3;
+    const ROUNDS: usize = 50;
+
+    let broker = Broker::start().await;
+
+    let mut creators = Vec::new();
+    let mut deleters = Vec::new();
+    let mut subscribers = Vec::new();
+    for _ in 0..2 {
+        creators.push(broker.connect().await);
+        deleters.push(broker.connect().await);
+        subscribers.push(broker.connect().await);
+    }
+
+    let mut tasks = Vec::new();
+    for mut creator in creators {
+        tasks.push(tokio::spawn(async move {
+            for round in 0..ROUNDS {
+                creator
+                    .create_topic(
+                        format!("topic{}", round % TOPICS),
+                        0,
+                        0,
+                        0,
+                        0,
+                        zaichik::protocol::CompactionMode::Dedup,
+                    )
+                    .await
+                    .unwrap();
+            }
+        }));
+    }
+    for mut deleter in deleters {
+        tasks.push(tokio::spawn(async move {
+            for round in 0..ROUNDS {
+                deleter
+                    .delete_topic(format!("topic{}", round % TOPICS))
+                    .await
+                    .unwrap();
+            }
+            deleter.list_topics().await.unwrap();
+        }));
+    }
+    // Подписка на удаленный топик получает Error, а не роняет соединение.
+    for mut subscriber in subscribers {
+        tasks.push(tokio::spawn(async move {
+            for round in 0..ROUNDS {
+                subscriber
+                    .subscribe_on(format!("topic{}", round % TOPICS))
+                    .await
+                    .unwrap();
+            }
+            subscriber.list_topics().await.unwrap();
+        }));
+    }
+
+    let all_done = futures::future::join_all(tasks);
+    for result in tokio::time::timeout(time::Duration::from_secs(10), all_done)
+        .await
+        .unwrap()
+    {
+        result.unwrap();
+    }
+
+    // Брокер продолжает работать и для новых клиентов.
+    let mut consumer = broker.connect().await;
+    consumer.subscribe_on("after".to_string()).await.unwrap();
+    consumer.list_topics().await.unwrap();
+    let mut producer = broker.connect().await;
+    producer
+        .publish("after".to_string(), None, vec![1])
+        .await
+        .unwrap();
+    let (_id, payload) = read_publish(&mut consumer).await;
+    assert_eq!(vec![1], payload);
+}